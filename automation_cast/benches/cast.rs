@@ -0,0 +1,81 @@
+//! Compares casting with [`Cast`] to the usual `as_any` downcast
+#![feature(test)]
+
+extern crate test;
+
+use std::any::Any;
+
+use automation_cast::Cast;
+use test::{black_box, Bencher};
+
+trait Switch {
+    fn on(&self) -> bool;
+}
+
+trait Device: Cast<dyn Switch> {
+    fn as_any(&self) -> &dyn Any;
+}
+
+struct Light;
+
+impl Switch for Light {
+    fn on(&self) -> bool {
+        true
+    }
+}
+
+impl Device for Light {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+struct Sensor;
+
+impl Device for Sensor {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+fn devices() -> Vec<Box<dyn Device>> {
+    (0..100)
+        .map(|i| -> Box<dyn Device> {
+            if i % 2 == 0 {
+                Box::new(Light)
+            } else {
+                Box::new(Sensor)
+            }
+        })
+        .collect()
+}
+
+#[bench]
+fn cast(b: &mut Bencher) {
+    let devices = devices();
+    b.iter(|| {
+        black_box(&devices)
+            .iter()
+            .filter_map(|device| Cast::<dyn Switch>::cast(device))
+            .filter(|switch| switch.on())
+            .count()
+    });
+}
+
+// Needs to know every concrete type that implements the trait
+#[bench]
+fn as_any(b: &mut Bencher) {
+    let devices = devices();
+    b.iter(|| {
+        black_box(&devices)
+            .iter()
+            .filter_map(|device| {
+                device
+                    .as_any()
+                    .downcast_ref::<Light>()
+                    .map(|light| light as &dyn Switch)
+            })
+            .filter(|switch| switch.on())
+            .count()
+    });
+}
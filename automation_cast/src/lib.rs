@@ -6,6 +6,10 @@ use std::marker::Unsize;
 
 pub trait Cast<P: ?Sized> {
     fn cast(&self) -> Option<&P>;
+
+    fn has(&self) -> bool {
+        self.cast().is_some()
+    }
 }
 
 impl<D, P> Cast<P> for D
@@ -13,7 +17,7 @@ where
     P: ?Sized,
 {
     default fn cast(&self) -> Option<&P> {
-        None
+        self.fallback()
     }
 }
 
@@ -26,3 +30,155 @@ where
         Some(self)
     }
 }
+
+// Used when the type itself can not be cast. An impl of Cast for Box<T> would overlap with the
+// Unsize impl above, so boxes are handled here instead.
+trait Fallback<P: ?Sized> {
+    fn fallback(&self) -> Option<&P>;
+}
+
+impl<D, P> Fallback<P> for D
+where
+    P: ?Sized,
+{
+    default fn fallback(&self) -> Option<&P> {
+        None
+    }
+}
+
+// Makes casting a Box<dyn Trait> look at the boxed value instead of the box itself
+impl<T, P> Fallback<P> for Box<T>
+where
+    T: Cast<P> + ?Sized,
+    P: ?Sized,
+{
+    fn fallback(&self) -> Option<&P> {
+        (**self).cast()
+    }
+}
+
+/// Consuming version of [`Cast`], reuses the allocation of the box
+pub trait CastBox<P: ?Sized> {
+    fn cast_box(self: Box<Self>) -> Option<Box<P>>;
+}
+
+impl<D, P> CastBox<P> for D
+where
+    P: ?Sized,
+{
+    default fn cast_box(self: Box<Self>) -> Option<Box<P>> {
+        None
+    }
+}
+
+impl<D, P> CastBox<P> for D
+where
+    D: Unsize<P>,
+    P: ?Sized,
+{
+    fn cast_box(self: Box<Self>) -> Option<Box<P>> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    trait Named {
+        fn name(&self) -> &str;
+    }
+
+    trait Switch {
+        fn on(&self) -> bool;
+    }
+
+    trait Device:
+        Cast<dyn Named> + Cast<dyn Switch> + CastBox<dyn Named> + CastBox<dyn Switch>
+    {
+    }
+
+    struct Light;
+
+    impl Named for Light {
+        fn name(&self) -> &str {
+            "light"
+        }
+    }
+
+    impl Switch for Light {
+        fn on(&self) -> bool {
+            true
+        }
+    }
+
+    impl Device for Light {}
+
+    struct Sensor;
+
+    impl Named for Sensor {
+        fn name(&self) -> &str {
+            "sensor"
+        }
+    }
+
+    impl Device for Sensor {}
+
+    #[test]
+    fn concrete() {
+        let named: Option<&dyn Named> = Light.cast();
+        assert_eq!(named.map(Named::name), Some("light"));
+
+        assert!(Cast::<dyn Switch>::has(&Light));
+        assert!(!Cast::<dyn Switch>::has(&Sensor));
+    }
+
+    #[test]
+    fn boxed_concrete() {
+        let light = Box::new(Light);
+        let switch: Option<&dyn Switch> = light.cast();
+        assert_eq!(switch.map(Switch::on), Some(true));
+
+        let sensor = Box::new(Sensor);
+        assert!(Cast::<dyn Switch>::cast(&sensor).is_none());
+    }
+
+    #[test]
+    fn boxed_dyn() {
+        let devices: Vec<Box<dyn Device>> = vec![Box::new(Light), Box::new(Sensor)];
+
+        // Goes through the Box, not the trait object
+        let names: Vec<_> = devices
+            .iter()
+            .filter_map(|device| Cast::<dyn Named>::cast(device))
+            .map(Named::name)
+            .collect();
+        assert_eq!(names, ["light", "sensor"]);
+
+        let switches = devices
+            .iter()
+            .filter(|device| Cast::<dyn Switch>::has(*device))
+            .count();
+        assert_eq!(switches, 1);
+
+        // Boxes of boxes are unwrapped all the way
+        let nested = Box::new(Box::new(Light) as Box<dyn Device>);
+        assert!(Cast::<dyn Switch>::has(&nested));
+    }
+
+    #[test]
+    fn cast_box() {
+        let light: Box<dyn Device> = Box::new(Light);
+        let address = &*light as *const dyn Device as *const ();
+        let switch: Box<dyn Switch> = light.cast_box().unwrap();
+        assert!(switch.on());
+        // The allocation is reused
+        assert_eq!(&*switch as *const dyn Switch as *const (), address);
+
+        let sensor: Box<dyn Device> = Box::new(Sensor);
+        assert!(CastBox::<dyn Switch>::cast_box(sensor).is_none());
+
+        let named: Option<Box<dyn Named>> = Box::new(Sensor).cast_box();
+        assert_eq!(named.unwrap().name(), "sensor");
+    }
+}
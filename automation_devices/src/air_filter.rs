@@ -1,33 +1,126 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
 use async_trait::async_trait;
+use automation_lib::action_callback::ActionCallback;
 use automation_lib::config::InfoConfig;
-use automation_lib::device::{Device, LuaDeviceCreate};
+use automation_lib::device::{
+    Device, DeviceHealth, DeviceLifecycle, HealthStatus, LuaDeviceCreate,
+};
+use automation_lib::helpers::weak::spawn_weak;
 use automation_macro::LuaDeviceConfig;
+use futures::StreamExt;
 use google_home::device::Name;
 use google_home::errors::ErrorCode;
 use google_home::traits::{
-    AvailableSpeeds, FanSpeed, HumiditySetting, OnOff, Speed, SpeedValue, TemperatureSetting,
-    TemperatureUnit,
+    AvailableSpeeds, FanSpeed, HumiditySetting, Mode, ModeSetting, ModeSettingValue, ModeValue,
+    Modes, OnOff, Speed, SpeedValue, TemperatureSetting, TemperatureUnit,
 };
 use google_home::types::Type;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tracing::{debug, trace};
+use tokio::sync::RwLock;
+use tokio::time::Instant;
+use tracing::{debug, trace, warn};
+
+fn default_hysteresis_pm25() -> f32 {
+    2.0
+}
+
+fn default_interval_seconds() -> u64 {
+    60
+}
+
+fn default_override_seconds() -> u64 {
+    30 * 60
+}
+
+// The fan is turned off at or below the target, and runs at low, medium or high above the target,
+// medium and high threshold respectively
+#[derive(Debug, Clone, Deserialize)]
+pub struct AutoModeConfig {
+    pub target_pm25: f32,
+    pub medium_pm25: f32,
+    pub high_pm25: f32,
+    // How far the PM2.5 has to drop below a threshold before the fan slows down again
+    #[serde(default = "default_hysteresis_pm25")]
+    pub hysteresis_pm25: f32,
+    #[serde(default = "default_interval_seconds")]
+    pub interval_seconds: u64,
+    // How long auto mode is suspended after the fan speed is changed manually
+    #[serde(default = "default_override_seconds")]
+    pub override_seconds: u64,
+}
+
+impl AutoModeConfig {
+    fn thresholds(&self) -> [f32; 3] {
+        [self.target_pm25, self.medium_pm25, self.high_pm25]
+    }
+
+    // Speed up as soon as a threshold is crossed, but only slow down when the PM2.5 is below the
+    // threshold by more than the hysteresis
+    fn next_level(&self, current: usize, pm25: f32) -> usize {
+        let up = self.thresholds().iter().filter(|t| pm25 > **t).count();
+        let down = self
+            .thresholds()
+            .iter()
+            .filter(|t| pm25 > **t - self.hysteresis_pm25)
+            .count();
+
+        if up > current {
+            up
+        } else if down < current {
+            down
+        } else {
+            current
+        }
+    }
+}
 
 #[derive(Debug, Clone, LuaDeviceConfig)]
 pub struct Config {
     #[device_config(flatten)]
     pub info: InfoConfig,
     pub url: String,
+    // Control the fan speed based on the PM2.5 reading, disabled if not set
+    #[device_config(default)]
+    pub auto_mode: Option<AutoModeConfig>,
+
+    // How often to read the sensors, polling is disabled if not set
+    #[device_config(rename("poll_interval_seconds"), default, with(|s: Option<u64>| s.map(Duration::from_secs)))]
+    pub poll_interval: Option<Duration>,
+    #[device_config(default)]
+    pub sensor_deltas: SensorDeltas,
+    // Called with the sensor readings when they have changed by more than the deltas
+    #[device_config(from_lua, default)]
+    pub sensor_callback: ActionCallback<AirFilter, SensorReading>,
+    // Called once the sensors could not be read this many times in a row
+    #[device_config(default(3))]
+    pub offline_after: u32,
+    #[device_config(from_lua, default)]
+    pub offline_callback: ActionCallback<AirFilter, ()>,
+}
+
+#[derive(Debug, Default)]
+struct AutoState {
+    enabled: bool,
+    // Auto mode is suspended until this moment because of a manual change
+    override_until: Option<Instant>,
 }
 
 #[derive(Debug, Clone)]
 pub struct AirFilter {
     config: Config,
+    auto: Arc<RwLock<AutoState>>,
 }
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Connection error")]
     ReqwestError(#[from] reqwest::Error),
+    #[error("Air filter does not report a fan direction")]
+    NoFanDirection,
 }
 
 impl From<Error> for google_home::errors::ErrorCode {
@@ -37,10 +130,105 @@ impl From<Error> for google_home::errors::ErrorCode {
             Error::ReqwestError(_) => {
                 Self::DeviceError(google_home::errors::DeviceError::DeviceOffline)
             }
+            Error::NoFanDirection => {
+                Self::DeviceError(google_home::errors::DeviceError::ActionNotAvailable)
+            }
         }
     }
 }
 
+// air_filter_types does not (yet) include the fan direction, so the messages are defined here
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+enum FanDirection {
+    Forward,
+    Reverse,
+}
+
+#[derive(Debug, Serialize)]
+struct SetFanDirection {
+    direction: FanDirection,
+}
+
+#[derive(Debug, Deserialize)]
+struct FanDirectionState {
+    direction: Option<FanDirection>,
+}
+
+// Same goes for the PM2.5 reading
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SensorReading {
+    // Degrees Celsius
+    pub temperature: f32,
+    // Relative humidity in percent
+    pub humidity: f32,
+    // μg/m³, not all air filters have a particulate matter sensor
+    pub pm25: Option<f32>,
+}
+
+fn default_temperature_delta() -> f32 {
+    0.5
+}
+
+fn default_humidity_delta() -> f32 {
+    1.0
+}
+
+fn default_pm25_delta() -> f32 {
+    1.0
+}
+
+// How much a value needs to change before the sensor callback is called again
+#[derive(Debug, Clone, Deserialize)]
+pub struct SensorDeltas {
+    #[serde(default = "default_temperature_delta")]
+    pub temperature: f32,
+    #[serde(default = "default_humidity_delta")]
+    pub humidity: f32,
+    #[serde(default = "default_pm25_delta")]
+    pub pm25: f32,
+}
+
+impl Default for SensorDeltas {
+    fn default() -> Self {
+        Self {
+            temperature: default_temperature_delta(),
+            humidity: default_humidity_delta(),
+            pm25: default_pm25_delta(),
+        }
+    }
+}
+
+impl SensorDeltas {
+    fn changed(&self, previous: &SensorReading, current: &SensorReading) -> bool {
+        let pm25 = match (previous.pm25, current.pm25) {
+            (Some(previous), Some(current)) => (current - previous).abs() >= self.pm25,
+            (None, None) => false,
+            _ => true,
+        };
+
+        pm25 || (current.temperature - previous.temperature).abs() >= self.temperature
+            || (current.humidity - previous.humidity).abs() >= self.humidity
+    }
+}
+
+// The poll interval doubles after every failure, up to this many times
+const MAX_BACKOFF_EXPONENT: u32 = 5;
+
+const SPEEDS: [air_filter_types::FanSpeed; 4] = [
+    air_filter_types::FanSpeed::Off,
+    air_filter_types::FanSpeed::Low,
+    air_filter_types::FanSpeed::Medium,
+    air_filter_types::FanSpeed::High,
+];
+
+fn speed_level(speed: &air_filter_types::FanSpeed) -> usize {
+    SPEEDS
+        .iter()
+        .position(|s| s == speed)
+        .expect("All speeds are listed")
+}
+
 // TODO: Handle error properly
 impl AirFilter {
     async fn set_fan_speed(&self, speed: air_filter_types::FanSpeed) -> Result<(), Error> {
@@ -52,6 +240,22 @@ impl AirFilter {
         Ok(())
     }
 
+    async fn set_fan_direction(&self, direction: FanDirection) -> Result<(), Error> {
+        let message = SetFanDirection { direction };
+        let url = format!("{}/state/fan", self.config.url);
+        let client = reqwest::Client::new();
+        client.put(url).json(&message).send().await?;
+
+        Ok(())
+    }
+
+    async fn get_fan_direction(&self) -> Result<FanDirection, Error> {
+        let url = format!("{}/state/fan", self.config.url);
+        let state: FanDirectionState = reqwest::get(url).await?.json().await?;
+
+        state.direction.ok_or(Error::NoFanDirection)
+    }
+
     async fn get_fan_state(&self) -> Result<air_filter_types::FanState, Error> {
         let url = format!("{}/state/fan", self.config.url);
         Ok(reqwest::get(url).await?.json().await?)
@@ -61,6 +265,120 @@ impl AirFilter {
         let url = format!("{}/state/sensor", self.config.url);
         Ok(reqwest::get(url).await?.json().await?)
     }
+
+    async fn get_reading(&self) -> Result<SensorReading, Error> {
+        let url = format!("{}/state/sensor", self.config.url);
+        Ok(reqwest::get(url).await?.json().await?)
+    }
+
+    pub async fn set_auto(&self, enabled: bool) {
+        debug!(id = Device::get_id(self), "Auto mode: {enabled}");
+        let mut auto = self.auto.write().await;
+        auto.enabled = enabled;
+        auto.override_until = None;
+    }
+
+    pub async fn is_auto(&self) -> bool {
+        self.auto.read().await.enabled
+    }
+
+    // Called when the fan is controlled by hand, auto mode backs off for a while
+    async fn manual_override(&self) {
+        let Some(auto_mode) = &self.config.auto_mode else {
+            return;
+        };
+
+        let mut auto = self.auto.write().await;
+        if auto.enabled {
+            debug!(id = Device::get_id(self), "Auto mode suspended");
+            auto.override_until =
+                Some(Instant::now() + Duration::from_secs(auto_mode.override_seconds));
+        }
+    }
+
+    async fn auto_tick(&self) -> Result<(), Error> {
+        let Some(auto_mode) = &self.config.auto_mode else {
+            return Ok(());
+        };
+
+        {
+            let auto = self.auto.read().await;
+            if !auto.enabled
+                || auto
+                    .override_until
+                    .is_some_and(|until| Instant::now() < until)
+            {
+                return Ok(());
+            }
+        }
+
+        let Some(pm25) = self.get_reading().await?.pm25 else {
+            trace!(id = Device::get_id(self), "No PM2.5 reading available");
+            return Ok(());
+        };
+
+        let current = speed_level(&self.get_fan_state().await?.speed);
+        let next = auto_mode.next_level(current, pm25);
+        if next != current {
+            debug!(
+                id = Device::get_id(self),
+                "PM2.5 is {pm25}, changing fan speed to {:?}", SPEEDS[next]
+            );
+            self.set_fan_speed(SPEEDS[next]).await?;
+        }
+
+        Ok(())
+    }
+
+    // Rebuilds the device in the background tasks, see automation_lib::helpers::weak
+    fn upgrade(config: &Config, auto: &Weak<RwLock<AutoState>>) -> Option<Self> {
+        Some(Self {
+            config: config.clone(),
+            auto: auto.upgrade()?,
+        })
+    }
+
+    async fn poll_loop(config: Config, auto: Weak<RwLock<AutoState>>, interval: Duration) {
+        let mut previous: Option<SensorReading> = None;
+        let mut failures = 0;
+        loop {
+            let backoff = 2u32.pow(failures.min(MAX_BACKOFF_EXPONENT));
+            tokio::time::sleep(interval * backoff).await;
+
+            let Some(air_filter) = Self::upgrade(&config, &auto) else {
+                break;
+            };
+
+            let reading = match air_filter.get_reading().await {
+                Ok(reading) => reading,
+                Err(err) => {
+                    failures += 1;
+                    warn!(
+                        id = Device::get_id(&air_filter),
+                        "Failed to read sensors ({failures} times): {err}"
+                    );
+
+                    if failures == config.offline_after {
+                        config.offline_callback.call(&air_filter, &()).await;
+                    }
+
+                    continue;
+                }
+            };
+            failures = 0;
+
+            if previous
+                .as_ref()
+                .is_some_and(|previous| !config.sensor_deltas.changed(previous, &reading))
+            {
+                continue;
+            }
+
+            trace!(id = Device::get_id(&air_filter), "Sensors: {reading:?}");
+            config.sensor_callback.call(&air_filter, &reading).await;
+            previous = Some(reading);
+        }
+    }
 }
 
 #[async_trait]
@@ -71,7 +389,64 @@ impl LuaDeviceCreate for AirFilter {
     async fn create(config: Self::Config) -> Result<Self, Self::Error> {
         trace!(id = config.info.identifier(), "Setting up AirFilter");
 
-        Ok(Self { config })
+        let auto = Arc::new(RwLock::new(AutoState {
+            enabled: config.auto_mode.is_some(),
+            override_until: None,
+        }));
+
+        if let Some(auto_mode) = &config.auto_mode {
+            let interval = Duration::from_secs(auto_mode.interval_seconds);
+            let ticks = futures::stream::repeat(()).then(move |_| tokio::time::sleep(interval));
+            let upgrade = {
+                let config = config.clone();
+                let auto = Arc::downgrade(&auto);
+                move || Self::upgrade(&config, &auto)
+            };
+
+            spawn_weak(upgrade, ticks, |air_filter, _| async move {
+                if let Err(err) = air_filter.auto_tick().await {
+                    warn!(id = Device::get_id(&air_filter), "Auto mode failed: {err}");
+                }
+            });
+        }
+
+        if let Some(interval) = config.poll_interval {
+            tokio::spawn(Self::poll_loop(
+                config.clone(),
+                Arc::downgrade(&auto),
+                interval,
+            ));
+        }
+
+        Ok(Self { config, auto })
+    }
+}
+
+#[async_trait]
+impl DeviceLifecycle for AirFilter {
+    async fn on_start(&self) {
+        // Fetch the state once so we know early on if the air filter can be reached
+        match self.get_fan_state().await {
+            Ok(state) => debug!(id = Device::get_id(self), "Fan state = {state:?}"),
+            Err(err) => warn!(
+                id = Device::get_id(self),
+                "Failed to get initial state: {err}"
+            ),
+        }
+    }
+}
+
+#[async_trait]
+impl DeviceHealth for AirFilter {
+    async fn check_health(&self) -> HealthStatus {
+        let url = format!("{}/state/sensor", self.config.url);
+        match reqwest::get(url).await {
+            Ok(res) if res.status().is_success() => HealthStatus::Healthy,
+            Ok(res) => {
+                HealthStatus::Degraded(format!("Status code is not success: {}", res.status()))
+            }
+            Err(err) => HealthStatus::Unhealthy(err.to_string()),
+        }
     }
 }
 
@@ -79,6 +454,10 @@ impl Device for AirFilter {
     fn get_id(&self) -> String {
         self.config.info.identifier()
     }
+
+    fn get_info(&self) -> Option<&InfoConfig> {
+        Some(&self.config.info)
+    }
 }
 
 #[async_trait]
@@ -88,7 +467,7 @@ impl google_home::Device for AirFilter {
     }
 
     fn get_device_name(&self) -> Name {
-        Name::new(&self.config.info.name)
+        self.config.info.device_name()
     }
 
     fn get_id(&self) -> String {
@@ -103,6 +482,10 @@ impl google_home::Device for AirFilter {
         self.config.info.room.as_deref()
     }
 
+    fn get_custom_data(&self) -> Option<serde_json::Value> {
+        self.config.info.custom_data()
+    }
+
     fn will_report_state(&self) -> bool {
         false
     }
@@ -116,6 +499,7 @@ impl OnOff for AirFilter {
 
     async fn set_on(&self, on: bool) -> Result<(), ErrorCode> {
         debug!("Turning on air filter: {on}");
+        self.manual_override().await;
 
         if on {
             self.set_fan_speed(air_filter_types::FanSpeed::High).await?;
@@ -129,6 +513,10 @@ impl OnOff for AirFilter {
 
 #[async_trait]
 impl FanSpeed for AirFilter {
+    fn reversible(&self) -> Option<bool> {
+        Some(true)
+    }
+
     fn available_fan_speeds(&self) -> AvailableSpeeds {
         AvailableSpeeds {
             speeds: vec![
@@ -191,10 +579,86 @@ impl FanSpeed for AirFilter {
             return Err(google_home::errors::DeviceError::TransientError.into());
         };
 
+        self.manual_override().await;
         self.set_fan_speed(speed).await?;
 
         Ok(())
     }
+
+    async fn current_fan_speed_direction(&self) -> Result<String, ErrorCode> {
+        let direction = match self.get_fan_direction().await? {
+            FanDirection::Forward => "FORWARD",
+            FanDirection::Reverse => "REVERSE",
+        };
+
+        Ok(direction.into())
+    }
+
+    async fn set_fan_speed_direction(&self, direction: String) -> Result<(), ErrorCode> {
+        debug!("Setting air filter direction: {direction}");
+
+        let direction = match direction.as_str() {
+            "FORWARD" => FanDirection::Forward,
+            "REVERSE" => FanDirection::Reverse,
+            _ => return Err(google_home::errors::DeviceError::TransientError.into()),
+        };
+
+        self.set_fan_direction(direction).await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Modes for AirFilter {
+    fn available_modes(&self) -> Vec<Mode> {
+        // Without auto mode there is nothing to choose from
+        if self.config.auto_mode.is_none() {
+            return Vec::new();
+        }
+
+        let setting = |name: &str, synonym: &str| ModeSetting {
+            setting_name: name.into(),
+            setting_values: vec![ModeSettingValue {
+                setting_synonym: vec![synonym.into()],
+                lang: "en".into(),
+            }],
+        };
+
+        vec![Mode {
+            name: "mode".into(),
+            name_values: vec![ModeValue {
+                name_synonym: vec!["Mode".into()],
+                lang: "en".into(),
+            }],
+            settings: vec![setting("auto", "Auto"), setting("manual", "Manual")],
+            ordered: false,
+        }]
+    }
+
+    async fn current_mode_settings(&self) -> Result<HashMap<String, String>, ErrorCode> {
+        if self.config.auto_mode.is_none() {
+            return Ok(HashMap::new());
+        }
+
+        let setting = if self.is_auto().await {
+            "auto"
+        } else {
+            "manual"
+        };
+
+        Ok(HashMap::from([("mode".into(), setting.into())]))
+    }
+
+    async fn set_mode(&self, mode: String, setting: String) -> Result<(), ErrorCode> {
+        match (mode.as_str(), setting.as_str()) {
+            ("mode", "auto") if self.config.auto_mode.is_some() => self.set_auto(true).await,
+            ("mode", "manual") => self.set_auto(false).await,
+            _ => return Err(google_home::errors::DeviceError::ActionNotAvailable.into()),
+        }
+
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -224,3 +688,213 @@ impl TemperatureSetting for AirFilter {
         Ok((10.0 * self.get_sensor_data().await?.temperature()).round() / 10.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use automation_lib::lua::testing::MockHttpServer;
+    use mlua::FromLua;
+    use serde_json::json;
+
+    use super::*;
+
+    fn auto_mode() -> AutoModeConfig {
+        AutoModeConfig {
+            target_pm25: 5.0,
+            medium_pm25: 15.0,
+            high_pm25: 35.0,
+            hysteresis_pm25: 2.0,
+            interval_seconds: 3600,
+            override_seconds: 1800,
+        }
+    }
+
+    fn config(server: &MockHttpServer) -> Config {
+        Config {
+            info: InfoConfig::new("Air Filter"),
+            url: server.url(),
+            auto_mode: None,
+            poll_interval: None,
+            sensor_deltas: Default::default(),
+            sensor_callback: Default::default(),
+            offline_after: 3,
+            offline_callback: Default::default(),
+        }
+    }
+
+    #[test]
+    fn next_level() {
+        let auto_mode = auto_mode();
+
+        assert_eq!(auto_mode.next_level(0, 4.0), 0);
+        assert_eq!(auto_mode.next_level(0, 20.0), 2);
+        assert_eq!(auto_mode.next_level(2, 40.0), 3);
+
+        // Hovering around the high threshold does not make the fan switch back and forth
+        assert_eq!(auto_mode.next_level(3, 34.0), 3);
+        assert_eq!(auto_mode.next_level(3, 36.0), 3);
+        assert_eq!(auto_mode.next_level(3, 32.0), 2);
+        assert_eq!(auto_mode.next_level(2, 34.0), 2);
+        assert_eq!(auto_mode.next_level(2, 2.0), 0);
+    }
+
+    fn speed(speed: air_filter_types::FanSpeed) -> serde_json::Value {
+        serde_json::to_value(air_filter_types::SetFanSpeed::new(speed)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn auto_mode_override() {
+        let server = MockHttpServer::start().await;
+        server
+            .respond_json(
+                "GET",
+                "/state/sensor",
+                json!({ "humidity": 50.0, "temperature": 20.0, "pm25": 20.0 }),
+            )
+            .await;
+        server
+            .respond_json("GET", "/state/fan", speed(air_filter_types::FanSpeed::Low))
+            .await;
+        server.respond_json("PUT", "/state/fan", json!({})).await;
+
+        let air_filter = AirFilter::create(Config {
+            auto_mode: Some(auto_mode()),
+            ..config(&server)
+        })
+        .await
+        .unwrap();
+        assert!(air_filter.is_auto().await);
+
+        air_filter.auto_tick().await.unwrap();
+        assert_eq!(
+            server.received_json("/state/fan").await,
+            vec![speed(air_filter_types::FanSpeed::Medium)]
+        );
+
+        // Changing the speed by hand suspends auto mode
+        FanSpeed::set_fan_speed(&air_filter, "high".into())
+            .await
+            .unwrap();
+        air_filter.auto_tick().await.unwrap();
+        assert_eq!(server.received_json("/state/fan").await.len(), 2);
+
+        // Selecting auto again resumes it right away
+        air_filter
+            .set_modes(HashMap::from([("mode".into(), "auto".into())]))
+            .await
+            .unwrap();
+        assert_eq!(
+            air_filter.current_mode_settings().await.unwrap()["mode"],
+            "auto"
+        );
+        air_filter.auto_tick().await.unwrap();
+        assert_eq!(
+            server.received_json("/state/fan").await,
+            vec![
+                speed(air_filter_types::FanSpeed::Medium),
+                speed(air_filter_types::FanSpeed::High),
+                speed(air_filter_types::FanSpeed::Medium),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn modes_without_auto_mode() {
+        let server = MockHttpServer::start().await;
+        let air_filter = AirFilter::create(config(&server)).await.unwrap();
+
+        assert!(air_filter.available_modes().is_empty());
+        assert!(air_filter.current_mode_settings().await.unwrap().is_empty());
+        assert_eq!(
+            air_filter
+                .set_modes(HashMap::from([("mode".into(), "auto".into())]))
+                .await,
+            Err(google_home::errors::DeviceError::ActionNotAvailable.into())
+        );
+    }
+
+    #[test]
+    fn sensor_deltas() {
+        let deltas = SensorDeltas::default();
+        let reading = SensorReading {
+            temperature: 20.0,
+            humidity: 50.0,
+            pm25: Some(10.0),
+        };
+
+        assert!(!deltas.changed(
+            &reading,
+            &SensorReading {
+                temperature: 20.4,
+                humidity: 50.9,
+                pm25: Some(10.9),
+            }
+        ));
+        assert!(deltas.changed(
+            &reading,
+            &SensorReading {
+                temperature: 20.5,
+                ..reading.clone()
+            }
+        ));
+        assert!(deltas.changed(
+            &reading,
+            &SensorReading {
+                pm25: None,
+                ..reading.clone()
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn sensor_polling() {
+        let lua = mlua::Lua::new();
+        lua.globals()
+            .set("readings", lua.create_table().unwrap())
+            .unwrap();
+        lua.globals().set("offline", 0).unwrap();
+        let sensor_callback = lua
+            .load("function(_, reading) table.insert(readings, reading.pm25) end")
+            .eval()
+            .unwrap();
+        let offline_callback = lua
+            .load("function() offline = offline + 1 end")
+            .eval()
+            .unwrap();
+
+        let server = MockHttpServer::start().await;
+        server
+            .respond_json(
+                "GET",
+                "/state/sensor",
+                json!({ "humidity": 50.0, "temperature": 20.0, "pm25": 12.0 }),
+            )
+            .await;
+        let offline_server = MockHttpServer::start().await;
+
+        let air_filter = AirFilter::create(Config {
+            poll_interval: Some(Duration::from_millis(10)),
+            sensor_callback: ActionCallback::from_lua(sensor_callback, &lua).unwrap(),
+            ..config(&server)
+        })
+        .await
+        .unwrap();
+        let offline_filter = AirFilter::create(Config {
+            poll_interval: Some(Duration::from_millis(1)),
+            offline_after: 2,
+            offline_callback: ActionCallback::from_lua(offline_callback, &lua).unwrap(),
+            ..config(&offline_server)
+        })
+        .await
+        .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // The reading does not change, so the callback is only called once
+        let readings: Vec<f32> = lua.globals().get("readings").unwrap();
+        assert_eq!(readings, vec![12.0]);
+        let offline: u32 = lua.globals().get("offline").unwrap();
+        assert_eq!(offline, 1);
+
+        drop((air_filter, offline_filter));
+    }
+}
@@ -0,0 +1,326 @@
+use std::sync::Arc;
+
+use bytes::{Buf, BufMut};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, SignatureScheme};
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const PORT: u16 = 8009;
+
+pub const SENDER_ID: &str = "sender-0";
+pub const RECEIVER_ID: &str = "receiver-0";
+
+pub const NS_CONNECTION: &str = "urn:x-cast:com.google.cast.tp.connection";
+pub const NS_HEARTBEAT: &str = "urn:x-cast:com.google.cast.tp.heartbeat";
+pub const NS_RECEIVER: &str = "urn:x-cast:com.google.cast.receiver";
+pub const NS_MEDIA: &str = "urn:x-cast:com.google.cast.media";
+
+// The receiver closes the connection when a message is larger than this
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+// Field numbers of the CastMessage protobuf
+const PROTOCOL_VERSION: u64 = 1;
+const SOURCE_ID: u64 = 2;
+const DESTINATION_ID: u64 = 3;
+const NAMESPACE: u64 = 4;
+const PAYLOAD_TYPE: u64 = 5;
+const PAYLOAD_UTF8: u64 = 6;
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_LENGTH_DELIMITED: u64 = 2;
+const WIRE_FIXED32: u64 = 5;
+
+// CASTV2_1_0 and STRING, the only values that are used for the receiver and media namespaces
+const PROTOCOL_VERSION_CASTV2_1_0: u64 = 0;
+const PAYLOAD_TYPE_STRING: u64 = 0;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Device did not respond in time")]
+    Timeout,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Tls(#[from] rustls::Error),
+    #[error("Invalid message: {0}")]
+    InvalidMessage(&'static str),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("Not connected to the device")]
+    NotConnected,
+    #[error("Nothing is playing on the device")]
+    NoMedia,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CastMessage {
+    pub source: String,
+    pub destination: String,
+    pub namespace: String,
+    pub payload: String,
+}
+
+impl CastMessage {
+    pub fn new(destination: &str, namespace: &str, payload: &serde_json::Value) -> Self {
+        Self {
+            source: SENDER_ID.into(),
+            destination: destination.into(),
+            namespace: namespace.into(),
+            payload: payload.to_string(),
+        }
+    }
+
+    pub fn payload<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        Ok(serde_json::from_str(&self.payload)?)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        put_varint(&mut buffer, PROTOCOL_VERSION << 3 | WIRE_VARINT);
+        put_varint(&mut buffer, PROTOCOL_VERSION_CASTV2_1_0);
+        put_string(&mut buffer, SOURCE_ID, &self.source);
+        put_string(&mut buffer, DESTINATION_ID, &self.destination);
+        put_string(&mut buffer, NAMESPACE, &self.namespace);
+        put_varint(&mut buffer, PAYLOAD_TYPE << 3 | WIRE_VARINT);
+        put_varint(&mut buffer, PAYLOAD_TYPE_STRING);
+        put_string(&mut buffer, PAYLOAD_UTF8, &self.payload);
+
+        buffer
+    }
+
+    fn decode(mut buffer: &[u8]) -> Result<Self, Error> {
+        let mut message = Self::default();
+
+        while buffer.has_remaining() {
+            let key = get_varint(&mut buffer)?;
+            let field = key >> 3;
+
+            match key & 0x7 {
+                WIRE_VARINT => {
+                    let value = get_varint(&mut buffer)?;
+                    if field == PAYLOAD_TYPE && value != PAYLOAD_TYPE_STRING {
+                        return Err(Error::InvalidMessage("Binary payloads are not supported"));
+                    }
+                }
+                WIRE_LENGTH_DELIMITED => {
+                    let length = get_varint(&mut buffer)? as usize;
+                    if buffer.remaining() < length {
+                        return Err(Error::InvalidMessage("Field is truncated"));
+                    }
+
+                    let value = &buffer[..length];
+                    buffer.advance(length);
+
+                    let target = match field {
+                        SOURCE_ID => &mut message.source,
+                        DESTINATION_ID => &mut message.destination,
+                        NAMESPACE => &mut message.namespace,
+                        PAYLOAD_UTF8 => &mut message.payload,
+                        _ => continue,
+                    };
+                    *target = String::from_utf8(value.to_vec())
+                        .or(Err(Error::InvalidMessage("Field is not valid UTF-8")))?;
+                }
+                WIRE_FIXED64 if buffer.remaining() >= 8 => buffer.advance(8),
+                WIRE_FIXED32 if buffer.remaining() >= 4 => buffer.advance(4),
+                _ => return Err(Error::InvalidMessage("Unsupported field")),
+            }
+        }
+
+        Ok(message)
+    }
+}
+
+fn put_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.put_u8(value as u8);
+}
+
+fn put_string(buffer: &mut Vec<u8>, field: u64, value: &str) {
+    put_varint(buffer, field << 3 | WIRE_LENGTH_DELIMITED);
+    put_varint(buffer, value.len() as u64);
+    buffer.put_slice(value.as_bytes());
+}
+
+fn get_varint(buffer: &mut &[u8]) -> Result<u64, Error> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        if !buffer.has_remaining() {
+            return Err(Error::InvalidMessage("Varint is truncated"));
+        }
+
+        let byte = buffer.get_u8();
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(Error::InvalidMessage("Varint is too long"))
+}
+
+// Every message is prefixed with its length as a big endian u32, returns None if the connection
+// was closed in between messages
+pub async fn read_message<R>(reader: &mut R) -> Result<Option<CastMessage>, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let length = match reader.read_u32().await {
+        Ok(length) => length as usize,
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    if length > MAX_MESSAGE_SIZE {
+        return Err(Error::InvalidMessage("Message is too large"));
+    }
+
+    let mut buffer = vec![0; length];
+    reader.read_exact(&mut buffer).await?;
+
+    CastMessage::decode(&buffer).map(Some)
+}
+
+pub async fn write_message<W>(writer: &mut W, message: &CastMessage) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let message = message.encode();
+    let mut buffer = Vec::with_capacity(4 + message.len());
+    buffer.put_u32(message.len() as u32);
+    buffer.put_slice(&message);
+
+    // Written in one go, so the message is not split over multiple TLS records
+    writer.write_all(&buffer).await?;
+    writer.flush().await?;
+
+    Ok(())
+}
+
+// Cast devices use a self-signed certificate, the signatures are still checked to make sure the
+// handshake itself is valid
+#[derive(Debug)]
+struct AcceptAnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+pub fn tls_config() -> Result<Arc<ClientConfig>, Error> {
+    let provider = Arc::new(ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate(provider)))
+        .with_no_client_auth();
+
+    Ok(Arc::new(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn ping() -> CastMessage {
+        CastMessage::new(RECEIVER_ID, NS_HEARTBEAT, &json!({ "type": "PING" }))
+    }
+
+    #[test]
+    fn encode() {
+        let mut expected = Vec::new();
+        expected.extend_from_slice(b"\x08\x00");
+        expected.extend_from_slice(b"\x12\x08sender-0");
+        expected.extend_from_slice(b"\x1a\x0areceiver-0");
+        expected.extend_from_slice(b"\x22\x27urn:x-cast:com.google.cast.tp.heartbeat");
+        expected.extend_from_slice(b"\x28\x00");
+        expected.extend_from_slice(b"\x32\x0f{\"type\":\"PING\"}");
+
+        assert_eq!(ping().encode(), expected);
+        assert_eq!(CastMessage::decode(&expected).unwrap(), ping());
+    }
+
+    #[test]
+    fn decode_invalid() {
+        let encoded = ping().encode();
+        assert!(matches!(
+            CastMessage::decode(&encoded[..encoded.len() - 1]),
+            Err(Error::InvalidMessage(_))
+        ));
+
+        let mut binary = encoded.clone();
+        let payload_type = encoded.len() - 19;
+        assert_eq!(binary[payload_type..payload_type + 2], [0x28, 0x00]);
+        binary[payload_type + 1] = 0x01;
+        assert!(matches!(
+            CastMessage::decode(&binary),
+            Err(Error::InvalidMessage("Binary payloads are not supported"))
+        ));
+    }
+
+    #[test]
+    fn tls() {
+        assert!(tls_config().is_ok());
+    }
+
+    #[tokio::test]
+    async fn framing() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        write_message(&mut client, &ping()).await.unwrap();
+        drop(client);
+
+        assert_eq!(read_message(&mut server).await.unwrap(), Some(ping()));
+        assert_eq!(read_message(&mut server).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,744 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use automation_lib::action_callback::ActionCallback;
+use automation_lib::device::{Device, LuaDeviceCreate};
+use automation_macro::LuaDeviceConfig;
+use rustls::pki_types::ServerName;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+use tracing::{debug, trace, warn};
+
+use crate::cast_channel::{
+    self, read_message, write_message, CastMessage, Error, NS_CONNECTION, NS_HEARTBEAT, NS_MEDIA,
+    NS_RECEIVER, RECEIVER_ID,
+};
+
+// The receiver drops the connection if it does not receive a ping for a while
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// Delay before reconnecting, doubles after every failed attempt
+const RECONNECT_DELAY_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(60);
+// Cast devices regularly drop the connection, so failing to connect is only reported after this
+// many attempts in a row
+const WARN_AFTER_FAILURES: u32 = 5;
+
+#[derive(Debug, Clone, LuaDeviceConfig)]
+pub struct Config {
+    pub identifier: String,
+    #[device_config(rename("ip"), with(|ip| SocketAddr::new(ip, cast_channel::PORT)))]
+    pub addr: SocketAddr,
+
+    #[device_config(from_lua, default)]
+    pub callback: ActionCallback<CastDevice, PlaybackState>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all(serialize = "lowercase", deserialize = "SCREAMING_SNAKE_CASE"))]
+pub enum PlayerState {
+    #[default]
+    Idle,
+    Playing,
+    Paused,
+    Buffering,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct PlaybackState {
+    pub state: PlayerState,
+    // Name of the app that is running on the device, e.g. 'Netflix'
+    pub app: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "SCREAMING_SNAKE_CASE")]
+enum HeartbeatMessage {
+    Ping,
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "SCREAMING_SNAKE_CASE")]
+enum ConnectionMessage {
+    Close,
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "SCREAMING_SNAKE_CASE")]
+enum ReceiverMessage {
+    ReceiverStatus {
+        status: ReceiverStatus,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReceiverStatus {
+    #[serde(default)]
+    applications: Vec<Application>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Application {
+    display_name: String,
+    transport_id: String,
+    #[serde(default)]
+    namespaces: Vec<Namespace>,
+    // The backdrop that is shown when nothing is running
+    #[serde(default)]
+    is_idle_screen: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct Namespace {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "SCREAMING_SNAKE_CASE")]
+enum MediaMessage {
+    MediaStatus {
+        status: Vec<MediaStatus>,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MediaStatus {
+    media_session_id: i64,
+    // Not included when only other parts of the status changed
+    player_state: Option<PlayerState>,
+}
+
+#[derive(Debug, Default)]
+struct Session {
+    // Only set while connected
+    tx: Option<mpsc::UnboundedSender<CastMessage>>,
+    // The app that supports the media namespace
+    transport_id: Option<String>,
+    media_session_id: Option<i64>,
+    request_id: u32,
+    playback: PlaybackState,
+}
+
+impl Session {
+    fn send(&mut self, destination: &str, namespace: &str, mut payload: serde_json::Value) {
+        self.request_id += 1;
+        payload["requestId"] = self.request_id.into();
+
+        if let Some(tx) = &self.tx {
+            // If this fails the connection is already closing
+            tx.send(CastMessage::new(destination, namespace, &payload))
+                .ok();
+        }
+    }
+
+    fn connect_media(&mut self, transport_id: Option<String>) {
+        if transport_id == self.transport_id {
+            return;
+        }
+
+        if let Some(transport_id) = &transport_id {
+            self.send(transport_id, NS_CONNECTION, json!({ "type": "CONNECT" }));
+            self.send(transport_id, NS_MEDIA, json!({ "type": "GET_STATUS" }));
+        }
+
+        self.transport_id = transport_id;
+        self.media_session_id = None;
+        self.playback.state = PlayerState::Idle;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CastDevice {
+    config: Config,
+    session: Arc<Mutex<Session>>,
+}
+
+impl CastDevice {
+    fn new(config: Config) -> Self {
+        Self {
+            config,
+            session: Default::default(),
+        }
+    }
+
+    pub fn playback_state(&self) -> PlaybackState {
+        self.session
+            .lock()
+            .expect("Lock should not be poisoned")
+            .playback
+            .clone()
+    }
+
+    pub fn play(&self) -> Result<(), Error> {
+        self.media_command("PLAY")
+    }
+
+    pub fn pause(&self) -> Result<(), Error> {
+        self.media_command("PAUSE")
+    }
+
+    pub fn stop(&self) -> Result<(), Error> {
+        self.media_command("STOP")
+    }
+
+    fn media_command(&self, command: &str) -> Result<(), Error> {
+        let mut session = self.session.lock().expect("Lock should not be poisoned");
+        if session.tx.is_none() {
+            return Err(Error::NotConnected);
+        }
+
+        let (Some(transport_id), Some(media_session_id)) =
+            (session.transport_id.clone(), session.media_session_id)
+        else {
+            return Err(Error::NoMedia);
+        };
+
+        debug!(id = self.get_id(), "{command}");
+        session.send(
+            &transport_id,
+            NS_MEDIA,
+            json!({ "type": command, "mediaSessionId": media_session_id }),
+        );
+
+        Ok(())
+    }
+
+    // Calls the callback if the playback state was changed by f
+    async fn update(&self, f: impl FnOnce(&mut Session)) {
+        let playback = {
+            let mut session = self.session.lock().expect("Lock should not be poisoned");
+            let previous = session.playback.clone();
+            f(&mut session);
+
+            if session.playback == previous {
+                return;
+            }
+
+            session.playback.clone()
+        };
+
+        debug!(id = self.get_id(), "Playback changed: {playback:?}");
+        self.config.callback.call(self, &playback).await;
+    }
+
+    async fn handle(&self, message: CastMessage) -> Result<(), Error> {
+        trace!(id = self.get_id(), "Received: {message:?}");
+
+        match message.namespace.as_str() {
+            NS_HEARTBEAT => {
+                if let HeartbeatMessage::Ping = message.payload()? {
+                    if let Some(tx) = &self.session.lock().expect("Lock should not be poisoned").tx
+                    {
+                        tx.send(CastMessage::new(
+                            &message.source,
+                            NS_HEARTBEAT,
+                            &json!({ "type": "PONG" }),
+                        ))
+                        .ok();
+                    }
+                }
+            }
+            NS_RECEIVER => {
+                let ReceiverMessage::ReceiverStatus { status } = message.payload()? else {
+                    return Ok(());
+                };
+
+                let app = status
+                    .applications
+                    .into_iter()
+                    .find(|app| !app.is_idle_screen);
+
+                self.update(|session| {
+                    session.playback.app = app.as_ref().map(|app| app.display_name.clone());
+                    session.connect_media(
+                        app.filter(|app| app.namespaces.iter().any(|ns| ns.name == NS_MEDIA))
+                            .map(|app| app.transport_id),
+                    );
+                })
+                .await;
+            }
+            NS_MEDIA => {
+                let MediaMessage::MediaStatus { status } = message.payload()? else {
+                    return Ok(());
+                };
+
+                self.update(|session| {
+                    // Status of an app that we are no longer following
+                    if session.transport_id.as_ref() != Some(&message.source) {
+                        return;
+                    }
+
+                    match status.first() {
+                        Some(status) => {
+                            session.media_session_id = Some(status.media_session_id);
+                            if let Some(state) = status.player_state {
+                                session.playback.state = state;
+                            }
+                        }
+                        None => {
+                            session.media_session_id = None;
+                            session.playback.state = PlayerState::Idle;
+                        }
+                    }
+                })
+                .await;
+            }
+            NS_CONNECTION => {
+                if let ConnectionMessage::Close = message.payload()? {
+                    self.update(|session| {
+                        if session.transport_id.as_ref() == Some(&message.source) {
+                            session.connect_media(None);
+                        }
+                    })
+                    .await;
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    // Rebuilds the device in the background tasks, see automation_lib::helpers::weak
+    fn upgrade(config: &Config, session: &Weak<Mutex<Session>>) -> Option<Self> {
+        Some(Self {
+            config: config.clone(),
+            session: session.upgrade()?,
+        })
+    }
+
+    async fn run<S>(config: &Config, session: &Weak<Mutex<Session>>, stream: S) -> Result<(), Error>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let Some(device) = Self::upgrade(config, session) else {
+            return Ok(());
+        };
+
+        let (mut reader, mut writer) = tokio::io::split(stream);
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let writer = tokio::spawn(async move {
+            while let Some(message) = rx.recv().await {
+                if write_message(&mut writer, &message).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let heartbeat = tokio::spawn({
+            let tx = tx.clone();
+            async move {
+                let mut interval = tokio::time::interval_at(
+                    Instant::now() + HEARTBEAT_INTERVAL,
+                    HEARTBEAT_INTERVAL,
+                );
+                loop {
+                    interval.tick().await;
+                    let ping =
+                        CastMessage::new(RECEIVER_ID, NS_HEARTBEAT, &json!({ "type": "PING" }));
+                    if tx.send(ping).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        {
+            let mut session = device.session.lock().expect("Lock should not be poisoned");
+            session.tx = Some(tx);
+            session.send(RECEIVER_ID, NS_CONNECTION, json!({ "type": "CONNECT" }));
+            session.send(RECEIVER_ID, NS_RECEIVER, json!({ "type": "GET_STATUS" }));
+        }
+        drop(device);
+
+        let result = loop {
+            // The device answers our pings, so it is gone if nothing is received for a while
+            let message =
+                match tokio::time::timeout(HEARTBEAT_INTERVAL * 3, read_message(&mut reader)).await
+                {
+                    Ok(Ok(Some(message))) => message,
+                    Ok(Ok(None)) => break Ok(()),
+                    Ok(Err(err)) => break Err(err),
+                    Err(_) => break Err(Error::Timeout),
+                };
+
+            // The device answers our pings, so this is checked regularly
+            let Some(device) = Self::upgrade(config, session) else {
+                break Ok(());
+            };
+
+            if let Err(err) = device.handle(message).await {
+                warn!(id = device.get_id(), "Failed to handle message: {err}");
+            }
+        };
+
+        heartbeat.abort();
+        writer.abort();
+
+        if let Some(session) = session.upgrade() {
+            let mut session = session.lock().expect("Lock should not be poisoned");
+            session.tx = None;
+            session.transport_id = None;
+            session.media_session_id = None;
+        }
+
+        result
+    }
+
+    async fn connect(&self) -> Result<TlsStream<TcpStream>, Error> {
+        let addr = self.config.addr;
+        let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+            .await
+            .or(Err(Error::Timeout))??;
+
+        let connector = TlsConnector::from(cast_channel::tls_config()?);
+        let stream = tokio::time::timeout(
+            CONNECT_TIMEOUT,
+            connector.connect(ServerName::IpAddress(addr.ip().into()), stream),
+        )
+        .await
+        .or(Err(Error::Timeout))??;
+
+        Ok(stream)
+    }
+
+    async fn reconnect_loop(config: Config, session: Weak<Mutex<Session>>) {
+        let mut delay = RECONNECT_DELAY_MIN;
+        let mut failures = 0;
+        loop {
+            let Some(device) = Self::upgrade(&config, &session) else {
+                break;
+            };
+
+            match device.connect().await {
+                Ok(stream) => {
+                    debug!(id = device.get_id(), "Connected");
+                    delay = RECONNECT_DELAY_MIN;
+                    failures = 0;
+                    drop(device);
+
+                    match Self::run(&config, &session, stream).await {
+                        Ok(()) => debug!(id = config.identifier, "Connection closed"),
+                        Err(err) => debug!(id = config.identifier, "Connection lost: {err}"),
+                    }
+                }
+                Err(err) => {
+                    failures += 1;
+                    if failures == WARN_AFTER_FAILURES {
+                        warn!(
+                            id = device.get_id(),
+                            "Failed to connect ({failures} times): {err}"
+                        );
+
+                        // Without a connection we can not know what is playing
+                        device
+                            .update(|session| {
+                                session.playback = PlaybackState::default();
+                            })
+                            .await;
+                    } else {
+                        debug!(id = device.get_id(), "Failed to connect: {err}");
+                    }
+                }
+            }
+
+            trace!(id = config.identifier, "Reconnecting in {delay:?}");
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(RECONNECT_DELAY_MAX);
+        }
+    }
+}
+
+#[async_trait]
+impl LuaDeviceCreate for CastDevice {
+    type Config = Config;
+    type Error = Infallible;
+
+    async fn create(config: Self::Config) -> Result<Self, Self::Error> {
+        trace!(id = config.identifier, "Setting up CastDevice");
+
+        let device = Self::new(config);
+        tokio::spawn(Self::reconnect_loop(
+            device.config.clone(),
+            Arc::downgrade(&device.session),
+        ));
+
+        Ok(device)
+    }
+}
+
+impl Device for CastDevice {
+    fn get_id(&self) -> String {
+        self.config.identifier.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{DuplexStream, ReadHalf, WriteHalf};
+
+    use super::*;
+
+    // The other end of the connection, pretending to be the device
+    struct FakeReceiver {
+        reader: ReadHalf<DuplexStream>,
+        writer: WriteHalf<DuplexStream>,
+    }
+
+    impl FakeReceiver {
+        async fn send(&mut self, source: &str, namespace: &str, payload: serde_json::Value) {
+            let message = CastMessage {
+                source: source.into(),
+                destination: cast_channel::SENDER_ID.into(),
+                namespace: namespace.into(),
+                payload: payload.to_string(),
+            };
+            write_message(&mut self.writer, &message).await.unwrap();
+        }
+
+        async fn recv(&mut self) -> (String, String, serde_json::Value) {
+            let message = read_message(&mut self.reader).await.unwrap().unwrap();
+            (
+                message.destination.clone(),
+                message.namespace.clone(),
+                message.payload().unwrap(),
+            )
+        }
+
+        async fn expect(
+            &mut self,
+            destination: &str,
+            namespace: &str,
+            kind: &str,
+        ) -> serde_json::Value {
+            let (actual_destination, actual_namespace, payload) = self.recv().await;
+            assert_eq!(
+                (actual_destination.as_str(), actual_namespace.as_str()),
+                (destination, namespace)
+            );
+            assert_eq!(payload["type"], kind);
+
+            payload
+        }
+
+        // Messages are handled in order, so once the pong arrives everything before the ping has
+        // been handled as well
+        async fn sync(&mut self) {
+            self.send(RECEIVER_ID, NS_HEARTBEAT, json!({ "type": "PING" }))
+                .await;
+            self.expect(RECEIVER_ID, NS_HEARTBEAT, "PONG").await;
+        }
+    }
+
+    fn connect(device: &CastDevice) -> FakeReceiver {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (reader, writer) = tokio::io::split(server);
+
+        tokio::spawn({
+            let config = device.config.clone();
+            let session = Arc::downgrade(&device.session);
+            async move { CastDevice::run(&config, &session, client).await }
+        });
+
+        FakeReceiver { reader, writer }
+    }
+
+    fn device() -> CastDevice {
+        CastDevice::new(Config {
+            identifier: "living/chromecast".into(),
+            addr: SocketAddr::new([127, 0, 0, 1].into(), cast_channel::PORT),
+            callback: Default::default(),
+        })
+    }
+
+    fn media_status(state: &str) -> serde_json::Value {
+        json!({
+            "type": "MEDIA_STATUS",
+            "requestId": 0,
+            "status": [{ "mediaSessionId": 1, "playerState": state, "currentTime": 12.5 }]
+        })
+    }
+
+    #[tokio::test]
+    async fn playback() {
+        let device = device();
+        let mut receiver = connect(&device);
+
+        receiver.expect(RECEIVER_ID, NS_CONNECTION, "CONNECT").await;
+        receiver
+            .expect(RECEIVER_ID, NS_RECEIVER, "GET_STATUS")
+            .await;
+
+        receiver
+            .send(
+                RECEIVER_ID,
+                NS_RECEIVER,
+                json!({
+                    "type": "RECEIVER_STATUS",
+                    "requestId": 2,
+                    "status": {
+                        "applications": [{
+                            "appId": "CA5E8412",
+                            "displayName": "Netflix",
+                            "isIdleScreen": false,
+                            "sessionId": "7d6c2f5a",
+                            "transportId": "web-5",
+                            "namespaces": [
+                                { "name": "urn:x-cast:com.google.cast.media" },
+                                { "name": "urn:x-cast:com.netflix.cast.media" }
+                            ]
+                        }],
+                        "volume": { "level": 0.5, "muted": false }
+                    }
+                }),
+            )
+            .await;
+        receiver.expect("web-5", NS_CONNECTION, "CONNECT").await;
+        receiver.expect("web-5", NS_MEDIA, "GET_STATUS").await;
+
+        receiver
+            .send("web-5", NS_MEDIA, media_status("PLAYING"))
+            .await;
+        receiver.sync().await;
+        assert_eq!(
+            device.playback_state(),
+            PlaybackState {
+                state: PlayerState::Playing,
+                app: Some("Netflix".into())
+            }
+        );
+
+        device.pause().unwrap();
+        let payload = receiver.expect("web-5", NS_MEDIA, "PAUSE").await;
+        assert_eq!(payload["mediaSessionId"], 1);
+
+        receiver
+            .send("web-5", NS_MEDIA, media_status("PAUSED"))
+            .await;
+        receiver.sync().await;
+        assert_eq!(device.playback_state().state, PlayerState::Paused);
+
+        // Back to the backdrop
+        receiver
+            .send(
+                RECEIVER_ID,
+                NS_RECEIVER,
+                json!({
+                    "type": "RECEIVER_STATUS",
+                    "requestId": 0,
+                    "status": {
+                        "applications": [{
+                            "appId": "E8C28D3C",
+                            "displayName": "Backdrop",
+                            "isIdleScreen": true,
+                            "sessionId": "1f2e3d4c",
+                            "transportId": "web-6",
+                            "namespaces": []
+                        }]
+                    }
+                }),
+            )
+            .await;
+        receiver.sync().await;
+        assert_eq!(device.playback_state(), PlaybackState::default());
+        assert!(matches!(device.play(), Err(Error::NoMedia)));
+    }
+
+    #[tokio::test]
+    async fn stops_with_device() {
+        // Nothing is listening on this port, so connecting fails straight away
+        let port = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let device = CastDevice::create(Config {
+            addr: SocketAddr::new([127, 0, 0, 1].into(), port),
+            ..device().config
+        })
+        .await
+        .unwrap();
+        let session = Arc::downgrade(&device.session);
+
+        drop(device);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(session.upgrade().is_none());
+    }
+
+    #[tokio::test]
+    async fn media_closed() {
+        let device = device();
+        let mut receiver = connect(&device);
+        receiver.expect(RECEIVER_ID, NS_CONNECTION, "CONNECT").await;
+        receiver
+            .expect(RECEIVER_ID, NS_RECEIVER, "GET_STATUS")
+            .await;
+
+        receiver
+            .send(
+                RECEIVER_ID,
+                NS_RECEIVER,
+                json!({
+                    "type": "RECEIVER_STATUS",
+                    "status": {
+                        "applications": [{
+                            "displayName": "YouTube",
+                            "transportId": "web-7",
+                            "namespaces": [{ "name": "urn:x-cast:com.google.cast.media" }]
+                        }]
+                    }
+                }),
+            )
+            .await;
+        receiver.expect("web-7", NS_CONNECTION, "CONNECT").await;
+        receiver.expect("web-7", NS_MEDIA, "GET_STATUS").await;
+        receiver
+            .send("web-7", NS_MEDIA, media_status("BUFFERING"))
+            .await;
+        receiver.sync().await;
+        assert_eq!(device.playback_state().state, PlayerState::Buffering);
+
+        // Status of the app that was playing before is ignored
+        receiver
+            .send("web-5", NS_MEDIA, media_status("PLAYING"))
+            .await;
+        receiver.sync().await;
+        assert_eq!(device.playback_state().state, PlayerState::Buffering);
+
+        receiver
+            .send("web-7", NS_CONNECTION, json!({ "type": "CLOSE" }))
+            .await;
+        receiver.sync().await;
+        assert_eq!(
+            device.playback_state(),
+            PlaybackState {
+                state: PlayerState::Idle,
+                app: Some("YouTube".into())
+            }
+        );
+
+        // Losing the connection is not an error
+        drop(receiver);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(matches!(device.stop(), Err(Error::NotConnected)));
+    }
+}
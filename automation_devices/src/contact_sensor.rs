@@ -1,17 +1,17 @@
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
 use automation_lib::action_callback::ActionCallback;
+use automation_lib::availability::{Availability, MqttDeviceStatus};
 use automation_lib::config::{InfoConfig, MqttDeviceConfig};
-use automation_lib::device::{Device, LuaDeviceCreate};
+use automation_lib::device::{Device, DeviceAvailability, LuaDeviceCreate};
 use automation_lib::error::DeviceConfigError;
 use automation_lib::event::{OnMqtt, OnPresence};
 use automation_lib::messages::{ContactMessage, PresenceMessage};
 use automation_lib::mqtt::WrappedAsyncClient;
 use automation_lib::presence::DEFAULT_PRESENCE;
 use automation_macro::LuaDeviceConfig;
-use google_home::device;
 use google_home::errors::{DeviceError, ErrorCode};
 use google_home::traits::OpenClose;
 use google_home::types::Type;
@@ -36,6 +36,17 @@ pub struct PresenceDeviceConfig {
     pub timeout: Duration,
 }
 
+#[derive(Debug, Clone, LuaDeviceConfig)]
+pub struct OpenWarningConfig {
+    #[device_config(with(Duration::from_secs))]
+    pub timeout: Duration,
+    #[device_config(default, with(|s: Option<u64>| s.map(Duration::from_secs)))]
+    pub repeat: Option<Duration>,
+    // Called with the amount of seconds the sensor has been open for
+    #[device_config(from_lua)]
+    pub callback: ActionCallback<ContactSensor, u64>,
+}
+
 #[derive(Debug, Clone, LuaDeviceConfig)]
 pub struct Config {
     #[device_config(flatten)]
@@ -44,12 +55,21 @@ pub struct Config {
     pub mqtt: MqttDeviceConfig,
     #[device_config(from_lua, default)]
     pub presence: Option<PresenceDeviceConfig>,
+    #[device_config(from_lua, default)]
+    pub open_warning: Option<OpenWarningConfig>,
 
     #[device_config(default(SensorType::Window))]
     pub sensor_type: SensorType,
 
     #[device_config(from_lua, default)]
     pub callback: ActionCallback<ContactSensor, bool>,
+    // Called when the sensor becomes available or unavailable
+    #[device_config(from_lua, default)]
+    pub availability_callback: ActionCallback<ContactSensor, bool>,
+    // Called every time the mqtt client (re)connects to the broker
+    #[device_config(from_lua, default)]
+    pub on_connect: ActionCallback<WrappedAsyncClient, ()>,
+
     #[device_config(from_lua)]
     pub client: WrappedAsyncClient,
 }
@@ -59,12 +79,14 @@ struct State {
     overall_presence: bool,
     is_closed: bool,
     handle: Option<JoinHandle<()>>,
+    open_warning_handle: Option<JoinHandle<()>>,
 }
 
 #[derive(Debug, Clone)]
 pub struct ContactSensor {
     config: Config,
     state: Arc<RwLock<State>>,
+    status: MqttDeviceStatus,
 }
 
 impl ContactSensor {
@@ -75,6 +97,41 @@ impl ContactSensor {
     async fn state_mut(&self) -> RwLockWriteGuard<State> {
         self.state.write().await
     }
+
+    async fn update_open_warning(&self, is_closed: bool) {
+        let Some(open_warning) = self.config.open_warning.clone() else {
+            return;
+        };
+
+        // Hold the lock while replacing the handle, so overlapping transitions can not leak tasks
+        let mut state = self.state_mut().await;
+        if let Some(handle) = state.open_warning_handle.take() {
+            handle.abort();
+        }
+
+        if is_closed {
+            return;
+        }
+
+        let device = self.clone();
+        state.open_warning_handle = Some(tokio::spawn(async move {
+            let opened = Instant::now();
+            let mut timeout = open_warning.timeout;
+
+            loop {
+                tokio::time::sleep(timeout).await;
+
+                let open_for = opened.elapsed().as_secs();
+                debug!(id = device.get_id(), "Sensor has been open for {open_for}s");
+                open_warning.callback.call(&device, &open_for).await;
+
+                match open_warning.repeat {
+                    Some(repeat) => timeout = repeat,
+                    None => break,
+                }
+            }
+        }));
+    }
 }
 
 #[async_trait]
@@ -89,15 +146,24 @@ impl LuaDeviceCreate for ContactSensor {
             .client
             .subscribe(&config.mqtt.topic, rumqttc::QoS::AtLeastOnce)
             .await?;
+        let status = MqttDeviceStatus::new(config.info.identifier(), &config.mqtt);
+        status.subscribe(&config.client).await?;
+
+        config.client.on_connect(config.on_connect.clone());
 
         let state = State {
             overall_presence: DEFAULT_PRESENCE,
             is_closed: true,
             handle: None,
+            open_warning_handle: None,
         };
         let state = Arc::new(RwLock::new(state));
 
-        Ok(Self { config, state })
+        Ok(Self {
+            config,
+            state,
+            status,
+        })
     }
 }
 
@@ -105,6 +171,16 @@ impl Device for ContactSensor {
     fn get_id(&self) -> String {
         self.config.info.identifier()
     }
+
+    fn get_info(&self) -> Option<&InfoConfig> {
+        Some(&self.config.info)
+    }
+}
+
+impl DeviceAvailability for ContactSensor {
+    fn availability(&self) -> &Availability {
+        self.status.availability()
+    }
 }
 
 #[async_trait]
@@ -122,19 +198,27 @@ impl google_home::Device for ContactSensor {
     }
 
     fn get_device_name(&self) -> google_home::device::Name {
-        device::Name::new(&self.config.info.name)
+        self.config.info.device_name()
     }
 
     fn get_room_hint(&self) -> Option<&str> {
         self.config.info.room.as_deref()
     }
 
+    fn get_device_info(&self) -> Option<google_home::device::Info> {
+        self.status.device_info()
+    }
+
+    fn get_custom_data(&self) -> Option<serde_json::Value> {
+        self.config.info.custom_data()
+    }
+
     fn will_report_state(&self) -> bool {
         false
     }
 
     async fn is_online(&self) -> bool {
-        true
+        self.status.is_available()
     }
 }
 
@@ -171,6 +255,14 @@ impl OnPresence for ContactSensor {
 #[async_trait]
 impl OnMqtt for ContactSensor {
     async fn on_mqtt(&self, message: rumqttc::Publish) {
+        if self
+            .status
+            .handle_with_callback(self, &message, &self.config.availability_callback)
+            .await
+        {
+            return;
+        }
+
         if !rumqttc::matches(&message.topic, &self.config.mqtt.topic) {
             return;
         }
@@ -192,6 +284,8 @@ impl OnMqtt for ContactSensor {
         debug!(id = self.get_id(), "Updating state to {is_closed}");
         self.state_mut().await.is_closed = is_closed;
 
+        self.update_open_warning(is_closed).await;
+
         // Check if this contact sensor works as a presence device
         // If not we are done here
         let presence = match &self.config.presence {
@@ -229,7 +323,11 @@ impl OnMqtt for ContactSensor {
         } else {
             // Once the door is closed again we start a timeout for removing the presence
             let device = self.clone();
-            self.state_mut().await.handle = Some(tokio::spawn(async move {
+            let mut state = self.state_mut().await;
+            if let Some(handle) = state.handle.take() {
+                handle.abort();
+            }
+            state.handle = Some(tokio::spawn(async move {
                 debug!(
                     id = device.get_id(),
                     "Starting timeout ({:?}) for contact sensor...", presence.timeout
@@ -252,3 +350,56 @@ impl OnMqtt for ContactSensor {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use automation_lib::event::EventChannel;
+    use automation_lib::lua::testing::MockMqttClient;
+    use rumqttc::{Publish, QoS};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn status() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let sensor = ContactSensor::create(Config {
+            info: InfoConfig::new("Door"),
+            mqtt: MqttDeviceConfig {
+                topic: "zigbee2mqtt/door".into(),
+                availability: None,
+            },
+            presence: None,
+            open_warning: None,
+            sensor_type: SensorType::Door,
+            callback: Default::default(),
+            availability_callback: Default::default(),
+            on_connect: Default::default(),
+            client: client.client(),
+        })
+        .await
+        .unwrap();
+
+        sensor
+            .on_mqtt(Publish::new(
+                "zigbee2mqtt/door/availability",
+                QoS::AtLeastOnce,
+                r#"{"state":"offline"}"#,
+            ))
+            .await;
+        assert!(!google_home::Device::is_online(&sensor).await);
+
+        sensor
+            .on_mqtt(Publish::new(
+                "zigbee2mqtt/door/$info",
+                QoS::AtLeastOnce,
+                r#"{"ieee_address":"0x00158d0002c0d4e5","manufacturer":"Aqara","model_id":"MCCGQ11LM"}"#,
+            ))
+            .await;
+        let info = google_home::Device::get_device_info(&sensor).unwrap();
+        assert_eq!(info.model.as_deref(), Some("MCCGQ11LM"));
+
+        // Neither message is a state update
+        assert!(sensor.state().await.is_closed);
+    }
+}
@@ -1,26 +1,191 @@
 use std::convert::Infallible;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use async_trait::async_trait;
 use automation_lib::config::MqttDeviceConfig;
 use automation_lib::device::{Device, LuaDeviceCreate};
-use automation_lib::event::{OnDarkness, OnPresence};
-use automation_lib::messages::{DarknessMessage, PresenceMessage};
+use automation_lib::event::{Event, EventChannel, EventRecord};
+use automation_lib::helpers::weak::spawn_weak;
 use automation_lib::mqtt::WrappedAsyncClient;
 use automation_macro::LuaDeviceConfig;
-use tracing::{trace, warn};
+use serde::{Deserialize, Serialize};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::Mutex;
+use tracing::{debug, trace, warn};
 
 #[derive(Debug, LuaDeviceConfig, Clone)]
 pub struct Config {
     pub identifier: String,
+    // Events are published on '<topic>/<event type>'
     #[device_config(flatten)]
     pub mqtt: MqttDeviceConfig,
+    // Append all events to this file as JSON lines, they can be replayed later
+    #[device_config(default)]
+    pub record_file: Option<PathBuf>,
+    #[device_config(from_lua)]
+    pub event_channel: EventChannel,
     #[device_config(from_lua)]
     pub client: WrappedAsyncClient,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct RecordedEvent {
+    // Milliseconds since the UNIX epoch
+    timestamp: u64,
+    #[serde(flatten)]
+    event: EventRecord,
+}
+
+impl RecordedEvent {
+    fn new(event: &Event) -> Self {
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time is after UNIX EPOCH")
+                .as_millis() as u64,
+            event: event.into(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DebugBridge {
-    config: Config,
+    config: Arc<Config>,
+    record_file: Arc<Mutex<Option<File>>>,
+}
+
+impl DebugBridge {
+    // Our own messages might come back in if something subscribes to everything
+    fn is_own_message(&self, event: &Event) -> bool {
+        matches!(event, Event::MqttMessage(message) if message.topic.starts_with(&self.config.mqtt.topic))
+    }
+
+    async fn publish(&self, event: &Event, payload: &str) {
+        let topic = format!("{}/{}", self.config.mqtt.topic, event.kind());
+        // Keep the last state around for the events that represent a state
+        let retain = matches!(event, Event::Darkness(_) | Event::Presence(_));
+
+        if let Err(err) = self
+            .config
+            .client
+            .publish(&topic, rumqttc::QoS::AtLeastOnce, retain, payload)
+            .await
+        {
+            warn!(
+                id = self.get_id(),
+                "Failed to publish event on {topic}: {err}"
+            );
+        }
+    }
+
+    async fn open_record_file(config: &Config) -> Option<File> {
+        let path = config.record_file.as_ref()?;
+
+        match OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+        {
+            Ok(file) => Some(file),
+            Err(err) => {
+                warn!(
+                    id = config.identifier,
+                    "Failed to open record file {}: {err}",
+                    path.display()
+                );
+                None
+            }
+        }
+    }
+
+    // Rebuilds the device in the background task, see automation_lib::helpers::weak
+    fn downgrade(&self) -> impl Fn() -> Option<Self> + Send + 'static {
+        let config = Arc::downgrade(&self.config);
+        let record_file = Arc::downgrade(&self.record_file);
+
+        move || {
+            Some(Self {
+                config: config.upgrade()?,
+                record_file: record_file.upgrade()?,
+            })
+        }
+    }
+
+    async fn mirror(&self, event: Event) {
+        if self.is_own_message(&event) {
+            return;
+        }
+
+        let payload = serde_json::to_string(&RecordedEvent::new(&event))
+            .expect("Serialization should not fail");
+        self.publish(&event, &payload).await;
+
+        if let Some(file) = &mut *self.record_file.lock().await {
+            if let Err(err) = file.write_all(format!("{payload}\n").as_bytes()).await {
+                warn!(id = self.get_id(), "Failed to record event: {err}");
+            }
+        }
+    }
+
+    /// Send the recorded events to the event channel again, with the original time in between
+    /// the events divided by speed
+    pub async fn replay(&self, path: &Path, speed: f64) -> std::io::Result<usize> {
+        // Dividing the delay by anything else would panic
+        if !speed.is_finite() || speed <= 0.0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Speed needs to be a positive number, got {speed}"),
+            ));
+        }
+
+        // Every replayed event gets recorded again, so replaying the record file would never end
+        if let Some(record_file) = &self.config.record_file {
+            if let (Ok(record_file), Ok(replay_file)) = (
+                tokio::fs::canonicalize(record_file).await,
+                tokio::fs::canonicalize(path).await,
+            ) {
+                if record_file == replay_file {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Can not replay the record file {}", path.display()),
+                    ));
+                }
+            }
+        }
+
+        let mut lines = BufReader::new(File::open(path).await?).lines();
+        let tx = self.config.event_channel.get_tx();
+
+        let mut count = 0;
+        let mut previous = None;
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let recorded: RecordedEvent = serde_json::from_str(&line)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+
+            if let Some(previous) = previous {
+                let delay = recorded.timestamp.saturating_sub(previous);
+                tokio::time::sleep(Duration::from_millis(delay).div_f64(speed)).await;
+            }
+            previous = Some(recorded.timestamp);
+
+            trace!(id = self.get_id(), "Replaying {:?}", recorded.event);
+            if tx.send(recorded.event.into()).await.is_err() {
+                warn!("There are no receivers on the event channel");
+            }
+            count += 1;
+        }
+
+        debug!(id = self.get_id(), "Replayed {count} events");
+        Ok(count)
+    }
 }
 
 #[async_trait]
@@ -30,7 +195,22 @@ impl LuaDeviceCreate for DebugBridge {
 
     async fn create(config: Self::Config) -> Result<Self, Self::Error> {
         trace!(id = config.identifier, "Setting up DebugBridge");
-        Ok(Self { config })
+
+        let bridge = Self {
+            record_file: Arc::new(Mutex::new(Self::open_record_file(&config).await)),
+            config: Arc::new(config),
+        };
+
+        // The subscription is dropped together with the task
+        let rx = bridge.config.event_channel.subscribe_filtered(|_| true);
+        let events = futures::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|event| (event, rx))
+        });
+        spawn_weak(bridge.downgrade(), events, |bridge, event| async move {
+            bridge.mirror(event).await
+        });
+
+        Ok(bridge)
     }
 }
 
@@ -40,50 +220,169 @@ impl Device for DebugBridge {
     }
 }
 
-#[async_trait]
-impl OnPresence for DebugBridge {
-    async fn on_presence(&self, presence: bool) {
-        let message = PresenceMessage::new(presence);
-        let topic = format!("{}/presence", self.config.mqtt.topic);
-        self.config
-            .client
-            .publish(
-                topic,
-                rumqttc::QoS::AtLeastOnce,
-                true,
-                serde_json::to_string(&message).expect("Serialization should not fail"),
-            )
-            .await
-            .map_err(|err| {
-                warn!(
-                    "Failed to update presence on {}/presence: {err}",
-                    self.config.mqtt.topic
-                )
-            })
-            .ok();
+#[cfg(test)]
+mod tests {
+    use automation_lib::device_manager::DeviceManager;
+    use automation_lib::lua::testing::MockMqttClient;
+    use rumqttc::{Publish, QoS};
+
+    use super::*;
+
+    fn record_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("debug_bridge_{name}_{}.jsonl", std::process::id()))
     }
-}
 
-#[async_trait]
-impl OnDarkness for DebugBridge {
-    async fn on_darkness(&self, dark: bool) {
-        let message = DarknessMessage::new(dark);
-        let topic = format!("{}/darkness", self.config.mqtt.topic);
-        self.config
-            .client
-            .publish(
-                topic,
-                rumqttc::QoS::AtLeastOnce,
-                true,
-                serde_json::to_string(&message).unwrap(),
-            )
+    async fn bridge(
+        event_channel: EventChannel,
+        record_file: Option<PathBuf>,
+    ) -> (DebugBridge, MockMqttClient) {
+        let client = MockMqttClient::new(event_channel.clone());
+        let bridge = DebugBridge::create(Config {
+            identifier: "debug_bridge".into(),
+            mqtt: MqttDeviceConfig {
+                topic: "automation/debug".into(),
+                availability: None,
+            },
+            record_file,
+            event_channel,
+            client: client.client(),
+        })
+        .await
+        .unwrap();
+
+        (bridge, client)
+    }
+
+    #[tokio::test]
+    async fn mirror_events() {
+        let path = record_path("mirror");
+        std::fs::remove_file(&path).ok();
+
+        let device_manager = DeviceManager::new().await;
+        let (_bridge, client) = bridge(device_manager.event_channel(), Some(path.clone())).await;
+
+        let tx = device_manager.event_channel().get_tx();
+        for event in [
+            Event::Presence(true),
+            Event::MqttMessage(Publish::new("zigbee2mqtt/light", QoS::AtLeastOnce, "{}")),
+            Event::MqttMessage(Publish::new(
+                "automation/debug/mqtt",
+                QoS::AtLeastOnce,
+                "{}",
+            )),
+            Event::Darkness(false),
+        ] {
+            tx.send(event).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let published = client.published();
+        let topics: Vec<_> = published.iter().map(|(topic, _)| topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            [
+                "automation/debug/presence",
+                "automation/debug/mqtt",
+                "automation/debug/darkness"
+            ]
+        );
+        let presence: serde_json::Value = serde_json::from_str(&published[0].1).unwrap();
+        assert_eq!(presence["type"], "presence");
+        assert_eq!(presence["state"], true);
+        assert!(presence["timestamp"].is_u64());
+
+        let recorded = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        let recorded: Vec<_> = recorded.lines().collect();
+        assert_eq!(
+            recorded,
+            published
+                .iter()
+                .map(|(_, payload)| payload.as_str())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn mirror_stops_with_bridge() {
+        let device_manager = DeviceManager::new().await;
+        let (bridge, client) = bridge(device_manager.event_channel(), None).await;
+        let config = Arc::downgrade(&bridge.config);
+
+        drop(bridge);
+        device_manager
+            .event_channel()
+            .get_tx()
+            .send(Event::Presence(true))
             .await
-            .map_err(|err| {
-                warn!(
-                    "Failed to update presence on {}/presence: {err}",
-                    self.config.mqtt.topic
-                )
-            })
-            .ok();
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert!(config.upgrade().is_none());
+        assert!(client.published().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replay() {
+        let path = record_path("replay");
+        std::fs::write(
+            &path,
+            [
+                r#"{"timestamp":1000,"type":"presence","state":true}"#,
+                r#"{"timestamp":2000,"type":"mqtt","topic":"zigbee2mqtt/light","payload":"{}","retain":false}"#,
+                r#"{"timestamp":3000,"type":"darkness","state":false}"#,
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+
+        let (event_channel, mut rx) = EventChannel::new();
+        let (bridge, _client) = bridge(event_channel, None).await;
+
+        let start = tokio::time::Instant::now();
+        let replayed = bridge.replay(&path, 2.0).await;
+        std::fs::remove_file(&path).ok();
+        assert_eq!(replayed.unwrap(), 3);
+
+        // Replayed at double speed
+        assert!(start.elapsed() >= Duration::from_millis(1000));
+        assert!(start.elapsed() < Duration::from_millis(1500));
+
+        assert!(matches!(rx.recv().await, Some(Event::Presence(true))));
+        assert!(matches!(
+            rx.recv().await,
+            Some(Event::MqttMessage(message)) if message.topic == "zigbee2mqtt/light"
+        ));
+        assert!(matches!(rx.recv().await, Some(Event::Darkness(false))));
+    }
+
+    #[tokio::test]
+    async fn replay_invalid_speed() {
+        let (event_channel, _rx) = EventChannel::new();
+        let (bridge, _client) = bridge(event_channel, None).await;
+
+        for speed in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let result = bridge
+                .replay(Path::new("does_not_exist.jsonl"), speed)
+                .await;
+            assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_record_file() {
+        let path = record_path("replay_record_file");
+        std::fs::write(
+            &path,
+            r#"{"timestamp":1000,"type":"presence","state":true}"#,
+        )
+        .unwrap();
+
+        let (event_channel, _rx) = EventChannel::new();
+        let (bridge, _client) = bridge(event_channel, Some(path.clone())).await;
+
+        let result = bridge.replay(&path, 1.0).await;
+        std::fs::remove_file(&path).ok();
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
     }
 }
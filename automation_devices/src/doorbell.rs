@@ -0,0 +1,312 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use automation_lib::action_callback::ActionCallback;
+use automation_lib::config::{MqttDeviceConfig, Secret};
+use automation_lib::device::{Device, LuaDeviceCreate};
+use automation_lib::event::{OnMqtt, OnNotification};
+use automation_lib::mqtt::WrappedAsyncClient;
+use automation_lib::ntfy::{Notification, Ntfy, Priority};
+use automation_macro::LuaDeviceConfig;
+use rumqttc::{matches, Publish};
+use thiserror::Error;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::{debug, trace, warn};
+
+// A camera that stops responding should not keep the notification from going out
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Mqtt(#[from] rumqttc::ClientError),
+    #[error(transparent)]
+    Request(#[from] reqwest::Error),
+}
+
+#[derive(Debug, Clone, LuaDeviceConfig)]
+pub struct Config {
+    pub identifier: String,
+    // Every (non retained) message on the topic is a press
+    #[device_config(flatten)]
+    pub mqtt: MqttDeviceConfig,
+    // Presses within this time of the previous one are ignored
+    #[device_config(rename("debounce_seconds"), default(3), with(Duration::from_secs))]
+    pub debounce: Duration,
+    #[device_config(default("Doorbell".into()))]
+    pub title: String,
+
+    // Camera image that is attached to the notification
+    #[device_config(default)]
+    pub snapshot_url: Option<String>,
+    #[device_config(default)]
+    pub snapshot_username: Option<String>,
+    #[device_config(secret, default)]
+    pub snapshot_password: Secret<Option<String>>,
+    #[device_config(from_lua, default)]
+    pub ntfy: Option<Ntfy>,
+
+    #[device_config(from_lua, default)]
+    pub callback: ActionCallback<Doorbell, ()>,
+
+    #[device_config(from_lua)]
+    pub client: WrappedAsyncClient,
+}
+
+#[derive(Debug, Clone)]
+pub struct Doorbell {
+    config: Config,
+    client: reqwest::Client,
+    last_press: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Doorbell {
+    async fn snapshot(&self, url: &str) -> Result<Vec<u8>, reqwest::Error> {
+        let mut req = self.client.get(url);
+        if let Some(username) = &self.config.snapshot_username {
+            req = req.basic_auth(username, self.config.snapshot_password.as_ref());
+        }
+
+        let res = req.send().await?.error_for_status()?;
+
+        Ok(res.bytes().await?.to_vec())
+    }
+
+    async fn notify(self, ntfy: Ntfy) {
+        let message = "Someone is at the door";
+
+        if let Some(url) = &self.config.snapshot_url {
+            match self.snapshot(url).await {
+                Ok(image) => {
+                    match ntfy
+                        .send_attachment(image, "snapshot.jpg", &self.config.title, Some(message))
+                        .await
+                    {
+                        Ok(()) => return,
+                        Err(err) => warn!(id = self.get_id(), "Failed to send snapshot: {err}"),
+                    }
+                }
+                Err(err) => warn!(id = self.get_id(), "Failed to get snapshot: {err}"),
+            }
+        }
+
+        // Still let people know someone is at the door
+        let notification = Notification::new()
+            .set_title(&self.config.title)
+            .set_message(message)
+            .add_tag("bell")
+            .set_priority(Priority::High);
+        ntfy.on_notification(notification).await;
+    }
+
+    // Returns false if the press should be ignored
+    async fn debounce(&self) -> bool {
+        let mut last_press = self.last_press.lock().await;
+        let now = Instant::now();
+
+        if last_press.is_some_and(|last| now.duration_since(last) < self.config.debounce) {
+            return false;
+        }
+        *last_press = Some(now);
+
+        true
+    }
+}
+
+#[async_trait]
+impl LuaDeviceCreate for Doorbell {
+    type Config = Config;
+    type Error = Error;
+
+    async fn create(config: Self::Config) -> Result<Self, Self::Error> {
+        trace!(id = config.identifier, "Setting up Doorbell");
+
+        config
+            .client
+            .subscribe(&config.mqtt.topic, rumqttc::QoS::AtLeastOnce)
+            .await?;
+
+        Ok(Self {
+            config,
+            client: reqwest::Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .timeout(REQUEST_TIMEOUT)
+                .build()?,
+            last_press: Default::default(),
+        })
+    }
+}
+
+impl Device for Doorbell {
+    fn get_id(&self) -> String {
+        self.config.identifier.clone()
+    }
+}
+
+#[async_trait]
+impl OnMqtt for Doorbell {
+    async fn on_mqtt(&self, message: Publish) {
+        // Retained messages are old presses
+        if message.retain || !matches(&message.topic, &self.config.mqtt.topic) {
+            return;
+        }
+
+        if !self.debounce().await {
+            trace!(id = self.get_id(), "Ignoring press");
+            return;
+        }
+
+        debug!(id = self.get_id(), "Doorbell pressed");
+        self.config.callback.call(self, &()).await;
+
+        // Fetching the snapshot can take a while, this should not hold up the other devices
+        if let Some(ntfy) = self.config.ntfy.clone() {
+            tokio::spawn(self.clone().notify(ntfy));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automation_lib::event::EventChannel;
+    use automation_lib::lua::testing::{MockHttpServer, MockMqttClient};
+    use automation_lib::ntfy;
+    use rumqttc::QoS;
+    use wiremock::matchers::{basic_auth, method, path};
+    use wiremock::{Mock, ResponseTemplate};
+
+    use super::*;
+
+    const IMAGE: &[u8] = &[0xff, 0xd8, 0xff, 0xe0, 0x00, 0x10];
+
+    async fn doorbell(server: &MockHttpServer, client: &MockMqttClient) -> Doorbell {
+        let (event_channel, _rx) = EventChannel::new();
+        let ntfy = Ntfy::create(ntfy::Config {
+            url: server.url(),
+            topic: "doorbell".into(),
+            token: Secret::new(None),
+            username: None,
+            password: Secret::new(None),
+            subscribe: false,
+            on_message: Default::default(),
+            tx: event_channel.get_tx(),
+        })
+        .await
+        .unwrap();
+
+        Doorbell::create(Config {
+            identifier: "doorbell".into(),
+            mqtt: MqttDeviceConfig {
+                topic: "doorbell/press".into(),
+                availability: None,
+            },
+            debounce: Duration::from_secs(3),
+            title: "Doorbell".into(),
+            snapshot_url: Some(format!("{}/snapshot.jpg", server.url())),
+            snapshot_username: Some("admin".into()),
+            snapshot_password: Secret::new(Some("hunter2".into())),
+            ntfy: Some(ntfy),
+            callback: Default::default(),
+            client: client.client(),
+        })
+        .await
+        .unwrap()
+    }
+
+    fn press() -> Publish {
+        Publish::new("doorbell/press", QoS::AtLeastOnce, r#"{"action":"single"}"#)
+    }
+
+    #[tokio::test]
+    async fn snapshot() {
+        let server = MockHttpServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/snapshot.jpg"))
+            .and(basic_auth("admin", "hunter2"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(IMAGE))
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path("/doorbell"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+
+        let client = MockMqttClient::new(EventChannel::new().0);
+        let doorbell = doorbell(&server, &client).await;
+
+        doorbell.on_mqtt(press()).await;
+        // Pressed again straight away
+        doorbell.on_mqtt(press()).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let uploads: Vec<_> = server
+            .received_requests()
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|request| request.method.as_str() == "PUT")
+            .collect();
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(uploads[0].body, IMAGE);
+        assert_eq!(uploads[0].headers["X-Title"], "Doorbell");
+        assert_eq!(uploads[0].headers["X-Filename"], "snapshot.jpg");
+    }
+
+    #[tokio::test]
+    async fn without_snapshot() {
+        let server = MockHttpServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/snapshot.jpg"))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+
+        let client = MockMqttClient::new(EventChannel::new().0);
+        let doorbell = doorbell(&server, &client).await;
+
+        // Old presses are ignored
+        let mut retained = press();
+        retained.retain = true;
+        doorbell.on_mqtt(retained).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(server.received_json("/").await.is_empty());
+
+        doorbell.on_mqtt(press()).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            server.received_json("/").await,
+            [serde_json::json!({
+                "topic": "doorbell",
+                "title": "Doorbell",
+                "message": "Someone is at the door",
+                "tags": ["bell"],
+                "priority": 4
+            })]
+        );
+    }
+
+    #[tokio::test]
+    async fn slow_camera() {
+        let server = MockHttpServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/snapshot.jpg"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(60)))
+            .mount(&server)
+            .await;
+
+        let client = MockMqttClient::new(EventChannel::new().0);
+        let doorbell = doorbell(&server, &client).await;
+
+        let handled = tokio::time::timeout(Duration::from_secs(1), doorbell.on_mqtt(press())).await;
+        assert!(handled.is_ok());
+    }
+}
@@ -0,0 +1,459 @@
+use async_trait::async_trait;
+use automation_cast::Cast;
+use automation_lib::config::InfoConfig;
+use automation_lib::device::{Device, LuaDeviceCreate};
+use automation_lib::error::DeviceConfigError;
+use automation_macro::LuaDeviceConfig;
+use futures::future::join_all;
+use google_home::device::Name;
+use google_home::errors::{DeviceError, ErrorCode};
+use google_home::traits::{Brightness, OnOff};
+use google_home::types::Type;
+use tracing::{trace, warn};
+
+#[derive(Debug, Clone, LuaDeviceConfig)]
+pub struct Config {
+    #[device_config(flatten)]
+    pub info: InfoConfig,
+    // Every member needs to implement OnOff, a DimmableGroup only dims the members that implement
+    // Brightness
+    #[device_config(from_lua)]
+    pub members: Vec<Box<dyn Device>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Group {
+    config: Config,
+}
+
+impl Group {
+    fn on_off(&self) -> impl Iterator<Item = (&Box<dyn Device>, &dyn OnOff)> {
+        self.config.members.iter().filter_map(|member| {
+            let on_off: &dyn OnOff = member.cast()?;
+            Some((member, on_off))
+        })
+    }
+
+    fn brightness_members(&self) -> impl Iterator<Item = (&Box<dyn Device>, &dyn Brightness)> {
+        self.config.members.iter().filter_map(|member| {
+            let brightness: &dyn Brightness = member.cast()?;
+            Some((member, brightness))
+        })
+    }
+
+    // Logs the failed members, the command only fails if none of the members succeeded
+    fn collect<T>(
+        &self,
+        results: impl IntoIterator<Item = (String, Result<T, ErrorCode>)>,
+    ) -> Result<Vec<T>, ErrorCode> {
+        let mut values = Vec::new();
+        let mut error = None;
+        for (member, result) in results {
+            match result {
+                Ok(value) => values.push(value),
+                Err(err) => {
+                    warn!(id = Device::get_id(self), member, "Member failed: {err}");
+                    error = Some(err);
+                }
+            }
+        }
+
+        match error {
+            Some(err) if values.is_empty() => Err(err),
+            _ => Ok(values),
+        }
+    }
+}
+
+#[async_trait]
+impl LuaDeviceCreate for Group {
+    type Config = Config;
+    type Error = DeviceConfigError;
+
+    async fn create(config: Self::Config) -> Result<Self, Self::Error> {
+        trace!(id = config.info.identifier(), "Setting up Group");
+
+        for member in &config.members {
+            if !Cast::<dyn OnOff>::has(member) {
+                return Err(DeviceConfigError::MissingTrait(
+                    member.get_id(),
+                    "OnOff".into(),
+                ));
+            }
+        }
+
+        Ok(Self { config })
+    }
+}
+
+impl Device for Group {
+    fn get_id(&self) -> String {
+        self.config.info.identifier()
+    }
+
+    fn get_info(&self) -> Option<&InfoConfig> {
+        Some(&self.config.info)
+    }
+}
+
+#[async_trait]
+impl google_home::Device for Group {
+    fn get_device_type(&self) -> Type {
+        Type::Light
+    }
+
+    fn get_device_name(&self) -> Name {
+        self.config.info.device_name()
+    }
+
+    fn get_id(&self) -> String {
+        Device::get_id(self)
+    }
+
+    async fn is_online(&self) -> bool {
+        for member in &self.config.members {
+            let member: Option<&dyn google_home::Device> = member.cast();
+            match member {
+                Some(member) if !member.is_online().await => {}
+                _ => return true,
+            }
+        }
+
+        false
+    }
+
+    fn get_room_hint(&self) -> Option<&str> {
+        self.config.info.room.as_deref()
+    }
+
+    fn get_custom_data(&self) -> Option<serde_json::Value> {
+        self.config.info.custom_data()
+    }
+
+    fn will_report_state(&self) -> bool {
+        false
+    }
+}
+
+#[async_trait]
+impl OnOff for Group {
+    async fn on(&self) -> Result<bool, ErrorCode> {
+        let results = join_all(
+            self.on_off()
+                .map(|(member, on_off)| async move { (member.get_id(), on_off.on().await) }),
+        )
+        .await;
+
+        Ok(self.collect(results)?.into_iter().any(|on| on))
+    }
+
+    async fn set_on(&self, on: bool) -> Result<(), ErrorCode> {
+        let results = join_all(
+            self.on_off()
+                .map(|(member, on_off)| async move { (member.get_id(), on_off.set_on(on).await) }),
+        )
+        .await;
+
+        self.collect(results).map(|_| ())
+    }
+}
+
+// Group that also dims its members, only members that implement Brightness are dimmed. This is a
+// separate device so groups of plain outlets do not advertise Brightness to Google Home.
+#[derive(Debug, Clone)]
+pub struct DimmableGroup {
+    group: Group,
+}
+
+#[async_trait]
+impl LuaDeviceCreate for DimmableGroup {
+    type Config = Config;
+    type Error = DeviceConfigError;
+
+    async fn create(config: Self::Config) -> Result<Self, Self::Error> {
+        let group = Group::create(config).await?;
+
+        if group.brightness_members().next().is_none() {
+            return Err(DeviceConfigError::InvalidValue(
+                "members".into(),
+                "none of the members implement Brightness".into(),
+            ));
+        }
+
+        Ok(Self { group })
+    }
+}
+
+impl Device for DimmableGroup {
+    fn get_id(&self) -> String {
+        Device::get_id(&self.group)
+    }
+
+    fn get_info(&self) -> Option<&InfoConfig> {
+        Device::get_info(&self.group)
+    }
+}
+
+#[async_trait]
+impl google_home::Device for DimmableGroup {
+    fn get_device_type(&self) -> Type {
+        google_home::Device::get_device_type(&self.group)
+    }
+
+    fn get_device_name(&self) -> Name {
+        google_home::Device::get_device_name(&self.group)
+    }
+
+    fn get_id(&self) -> String {
+        Device::get_id(self)
+    }
+
+    async fn is_online(&self) -> bool {
+        google_home::Device::is_online(&self.group).await
+    }
+
+    fn get_room_hint(&self) -> Option<&str> {
+        google_home::Device::get_room_hint(&self.group)
+    }
+
+    fn get_custom_data(&self) -> Option<serde_json::Value> {
+        google_home::Device::get_custom_data(&self.group)
+    }
+
+    fn will_report_state(&self) -> bool {
+        google_home::Device::will_report_state(&self.group)
+    }
+}
+
+#[async_trait]
+impl OnOff for DimmableGroup {
+    async fn on(&self) -> Result<bool, ErrorCode> {
+        self.group.on().await
+    }
+
+    async fn set_on(&self, on: bool) -> Result<(), ErrorCode> {
+        self.group.set_on(on).await
+    }
+}
+
+#[async_trait]
+impl Brightness for DimmableGroup {
+    async fn brightness(&self) -> Result<u8, ErrorCode> {
+        let group = &self.group;
+        let results = join_all(
+            group
+                .brightness_members()
+                .map(|(member, brightness)| async move {
+                    (member.get_id(), brightness.brightness().await)
+                }),
+        )
+        .await;
+
+        let values = group.collect(results)?;
+        if values.is_empty() {
+            return Err(DeviceError::ActionNotAvailable.into());
+        }
+
+        let total: u32 = values.iter().map(|&brightness| brightness as u32).sum();
+        Ok((total as f64 / values.len() as f64).round() as u8)
+    }
+
+    async fn set_brightness(&self, brightness: u8) -> Result<(), ErrorCode> {
+        let group = &self.group;
+        let results = join_all(group.brightness_members().map(
+            |(member, member_brightness)| async move {
+                (
+                    member.get_id(),
+                    member_brightness.set_brightness(brightness).await,
+                )
+            },
+        ))
+        .await;
+
+        group.collect(results).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[derive(Debug, Clone)]
+    struct Member {
+        id: &'static str,
+        state: Arc<Mutex<Option<(bool, u8)>>>,
+    }
+
+    impl Member {
+        fn new(id: &'static str, on: bool, brightness: u8) -> Self {
+            Self {
+                id,
+                state: Arc::new(Mutex::new(Some((on, brightness)))),
+            }
+        }
+
+        // Every call fails, as if the device is unreachable
+        fn offline(id: &'static str) -> Self {
+            Self {
+                id,
+                state: Default::default(),
+            }
+        }
+
+        fn state(&self) -> Option<(bool, u8)> {
+            *self.state.lock().unwrap()
+        }
+    }
+
+    impl Device for Member {
+        fn get_id(&self) -> String {
+            self.id.into()
+        }
+    }
+
+    #[async_trait]
+    impl OnOff for Member {
+        async fn on(&self) -> Result<bool, ErrorCode> {
+            let (on, _) = self.state().ok_or(DeviceError::DeviceOffline)?;
+            Ok(on)
+        }
+
+        async fn set_on(&self, on: bool) -> Result<(), ErrorCode> {
+            let mut state = self.state.lock().unwrap();
+            let state = state.as_mut().ok_or(DeviceError::DeviceOffline)?;
+            state.0 = on;
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl Brightness for Member {
+        async fn brightness(&self) -> Result<u8, ErrorCode> {
+            let (_, brightness) = self.state().ok_or(DeviceError::DeviceOffline)?;
+            Ok(brightness)
+        }
+
+        async fn set_brightness(&self, brightness: u8) -> Result<(), ErrorCode> {
+            let mut state = self.state.lock().unwrap();
+            let state = state.as_mut().ok_or(DeviceError::DeviceOffline)?;
+            state.1 = brightness;
+            Ok(())
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct Sensor;
+
+    impl Device for Sensor {
+        fn get_id(&self) -> String {
+            "sensor".into()
+        }
+    }
+
+    fn config(members: Vec<Box<dyn Device>>) -> Config {
+        Config {
+            info: InfoConfig {
+                room: Some("Living room".into()),
+                ..InfoConfig::new("Living room lights")
+            },
+            members,
+        }
+    }
+
+    async fn group(members: Vec<Box<dyn Device>>) -> Result<Group, DeviceConfigError> {
+        Group::create(config(members)).await
+    }
+
+    #[tokio::test]
+    async fn fan_out() {
+        let members = [
+            Member::new("a", false, 20),
+            Member::new("b", true, 60),
+            Member::offline("c"),
+        ];
+        let group = DimmableGroup::create(config(
+            members
+                .iter()
+                .map(|member| Box::new(member.clone()) as Box<dyn Device>)
+                .collect(),
+        ))
+        .await
+        .unwrap();
+
+        // The offline member is ignored
+        assert!(group.on().await.unwrap());
+        assert_eq!(group.brightness().await.unwrap(), 40);
+
+        group.set_brightness(80).await.unwrap();
+        group.set_on(false).await.unwrap();
+        assert_eq!(members[0].state(), Some((false, 80)));
+        assert_eq!(members[1].state(), Some((false, 80)));
+        assert!(!group.on().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn all_offline() {
+        let group = group(vec![Box::new(Member::offline("a"))]).await.unwrap();
+
+        assert_eq!(group.on().await, Err(DeviceError::DeviceOffline.into()));
+        assert_eq!(
+            group.set_on(true).await,
+            Err(DeviceError::DeviceOffline.into())
+        );
+    }
+
+    #[derive(Debug, Clone)]
+    struct Outlet;
+
+    impl Device for Outlet {
+        fn get_id(&self) -> String {
+            "outlet".into()
+        }
+    }
+
+    #[async_trait]
+    impl OnOff for Outlet {
+        async fn on(&self) -> Result<bool, ErrorCode> {
+            Ok(true)
+        }
+
+        async fn set_on(&self, _on: bool) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn brightness_only_when_dimmable() {
+        let group = group(vec![Box::new(Outlet)]).await.unwrap();
+        assert!(!Cast::<dyn Brightness>::has(&group));
+        assert!(group.on().await.unwrap());
+
+        let result = DimmableGroup::create(config(vec![Box::new(Outlet)])).await;
+        assert!(matches!(result, Err(DeviceConfigError::InvalidValue(..))));
+
+        let group = DimmableGroup::create(config(vec![
+            Box::new(Outlet),
+            Box::new(Member::new("a", true, 50)),
+        ]))
+        .await
+        .unwrap();
+        assert!(Cast::<dyn Brightness>::has(&group));
+        assert_eq!(group.brightness().await.unwrap(), 50);
+    }
+
+    #[tokio::test]
+    async fn missing_trait() {
+        let result = group(vec![
+            Box::new(Member::new("a", true, 100)),
+            Box::new(Sensor),
+        ])
+        .await;
+
+        assert!(matches!(
+            result,
+            Err(DeviceConfigError::MissingTrait(id, name)) if id == "sensor" && name == "OnOff"
+        ));
+    }
+}
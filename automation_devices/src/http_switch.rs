@@ -0,0 +1,404 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use automation_lib::availability::Availability;
+use automation_lib::config::{InfoConfig, Secret};
+use automation_lib::device::{Device, DeviceAvailability, LuaDeviceCreate};
+use automation_lib::helpers::weak::spawn_weak_interval;
+use automation_macro::LuaDeviceConfig;
+use google_home::device::Name;
+use google_home::errors::{DeviceError, ErrorCode};
+use google_home::traits::OnOff;
+use google_home::types::Type;
+use reqwest::Method;
+use serde::{Deserialize, Deserializer};
+use thiserror::Error;
+use tokio::sync::RwLock;
+use tracing::{debug, trace, warn};
+
+fn deserialize_method<'de, D>(deserializer: D) -> Result<Option<Method>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let method = String::deserialize(deserializer)?;
+    Method::from_bytes(method.to_uppercase().as_bytes())
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HttpRequest {
+    // Defaults to POST if the request has a body and GET otherwise
+    #[serde(default, deserialize_with = "deserialize_method")]
+    pub method: Option<Method>,
+    pub url: String,
+    // Usually contains credentials, so they are kept out of the logs
+    #[serde(default)]
+    pub headers: Secret<HashMap<String, String>>,
+    // Sent as json
+    #[serde(default)]
+    pub body: Option<serde_json::Value>,
+}
+
+impl HttpRequest {
+    fn method(&self) -> Method {
+        match (&self.method, &self.body) {
+            (Some(method), _) => method.clone(),
+            (None, Some(_)) => Method::POST,
+            (None, None) => Method::GET,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StateRequest {
+    #[serde(flatten)]
+    pub request: HttpRequest,
+    // JSON pointer to the boolean state in the response, e.g. '/relay/0/on'
+    pub pointer: String,
+}
+
+#[derive(Debug, Clone, LuaDeviceConfig)]
+pub struct Config {
+    #[device_config(flatten)]
+    pub info: InfoConfig,
+    pub on_request: HttpRequest,
+    pub off_request: HttpRequest,
+    // Without a state request the state is only known after it has been set
+    #[device_config(default)]
+    pub state_request: Option<StateRequest>,
+    // How often the state is refreshed using the state request
+    #[device_config(
+        rename("poll_interval_seconds"),
+        default(30),
+        with(Duration::from_secs)
+    )]
+    pub poll_interval: Duration,
+    #[device_config(rename("timeout_seconds"), default(5), with(Duration::from_secs))]
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Request(#[from] reqwest::Error),
+    #[error("Response does not contain a boolean at {0}")]
+    MissingState(String),
+}
+
+#[derive(Debug)]
+struct State {
+    // Last known state
+    on: RwLock<Option<bool>>,
+    // Whether the last state request succeeded
+    availability: Availability,
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpSwitch {
+    config: Config,
+    client: reqwest::Client,
+    state: Arc<State>,
+}
+
+impl HttpSwitch {
+    async fn send(&self, request: &HttpRequest) -> Result<reqwest::Response, reqwest::Error> {
+        let mut builder = self
+            .client
+            .request(request.method(), &request.url)
+            .timeout(self.config.timeout);
+
+        for (name, value) in request.headers.iter() {
+            builder = builder.header(name, value);
+        }
+
+        if let Some(body) = &request.body {
+            builder = builder.json(body);
+        }
+
+        builder.send().await?.error_for_status()
+    }
+
+    async fn get_state(&self) -> Result<Option<bool>, Error> {
+        let Some(state_request) = &self.config.state_request else {
+            return Ok(*self.state.on.read().await);
+        };
+
+        let result = self.request_state(state_request).await;
+        self.state.availability.set(result.is_ok());
+
+        let on = result?;
+        *self.state.on.write().await = Some(on);
+
+        Ok(Some(on))
+    }
+
+    async fn request_state(&self, state_request: &StateRequest) -> Result<bool, Error> {
+        let response: serde_json::Value = self.send(&state_request.request).await?.json().await?;
+
+        response
+            .pointer(&state_request.pointer)
+            .and_then(serde_json::Value::as_bool)
+            .ok_or_else(|| Error::MissingState(state_request.pointer.clone()))
+    }
+
+    async fn set_state(&self, on: bool) -> Result<(), Error> {
+        let request = if on {
+            &self.config.on_request
+        } else {
+            &self.config.off_request
+        };
+
+        self.send(request).await?;
+        *self.state.on.write().await = Some(on);
+
+        Ok(())
+    }
+
+    // Rebuilds the device in the background task, see automation_lib::helpers::weak
+    fn downgrade(&self) -> impl Fn() -> Option<Self> + Send + 'static {
+        let config = self.config.clone();
+        let client = self.client.clone();
+        let state = Arc::downgrade(&self.state);
+
+        move || {
+            Some(Self {
+                config: config.clone(),
+                client: client.clone(),
+                state: state.upgrade()?,
+            })
+        }
+    }
+
+    async fn poll(&self) {
+        match self.get_state().await {
+            Ok(on) => trace!(id = self.get_id(), "State: {on:?}"),
+            Err(err) => warn!(id = self.get_id(), "Failed to get state: {err}"),
+        }
+    }
+}
+
+#[async_trait]
+impl LuaDeviceCreate for HttpSwitch {
+    type Config = Config;
+    type Error = reqwest::Error;
+
+    async fn create(config: Self::Config) -> Result<Self, Self::Error> {
+        trace!(id = config.info.identifier(), "Setting up HttpSwitch");
+
+        let switch = Self {
+            client: reqwest::Client::builder().build()?,
+            state: Arc::new(State {
+                on: Default::default(),
+                availability: Availability::new(config.info.identifier()),
+            }),
+            config,
+        };
+
+        if switch.config.state_request.is_some() {
+            spawn_weak_interval(
+                switch.downgrade(),
+                switch.config.poll_interval,
+                |switch| async move { switch.poll().await },
+            );
+        }
+
+        Ok(switch)
+    }
+}
+
+impl Device for HttpSwitch {
+    fn get_id(&self) -> String {
+        self.config.info.identifier()
+    }
+
+    fn get_info(&self) -> Option<&InfoConfig> {
+        Some(&self.config.info)
+    }
+}
+
+impl DeviceAvailability for HttpSwitch {
+    fn availability(&self) -> &Availability {
+        &self.state.availability
+    }
+}
+
+#[async_trait]
+impl google_home::Device for HttpSwitch {
+    fn get_device_type(&self) -> Type {
+        Type::Switch
+    }
+
+    fn get_device_name(&self) -> Name {
+        self.config.info.device_name()
+    }
+
+    fn get_id(&self) -> String {
+        Device::get_id(self)
+    }
+
+    async fn is_online(&self) -> bool {
+        self.state.availability.get()
+    }
+
+    fn get_room_hint(&self) -> Option<&str> {
+        self.config.info.room.as_deref()
+    }
+
+    fn get_custom_data(&self) -> Option<serde_json::Value> {
+        self.config.info.custom_data()
+    }
+
+    fn will_report_state(&self) -> bool {
+        false
+    }
+}
+
+#[async_trait]
+impl OnOff for HttpSwitch {
+    async fn on(&self) -> Result<bool, ErrorCode> {
+        // Use the state from the last poll, only fall back to a request if we do not know it yet
+        if let Some(on) = *self.state.on.read().await {
+            return Ok(on);
+        }
+
+        match self.get_state().await {
+            Ok(on) => Ok(on.unwrap_or(false)),
+            Err(err) => {
+                warn!(id = Device::get_id(self), "Failed to get state: {err}");
+                Err(DeviceError::DeviceOffline.into())
+            }
+        }
+    }
+
+    async fn set_on(&self, on: bool) -> Result<(), ErrorCode> {
+        debug!(
+            id = Device::get_id(self),
+            "Turning {}",
+            if on { "on" } else { "off" }
+        );
+
+        self.set_state(on).await.map_err(|err| {
+            warn!(id = Device::get_id(self), "Failed to set state: {err}");
+            DeviceError::DeviceOffline.into()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automation_lib::lua::testing::MockHttpServer;
+    use serde_json::json;
+
+    use super::*;
+
+    fn request(url: String, headers: HashMap<String, String>) -> HttpRequest {
+        HttpRequest {
+            method: None,
+            url,
+            headers: headers.into(),
+            body: None,
+        }
+    }
+
+    async fn switch(server: &MockHttpServer, state_pointer: Option<&str>) -> HttpSwitch {
+        let headers = HashMap::from([("Authorization".into(), "Bearer secret".into())]);
+
+        HttpSwitch::create(Config {
+            info: InfoConfig::new("Relay"),
+            on_request: HttpRequest {
+                body: Some(json!({ "relay": 0, "on": true })),
+                ..request(format!("{}/relay", server.url()), headers.clone())
+            },
+            off_request: HttpRequest {
+                body: Some(json!({ "relay": 0, "on": false })),
+                ..request(format!("{}/relay", server.url()), headers.clone())
+            },
+            state_request: state_pointer.map(|pointer| StateRequest {
+                request: request(format!("{}/status", server.url()), headers),
+                pointer: pointer.into(),
+            }),
+            poll_interval: Duration::from_secs(3600),
+            timeout: Duration::from_secs(1),
+        })
+        .await
+        .unwrap()
+    }
+
+    #[test]
+    fn request_method() {
+        let request: HttpRequest = serde_json::from_value(json!({
+            "method": "put",
+            "url": "http://relay.local/on",
+        }))
+        .unwrap();
+        assert_eq!(request.method(), Method::PUT);
+
+        let request: HttpRequest = serde_json::from_value(json!({
+            "url": "http://relay.local/on",
+            "body": { "on": true },
+        }))
+        .unwrap();
+        assert_eq!(request.method(), Method::POST);
+    }
+
+    #[tokio::test]
+    async fn set_on() {
+        let server = MockHttpServer::start().await;
+        server.respond_json("POST", "/relay", json!({})).await;
+        let switch = switch(&server, None).await;
+
+        // Without a state request the state is unknown until it is set
+        assert_eq!(switch.on().await, Ok(false));
+        switch.set_on(true).await.unwrap();
+        assert_eq!(switch.on().await, Ok(true));
+
+        assert_eq!(
+            server.received_json("/relay").await,
+            vec![json!({ "relay": 0, "on": true })]
+        );
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(requests[0].headers["authorization"], "Bearer secret");
+    }
+
+    #[tokio::test]
+    async fn state_request() {
+        let server = MockHttpServer::start().await;
+        server
+            .respond_json("GET", "/status", json!({ "relays": [{ "on": true }] }))
+            .await;
+
+        let found = switch(&server, Some("/relays/0/on")).await;
+        assert_eq!(found.on().await, Ok(true));
+
+        let missing = switch(&server, Some("/relays/1/on")).await;
+        assert_eq!(missing.on().await, Err(DeviceError::DeviceOffline.into()));
+        assert!(!google_home::Device::is_online(&missing).await);
+    }
+
+    #[tokio::test]
+    async fn poll_stops_with_device() {
+        let server = MockHttpServer::start().await;
+        let switch = switch(&server, Some("/relays/0/on")).await;
+        let state = Arc::downgrade(&switch.state);
+
+        drop(switch);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(state.upgrade().is_none());
+    }
+
+    #[tokio::test]
+    async fn request_failure() {
+        // Unmatched requests get a 404
+        let server = MockHttpServer::start().await;
+        let switch = switch(&server, None).await;
+
+        assert_eq!(
+            switch.set_on(true).await,
+            Err(DeviceError::DeviceOffline.into())
+        );
+        assert_eq!(switch.on().await, Ok(false));
+    }
+}
@@ -1,12 +1,28 @@
 use std::convert::Infallible;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
 
 use async_trait::async_trait;
+use automation_lib::config::Secret;
 use automation_lib::device::{Device, LuaDeviceCreate};
 use automation_lib::event::{OnDarkness, OnPresence};
 use automation_macro::LuaDeviceConfig;
+use mlua::FromLua;
+use reqwest::header::ACCEPT;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{CertificateError, ClientConfig, DigitallySignedStruct, SignatureScheme};
 use serde::{Deserialize, Serialize};
-use tracing::{error, trace, warn};
+use tokio::sync::broadcast;
+use tracing::{debug, error, trace, warn};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+// Delay before reconnecting to the event stream, doubles after every failed attempt
+const RECONNECT_DELAY_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(60);
 
 #[derive(Debug)]
 pub enum Flag {
@@ -25,13 +41,33 @@ pub struct Config {
     pub identifier: String,
     #[device_config(rename("ip"), with(|ip| SocketAddr::new(ip, 80)))]
     pub addr: SocketAddr,
-    pub login: String,
+    #[device_config(secret)]
+    pub login: Secret<String>,
     pub flags: FlagIDs,
+    // Id of the bridge, e.g. '001788fffe6a1b2c', used to verify the certificate of the event
+    // stream. The event stream is only used if this is set.
+    #[device_config(default)]
+    pub bridge_id: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    // The event stream was (re)established, so earlier events might have been missed
+    Connected,
+    // The on state of a light or group changed, identified by its v1 id, e.g. '/groups/1'
+    Update { id_v1: String, on: bool },
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
+struct State {
+    events: broadcast::Sender<Event>,
+    streaming: AtomicBool,
+}
+
+#[derive(Debug, Clone, FromLua)]
 pub struct HueBridge {
     config: Config,
+    state: Arc<State>,
 }
 
 #[derive(Debug, Serialize)]
@@ -46,11 +82,147 @@ impl LuaDeviceCreate for HueBridge {
 
     async fn create(config: Self::Config) -> Result<Self, Infallible> {
         trace!(id = config.identifier, "Setting up HueBridge");
-        Ok(Self { config })
+
+        let bridge = Self::new(config);
+
+        match &bridge.config.bridge_id {
+            Some(bridge_id) => {
+                tokio::spawn(Self::stream_loop(
+                    bridge.config.clone(),
+                    bridge_id.clone(),
+                    Arc::downgrade(&bridge.state),
+                ));
+            }
+            None => debug!(
+                id = bridge.get_id(),
+                "No bridge_id configured, not using the event stream"
+            ),
+        }
+
+        Ok(bridge)
     }
 }
 
 impl HueBridge {
+    // Does not connect to the event stream
+    pub(crate) fn new(config: Config) -> Self {
+        let (events, _) = broadcast::channel(100);
+
+        Self {
+            config,
+            state: Arc::new(State {
+                events,
+                streaming: Default::default(),
+            }),
+        }
+    }
+
+    // Receive the updates from the event stream
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.state.events.subscribe()
+    }
+
+    // If this is false, updates might be missed and the state should be polled instead
+    pub fn is_streaming(&self) -> bool {
+        self.state.streaming.load(Ordering::Relaxed)
+    }
+
+    // Only holds on to a weak reference, see automation_lib::helpers::weak. The connection is
+    // kept across events, so this can not use the helpers directly.
+    async fn stream_loop(config: Config, bridge_id: String, state: Weak<State>) {
+        let client = match stream_client(bridge_id) {
+            Ok(client) => client,
+            Err(err) => {
+                error!(
+                    id = config.identifier,
+                    "Failed to set up event stream: {err}"
+                );
+                return;
+            }
+        };
+
+        let mut delay = RECONNECT_DELAY_MIN;
+        while state.strong_count() > 0 {
+            if let Err(err) = Self::event_stream(&config, &client, &state, &mut delay).await {
+                warn!(id = config.identifier, "Event stream failed: {err}");
+            }
+
+            debug!(
+                id = config.identifier,
+                "Reconnecting to event stream in {delay:?}"
+            );
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(RECONNECT_DELAY_MAX);
+        }
+    }
+
+    async fn event_stream(
+        config: &Config,
+        client: &reqwest::Client,
+        state: &Weak<State>,
+        delay: &mut Duration,
+    ) -> Result<(), reqwest::Error> {
+        let url = format!("https://{}/eventstream/clip/v2", config.addr.ip());
+
+        let mut response = client
+            .get(url)
+            .header("hue-application-key", &*config.login)
+            .header(ACCEPT, "text/event-stream")
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let Some(connected) = state.upgrade() else {
+            return Ok(());
+        };
+
+        debug!(id = config.identifier, "Connected to event stream");
+        *delay = RECONNECT_DELAY_MIN;
+        connected.streaming.store(true, Ordering::Relaxed);
+        connected.events.send(Event::Connected).ok();
+        drop(connected);
+
+        let mut parser = EventStreamParser::default();
+        let result = loop {
+            let chunk = match response.chunk().await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break Ok(()),
+                Err(err) => break Err(err),
+            };
+
+            let Some(state) = state.upgrade() else {
+                break Ok(());
+            };
+
+            for data in parser.feed(&chunk) {
+                let events: Vec<message::Event> = match serde_json::from_str(&data) {
+                    Ok(events) => events,
+                    Err(err) => {
+                        warn!(id = config.identifier, "Failed to parse event: {err}");
+                        continue;
+                    }
+                };
+
+                for event in events.into_iter().flat_map(message::Event::updates) {
+                    trace!(id = config.identifier, ?event, "Received event");
+                    state.events.send(event).ok();
+                }
+            }
+        };
+
+        if let Some(state) = state.upgrade() {
+            state.streaming.store(false, Ordering::Relaxed);
+        }
+
+        result
+    }
+
+    #[cfg(test)]
+    pub(crate) fn inject(&self, event: Event) {
+        self.state.streaming.store(true, Ordering::Relaxed);
+        self.state.events.send(event).ok();
+    }
+
     pub async fn set_flag(&self, flag: Flag, value: bool) {
         let flag_id = match flag {
             Flag::Presence => self.config.flags.presence,
@@ -59,7 +231,7 @@ impl HueBridge {
 
         let url = format!(
             "http://{}/api/{}/sensors/{flag_id}/state",
-            self.config.addr, self.config.login
+            self.config.addr, *self.config.login
         );
 
         trace!(?flag, flag_id, value, "Sending request to change flag");
@@ -89,6 +261,173 @@ impl Device for HueBridge {
     }
 }
 
+// The application key is sent with every request, so the client only talks to the bridge with the
+// configured id
+fn stream_client(bridge_id: String) -> Result<reqwest::Client, anyhow::Error> {
+    let provider = Arc::new(ring::default_provider());
+    let tls = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(BridgeCertificate {
+            bridge_id,
+            provider,
+        }))
+        .with_no_client_auth();
+
+    Ok(reqwest::Client::builder()
+        .use_preconfigured_tls(tls)
+        .build()?)
+}
+
+// The certificate of the bridge is not signed by a public CA, instead the common name is the id of
+// the bridge
+#[derive(Debug)]
+struct BridgeCertificate {
+    bridge_id: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for BridgeCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let (_, certificate) = X509Certificate::from_der(end_entity)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+
+        let time = x509_parser::time::ASN1Time::from_timestamp(now.as_secs() as i64)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        if !certificate.validity().is_valid_at(time) {
+            return Err(rustls::Error::InvalidCertificate(CertificateError::Expired));
+        }
+
+        let matches = certificate
+            .subject()
+            .iter_common_name()
+            .filter_map(|common_name| common_name.as_str().ok())
+            .any(|common_name| common_name.eq_ignore_ascii_case(&self.bridge_id));
+        if !matches {
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::NotValidForName,
+            ));
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+// Collects the data of server-sent events, which can be split across multiple chunks
+#[derive(Debug, Default)]
+struct EventStreamParser {
+    buffer: String,
+}
+
+impl EventStreamParser {
+    fn feed(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer.push_str(&String::from_utf8_lossy(chunk));
+
+        let mut events = Vec::new();
+        while let Some(end) = self.buffer.find("\n\n") {
+            let event: String = self.buffer.drain(..end + 2).collect();
+
+            let data: Vec<_> = event
+                .lines()
+                .filter_map(|line| line.strip_prefix("data:"))
+                .map(str::trim_start)
+                .collect();
+
+            // Events without data are used as keep-alive
+            if !data.is_empty() {
+                events.push(data.join("\n"));
+            }
+        }
+
+        events
+    }
+}
+
+mod message {
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize)]
+    struct On {
+        on: bool,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Resource {
+        #[serde(rename = "type")]
+        kind: String,
+        id_v1: Option<String>,
+        on: Option<On>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Event {
+        #[serde(rename = "type")]
+        kind: String,
+        data: Vec<Resource>,
+    }
+
+    impl Event {
+        pub fn updates(self) -> impl Iterator<Item = super::Event> {
+            let updates = if self.kind == "update" {
+                self.data
+            } else {
+                Vec::new()
+            };
+
+            updates
+                .into_iter()
+                .filter(|resource| matches!(resource.kind.as_str(), "light" | "grouped_light"))
+                .filter_map(|resource| {
+                    Some(super::Event::Update {
+                        id_v1: resource.id_v1?,
+                        on: resource.on?.on,
+                    })
+                })
+        }
+    }
+}
+
 #[async_trait]
 impl OnPresence for HueBridge {
     async fn on_presence(&self, presence: bool) {
@@ -104,3 +443,100 @@ impl OnDarkness for HueBridge {
         self.set_flag(Flag::Darkness, dark).await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bridge_certificate() {
+        let certificate = CertificateDer::from(
+            &include_bytes!("../tests/fixtures/hue/bridge_certificate.der")[..],
+        );
+        let verify = |bridge_id: &str| {
+            let verifier = BridgeCertificate {
+                bridge_id: bridge_id.into(),
+                provider: Arc::new(ring::default_provider()),
+            };
+
+            verifier.verify_server_cert(
+                &certificate,
+                &[],
+                &ServerName::try_from("192.168.1.2").unwrap(),
+                &[],
+                UnixTime::now(),
+            )
+        };
+
+        assert!(verify("001788fffe6a1b2c").is_ok());
+        assert!(verify("001788FFFE6A1B2C").is_ok());
+        assert!(matches!(
+            verify("001788fffe000000"),
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::NotValidForName
+            ))
+        ));
+    }
+
+    #[test]
+    fn stream_client_config() {
+        assert!(super::stream_client("001788fffe6a1b2c".into()).is_ok());
+    }
+
+    #[test]
+    fn parse_event_stream() {
+        let mut parser = EventStreamParser::default();
+
+        assert!(parser.feed(b": hi\n\n").is_empty());
+        assert!(parser.feed(b"id: 1:0\ndata: [{\"type\"").is_empty());
+        assert_eq!(
+            parser.feed(b":\"update\"}]\n\nid: 2:0\n"),
+            vec![r#"[{"type":"update"}]"#]
+        );
+    }
+
+    #[test]
+    fn parse_updates() {
+        let events: Vec<message::Event> = serde_json::from_str(
+            r#"[{
+                "creationtime": "2024-12-01T12:00:00Z",
+                "id": "5c8a1b2e-0d4b-4f37-9f2d-0a6a8f1e2b3c",
+                "type": "update",
+                "data": [
+                    {
+                        "id": "f2a1e7a4-3b6c-4a4e-8f4d-2b1c9d0e7a6b",
+                        "id_v1": "/groups/3",
+                        "on": { "on": true },
+                        "owner": { "rid": "0b8e2c1d-7a4f-4d2e-9c3b-6a5f4e3d2c1b", "rtype": "room" },
+                        "type": "grouped_light"
+                    },
+                    {
+                        "id": "a7c3e9f1-2b4d-4e6f-8a1c-3d5e7f9a1b2c",
+                        "id_v1": "/lights/7",
+                        "dimming": { "brightness": 50.0 },
+                        "type": "light"
+                    },
+                    {
+                        "id": "c1d2e3f4-a5b6-4c7d-8e9f-0a1b2c3d4e5f",
+                        "id_v1": "/sensors/2",
+                        "type": "motion"
+                    }
+                ]
+            }]"#,
+        )
+        .unwrap();
+
+        let updates: Vec<_> = events
+            .into_iter()
+            .flat_map(message::Event::updates)
+            .collect();
+
+        assert_eq!(
+            updates,
+            vec![Event::Update {
+                id_v1: "/groups/3".into(),
+                on: true
+            }]
+        );
+    }
+}
@@ -1,27 +1,54 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::Arc;
 
 use anyhow::Result;
 use async_trait::async_trait;
+use automation_lib::config::{InfoConfig, Secret};
+use automation_lib::device::{DeviceHealth, HealthStatus};
+use automation_lib::helpers::weak::spawn_weak;
 use automation_macro::LuaDeviceConfig;
-use google_home::errors::ErrorCode;
-use google_home::traits::OnOff;
-use tracing::{error, trace, warn};
+use google_home::device;
+use google_home::errors::{DeviceError, ErrorCode};
+use google_home::traits::{Brightness, OnOff};
+use google_home::types::Type;
+use mlua::FromLua;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::RwLock;
+use tracing::{debug, error, trace, warn};
 
 use super::{Device, LuaDeviceCreate};
+use crate::hue_bridge::{self, HueBridge};
 
 #[derive(Debug, Clone, LuaDeviceConfig)]
 pub struct Config {
     pub identifier: String,
     #[device_config(rename("ip"), with(|ip| SocketAddr::new(ip, 80)))]
     pub addr: SocketAddr,
-    pub login: String,
+    #[device_config(secret)]
+    pub login: Secret<String>,
     pub group_id: isize,
-    pub scene_id: String,
+    // Name or id of the scene that is recalled when the group is turned on
+    #[device_config(default)]
+    pub default_scene: Option<String>,
+    // Deprecated, use default_scene instead
+    #[device_config(default)]
+    pub scene_id: Option<String>,
+    // Name and room used for Google Home, defaults to the identifier
+    #[device_config(default)]
+    pub info: Option<InfoConfig>,
+    // Keep track of the state using the event stream of the bridge instead of polling
+    #[device_config(from_lua, default)]
+    pub bridge: Option<HueBridge>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, FromLua)]
 pub struct HueGroup {
     config: Config,
+    // Last known state according to the event stream of the bridge
+    on: Arc<RwLock<Option<bool>>>,
+    // Maps the scene names of this group to their ids
+    scenes: Arc<RwLock<HashMap<String, String>>>,
 }
 
 // Couple of helper function to get the correct urls
@@ -33,13 +60,32 @@ impl LuaDeviceCreate for HueGroup {
     async fn create(config: Self::Config) -> Result<Self, Self::Error> {
         trace!(id = config.identifier, "Setting up AudioSetup");
 
-        Ok(Self { config })
+        let group = Self {
+            config,
+            on: Default::default(),
+            scenes: Default::default(),
+        };
+
+        if let Some(bridge) = &group.config.bridge {
+            let events = futures::stream::unfold(bridge.subscribe(), |mut events| async move {
+                match events.recv().await {
+                    Err(RecvError::Closed) => None,
+                    event => Some((event, events)),
+                }
+            });
+
+            spawn_weak(group.downgrade(), events, |group, event| async move {
+                group.handle_event(event).await
+            });
+        }
+
+        Ok(group)
     }
 }
 
 impl HueGroup {
     fn url_base(&self) -> String {
-        format!("http://{}/api/{}", self.config.addr, self.config.login)
+        format!("http://{}/api/{}", self.config.addr, *self.config.login)
     }
 
     fn url_set_action(&self) -> String {
@@ -49,26 +95,54 @@ impl HueGroup {
     fn url_get_state(&self) -> String {
         format!("{}/groups/{}", self.url_base(), self.config.group_id)
     }
-}
 
-impl Device for HueGroup {
-    fn get_id(&self) -> String {
-        self.config.identifier.clone()
+    fn url_get_scenes(&self) -> String {
+        format!("{}/scenes", self.url_base())
     }
-}
 
-#[async_trait]
-impl OnOff for HueGroup {
-    async fn set_on(&self, on: bool) -> Result<(), ErrorCode> {
-        let message = if on {
-            message::Action::scene(self.config.scene_id.clone())
-        } else {
-            message::Action::on(false)
-        };
+    // Rebuilds the device in the background task, see automation_lib::helpers::weak
+    fn downgrade(&self) -> impl Fn() -> Option<Self> + Send + 'static {
+        let config = self.config.clone();
+        let on = Arc::downgrade(&self.on);
+        let scenes = Arc::downgrade(&self.scenes);
+
+        move || {
+            Some(Self {
+                config: config.clone(),
+                on: on.upgrade()?,
+                scenes: scenes.upgrade()?,
+            })
+        }
+    }
+
+    async fn handle_event(&self, event: Result<hue_bridge::Event, RecvError>) {
+        match event {
+            Ok(hue_bridge::Event::Update { id_v1, on }) if id_v1 == self.id_v1() => {
+                debug!(id = self.get_id(), "Group is now on: {on}");
+                *self.on.write().await = Some(on);
+            }
+            Ok(hue_bridge::Event::Update { .. }) | Err(RecvError::Closed) => {}
+            // We might have missed updates, so we no longer know the state
+            Ok(hue_bridge::Event::Connected) | Err(RecvError::Lagged(_)) => {
+                *self.on.write().await = None;
+            }
+        }
+    }
+
+    fn id_v1(&self) -> String {
+        format!("/groups/{}", self.config.group_id)
+    }
 
+    fn default_scene(&self) -> Option<&str> {
+        self.config
+            .default_scene
+            .as_deref()
+            .or(self.config.scene_id.as_deref())
+    }
+
+    async fn poll(&self) -> Option<message::Info> {
         let res = reqwest::Client::new()
-            .put(self.url_set_action())
-            .json(&message)
+            .get(self.url_get_state())
             .send()
             .await;
 
@@ -78,52 +152,201 @@ impl OnOff for HueGroup {
                 if !status.is_success() {
                     warn!(id = self.get_id(), "Status code is not success: {status}");
                 }
+
+                match res.json::<message::Info>().await {
+                    Ok(info) => return Some(info),
+                    Err(err) => error!(id = self.get_id(), "Failed to parse message: {err}"),
+                }
             }
             Err(err) => error!(id = self.get_id(), "Error: {err}"),
         }
 
+        None
+    }
+
+    async fn set_action(&self, action: &message::Action) -> Result<()> {
+        reqwest::Client::new()
+            .put(self.url_set_action())
+            .json(action)
+            .send()
+            .await?
+            .error_for_status()?;
+
         Ok(())
     }
 
-    async fn on(&self) -> Result<bool, ErrorCode> {
-        let res = reqwest::Client::new()
-            .get(self.url_get_state())
+    async fn fetch_scenes(&self) -> Result<HashMap<String, String>> {
+        let scenes: HashMap<String, message::Scene> = reqwest::Client::new()
+            .get(self.url_get_scenes())
             .send()
-            .await;
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
 
-        match res {
+        let group_id = self.config.group_id.to_string();
+        Ok(scenes
+            .into_iter()
+            .filter(|(_, scene)| scene.group.as_ref() == Some(&group_id))
+            .map(|(id, scene)| (scene.name, id))
+            .collect())
+    }
+
+    async fn scene_id(&self, scene: &str) -> String {
+        if let Some(id) = self.scenes.read().await.get(scene) {
+            return id.clone();
+        }
+
+        // The scene might have been added after we last looked
+        match self.fetch_scenes().await {
+            Ok(scenes) => *self.scenes.write().await = scenes,
+            Err(err) => warn!(id = self.get_id(), "Failed to get scenes: {err}"),
+        }
+
+        // If there is no scene with this name, assume it is already an id
+        self.scenes
+            .read()
+            .await
+            .get(scene)
+            .cloned()
+            .unwrap_or_else(|| scene.to_owned())
+    }
+
+    // Recall a scene of this group by either its name or id
+    pub async fn activate_scene(&self, scene: &str) -> Result<()> {
+        let id = self.scene_id(scene).await;
+        trace!(id = self.get_id(), scene, scene_id = id, "Activating scene");
+
+        self.set_action(&message::Action::scene(id)).await
+    }
+}
+
+#[async_trait]
+impl DeviceHealth for HueGroup {
+    async fn check_health(&self) -> HealthStatus {
+        match reqwest::get(self.url_get_state()).await {
+            Ok(res) if res.status().is_success() => HealthStatus::Healthy,
             Ok(res) => {
-                let status = res.status();
-                if !status.is_success() {
-                    warn!(id = self.get_id(), "Status code is not success: {status}");
-                }
+                HealthStatus::Degraded(format!("Status code is not success: {}", res.status()))
+            }
+            Err(err) => HealthStatus::Unhealthy(err.to_string()),
+        }
+    }
+}
+
+impl Device for HueGroup {
+    fn get_id(&self) -> String {
+        self.config.identifier.clone()
+    }
+}
 
-                let on = match res.json::<message::Info>().await {
-                    Ok(info) => info.any_on(),
-                    Err(err) => {
-                        error!(id = self.get_id(), "Failed to parse message: {err}");
-                        // TODO: Error code
-                        return Ok(false);
-                    }
-                };
+#[async_trait]
+impl OnOff for HueGroup {
+    async fn set_on(&self, on: bool) -> Result<(), ErrorCode> {
+        let res = match (on, self.default_scene()) {
+            (true, Some(scene)) => self.activate_scene(scene).await,
+            (on, _) => self.set_action(&message::Action::on(on)).await,
+        };
+
+        if let Err(err) = res {
+            error!(id = self.get_id(), "Error: {err}");
+        }
+
+        Ok(())
+    }
+
+    async fn on(&self) -> Result<bool, ErrorCode> {
+        // Fall back to polling if the event stream is not available
+        let streaming = self
+            .config
+            .bridge
+            .as_ref()
+            .is_some_and(HueBridge::is_streaming);
 
+        if streaming {
+            if let Some(on) = *self.on.read().await {
                 return Ok(on);
             }
-            Err(err) => error!(id = self.get_id(), "Error: {err}"),
         }
 
-        Ok(false)
+        // TODO: Error code
+        let Some(on) = self.poll().await.map(|info| info.any_on()) else {
+            return Ok(false);
+        };
+
+        if streaming {
+            // Any changes after this will be received through the event stream
+            *self.on.write().await = Some(on);
+        }
+
+        Ok(on)
+    }
+}
+
+#[async_trait]
+impl Brightness for HueGroup {
+    async fn brightness(&self) -> Result<u8, ErrorCode> {
+        // Relative changes are based on this, so guessing a value is worse than failing
+        let info = self.poll().await.ok_or(DeviceError::DeviceOffline)?;
+        let bri = info.bri().unwrap_or_default();
+
+        Ok((bri as f64 * 100.0 / message::BRI_MAX as f64).round() as u8)
+    }
+
+    async fn set_brightness(&self, brightness: u8) -> Result<(), ErrorCode> {
+        let bri = (brightness.min(100) as f64 * message::BRI_MAX as f64 / 100.0).round() as u8;
+
+        self.set_action(&message::Action::brightness(bri))
+            .await
+            .map_err(|err| {
+                error!(id = self.get_id(), "Error: {err}");
+                DeviceError::TransientError.into()
+            })
+    }
+}
+
+#[async_trait]
+impl google_home::Device for HueGroup {
+    fn get_device_type(&self) -> Type {
+        Type::Light
+    }
+
+    fn get_device_name(&self) -> device::Name {
+        match &self.config.info {
+            Some(info) => info.device_name(),
+            None => device::Name::new(&self.config.identifier),
+        }
+    }
+
+    fn get_id(&self) -> String {
+        Device::get_id(self)
+    }
+
+    async fn is_online(&self) -> bool {
+        true
+    }
+
+    fn get_room_hint(&self) -> Option<&str> {
+        self.config.info.as_ref()?.room.as_deref()
+    }
+
+    fn get_custom_data(&self) -> Option<serde_json::Value> {
+        self.config.info.as_ref()?.custom_data()
     }
 }
 
 mod message {
     use serde::{Deserialize, Serialize};
 
-    #[derive(Debug, Serialize, Deserialize)]
+    pub const BRI_MAX: u8 = 254;
+
+    #[derive(Debug, Default, Serialize, Deserialize)]
     pub struct Action {
         #[serde(skip_serializing_if = "Option::is_none")]
         on: Option<bool>,
         #[serde(skip_serializing_if = "Option::is_none")]
+        bri: Option<u8>,
+        #[serde(skip_serializing_if = "Option::is_none")]
         scene: Option<String>,
     }
 
@@ -131,14 +354,23 @@ mod message {
         pub fn on(on: bool) -> Self {
             Self {
                 on: Some(on),
-                scene: None,
+                ..Default::default()
+            }
+        }
+
+        pub fn brightness(bri: u8) -> Self {
+            // Setting the brightness only has an effect when the group is on
+            Self {
+                on: Some(bri > 0),
+                bri: (bri > 0).then_some(bri),
+                ..Default::default()
             }
         }
 
         pub fn scene(scene: String) -> Self {
             Self {
-                on: None,
                 scene: Some(scene),
+                ..Default::default()
             }
         }
     }
@@ -152,11 +384,212 @@ mod message {
     #[derive(Debug, Serialize, Deserialize)]
     pub struct Info {
         state: State,
+        #[serde(default)]
+        action: Action,
     }
 
     impl Info {
         pub fn any_on(&self) -> bool {
             self.state.any_on
         }
+
+        pub fn bri(&self) -> Option<u8> {
+            self.action.bri
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Scene {
+        pub name: String,
+        // Only set for scenes of a group
+        pub group: Option<String>,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use automation_lib::lua::testing::MockHttpServer;
+    use serde_json::json;
+
+    use super::*;
+
+    // Events are injected by the tests, so the bridge does not connect to the event stream
+    fn bridge() -> HueBridge {
+        HueBridge::new(hue_bridge::Config {
+            identifier: "bridge".into(),
+            addr: (Ipv4Addr::LOCALHOST, 80).into(),
+            login: Secret::new("login".into()),
+            flags: serde_json::from_value(json!({ "presence": 1, "darkness": 2 })).unwrap(),
+            bridge_id: None,
+        })
+    }
+
+    async fn group(server: &MockHttpServer, bridge: Option<HueBridge>) -> HueGroup {
+        HueGroup::create(Config {
+            identifier: "group".into(),
+            addr: *server.address(),
+            login: Secret::new("login".into()),
+            group_id: 3,
+            default_scene: None,
+            scene_id: Some("scene".into()),
+            info: None,
+            bridge,
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn brightness() {
+        let server = MockHttpServer::start().await;
+        server
+            .respond_json(
+                "GET",
+                "/api/login/groups/3",
+                json!({
+                    "state": { "all_on": true, "any_on": true },
+                    "action": { "on": true, "bri": 127 }
+                }),
+            )
+            .await;
+        server
+            .respond_json("PUT", "/api/login/groups/3/action", json!([]))
+            .await;
+
+        let group = group(&server, None).await;
+
+        let sync = serde_json::to_value(google_home::Device::sync(&group).await).unwrap();
+        assert_eq!(
+            sync["traits"],
+            json!([
+                "action.devices.traits.OnOff",
+                "action.devices.traits.Brightness"
+            ])
+        );
+
+        assert_eq!(group.brightness().await, Ok(50));
+
+        group.set_brightness(100).await.unwrap();
+        assert_eq!(
+            server.received_json("/api/login/groups/3/action").await,
+            vec![json!({ "on": true, "bri": 254 })]
+        );
+    }
+
+    #[tokio::test]
+    async fn brightness_unreachable() {
+        let server = MockHttpServer::start().await;
+        let group = group(&server, None).await;
+
+        // Without a known brightness relative changes should fail instead of starting from 0
+        assert_eq!(
+            group.brightness().await,
+            Err(DeviceError::DeviceOffline.into())
+        );
+        assert_eq!(
+            group.set_brightness_relative(10).await,
+            Err(DeviceError::DeviceOffline.into())
+        );
+        assert_eq!(
+            group.set_brightness(100).await,
+            Err(DeviceError::TransientError.into())
+        );
+    }
+
+    #[tokio::test]
+    async fn health() {
+        let server = MockHttpServer::start().await;
+        let group = group(&server, None).await;
+
+        assert!(matches!(
+            group.check_health().await,
+            HealthStatus::Degraded(_)
+        ));
+
+        server
+            .respond_json("GET", "/api/login/groups/3", json!({}))
+            .await;
+        assert_eq!(group.check_health().await, HealthStatus::Healthy);
+    }
+
+    #[tokio::test]
+    async fn activate_scene() {
+        let server = MockHttpServer::start().await;
+        server
+            .respond_json(
+                "GET",
+                "/api/login/scenes",
+                json!({
+                    "4e1c6b20e-on-0": { "name": "Relax", "type": "GroupScene", "group": "3" },
+                    "ab341ef24-on-0": { "name": "Relax", "type": "GroupScene", "group": "4" },
+                    "b0e2a8c2f-on-0": { "name": "Bright", "type": "LightScene" }
+                }),
+            )
+            .await;
+        server
+            .respond_json("PUT", "/api/login/groups/3/action", json!([]))
+            .await;
+
+        let group = group(&server, None).await;
+
+        group.activate_scene("Relax").await.unwrap();
+        group.activate_scene("Relax").await.unwrap();
+        // Unknown names are used as the id for backwards compatibility
+        group.set_on(true).await.unwrap();
+
+        assert_eq!(
+            server.received_json("/api/login/groups/3/action").await,
+            vec![
+                json!({ "scene": "4e1c6b20e-on-0" }),
+                json!({ "scene": "4e1c6b20e-on-0" }),
+                json!({ "scene": "scene" }),
+            ]
+        );
+        // The scenes are cached after the first lookup, 'scene' is not a known name
+        assert_eq!(
+            server
+                .received_requests()
+                .await
+                .unwrap()
+                .iter()
+                .filter(|request| request.url.path() == "/api/login/scenes")
+                .count(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn event_stream() {
+        let server = MockHttpServer::start().await;
+        server
+            .respond_json(
+                "GET",
+                "/api/login/groups/3",
+                json!({ "state": { "all_on": false, "any_on": false } }),
+            )
+            .await;
+
+        let bridge = bridge();
+        let group = group(&server, Some(bridge.clone())).await;
+
+        bridge.inject(hue_bridge::Event::Update {
+            id_v1: "/groups/3".into(),
+            on: true,
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        // The state is known from the event stream, so no request is made
+        assert_eq!(group.on().await, Ok(true));
+        assert!(server.received_requests().await.unwrap().is_empty());
+
+        // After reconnecting the state has to be polled again
+        bridge.inject(hue_bridge::Event::Connected);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(group.on().await, Ok(false));
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
     }
 }
@@ -1,3 +1,6 @@
+use std::sync::Arc;
+use std::time::Duration;
+
 use async_trait::async_trait;
 use automation_lib::action_callback::ActionCallback;
 use automation_lib::config::{InfoConfig, MqttDeviceConfig};
@@ -5,9 +8,27 @@ use automation_lib::device::{Device, LuaDeviceCreate};
 use automation_lib::event::OnMqtt;
 use automation_lib::mqtt::WrappedAsyncClient;
 use automation_macro::LuaDeviceConfig;
+use google_home::device;
+use google_home::errors::ErrorCode;
+use google_home::traits::Scene;
+use google_home::types::Type;
 use rumqttc::{matches, Publish};
 use serde::Deserialize;
-use tracing::{debug, trace, warn};
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, trace, warn};
+
+use crate::hue_group::HueGroup;
+
+// Hue scene that is activated when the button is pressed
+#[derive(Debug, Clone, LuaDeviceConfig)]
+pub struct SceneConfig {
+    pub button: Button,
+    // Name or id of the scene
+    pub scene_id: String,
+    #[device_config(from_lua)]
+    pub hue_group: HueGroup,
+}
 
 #[derive(Debug, Clone, LuaDeviceConfig)]
 pub struct Config {
@@ -31,6 +52,30 @@ pub struct Config {
 
     #[device_config(from_lua, default)]
     pub right_hold_callback: ActionCallback<HueSwitch, ()>,
+
+    // Setting these delays the normal press callback until the multi press window has passed
+    #[device_config(from_lua, default)]
+    pub left_double_callback: ActionCallback<HueSwitch, ()>,
+
+    #[device_config(from_lua, default)]
+    pub right_double_callback: ActionCallback<HueSwitch, ()>,
+
+    #[device_config(
+        rename("multi_press_window_ms"),
+        default(300),
+        with(Duration::from_millis)
+    )]
+    pub multi_press_window: Duration,
+
+    #[device_config(from_lua, default)]
+    pub scenes: Vec<SceneConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Button {
+    Left,
+    Right,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -51,15 +96,92 @@ struct State {
     action: Action,
 }
 
+// Single press that is waiting to see if a second press follows
+#[derive(Debug, Default)]
+struct Pending {
+    left: Option<JoinHandle<()>>,
+    right: Option<JoinHandle<()>>,
+}
+
+impl Pending {
+    fn get_mut(&mut self, button: Button) -> &mut Option<JoinHandle<()>> {
+        match button {
+            Button::Left => &mut self.left,
+            Button::Right => &mut self.right,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct HueSwitch {
     config: Config,
+    pending: Arc<RwLock<Pending>>,
+}
+
+impl HueSwitch {
+    async fn single_press(&self, button: Button) {
+        let callback = match button {
+            Button::Left => &self.config.left_callback,
+            Button::Right => &self.config.right_callback,
+        };
+
+        callback.call(self, &()).await;
+
+        for scene in self
+            .config
+            .scenes
+            .iter()
+            .filter(|scene| scene.button == button)
+        {
+            if let Err(err) = scene.hue_group.activate_scene(&scene.scene_id).await {
+                error!(
+                    id = Device::get_id(self),
+                    scene = scene.scene_id,
+                    "Failed to activate scene: {err}"
+                );
+            }
+        }
+    }
+
+    async fn press(&self, button: Button) {
+        let double_callback = match button {
+            Button::Left => &self.config.left_double_callback,
+            Button::Right => &self.config.right_double_callback,
+        };
+
+        if !double_callback.is_set() {
+            self.single_press(button).await;
+            return;
+        }
+
+        let mut pending = self.pending.write().await;
+        if let Some(handle) = pending.get_mut(button).take() {
+            handle.abort();
+            drop(pending);
+
+            debug!(id = Device::get_id(self), "Double press {button:?}");
+            double_callback.call(self, &()).await;
+            return;
+        }
+
+        let device = self.clone();
+        *pending.get_mut(button) = Some(tokio::spawn(async move {
+            tokio::time::sleep(device.config.multi_press_window).await;
+            device.pending.write().await.get_mut(button).take();
+
+            device.single_press(button).await;
+        }));
+    }
 }
 
 impl Device for HueSwitch {
     fn get_id(&self) -> String {
         self.config.info.identifier()
     }
+
+    fn get_info(&self) -> Option<&InfoConfig> {
+        Some(&self.config.info)
+    }
 }
 
 #[async_trait]
@@ -75,7 +197,10 @@ impl LuaDeviceCreate for HueSwitch {
             .subscribe(&config.mqtt.topic, rumqttc::QoS::AtLeastOnce)
             .await?;
 
-        Ok(Self { config })
+        Ok(Self {
+            config,
+            pending: Default::default(),
+        })
     }
 }
 
@@ -94,19 +219,19 @@ impl OnMqtt for HueSwitch {
             debug!(id = Device::get_id(self), "Remote action = {:?}", action);
 
             match action {
-                Action::LeftPressRelease => self.config.left_callback.call(self, &()).await,
-                Action::RightPressRelease => self.config.right_callback.call(self, &()).await,
+                Action::LeftPressRelease => self.press(Button::Left).await,
+                Action::RightPressRelease => self.press(Button::Right).await,
                 Action::LeftHold => self.config.left_hold_callback.call(self, &()).await,
                 Action::RightHold => self.config.right_hold_callback.call(self, &()).await,
                 // If there is no hold action, the switch will act like a normal release
                 Action::RightHoldRelease => {
                     if !self.config.right_hold_callback.is_set() {
-                        self.config.right_callback.call(self, &()).await
+                        self.press(Button::Right).await
                     }
                 }
                 Action::LeftHoldRelease => {
                     if !self.config.left_hold_callback.is_set() {
-                        self.config.left_callback.call(self, &()).await
+                        self.press(Button::Left).await
                     }
                 }
                 _ => {}
@@ -114,3 +239,234 @@ impl OnMqtt for HueSwitch {
         }
     }
 }
+
+#[async_trait]
+impl google_home::Device for HueSwitch {
+    fn get_device_type(&self) -> Type {
+        Type::Switch
+    }
+
+    fn get_device_name(&self) -> device::Name {
+        self.config.info.device_name()
+    }
+
+    fn get_id(&self) -> String {
+        Device::get_id(self)
+    }
+
+    async fn is_online(&self) -> bool {
+        true
+    }
+
+    fn get_room_hint(&self) -> Option<&str> {
+        self.config.info.room.as_deref()
+    }
+
+    fn get_custom_data(&self) -> Option<serde_json::Value> {
+        self.config.info.custom_data()
+    }
+}
+
+// Activating the switch from Google Home acts like pressing the left button, deactivating it like
+// pressing the right button
+#[async_trait]
+impl Scene for HueSwitch {
+    fn scene_reversible(&self) -> Option<bool> {
+        Some(true)
+    }
+
+    async fn set_active(&self, deactivate: bool) -> Result<(), ErrorCode> {
+        let button = if deactivate {
+            Button::Right
+        } else {
+            Button::Left
+        };
+        debug!(id = Device::get_id(self), "Simulating {button:?} press");
+
+        self.press(button).await;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automation_lib::config::Secret;
+    use automation_lib::event::EventChannel;
+    use automation_lib::lua::testing::{MockHttpServer, MockMqttClient};
+    use mlua::FromLua;
+    use rumqttc::QoS;
+    use serde_json::json;
+
+    use super::*;
+    use crate::hue_group;
+
+    // Callback that counts how often it is called in a lua global
+    fn counter(lua: &mlua::Lua, name: &str) -> ActionCallback<HueSwitch, ()> {
+        let f: mlua::Function = lua
+            .load(format!("return function() {name} = ({name} or 0) + 1 end"))
+            .eval()
+            .unwrap();
+
+        ActionCallback::from_lua(mlua::Value::Function(f), lua).unwrap()
+    }
+
+    fn count(lua: &mlua::Lua, name: &str) -> u32 {
+        lua.globals().get::<Option<u32>>(name).unwrap().unwrap_or(0)
+    }
+
+    async fn switch(lua: &mlua::Lua, client: &MockMqttClient, double: bool) -> HueSwitch {
+        HueSwitch::create(Config {
+            info: InfoConfig::new("Switch"),
+            mqtt: MqttDeviceConfig {
+                topic: "zigbee2mqtt/switch".into(),
+                availability: None,
+            },
+            client: client.client(),
+            left_callback: counter(lua, "left"),
+            right_callback: counter(lua, "right"),
+            left_hold_callback: counter(lua, "left_hold"),
+            right_hold_callback: Default::default(),
+            left_double_callback: if double {
+                counter(lua, "left_double")
+            } else {
+                Default::default()
+            },
+            right_double_callback: Default::default(),
+            multi_press_window: Duration::from_millis(50),
+            scenes: Vec::new(),
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn action(switch: &HueSwitch, action: &str) {
+        let payload = format!(r#"{{"action":"{action}"}}"#);
+        switch
+            .on_mqtt(Publish::new(
+                "zigbee2mqtt/switch",
+                QoS::AtLeastOnce,
+                payload,
+            ))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn single_press() {
+        let lua = mlua::Lua::new();
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let switch = switch(&lua, &client, true).await;
+
+        action(&switch, "left_press_release").await;
+        assert_eq!(count(&lua, "left"), 0);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(count(&lua, "left"), 1);
+        assert_eq!(count(&lua, "left_double"), 0);
+    }
+
+    #[tokio::test]
+    async fn double_press() {
+        let lua = mlua::Lua::new();
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let switch = switch(&lua, &client, true).await;
+
+        action(&switch, "left_press_release").await;
+        action(&switch, "left_press_release").await;
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(count(&lua, "left"), 0);
+        assert_eq!(count(&lua, "left_double"), 1);
+
+        // Hold is not affected by the multi press detection
+        action(&switch, "left_hold").await;
+        assert_eq!(count(&lua, "left_hold"), 1);
+    }
+
+    #[tokio::test]
+    async fn without_double_callback() {
+        let lua = mlua::Lua::new();
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let switch = switch(&lua, &client, false).await;
+
+        // Presses are handled immediately
+        action(&switch, "left_press_release").await;
+        action(&switch, "left_press_release").await;
+        assert_eq!(count(&lua, "left"), 2);
+
+        // Releasing a hold without a hold callback counts as a press
+        action(&switch, "right_hold_release").await;
+        assert_eq!(count(&lua, "right"), 1);
+    }
+
+    #[tokio::test]
+    async fn scenes() {
+        let server = MockHttpServer::start().await;
+        server
+            .respond_json(
+                "GET",
+                "/api/login/scenes",
+                json!({ "abc": { "name": "Relax", "group": "3" } }),
+            )
+            .await;
+        server
+            .respond_json("PUT", "/api/login/groups/3/action", json!([]))
+            .await;
+
+        let hue_group = HueGroup::create(hue_group::Config {
+            identifier: "group".into(),
+            addr: *server.address(),
+            login: Secret::new("login".into()),
+            group_id: 3,
+            default_scene: None,
+            scene_id: None,
+            info: None,
+            bridge: None,
+        })
+        .await
+        .unwrap();
+
+        let lua = mlua::Lua::new();
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let mut switch = switch(&lua, &client, false).await;
+        switch.config.scenes = vec![
+            SceneConfig {
+                button: Button::Left,
+                scene_id: "Relax".into(),
+                hue_group: hue_group.clone(),
+            },
+            SceneConfig {
+                button: Button::Right,
+                scene_id: "off".into(),
+                hue_group,
+            },
+        ];
+
+        action(&switch, "left_press_release").await;
+        assert_eq!(
+            server.received_json("/api/login/groups/3/action").await,
+            vec![json!({ "scene": "abc" })]
+        );
+
+        // Deactivating the scene from Google Home presses the right button
+        google_home::Device::execute(
+            &switch,
+            google_home::traits::Command::ActivateScene { deactivate: true },
+        )
+        .await
+        .unwrap();
+        assert_eq!(count(&lua, "right"), 1);
+        assert_eq!(
+            server.received_json("/api/login/groups/3/action").await,
+            vec![json!({ "scene": "abc" }), json!({ "scene": "off" })]
+        );
+
+        let sync = serde_json::to_value(google_home::Device::sync(&switch).await).unwrap();
+        assert_eq!(sync["type"], "action.devices.types.SWITCH");
+        assert_eq!(sync["traits"], json!(["action.devices.traits.Scene"]));
+    }
+}
@@ -0,0 +1,98 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::{Duration, Instant};
+
+use pnet_packet::icmp::echo_reply::EchoReplyPacket;
+use pnet_packet::icmp::echo_request::MutableEchoRequestPacket;
+use pnet_packet::icmp::{self, IcmpPacket, IcmpTypes};
+use pnet_packet::ip::IpNextHeaderProtocols;
+use pnet_packet::Packet;
+use pnet_transport::TransportChannelType::Layer4;
+use pnet_transport::TransportProtocol::Ipv4;
+use pnet_transport::{icmp_packet_iter, transport_channel};
+
+static SEQUENCE: AtomicU16 = AtomicU16::new(0);
+
+// Sends a single ICMP echo request and waits for the reply. Opening the raw socket requires
+// CAP_NET_RAW, without it this fails with PermissionDenied and devices fall back to probing a tcp
+// port instead.
+pub async fn ping(ip: IpAddr, timeout: Duration) -> io::Result<bool> {
+    let IpAddr::V4(ip) = ip else {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Only IPv4 addresses can be pinged",
+        ));
+    };
+
+    tokio::task::spawn_blocking(move || ping_blocking(ip, timeout))
+        .await
+        .map_err(io::Error::other)?
+}
+
+fn ping_blocking(ip: Ipv4Addr, timeout: Duration) -> io::Result<bool> {
+    let (mut tx, mut rx) = transport_channel(1024, Layer4(Ipv4(IpNextHeaderProtocols::Icmp)))?;
+
+    // Raw sockets receive all ICMP traffic, so the reply is matched on identifier and sequence
+    let identifier = std::process::id() as u16;
+    let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+
+    let mut buffer = [0; MutableEchoRequestPacket::minimum_packet_size()];
+    let mut request =
+        MutableEchoRequestPacket::new(&mut buffer).expect("Buffer should be large enough");
+    request.set_icmp_type(IcmpTypes::EchoRequest);
+    request.set_identifier(identifier);
+    request.set_sequence_number(sequence);
+    let checksum = icmp::checksum(&IcmpPacket::new(request.packet()).expect("Packet is valid"));
+    request.set_checksum(checksum);
+    tx.send_to(request, ip.into())?;
+
+    let deadline = Instant::now() + timeout;
+    let mut packets = icmp_packet_iter(&mut rx);
+    loop {
+        // A timeout of zero would block forever
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(false);
+        }
+
+        let Some((packet, source)) = packets.next_with_timeout(remaining)? else {
+            return Ok(false);
+        };
+
+        if source != ip || packet.get_icmp_type() != IcmpTypes::EchoReply {
+            continue;
+        }
+
+        let matches = EchoReplyPacket::new(packet.packet()).is_some_and(|reply| {
+            reply.get_identifier() == identifier && reply.get_sequence_number() == sequence
+        });
+        if matches {
+            return Ok(true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn localhost() {
+        match ping(Ipv4Addr::LOCALHOST.into(), Duration::from_secs(1)).await {
+            // Not allowed to open raw sockets
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {}
+            result => assert!(result.unwrap()),
+        }
+    }
+
+    #[tokio::test]
+    async fn ipv6() {
+        let result = ping(
+            IpAddr::V6(std::net::Ipv6Addr::LOCALHOST),
+            Duration::from_secs(1),
+        )
+        .await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::Unsupported);
+    }
+}
@@ -1,3 +1,6 @@
+use std::sync::Arc;
+use std::time::Duration;
+
 use automation_lib::action_callback::ActionCallback;
 use automation_lib::config::{InfoConfig, MqttDeviceConfig};
 use automation_lib::device::{Device, LuaDeviceCreate};
@@ -6,8 +9,15 @@ use automation_lib::messages::{RemoteAction, RemoteMessage};
 use automation_lib::mqtt::WrappedAsyncClient;
 use automation_macro::LuaDeviceConfig;
 use axum::async_trait;
+use google_home::traits::Brightness;
 use rumqttc::{matches, Publish};
-use tracing::{debug, error, trace};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, trace, warn};
+
+// How often and by how much the brightness changes while holding one of the dim buttons
+const DIM_INTERVAL: Duration = Duration::from_millis(300);
+const DIM_STEP: i16 = 10;
 
 #[derive(Debug, Clone, LuaDeviceConfig)]
 pub struct Config {
@@ -25,17 +35,85 @@ pub struct Config {
 
     #[device_config(from_lua)]
     pub callback: ActionCallback<IkeaRemote, bool>,
+
+    #[device_config(from_lua, default)]
+    pub arrow_left_callback: ActionCallback<IkeaRemote, ()>,
+    #[device_config(from_lua, default)]
+    pub arrow_left_hold_callback: ActionCallback<IkeaRemote, ()>,
+    #[device_config(from_lua, default)]
+    pub arrow_left_release_callback: ActionCallback<IkeaRemote, ()>,
+    #[device_config(from_lua, default)]
+    pub arrow_right_callback: ActionCallback<IkeaRemote, ()>,
+    #[device_config(from_lua, default)]
+    pub arrow_right_hold_callback: ActionCallback<IkeaRemote, ()>,
+    #[device_config(from_lua, default)]
+    pub arrow_right_release_callback: ActionCallback<IkeaRemote, ()>,
+
+    // Devices that are dimmed while holding the brightness buttons
+    #[device_config(from_lua, default)]
+    pub targets: Vec<Box<dyn Device>>,
 }
 
 #[derive(Debug, Clone)]
 pub struct IkeaRemote {
     config: Config,
+    dimming: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl IkeaRemote {
+    async fn start_dimming(&self, step: i16) {
+        let mut dimming = self.dimming.lock().await;
+        if let Some(handle) = dimming.take() {
+            handle.abort();
+        }
+
+        if self.config.targets.is_empty() {
+            return;
+        }
+
+        let device = self.clone();
+        *dimming = Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(DIM_INTERVAL);
+            loop {
+                interval.tick().await;
+
+                for target in &device.config.targets {
+                    let Some(target) = target.cast() as Option<&dyn Brightness> else {
+                        continue;
+                    };
+
+                    let brightness = match target.brightness().await {
+                        Ok(brightness) => brightness as i16,
+                        Err(err) => {
+                            warn!(id = device.get_id(), "Failed to get brightness: {err:?}");
+                            continue;
+                        }
+                    };
+
+                    let new = (brightness + step).clamp(0, 100);
+                    if new != brightness {
+                        target.set_brightness(new as u8).await.ok();
+                    }
+                }
+            }
+        }));
+    }
+
+    async fn stop_dimming(&self) {
+        if let Some(handle) = self.dimming.lock().await.take() {
+            handle.abort();
+        }
+    }
 }
 
 impl Device for IkeaRemote {
     fn get_id(&self) -> String {
         self.config.info.identifier()
     }
+
+    fn get_info(&self) -> Option<&InfoConfig> {
+        Some(&self.config.info)
+    }
 }
 
 #[async_trait]
@@ -51,7 +129,10 @@ impl LuaDeviceCreate for IkeaRemote {
             .subscribe(&config.mqtt.topic, rumqttc::QoS::AtLeastOnce)
             .await?;
 
-        Ok(Self { config })
+        Ok(Self {
+            config,
+            dimming: Default::default(),
+        })
     }
 }
 
@@ -85,7 +166,128 @@ impl OnMqtt for IkeaRemote {
 
             if let Some(on) = on {
                 self.config.callback.call(self, &on).await;
+                return;
+            }
+
+            match action {
+                RemoteAction::BrightnessMoveUp => self.start_dimming(DIM_STEP).await,
+                RemoteAction::BrightnessMoveDown => self.start_dimming(-DIM_STEP).await,
+                RemoteAction::BrightnessStop => self.stop_dimming().await,
+                RemoteAction::ArrowLeftClick => {
+                    self.config.arrow_left_callback.call(self, &()).await
+                }
+                RemoteAction::ArrowLeftHold => {
+                    self.config.arrow_left_hold_callback.call(self, &()).await
+                }
+                RemoteAction::ArrowLeftRelease => {
+                    self.config
+                        .arrow_left_release_callback
+                        .call(self, &())
+                        .await
+                }
+                RemoteAction::ArrowRightClick => {
+                    self.config.arrow_right_callback.call(self, &()).await
+                }
+                RemoteAction::ArrowRightHold => {
+                    self.config.arrow_right_hold_callback.call(self, &()).await
+                }
+                RemoteAction::ArrowRightRelease => {
+                    self.config
+                        .arrow_right_release_callback
+                        .call(self, &())
+                        .await
+                }
+                RemoteAction::On | RemoteAction::Off => {}
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use automation_lib::event::EventChannel;
+    use automation_lib::lua::testing::MockMqttClient;
+    use google_home::errors::ErrorCode;
+    use rumqttc::QoS;
+    use tokio::sync::RwLock;
+
+    use super::*;
+
+    #[derive(Debug, Clone, Default)]
+    struct MockLight {
+        brightness: Arc<RwLock<u8>>,
+    }
+
+    impl Device for MockLight {
+        fn get_id(&self) -> String {
+            "light".into()
+        }
+    }
+
+    #[async_trait]
+    impl Brightness for MockLight {
+        async fn brightness(&self) -> Result<u8, ErrorCode> {
+            Ok(*self.brightness.read().await)
+        }
+
+        async fn set_brightness(&self, brightness: u8) -> Result<(), ErrorCode> {
+            *self.brightness.write().await = brightness;
+            Ok(())
+        }
+    }
+
+    async fn remote(client: &MockMqttClient, light: &MockLight) -> IkeaRemote {
+        IkeaRemote::create(Config {
+            info: InfoConfig::new("Remote"),
+            single_button: false,
+            mqtt: MqttDeviceConfig {
+                topic: "zigbee2mqtt/remote".into(),
+                availability: None,
+            },
+            client: client.client(),
+            callback: Default::default(),
+            arrow_left_callback: Default::default(),
+            arrow_left_hold_callback: Default::default(),
+            arrow_left_release_callback: Default::default(),
+            arrow_right_callback: Default::default(),
+            arrow_right_hold_callback: Default::default(),
+            arrow_right_release_callback: Default::default(),
+            targets: vec![Box::new(light.clone())],
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn action(remote: &IkeaRemote, action: &str) {
+        let payload = format!(r#"{{"action":"{action}"}}"#);
+        remote
+            .on_mqtt(Publish::new(
+                "zigbee2mqtt/remote",
+                QoS::AtLeastOnce,
+                payload,
+            ))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn hold_to_dim() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let light = MockLight::default();
+        *light.brightness.write().await = 85;
+        let remote = remote(&client, &light).await;
+
+        // The brightness stops at 100
+        action(&remote, "brightness_move_up").await;
+        tokio::time::sleep(DIM_INTERVAL * 2 + DIM_INTERVAL / 2).await;
+        assert_eq!(*light.brightness.read().await, 100);
+
+        action(&remote, "brightness_move_down").await;
+        tokio::time::sleep(DIM_INTERVAL / 2).await;
+        action(&remote, "brightness_stop").await;
+        assert_eq!(*light.brightness.read().await, 90);
+
+        tokio::time::sleep(DIM_INTERVAL * 2).await;
+        assert_eq!(*light.brightness.read().await, 90);
+    }
+}
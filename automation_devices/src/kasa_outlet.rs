@@ -1,10 +1,13 @@
-use std::convert::Infallible;
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::str::Utf8Error;
+use std::sync::Arc;
+use std::time::Duration;
 
 use async_trait::async_trait;
-use automation_lib::device::{Device, LuaDeviceCreate};
+use automation_lib::action_callback::ActionCallback;
+use automation_lib::device::{Device, DeviceHealth, HealthStatus, LuaDeviceCreate};
 use automation_lib::event::OnPresence;
+use automation_lib::helpers::weak::spawn_weak_interval;
 use automation_macro::LuaDeviceConfig;
 use bytes::{Buf, BufMut};
 use google_home::errors::{self, DeviceError};
@@ -12,29 +15,382 @@ use google_home::traits::OnOff;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tracing::{debug, trace};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::{Mutex, RwLock};
+use tokio::time::Instant;
+use tracing::{debug, trace, warn};
+
+const PORT: u16 = 9999;
+// Responses are a few hundred bytes, anything larger is not coming from an outlet
+const MAX_RESPONSE_SIZE: u32 = 64 * 1024;
+// How long to wait for devices to respond to the discovery broadcast
+pub const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
 
 #[derive(Debug, Clone, LuaDeviceConfig)]
 pub struct Config {
     pub identifier: String,
-    #[device_config(rename("ip"), with(|ip| SocketAddr::new(ip, 9999)))]
-    pub addr: SocketAddr,
+    // If no ip is given the outlet is discovered on the network using the alias or device_id
+    #[device_config(rename("ip"), default, with(|ip: Option<IpAddr>| ip.map(|ip| SocketAddr::new(ip, PORT))))]
+    pub addr: Option<SocketAddr>,
+    #[device_config(default)]
+    pub alias: Option<String>,
+    #[device_config(default)]
+    pub device_id: Option<String>,
+    // How long to wait when connecting to, sending to or receiving from the outlet
+    #[device_config(rename("timeout_seconds"), default(3), with(Duration::from_secs))]
+    pub timeout: Duration,
+    // How long the last known relay state is used when the outlet does not respond
+    #[device_config(
+        rename("max_state_age_seconds"),
+        default(300),
+        with(Duration::from_secs)
+    )]
+    pub max_state_age: Duration,
+
+    // How often to read the energy meter, polling is disabled if not set
+    #[device_config(rename("poll_interval_seconds"), default, with(|s: Option<u64>| s.map(Duration::from_secs)))]
+    pub poll_interval: Option<Duration>,
+    // Called with the current power draw in Watt every time the energy meter is read
+    #[device_config(from_lua, default)]
+    pub power_callback: ActionCallback<KasaOutlet, f32>,
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Either ip, alias or device_id needs to be set")]
+    MissingAddress,
+    #[error("No device found matching alias {alias:?} or device_id {device_id:?}")]
+    NotFound {
+        alias: Option<String>,
+        device_id: Option<String>,
+    },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DiscoveredDevice {
+    pub ip: IpAddr,
+    pub alias: String,
+    pub device_id: String,
+    pub model: String,
+}
+
+// Broadcast a sysinfo request and collect all the replies that come in before the timeout
+pub async fn discover(timeout: Duration) -> Result<Vec<DiscoveredDevice>, std::io::Error> {
+    discover_at(SocketAddr::new(Ipv4Addr::BROADCAST.into(), PORT), timeout).await
+}
+
+async fn discover_at(
+    addr: SocketAddr,
+    timeout: Duration,
+) -> Result<Vec<DiscoveredDevice>, std::io::Error> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+    // Discovery uses the same encryption, but without the length prefix
+    socket
+        .send_to(&Request::get_sysinfo().encrypt()[4..], addr)
+        .await?;
+
+    let mut devices: Vec<DiscoveredDevice> = Vec::new();
+    let mut rx_bytes = [0; 4096];
+    let deadline = Instant::now() + timeout;
+    while let Ok(received) =
+        tokio::time::timeout_at(deadline, socket.recv_from(&mut rx_bytes)).await
+    {
+        let (read, from) = received?;
+
+        let sysinfo = match Response::parse(&autokey_decrypt(&rx_bytes[..read])) {
+            Ok(Response {
+                system:
+                    ResponseSystem {
+                        get_sysinfo: Some(sysinfo),
+                        ..
+                    },
+                ..
+            }) => sysinfo,
+            _ => {
+                debug!("Received invalid discovery response from {from}");
+                continue;
+            }
+        };
+
+        if devices.iter().any(|device| device.ip == from.ip()) {
+            continue;
+        }
+
+        devices.push(DiscoveredDevice {
+            ip: from.ip(),
+            alias: sysinfo.alias,
+            device_id: sysinfo.device_id,
+            model: sysinfo.model,
+        });
+    }
+
+    Ok(devices)
+}
+
+#[derive(Debug, Clone)]
+struct Client {
+    timeout: Duration,
+    // The connection is kept open and reused for the next request
+    connection: Arc<Mutex<Option<(SocketAddr, TcpStream)>>>,
+}
+
+impl Client {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            connection: Default::default(),
+        }
+    }
+
+    async fn request(
+        &self,
+        addr: SocketAddr,
+        request: &Request,
+    ) -> Result<Response, errors::ErrorCode> {
+        let mut connection = self.connection.lock().await;
+
+        match self.try_request(&mut connection, addr, request).await {
+            // The outlet might have closed the connection we were holding on to
+            Err(errors::ErrorCode::DeviceError(DeviceError::TransientError)) => {
+                debug!("Request to {addr} failed, retrying");
+                self.try_request(&mut connection, addr, request).await
+            }
+            result => result,
+        }
+    }
+
+    async fn try_request(
+        &self,
+        connection: &mut Option<(SocketAddr, TcpStream)>,
+        addr: SocketAddr,
+        request: &Request,
+    ) -> Result<Response, errors::ErrorCode> {
+        if !matches!(connection, Some((connected, _)) if *connected == addr) {
+            let stream = tokio::time::timeout(self.timeout, TcpStream::connect(addr))
+                .await
+                .ok()
+                .and_then(Result::ok)
+                .ok_or(DeviceError::DeviceOffline)?;
+
+            *connection = Some((addr, stream));
+        }
+
+        let (_, stream) = connection
+            .as_mut()
+            .expect("Connection should be established");
+
+        match tokio::time::timeout(self.timeout, Self::exchange(stream, request)).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(err)) => {
+                debug!("Request to {addr} failed: {err}");
+                *connection = None;
+                Err(DeviceError::TransientError.into())
+            }
+            Err(_) => {
+                debug!("Request to {addr} timed out");
+                *connection = None;
+                Err(DeviceError::TransientError.into())
+            }
+        }
+    }
+
+    async fn exchange(
+        stream: &mut TcpStream,
+        request: &Request,
+    ) -> Result<Response, ResponseError> {
+        stream.write_all(&request.encrypt()).await?;
+        stream.flush().await?;
+
+        // The response is prefixed with its length
+        let length = stream.read_u32().await?;
+        if length > MAX_RESPONSE_SIZE {
+            return Err(ResponseError::TooLarge(length));
+        }
+
+        let mut received = vec![0; length as usize + 4];
+        received[..4].copy_from_slice(&length.to_be_bytes());
+        stream.read_exact(&mut received[4..]).await?;
+
+        Response::decrypt(received.into())
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    client: Client,
+    // Either the configured address or the address found using discovery
+    addr: RwLock<Option<SocketAddr>>,
+    // Last known relay state and when it was read
+    relay_state: RwLock<Option<(bool, Instant)>>,
 }
 
 #[derive(Debug, Clone)]
 pub struct KasaOutlet {
     config: Config,
+    state: Arc<State>,
+}
+
+impl KasaOutlet {
+    fn matches(&self, device: &DiscoveredDevice) -> bool {
+        self.config.alias.as_ref() == Some(&device.alias)
+            || self.config.device_id.as_ref() == Some(&device.device_id)
+    }
+
+    async fn resolve(&self) -> Result<SocketAddr, Error> {
+        let device = discover(DISCOVERY_TIMEOUT)
+            .await?
+            .into_iter()
+            .find(|device| self.matches(device))
+            .ok_or_else(|| Error::NotFound {
+                alias: self.config.alias.clone(),
+                device_id: self.config.device_id.clone(),
+            })?;
+
+        let addr = SocketAddr::new(device.ip, PORT);
+        debug!(id = Device::get_id(self), "Discovered at {addr}");
+        *self.state.addr.write().await = Some(addr);
+
+        Ok(addr)
+    }
+
+    async fn request(&self, request: Request) -> Result<Response, errors::ErrorCode> {
+        let addr = match *self.state.addr.read().await {
+            Some(addr) => addr,
+            None => self
+                .resolve()
+                .await
+                .or::<DeviceError>(Err(DeviceError::DeviceOffline))?,
+        };
+
+        match self.state.client.request(addr, &request).await {
+            // The address might have changed, so try to discover the device again
+            Err(errors::ErrorCode::DeviceError(DeviceError::DeviceOffline))
+                if self.config.addr.is_none() =>
+            {
+                debug!(
+                    id = Device::get_id(self),
+                    "Device offline, trying to discover it again"
+                );
+                let addr = self
+                    .resolve()
+                    .await
+                    .or::<DeviceError>(Err(DeviceError::DeviceOffline))?;
+
+                self.state.client.request(addr, &request).await
+            }
+            result => result,
+        }
+    }
+
+    async fn realtime(&self) -> Result<Realtime, errors::ErrorCode> {
+        self.request(Request::get_realtime())
+            .await?
+            .get_realtime()
+            .or(Err(DeviceError::TransientError.into()))
+    }
+
+    // Current power draw in Watt
+    pub async fn power(&self) -> Result<f32, errors::ErrorCode> {
+        Ok(self.realtime().await?.power())
+    }
+
+    // Total energy used in kWh
+    pub async fn energy_total(&self) -> Result<f32, errors::ErrorCode> {
+        Ok(self.realtime().await?.energy_total())
+    }
+
+    // Time since the relay state was last successfully read or set
+    pub async fn updated(&self) -> Option<Duration> {
+        self.state
+            .relay_state
+            .read()
+            .await
+            .map(|(_, updated)| updated.elapsed())
+    }
+
+    // Rebuilds the device in the background task, see automation_lib::helpers::weak
+    fn downgrade(&self) -> impl Fn() -> Option<Self> + Send + 'static {
+        let config = self.config.clone();
+        let state = Arc::downgrade(&self.state);
+
+        move || {
+            Some(Self {
+                config: config.clone(),
+                state: state.upgrade()?,
+            })
+        }
+    }
+
+    async fn poll(&self) {
+        match self.realtime().await {
+            Ok(realtime) => {
+                let power = realtime.power();
+                trace!(
+                    id = Device::get_id(self),
+                    "Power: {power}W, voltage: {}V",
+                    realtime.voltage()
+                );
+                self.config.power_callback.call(self, &power).await;
+            }
+            Err(err) => warn!(
+                id = Device::get_id(self),
+                "Failed to read energy meter: {err:?}"
+            ),
+        }
+    }
 }
 
 #[async_trait]
 impl LuaDeviceCreate for KasaOutlet {
     type Config = Config;
-    type Error = Infallible;
+    type Error = Error;
 
     async fn create(config: Self::Config) -> Result<Self, Self::Error> {
         trace!(id = config.identifier, "Setting up KasaOutlet");
-        Ok(Self { config })
+
+        if config.addr.is_none() && config.alias.is_none() && config.device_id.is_none() {
+            return Err(Error::MissingAddress);
+        }
+
+        let outlet = Self {
+            state: Arc::new(State {
+                client: Client::new(config.timeout),
+                addr: RwLock::new(config.addr),
+                relay_state: Default::default(),
+            }),
+            config,
+        };
+
+        if outlet.config.addr.is_none() {
+            outlet.resolve().await?;
+        }
+
+        if let Some(interval) = outlet.config.poll_interval {
+            spawn_weak_interval(outlet.downgrade(), interval, |outlet| async move {
+                outlet.poll().await
+            });
+        }
+
+        Ok(outlet)
+    }
+}
+
+#[async_trait]
+impl DeviceHealth for KasaOutlet {
+    async fn check_health(&self) -> HealthStatus {
+        let addr = match *self.state.addr.read().await {
+            Some(addr) => addr,
+            None => return HealthStatus::Unhealthy("Outlet has not been discovered".into()),
+        };
+
+        match tokio::time::timeout(self.config.timeout, TcpStream::connect(addr)).await {
+            Ok(Ok(_)) => HealthStatus::Healthy,
+            Ok(Err(err)) => HealthStatus::Unhealthy(err.to_string()),
+            Err(_) => HealthStatus::Unhealthy(format!("Connecting to {addr} timed out")),
+        }
     }
 }
 
@@ -50,7 +406,7 @@ struct RequestRelayState {
 }
 
 #[derive(Debug, Serialize)]
-struct RequestSysinfo;
+struct RequestSysinfo {}
 
 #[derive(Debug, Serialize)]
 struct RequestSystem {
@@ -60,47 +416,87 @@ struct RequestSystem {
     set_relay_state: Option<RequestRelayState>,
 }
 
+#[derive(Debug, Serialize)]
+struct RequestRealtime {}
+
+#[derive(Debug, Serialize)]
+struct RequestEmeter {
+    get_realtime: RequestRealtime,
+}
+
 #[derive(Debug, Serialize)]
 struct Request {
-    system: RequestSystem,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<RequestSystem>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    emeter: Option<RequestEmeter>,
 }
 
 impl Request {
     fn get_sysinfo() -> Self {
         Self {
-            system: RequestSystem {
+            system: Some(RequestSystem {
                 get_sysinfo: Some(RequestSysinfo {}),
                 set_relay_state: None,
-            },
+            }),
+            emeter: None,
         }
     }
 
     fn set_relay_state(on: bool) -> Self {
         Self {
-            system: RequestSystem {
+            system: Some(RequestSystem {
                 get_sysinfo: None,
                 set_relay_state: Some(RequestRelayState {
                     state: if on { 1 } else { 0 },
                 }),
-            },
+            }),
+            emeter: None,
+        }
+    }
+
+    fn get_realtime() -> Self {
+        Self {
+            system: None,
+            emeter: Some(RequestEmeter {
+                get_realtime: RequestRealtime {},
+            }),
         }
     }
 
     fn encrypt(&self) -> bytes::Bytes {
-        let data: bytes::Bytes = serde_json::to_string(self).unwrap().into();
+        encrypt(serde_json::to_string(self).unwrap().as_bytes())
+    }
+}
 
-        let mut key: u8 = 171;
-        let mut encrypted = bytes::BytesMut::with_capacity(data.len() + 4);
+fn autokey_encrypt(data: &[u8]) -> Vec<u8> {
+    let mut key: u8 = 171;
+    data.iter()
+        .map(|c| {
+            key ^= c;
+            key
+        })
+        .collect()
+}
 
-        encrypted.put_u32(data.len() as u32);
+fn autokey_decrypt(data: &[u8]) -> Vec<u8> {
+    let mut key: u8 = 171;
+    data.iter()
+        .map(|&c| {
+            let decrypted = key ^ c;
+            key = c;
+            decrypted
+        })
+        .collect()
+}
 
-        for c in data {
-            key ^= c;
-            encrypted.put_u8(key);
-        }
+fn encrypt(data: &[u8]) -> bytes::Bytes {
+    let mut encrypted = bytes::BytesMut::with_capacity(data.len() + 4);
 
-        encrypted.freeze()
-    }
+    encrypted.put_u32(data.len() as u32);
+    encrypted.put_slice(&autokey_encrypt(data));
+
+    encrypted.freeze()
 }
 
 #[derive(Debug, Deserialize)]
@@ -129,17 +525,61 @@ struct ResponseGetSysinfo {
     #[serde(flatten)]
     err_code: ErrorCode,
     relay_state: isize,
+    #[serde(default)]
+    alias: String,
+    #[serde(default, rename = "deviceId")]
+    device_id: String,
+    #[serde(default)]
+    model: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 struct ResponseSystem {
     set_relay_state: Option<ResponseSetRelayState>,
     get_sysinfo: Option<ResponseGetSysinfo>,
 }
 
+#[derive(Debug, Clone, Copy, Deserialize)]
+struct Realtime {
+    power_mw: u32,
+    voltage_mv: u32,
+    total_wh: u32,
+}
+
+impl Realtime {
+    fn power(&self) -> f32 {
+        self.power_mw as f32 / 1000.0
+    }
+
+    fn voltage(&self) -> f32 {
+        self.voltage_mv as f32 / 1000.0
+    }
+
+    fn energy_total(&self) -> f32 {
+        self.total_wh as f32 / 1000.0
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ResponseGetRealtime {
+    #[serde(flatten)]
+    err_code: ErrorCode,
+    // Not present if the device returned an error
+    #[serde(flatten)]
+    realtime: Option<Realtime>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ResponseEmeter {
+    get_realtime: Option<ResponseGetRealtime>,
+}
+
 #[derive(Debug, Deserialize)]
 struct Response {
+    #[serde(default)]
     system: ResponseSystem,
+    #[serde(default)]
+    emeter: ResponseEmeter,
 }
 
 // TODO: Improve this error
@@ -147,10 +587,14 @@ struct Response {
 enum ResponseError {
     #[error("Expected a minimum data length of 4")]
     ToShort,
+    #[error("Response of {0} bytes is too large")]
+    TooLarge(u32),
     #[error("No sysinfo found in response")]
     SysinfoNotFound,
     #[error("No relay_state not found in response")]
     RelayStateNotFound,
+    #[error("No realtime emeter data found in response")]
+    RealtimeNotFound,
     #[error("Error code: {0}")]
     ErrorCode(isize),
     #[error(transparent)]
@@ -163,6 +607,12 @@ impl From<Utf8Error> for ResponseError {
     }
 }
 
+impl From<std::io::Error> for ResponseError {
+    fn from(err: std::io::Error) -> Self {
+        ResponseError::Other(err.into())
+    }
+}
+
 impl From<serde_json::Error> for ResponseError {
     fn from(err: serde_json::Error) -> Self {
         ResponseError::Other(err.into())
@@ -186,21 +636,27 @@ impl Response {
         Err(ResponseError::RelayStateNotFound)
     }
 
+    fn get_realtime(&self) -> Result<Realtime, ResponseError> {
+        if let Some(realtime) = &self.emeter.get_realtime {
+            realtime.err_code.ok()?;
+            return realtime.realtime.ok_or(ResponseError::RealtimeNotFound);
+        }
+
+        Err(ResponseError::RealtimeNotFound)
+    }
+
     fn decrypt(mut data: bytes::Bytes) -> Result<Self, ResponseError> {
-        let mut key: u8 = 171;
         if data.len() < 4 {
             return Err(ResponseError::ToShort);
         }
 
-        let length = data.get_u32();
-        let mut decrypted = bytes::BytesMut::with_capacity(length as usize);
+        let _length = data.get_u32();
 
-        for c in data {
-            decrypted.put_u8(key ^ c);
-            key = c;
-        }
+        Self::parse(&autokey_decrypt(&data))
+    }
 
-        let decrypted = std::str::from_utf8(&decrypted)?;
+    fn parse(decrypted: &[u8]) -> Result<Self, ResponseError> {
+        let decrypted = std::str::from_utf8(decrypted)?;
         Ok(serde_json::from_str(decrypted)?)
     }
 }
@@ -208,71 +664,50 @@ impl Response {
 #[async_trait]
 impl OnOff for KasaOutlet {
     async fn on(&self) -> Result<bool, errors::ErrorCode> {
-        let mut stream = TcpStream::connect(self.config.addr)
-            .await
-            .or::<DeviceError>(Err(DeviceError::DeviceOffline))?;
-
-        let body = Request::get_sysinfo().encrypt();
-        stream
-            .write_all(&body)
-            .await
-            .and(stream.flush().await)
-            .or::<DeviceError>(Err(DeviceError::TransientError))?;
-
-        let mut received = Vec::new();
-        let mut rx_bytes = [0; 1024];
-        loop {
-            let read = stream
-                .read(&mut rx_bytes)
-                .await
-                .or::<errors::ErrorCode>(Err(DeviceError::TransientError.into()))?;
-
-            received.extend_from_slice(&rx_bytes[..read]);
-
-            if read < rx_bytes.len() {
-                break;
+        let result = self.request(Request::get_sysinfo()).await.and_then(|resp| {
+            resp.get_current_relay_state()
+                .or(Err(DeviceError::TransientError.into()))
+        });
+
+        match result {
+            Ok(on) => {
+                *self.state.relay_state.write().await = Some((on, Instant::now()));
+                Ok(on)
+            }
+            Err(err) => {
+                // Fall back to the last known state instead of failing the query, as long as it is
+                // recent enough
+                let Some((on, updated)) = *self.state.relay_state.read().await else {
+                    return Err(err);
+                };
+
+                if updated.elapsed() > self.config.max_state_age {
+                    warn!(
+                        id = Device::get_id(self),
+                        "Failed to get relay state ({err:?}), last known state is too old"
+                    );
+                    return Err(DeviceError::DeviceOffline.into());
+                }
+
+                warn!(
+                    id = Device::get_id(self),
+                    "Failed to get relay state ({err:?}), using state from {:?} ago",
+                    updated.elapsed()
+                );
+                Ok(on)
             }
         }
-
-        let resp = Response::decrypt(received.into())
-            .or::<errors::ErrorCode>(Err(DeviceError::TransientError.into()))?;
-
-        resp.get_current_relay_state()
-            .or(Err(DeviceError::TransientError.into()))
     }
 
     async fn set_on(&self, on: bool) -> Result<(), errors::ErrorCode> {
-        let mut stream = TcpStream::connect(self.config.addr)
-            .await
-            .or::<DeviceError>(Err(DeviceError::DeviceOffline))?;
-
-        let body = Request::set_relay_state(on).encrypt();
-        stream
-            .write_all(&body)
-            .await
-            .and(stream.flush().await)
-            .or::<DeviceError>(Err(DeviceError::TransientError))?;
-
-        let mut received = Vec::new();
-        let mut rx_bytes = [0; 1024];
-        loop {
-            let read = match stream.read(&mut rx_bytes).await {
-                Ok(read) => read,
-                Err(_) => return Err(DeviceError::TransientError.into()),
-            };
-
-            received.extend_from_slice(&rx_bytes[..read]);
-
-            if read < rx_bytes.len() {
-                break;
-            }
-        }
-
-        let resp = Response::decrypt(received.into())
+        self.request(Request::set_relay_state(on))
+            .await?
+            .check_set_relay_success()
             .or::<errors::ErrorCode>(Err(DeviceError::TransientError.into()))?;
 
-        resp.check_set_relay_success()
-            .or(Err(DeviceError::TransientError.into()))
+        *self.state.relay_state.write().await = Some((on, Instant::now()));
+
+        Ok(())
     }
 }
 
@@ -285,3 +720,270 @@ impl OnPresence for KasaOutlet {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    // Captured from a KP115
+    const REALTIME: &str = r#"{"emeter":{"get_realtime":{"current_ma":1126,"voltage_mv":229522,"power_mw":251763,"total_wh":1033,"err_code":0}}}"#;
+
+    fn decrypt(data: bytes::Bytes) -> String {
+        String::from_utf8(autokey_decrypt(&data[4..])).unwrap()
+    }
+
+    // Receive a request as the outlet
+    async fn receive(stream: &mut TcpStream) -> String {
+        let length = stream.read_u32().await.unwrap();
+        let mut request = vec![0; length as usize];
+        stream.read_exact(&mut request).await.unwrap();
+
+        String::from_utf8(autokey_decrypt(&request)).unwrap()
+    }
+
+    fn config(addr: Option<SocketAddr>) -> Config {
+        Config {
+            identifier: "outlet".into(),
+            addr,
+            alias: None,
+            device_id: None,
+            timeout: Duration::from_millis(100),
+            max_state_age: Duration::from_secs(300),
+            poll_interval: None,
+            power_callback: Default::default(),
+        }
+    }
+
+    #[test]
+    fn serialize_get_realtime() {
+        assert_eq!(
+            decrypt(Request::get_realtime().encrypt()),
+            r#"{"emeter":{"get_realtime":{}}}"#
+        );
+        assert_eq!(
+            decrypt(Request::set_relay_state(true).encrypt()),
+            r#"{"system":{"set_relay_state":{"state":1}}}"#
+        );
+    }
+
+    #[test]
+    fn deserialize_realtime() {
+        let response = Response::decrypt(encrypt(REALTIME.as_bytes())).unwrap();
+        let realtime = response.get_realtime().unwrap();
+
+        assert_eq!(realtime.power(), 251.763);
+        assert_eq!(realtime.energy_total(), 1.033);
+        assert!(response.get_current_relay_state().is_err());
+
+        let response = Response::decrypt(encrypt(
+            r#"{"emeter":{"get_realtime":{"err_code":-1,"err_msg":"module not support"}}}"#
+                .as_bytes(),
+        ))
+        .unwrap();
+        assert!(response.get_realtime().is_err());
+    }
+
+    #[tokio::test]
+    async fn power() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert_eq!(
+                receive(&mut stream).await,
+                r#"{"emeter":{"get_realtime":{}}}"#
+            );
+
+            stream
+                .write_all(&encrypt(REALTIME.as_bytes()))
+                .await
+                .unwrap();
+        });
+
+        let outlet = KasaOutlet::create(config(Some(addr))).await.unwrap();
+
+        assert_eq!(outlet.power().await.unwrap(), 251.763);
+    }
+
+    #[tokio::test]
+    async fn discovery() {
+        let device = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = device.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut request = [0; 1024];
+            let (read, from) = device.recv_from(&mut request).await.unwrap();
+            assert_eq!(
+                autokey_decrypt(&request[..read]),
+                br#"{"system":{"get_sysinfo":{}}}"#
+            );
+
+            let response = autokey_encrypt(
+                br#"{"system":{"get_sysinfo":{"sw_ver":"1.0.10 Build 221019 Rel.194527","model":"KP115(EU)","deviceId":"8006B8E2A1","alias":"Washing machine","relay_state":1,"err_code":0}}}"#,
+            );
+            device.send_to(&response, from).await.unwrap();
+            // Replies that are not valid should be ignored
+            device.send_to(b"invalid", from).await.unwrap();
+        });
+
+        let devices = discover_at(addr, Duration::from_millis(100)).await.unwrap();
+
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].ip, addr.ip());
+        assert_eq!(devices[0].alias, "Washing machine");
+        assert_eq!(devices[0].device_id, "8006B8E2A1");
+        assert_eq!(devices[0].model, "KP115(EU)");
+    }
+
+    #[tokio::test]
+    async fn split_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            receive(&mut stream).await;
+
+            let response = encrypt(br#"{"system":{"get_sysinfo":{"relay_state":1,"err_code":0}}}"#);
+            let (first, second) = response.split_at(10);
+            stream.write_all(first).await.unwrap();
+            stream.flush().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            stream.write_all(second).await.unwrap();
+        });
+
+        let outlet = KasaOutlet::create(config(Some(addr))).await.unwrap();
+
+        assert!(outlet.on().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn response_too_large() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            receive(&mut stream).await;
+            stream.write_u32(u32::MAX).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let result = Client::exchange(&mut stream, &Request::get_sysinfo()).await;
+
+        assert!(matches!(result, Err(ResponseError::TooLarge(u32::MAX))));
+    }
+
+    #[tokio::test]
+    async fn cached_state() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            receive(&mut stream).await;
+            stream
+                .write_all(&encrypt(
+                    br#"{"system":{"get_sysinfo":{"relay_state":1,"err_code":0}}}"#,
+                ))
+                .await
+                .unwrap();
+
+            // Stop responding, but keep the connection open
+            receive(&mut stream).await;
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+
+        let outlet = KasaOutlet::create(config(Some(addr))).await.unwrap();
+
+        assert!(outlet.on().await.unwrap());
+        // The request times out, so the last known state is returned
+        assert!(outlet.on().await.unwrap());
+        assert!(outlet.updated().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn stale_state() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            receive(&mut stream).await;
+            stream
+                .write_all(&encrypt(
+                    br#"{"system":{"get_sysinfo":{"relay_state":1,"err_code":0}}}"#,
+                ))
+                .await
+                .unwrap();
+
+            // Stop responding, but keep the connection open
+            receive(&mut stream).await;
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+
+        let outlet = KasaOutlet::create(Config {
+            max_state_age: Duration::from_millis(50),
+            ..config(Some(addr))
+        })
+        .await
+        .unwrap();
+
+        assert!(outlet.updated().await.is_none());
+        assert!(outlet.on().await.unwrap());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // The request times out and the last known state is too old to use
+        let err = outlet.on().await.unwrap_err();
+        assert_eq!(err, DeviceError::DeviceOffline.into());
+        assert!(outlet.updated().await.unwrap() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+
+        let outlet = KasaOutlet::create(config(Some(addr))).await.unwrap();
+
+        assert!(outlet.on().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn poll_stops_with_outlet() {
+        // Nothing is listening on this port, so every poll fails straight away
+        let addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+
+        let outlet = KasaOutlet::create(Config {
+            poll_interval: Some(Duration::from_millis(10)),
+            ..config(Some(addr))
+        })
+        .await
+        .unwrap();
+        let state = Arc::downgrade(&outlet.state);
+
+        drop(outlet);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(state.upgrade().is_none());
+    }
+
+    #[tokio::test]
+    async fn missing_address() {
+        assert!(matches!(
+            KasaOutlet::create(config(None)).await,
+            Err(Error::MissingAddress)
+        ));
+    }
+}
@@ -1,34 +1,71 @@
 mod air_filter;
+mod cast_channel;
+mod cast_device;
 mod contact_sensor;
 mod debug_bridge;
+mod doorbell;
+mod group;
+mod http_switch;
 mod hue_bridge;
 mod hue_group;
 mod hue_switch;
+mod icmp;
 mod ikea_remote;
 mod kasa_outlet;
 mod light_sensor;
+pub mod miio;
+mod mqtt_sensor;
+mod ping_presence;
+mod power_strip;
+mod shelly;
+mod system_monitor;
+mod tasmota;
 mod wake_on_lan;
 mod washer;
+mod wled;
+mod xiaomi_air_purifier;
 mod zigbee;
 
 use std::ops::Deref;
 
 use automation_cast::Cast;
 use automation_lib::device::{Device, LuaDeviceCreate};
-use zigbee::light::{LightBrightness, LightOnOff};
-use zigbee::outlet::{OutletOnOff, OutletPower};
+use mlua::LuaSerdeExt;
 
 pub use self::air_filter::AirFilter;
+pub use self::cast_device::CastDevice;
 pub use self::contact_sensor::ContactSensor;
 pub use self::debug_bridge::DebugBridge;
+pub use self::doorbell::Doorbell;
+pub use self::group::{DimmableGroup, Group};
+pub use self::http_switch::HttpSwitch;
 pub use self::hue_bridge::HueBridge;
 pub use self::hue_group::HueGroup;
 pub use self::hue_switch::HueSwitch;
 pub use self::ikea_remote::IkeaRemote;
 pub use self::kasa_outlet::KasaOutlet;
 pub use self::light_sensor::LightSensor;
+pub use self::mqtt_sensor::GenericMqttSensor;
+pub use self::ping_presence::PingPresence;
+pub use self::power_strip::PowerStrip;
+pub use self::shelly::ShellySwitch;
+pub use self::system_monitor::SystemMonitor;
+pub use self::tasmota::TasmotaOutlet;
 pub use self::wake_on_lan::WakeOnLAN;
 pub use self::washer::Washer;
+pub use self::wled::Wled;
+pub use self::xiaomi_air_purifier::XiaomiAirPurifier;
+pub use self::zigbee::blind::Blind;
+pub use self::zigbee::bridge::Zigbee2MqttBridge;
+pub use self::zigbee::climate::ClimateSensor;
+pub use self::zigbee::dehumidifier::SmartDehumidifier;
+pub use self::zigbee::leak::LeakSensor;
+pub use self::zigbee::light::{LightBrightness, LightColor, LightOnOff};
+pub use self::zigbee::motion::MotionSensor;
+pub use self::zigbee::outlet::{OutletOnOff, OutletPower};
+pub use self::zigbee::remote::ActionRemote;
+pub use self::zigbee::smoke::SmokeDetector;
+pub use self::zigbee::valve::Valve;
 
 macro_rules! register_device {
     ($lua:expr, $device:ty) => {
@@ -39,10 +76,28 @@ macro_rules! register_device {
 
 macro_rules! impl_device {
     ($device:ty) => {
+        impl_device!($device, _methods => {});
+    };
+    ($device:ty, $methods:ident => $extra:block) => {
         impl mlua::UserData for $device {
             fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
-                methods.add_async_function("new", |_lua, config| async {
-                    let device: $device = LuaDeviceCreate::create(config)
+                methods.add_async_function("new", |lua, config: mlua::Value| async move {
+                    let retry: automation_lib::config::RetryConfig = mlua::LuaSerdeExt::from_value_with(
+                        &lua,
+                        config.clone(),
+                        mlua::DeserializeOptions::new().deny_unsupported_types(false),
+                    )?;
+                    let id: automation_lib::config::ConfigIdentifier = mlua::LuaSerdeExt::from_value_with(
+                        &lua,
+                        config.clone(),
+                        mlua::DeserializeOptions::new().deny_unsupported_types(false),
+                    )?;
+                    let config = automation_lib::error::ConfigContext::with_device_type(
+                        mlua::FromLua::from_lua(config, &lua),
+                        stringify!($device),
+                    )?;
+
+                    let device: $device = LuaDeviceCreate::create_with_retry(&id.identifier(), config, retry.retry_count, retry.delay())
                         .await
                         .map_err(mlua::ExternalError::into_lua_err)?;
 
@@ -56,6 +111,10 @@ macro_rules! impl_device {
 
                 methods.add_async_method("get_id", |_lua, this, _: ()| async move { Ok(this.get_id()) });
 
+                methods.add_method("implements", |_lua, this, name: String| {
+                    Ok(google_home::traits::has_trait(this, &name))
+                });
+
                 if impls::impls!($device: google_home::traits::OnOff) {
                     methods.add_async_method("set_on", |_lua, this, on: bool| async move {
                         (this.deref().cast() as Option<&dyn google_home::traits::OnOff>)
@@ -74,6 +133,13 @@ macro_rules! impl_device {
                             .await
                             .unwrap())
                     });
+
+                    methods.add_async_method("toggle", |_lua, this, _: ()| async move {
+                        automation_lib::lua::traits::on_off::toggle((this.deref().cast() as Option<&dyn google_home::traits::OnOff>)
+                            .expect("Cast should be valid"))
+                            .await
+                            .map_err(mlua::ExternalError::into_lua_err)
+                    });
                 }
 
                 if impls::impls!($device: google_home::traits::Brightness) {
@@ -117,43 +183,390 @@ macro_rules! impl_device {
                             .unwrap())
                     });
                 }
+
+                if impls::impls!($device: automation_lib::lua::traits::LightEffect) {
+                    methods.add_async_method("effect", |lua, this, effect: mlua::Value| async move {
+                        let effect = lua.from_value(effect)?;
+                        (this.deref().cast() as Option<&dyn automation_lib::lua::traits::LightEffect>)
+                            .expect("Cast should be valid")
+                            .effect(effect)
+                            .await
+                            .map_err(mlua::ExternalError::into_lua_err)?;
+
+                        Ok(())
+                    });
+                }
+
+                if impls::impls!($device: automation_lib::lua::traits::Timeout) {
+                    methods.add_async_method("start_timeout", |_lua, this, timeout: u64| async move {
+                        (this.deref().cast() as Option<&dyn automation_lib::lua::traits::Timeout>)
+                            .expect("Cast should be valid")
+                            .start_timeout(std::time::Duration::from_secs(timeout))
+                            .await;
+
+                        Ok(())
+                    });
+
+                    methods.add_async_method("stop_timeout", |_lua, this, _: ()| async move {
+                        (this.deref().cast() as Option<&dyn automation_lib::lua::traits::Timeout>)
+                            .expect("Cast should be valid")
+                            .stop_timeout()
+                            .await;
+
+                        Ok(())
+                    });
+                }
+
+                let $methods = methods;
+                $extra
             }
         }
     };
 }
 
-impl_device!(LightOnOff);
-impl_device!(LightBrightness);
+impl_device!(LightOnOff, methods => {
+    methods.add_async_method("set_state", |lua, this, state: mlua::Value| async move {
+        this.set_state(lua.from_value(state)?).await;
+
+        Ok(())
+    });
+});
+impl_device!(LightBrightness, methods => {
+    methods.add_async_method("set_state", |lua, this, state: mlua::Value| async move {
+        this.set_state(lua.from_value(state)?).await;
+
+        Ok(())
+    });
+});
+impl_device!(LightColor, methods => {
+    methods.add_async_method("set_state", |lua, this, state: mlua::Value| async move {
+        this.set_state(lua.from_value(state)?).await;
+
+        Ok(())
+    });
+});
 impl_device!(OutletOnOff);
-impl_device!(OutletPower);
-impl_device!(AirFilter);
+impl_device!(OutletPower, methods => {
+    methods.add_async_method("energy_today", |_lua, this, _: ()| async move {
+        Ok(this.energy_today().await)
+    });
+
+    methods.add_async_method("energy_today_wh", |_lua, this, _: ()| async move {
+        Ok(this.energy_today().await * 1000.0)
+    });
+
+    methods.add_async_method("energy_total", |_lua, this, _: ()| async move {
+        Ok(this.energy_total().await)
+    });
+});
+impl_device!(ActionRemote);
+impl_device!(AirFilter, methods => {
+    methods.add_async_method("set_auto", |_lua, this, enabled: bool| async move {
+        this.set_auto(enabled).await;
+        Ok(())
+    });
+
+    methods.add_async_method("is_auto", |_lua, this, _: ()| async move {
+        Ok(this.is_auto().await)
+    });
+});
+impl_device!(CastDevice, methods => {
+    methods.add_method("play", |_lua, this, _: ()| {
+        this.play().map_err(mlua::ExternalError::into_lua_err)
+    });
+
+    methods.add_method("pause", |_lua, this, _: ()| {
+        this.pause().map_err(mlua::ExternalError::into_lua_err)
+    });
+
+    methods.add_method("stop", |_lua, this, _: ()| {
+        this.stop().map_err(mlua::ExternalError::into_lua_err)
+    });
+
+    methods.add_method("playback_state", |lua, this, _: ()| {
+        lua.to_value(&this.playback_state())
+    });
+});
+impl_device!(ClimateSensor, methods => {
+    methods.add_async_method("temperature", |_lua, this, _: ()| async move {
+        Ok(this.temperature().await)
+    });
+
+    methods.add_async_method("humidity", |_lua, this, _: ()| async move {
+        Ok(this.humidity().await)
+    });
+
+    methods.add_async_method("reading", |lua, this, _: ()| async move {
+        lua.to_value(&this.reading().await)
+    });
+});
+impl_device!(Blind);
 impl_device!(ContactSensor);
-impl_device!(DebugBridge);
+impl_device!(DebugBridge, methods => {
+    // Returns the number of events that were replayed
+    methods.add_async_method(
+        "replay",
+        |_lua, this, (file, speed): (String, Option<f64>)| async move {
+            this.replay(std::path::Path::new(&file), speed.unwrap_or(1.0))
+                .await
+                .map_err(mlua::ExternalError::into_lua_err)
+        },
+    );
+});
+impl_device!(DimmableGroup);
+impl_device!(Doorbell);
+impl_device!(GenericMqttSensor, methods => {
+    methods.add_async_method("value", |lua, this, _: ()| async move {
+        lua.to_value(&this.value().await)
+    });
+});
+impl_device!(Group);
+impl_device!(HttpSwitch);
 impl_device!(HueBridge);
-impl_device!(HueGroup);
+impl_device!(HueGroup, methods => {
+    methods.add_async_method("activate_scene", |_lua, this, scene: String| async move {
+        this.activate_scene(&scene)
+            .await
+            .map_err(mlua::ExternalError::into_lua_err)
+    });
+});
 impl_device!(HueSwitch);
 impl_device!(IkeaRemote);
-impl_device!(KasaOutlet);
+impl_device!(KasaOutlet, methods => {
+    methods.add_async_function("discover", |lua, timeout_seconds: Option<u64>| async move {
+        let timeout = timeout_seconds
+            .map(std::time::Duration::from_secs)
+            .unwrap_or(kasa_outlet::DISCOVERY_TIMEOUT);
+        let devices = kasa_outlet::discover(timeout)
+            .await
+            .map_err(mlua::ExternalError::into_lua_err)?;
+
+        lua.to_value(&devices)
+    });
+
+    methods.add_async_method("power", |_lua, this, _: ()| async move {
+        this.power().await.map_err(mlua::ExternalError::into_lua_err)
+    });
+
+    methods.add_async_method("energy_total", |_lua, this, _: ()| async move {
+        this.energy_total()
+            .await
+            .map_err(mlua::ExternalError::into_lua_err)
+    });
+
+    // Seconds since the relay state was last read or set, nil if it is not known yet
+    methods.add_async_method("updated", |_lua, this, _: ()| async move {
+        Ok(this.updated().await.map(|updated| updated.as_secs_f64()))
+    });
+});
+impl_device!(LeakSensor, methods => {
+    methods.add_async_method("leak", |_lua, this, _: ()| async move { Ok(this.leak().await) });
+
+    methods.add_async_method("battery", |_lua, this, _: ()| async move {
+        Ok(this.battery().await)
+    });
+});
 impl_device!(LightSensor);
-impl_device!(WakeOnLAN);
-impl_device!(Washer);
+impl_device!(MotionSensor, methods => {
+    methods.add_async_method("occupancy", |_lua, this, _: ()| async move {
+        Ok(this.occupancy().await)
+    });
+
+    methods.add_async_method("illuminance", |_lua, this, _: ()| async move {
+        Ok(this.illuminance().await)
+    });
+
+    methods.add_async_method("battery", |_lua, this, _: ()| async move {
+        Ok(this.battery().await)
+    });
+});
+impl_device!(PingPresence, methods => {
+    methods.add_async_method("is_present", |_lua, this, name: String| async move {
+        Ok(this.is_present(&name).await)
+    });
+});
+impl_device!(PowerStrip, methods => {
+    methods.add_async_method("socket", |_lua, this, key: String| async move {
+        Ok(this.socket(&key).await)
+    });
+
+    methods.add_async_method("set_socket", |_lua, this, (key, on): (String, bool)| async move {
+        this.set_sockets(std::collections::HashMap::from([(key, on)]))
+            .await
+            .map_err(mlua::ExternalError::into_lua_err)
+    });
+});
+impl_device!(SmokeDetector, methods => {
+    methods.add_async_method("smoke", |_lua, this, _: ()| async move { Ok(this.smoke().await) });
+
+    methods.add_async_method("battery", |_lua, this, _: ()| async move {
+        Ok(this.battery().await)
+    });
+
+    methods.add_async_method("hush", |_lua, this, _: ()| async move {
+        this.hush().await.map_err(mlua::ExternalError::into_lua_err)
+    });
+
+    methods.add_async_method("test", |_lua, this, _: ()| async move {
+        this.test().await.map_err(mlua::ExternalError::into_lua_err)
+    });
+});
+impl_device!(SmartDehumidifier, methods => {
+    methods.add_async_method("humidity", |_lua, this, _: ()| async move {
+        Ok(this.humidity().await)
+    });
+
+    methods.add_async_method("target_humidity", |_lua, this, _: ()| async move {
+        Ok(this.target_humidity().await)
+    });
+});
+impl_device!(ShellySwitch, methods => {
+    methods.add_async_method("power", |_lua, this, _: ()| async move {
+        this.power().await.map_err(mlua::ExternalError::into_lua_err)
+    });
+
+    methods.add_async_method("status", |lua, this, _: ()| async move {
+        let status = this
+            .status()
+            .await
+            .map_err(mlua::ExternalError::into_lua_err)?;
+        lua.to_value(&status)
+    });
+});
+impl_device!(SystemMonitor, methods => {
+    methods.add_async_method("reading", |lua, this, _: ()| async move {
+        lua.to_value(&this.reading().await)
+    });
+
+    methods.add_async_method("temperature", |_lua, this, _: ()| async move {
+        Ok(this.temperature().await)
+    });
+
+    methods.add_async_method("load", |_lua, this, _: ()| async move {
+        Ok(this.load().await)
+    });
+
+    methods.add_async_method("memory", |_lua, this, _: ()| async move {
+        Ok(this.memory().await)
+    });
+
+    methods.add_async_method("disk", |_lua, this, _: ()| async move {
+        Ok(this.disk().await)
+    });
+});
+impl_device!(TasmotaOutlet, methods => {
+    methods.add_async_method("power", |_lua, this, _: ()| async move {
+        Ok(this.power().await)
+    });
+
+    methods.add_async_method("energy", |lua, this, _: ()| async move {
+        lua.to_value(&this.energy().await)
+    });
+});
+impl_device!(Valve, methods => {
+    methods.add_async_method("run_for", |_lua, this, seconds: u64| async move {
+        this.run_for(std::time::Duration::from_secs(seconds)).await;
+
+        Ok(())
+    });
+});
+impl_device!(WakeOnLAN, methods => {
+    methods.add_async_method("on", |_lua, this, _: ()| async move { Ok(this.on().await) });
+
+    methods.add_async_method("shutdown", |_lua, this, _: ()| async move {
+        this.shutdown()
+            .await
+            .map_err(mlua::ExternalError::into_lua_err)
+    });
+});
+impl_device!(Zigbee2MqttBridge, methods => {
+    methods.add_async_method("is_online", |_lua, this, _: ()| async move {
+        Ok(this.is_online().await)
+    });
+
+    methods.add_async_method("permit_join", |_lua, this, seconds: u32| async move {
+        this.permit_join(seconds)
+            .await
+            .map_err(mlua::ExternalError::into_lua_err)
+    });
+
+    methods.add_async_method("restart", |_lua, this, _: ()| async move {
+        this.restart()
+            .await
+            .map_err(mlua::ExternalError::into_lua_err)
+    });
+
+    methods.add_async_method("log_level", |_lua, this, level: String| async move {
+        this.log_level(&level)
+            .await
+            .map_err(mlua::ExternalError::into_lua_err)
+    });
+});
+impl_device!(Washer, methods => {
+    methods.add_async_method("current_phase", |lua, this, _: ()| async move {
+        lua.to_value(&this.current_phase().await)
+    });
+
+    methods.add_async_method("elapsed", |_lua, this, _: ()| async move {
+        Ok(this.elapsed().await.map(|elapsed| elapsed.as_secs()))
+    });
+});
+
+impl_device!(Wled, methods => {
+    methods.add_async_method("set_preset", |_lua, this, id: u8| async move {
+        this.set_preset(id)
+            .await
+            .map_err(mlua::ExternalError::into_lua_err)
+    });
+
+    methods.add_async_method("preset", |_lua, this, _: ()| async move {
+        this.preset()
+            .await
+            .map_err(mlua::ExternalError::into_lua_err)
+    });
+});
+
+impl_device!(XiaomiAirPurifier);
 
 pub fn register_with_lua(lua: &mlua::Lua) -> mlua::Result<()> {
     register_device!(lua, LightOnOff);
     register_device!(lua, LightBrightness);
+    register_device!(lua, LightColor);
     register_device!(lua, OutletOnOff);
     register_device!(lua, OutletPower);
+    register_device!(lua, ActionRemote);
     register_device!(lua, AirFilter);
+    register_device!(lua, CastDevice);
+    register_device!(lua, ClimateSensor);
+    register_device!(lua, Blind);
     register_device!(lua, ContactSensor);
     register_device!(lua, DebugBridge);
+    register_device!(lua, DimmableGroup);
+    register_device!(lua, Doorbell);
+    register_device!(lua, GenericMqttSensor);
+    register_device!(lua, Group);
+    register_device!(lua, HttpSwitch);
     register_device!(lua, HueBridge);
     register_device!(lua, HueGroup);
     register_device!(lua, HueSwitch);
     register_device!(lua, IkeaRemote);
     register_device!(lua, KasaOutlet);
+    register_device!(lua, LeakSensor);
     register_device!(lua, LightSensor);
+    register_device!(lua, MotionSensor);
+    register_device!(lua, PingPresence);
+    register_device!(lua, PowerStrip);
+    register_device!(lua, ShellySwitch);
+    register_device!(lua, SmartDehumidifier);
+    register_device!(lua, SmokeDetector);
+    register_device!(lua, SystemMonitor);
+    register_device!(lua, TasmotaOutlet);
+    register_device!(lua, Valve);
     register_device!(lua, WakeOnLAN);
     register_device!(lua, Washer);
+    register_device!(lua, Wled);
+    register_device!(lua, XiaomiAirPurifier);
+    register_device!(lua, Zigbee2MqttBridge);
 
     Ok(())
 }
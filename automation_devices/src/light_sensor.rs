@@ -1,14 +1,19 @@
+use std::collections::VecDeque;
 use std::sync::Arc;
+use std::time::Duration;
 
 use async_trait::async_trait;
 use automation_lib::config::MqttDeviceConfig;
 use automation_lib::device::{Device, LuaDeviceCreate};
 use automation_lib::event::{self, Event, EventChannel, OnMqtt};
-use automation_lib::messages::BrightnessMessage;
+use automation_lib::messages::{BrightnessMessage, DarknessMessage};
 use automation_lib::mqtt::WrappedAsyncClient;
 use automation_macro::LuaDeviceConfig;
-use rumqttc::Publish;
-use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use chrono::{Local, NaiveTime};
+use rumqttc::{Publish, QoS};
+use serde::{Deserialize, Deserializer};
+use tokio::sync::{RwLock, RwLockWriteGuard};
+use tokio::time::Instant;
 use tracing::{debug, trace, warn};
 
 #[derive(Debug, Clone, LuaDeviceConfig)]
@@ -16,19 +21,122 @@ pub struct Config {
     pub identifier: String,
     #[device_config(flatten)]
     pub mqtt: MqttDeviceConfig,
-    pub min: isize,
-    pub max: isize,
+    // It becomes dark below the low threshold and light again above the high threshold, the old
+    // 'min' and 'max' names are still accepted
+    #[device_config(alias("min"))]
+    pub threshold_low: isize,
+    #[device_config(alias("max"))]
+    pub threshold_high: isize,
+    // Replaces the low threshold depending on the (local) time of day, the high threshold moves
+    // along so the hysteresis band stays the same width
+    #[device_config(default, with(ThresholdSchedule::new))]
+    pub threshold_schedule: ThresholdSchedule,
+    // Average the readings to filter out short spikes, e.g. a car driving by
+    #[device_config(default)]
+    pub smoothing: Option<Smoothing>,
+    // Minimum time between two changes of the darkness state
+    #[device_config(rename("min_interval_seconds"), default(0), with(Duration::from_secs))]
+    pub min_interval: Duration,
+    // The darkness state is published (retained) on this topic and restored from it on startup
+    #[device_config(default)]
+    pub darkness_topic: Option<String>,
     #[device_config(rename("event_channel"), from_lua, with(|ec: EventChannel| ec.get_tx()))]
     pub tx: event::Sender,
     #[device_config(from_lua)]
     pub client: WrappedAsyncClient,
 }
 
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(untagged)]
+pub enum Smoothing {
+    // Average of the last N readings
+    Readings { readings: usize },
+    // Average of all readings received in the window
+    Window { seconds: u64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct ScheduledThreshold {
+    #[serde(deserialize_with = "deserialize_time")]
+    pub after: NaiveTime,
+    pub threshold: isize,
+}
+
+fn deserialize_time<'de, D>(deserializer: D) -> Result<NaiveTime, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let time = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&time, "%H:%M").map_err(serde::de::Error::custom)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ThresholdSchedule(Vec<ScheduledThreshold>);
+
+impl ThresholdSchedule {
+    pub fn new(mut entries: Vec<ScheduledThreshold>) -> Self {
+        entries.sort_by_key(|entry| entry.after);
+        Self(entries)
+    }
+
+    // The threshold of the last entry that started before the given time, before the first entry
+    // of the day the last entry of the previous day is still active
+    fn active(&self, time: NaiveTime) -> Option<isize> {
+        self.0
+            .iter()
+            .rev()
+            .find(|entry| entry.after <= time)
+            .or(self.0.last())
+            .map(|entry| entry.threshold)
+    }
+}
+
 const DEFAULT: bool = false;
 
 #[derive(Debug)]
 pub struct State {
     is_dark: bool,
+    readings: VecDeque<(Instant, isize)>,
+    last_change: Option<Instant>,
+}
+
+impl State {
+    // Add the reading and return the average of the readings inside the smoothing window
+    fn add_reading(&mut self, smoothing: Option<Smoothing>, illuminance: isize) -> isize {
+        let now = Instant::now();
+        self.readings.push_back((now, illuminance));
+
+        match smoothing {
+            Some(Smoothing::Readings { readings }) => {
+                while self.readings.len() > readings.max(1) {
+                    self.readings.pop_front();
+                }
+            }
+            Some(Smoothing::Window { seconds }) => {
+                let window = Duration::from_secs(seconds);
+                while self.readings.len() > 1
+                    && self
+                        .readings
+                        .front()
+                        .is_some_and(|(at, _)| now.duration_since(*at) > window)
+                {
+                    self.readings.pop_front();
+                }
+            }
+            None => {
+                while self.readings.len() > 1 {
+                    self.readings.pop_front();
+                }
+            }
+        }
+
+        let sum: isize = self
+            .readings
+            .iter()
+            .map(|(_, illuminance)| illuminance)
+            .sum();
+        sum / self.readings.len() as isize
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -38,13 +146,77 @@ pub struct LightSensor {
 }
 
 impl LightSensor {
-    async fn state(&self) -> RwLockReadGuard<State> {
-        self.state.read().await
-    }
-
     async fn state_mut(&self) -> RwLockWriteGuard<State> {
         self.state.write().await
     }
+
+    // The low and high threshold that are active at the moment
+    fn thresholds(&self) -> (isize, isize) {
+        match self.config.threshold_schedule.active(Local::now().time()) {
+            Some(low) => (
+                low,
+                low + self.config.threshold_high - self.config.threshold_low,
+            ),
+            None => (self.config.threshold_low, self.config.threshold_high),
+        }
+    }
+
+    async fn set_dark(&self, is_dark: bool) {
+        debug!(id = self.get_id(), "Dark state has changed: {is_dark}");
+        {
+            let mut state = self.state_mut().await;
+            state.is_dark = is_dark;
+            state.last_change = Some(Instant::now());
+        }
+
+        if let Some(topic) = &self.config.darkness_topic {
+            let message = DarknessMessage::new(is_dark);
+            if let Err(err) = self
+                .config
+                .client
+                .publish(
+                    topic,
+                    QoS::AtLeastOnce,
+                    true,
+                    serde_json::to_string(&message).expect("Serialization should not fail"),
+                )
+                .await
+            {
+                warn!(
+                    id = self.get_id(),
+                    "Failed to publish darkness state: {err}"
+                );
+            }
+        }
+
+        if self.config.tx.send(Event::Darkness(is_dark)).await.is_err() {
+            warn!("There are no receivers on the event channel");
+        }
+    }
+
+    // Take over the published state when no readings have been received yet, e.g. after a restart
+    async fn restore(&self, message: Publish) {
+        let is_dark = match DarknessMessage::try_from(message) {
+            Ok(message) => message.is_dark(),
+            Err(err) => {
+                warn!(id = self.get_id(), "Failed to parse message: {err}");
+                return;
+            }
+        };
+
+        let mut state = self.state_mut().await;
+        if !state.readings.is_empty() || state.is_dark == is_dark {
+            return;
+        }
+
+        debug!(id = self.get_id(), "Restored dark state: {is_dark}");
+        state.is_dark = is_dark;
+        drop(state);
+
+        if self.config.tx.send(Event::Darkness(is_dark)).await.is_err() {
+            warn!("There are no receivers on the event channel");
+        }
+    }
 }
 
 #[async_trait]
@@ -60,7 +232,15 @@ impl LuaDeviceCreate for LightSensor {
             .subscribe(&config.mqtt.topic, rumqttc::QoS::AtLeastOnce)
             .await?;
 
-        let state = State { is_dark: DEFAULT };
+        if let Some(topic) = &config.darkness_topic {
+            config.client.subscribe(topic, QoS::AtLeastOnce).await?;
+        }
+
+        let state = State {
+            is_dark: DEFAULT,
+            readings: VecDeque::new(),
+            last_change: None,
+        };
         let state = Arc::new(RwLock::new(state));
 
         Ok(Self { config, state })
@@ -76,6 +256,16 @@ impl Device for LightSensor {
 #[async_trait]
 impl OnMqtt for LightSensor {
     async fn on_mqtt(&self, message: Publish) {
+        if self
+            .config
+            .darkness_topic
+            .as_ref()
+            .is_some_and(|topic| *topic == message.topic)
+        {
+            self.restore(message).await;
+            return;
+        }
+
         if !rumqttc::matches(&message.topic, &self.config.mqtt.topic) {
             return;
         }
@@ -88,31 +278,175 @@ impl OnMqtt for LightSensor {
             }
         };
 
-        debug!("Illuminance: {illuminance}");
-        let is_dark = if illuminance <= self.config.min {
+        let mut state = self.state_mut().await;
+        let average = state.add_reading(self.config.smoothing, illuminance);
+        debug!("Illuminance: {illuminance}, average: {average}");
+
+        let (low, high) = self.thresholds();
+        let is_dark = if average <= low {
             trace!("It is dark");
             true
-        } else if illuminance >= self.config.max {
+        } else if average >= high {
             trace!("It is light");
             false
         } else {
-            let is_dark = self.state().await.is_dark;
             trace!(
-                "In between min ({}) and max ({}) value, keeping current state: {}",
-                self.config.min,
-                self.config.max,
-                is_dark
+                "In between low ({low}) and high ({high}) threshold, keeping current state: {}",
+                state.is_dark
             );
-            is_dark
+            state.is_dark
         };
 
-        if is_dark != self.state().await.is_dark {
-            debug!("Dark state has changed: {is_dark}");
-            self.state_mut().await.is_dark = is_dark;
+        if is_dark == state.is_dark {
+            return;
+        }
+
+        if let Some(last_change) = state.last_change {
+            if last_change.elapsed() < self.config.min_interval {
+                trace!("Changed too recently, keeping current state");
+                return;
+            }
+        }
+
+        drop(state);
+        self.set_dark(is_dark).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automation_lib::event::Receiver;
+    use automation_lib::lua::testing::MockMqttClient;
+
+    use super::*;
+
+    async fn sensor(
+        smoothing: Option<Smoothing>,
+        min_interval: Duration,
+    ) -> (LightSensor, MockMqttClient, Receiver) {
+        let (event_channel, rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel.clone());
+        let sensor = LightSensor::create(Config {
+            identifier: "light_sensor".into(),
+            mqtt: MqttDeviceConfig {
+                topic: "zigbee2mqtt/light".into(),
+                availability: None,
+            },
+            threshold_low: 100,
+            threshold_high: 200,
+            threshold_schedule: Default::default(),
+            smoothing,
+            min_interval,
+            darkness_topic: Some("automation/darkness".into()),
+            tx: event_channel.get_tx(),
+            client: client.client(),
+        })
+        .await
+        .unwrap();
+
+        (sensor, client, rx)
+    }
+
+    async fn illuminance(sensor: &LightSensor, illuminance: isize) {
+        sensor
+            .on_mqtt(Publish::new(
+                "zigbee2mqtt/light",
+                QoS::AtLeastOnce,
+                format!(r#"{{"illuminance": {illuminance}}}"#),
+            ))
+            .await;
+    }
 
-            if self.config.tx.send(Event::Darkness(is_dark)).await.is_err() {
-                warn!("There are no receivers on the event channel");
+    fn darkness_events(rx: &mut Receiver) -> Vec<bool> {
+        let mut events = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            if let Event::Darkness(dark) = event {
+                events.push(dark);
             }
         }
+
+        events
+    }
+
+    fn time(time: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn threshold_schedule() {
+        let schedule: Vec<ScheduledThreshold> = serde_json::from_value(serde_json::json!([
+            { "after": "17:00", "threshold": 80 },
+            { "after": "06:30", "threshold": 20 },
+            { "after": "23:00", "threshold": 50 },
+        ]))
+        .unwrap();
+        let schedule = ThresholdSchedule::new(schedule);
+
+        assert_eq!(schedule.active(time("06:30")), Some(20));
+        assert_eq!(schedule.active(time("12:00")), Some(20));
+        assert_eq!(schedule.active(time("17:00")), Some(80));
+        assert_eq!(schedule.active(time("23:59")), Some(50));
+        // The entry of the previous evening is still active after midnight
+        assert_eq!(schedule.active(time("00:00")), Some(50));
+        assert_eq!(schedule.active(time("06:29")), Some(50));
+
+        assert_eq!(ThresholdSchedule::default().active(time("12:00")), None);
+        assert!(serde_json::from_value::<ScheduledThreshold>(
+            serde_json::json!({ "after": "25:00", "threshold": 10 })
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn noisy_dusk() {
+        let (sensor, client, mut rx) =
+            sensor(Some(Smoothing::Readings { readings: 3 }), Duration::ZERO).await;
+
+        // Slowly getting dark and light again, with the headlights of a car in between
+        for lux in [
+            400, 380, 420, 300, 250, 180, 220, 150, 210, 120, 90, 130, 80, 110, 60, 40, 260, 20,
+            10, 50, 120, 60, 150, 90, 200, 180, 250, 220, 300, 350,
+        ] {
+            illuminance(&sensor, lux).await;
+        }
+
+        assert_eq!(darkness_events(&mut rx), vec![true, false]);
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let published: Vec<_> = client
+            .published()
+            .into_iter()
+            .filter(|(topic, _)| topic == "automation/darkness")
+            .map(|(_, payload)| serde_json::from_str::<serde_json::Value>(&payload).unwrap())
+            .collect();
+        assert_eq!(published.len(), 2);
+        assert_eq!(published[0]["state"], true);
+        assert!(published[0]["updated"].is_u64());
+        assert_eq!(published[1]["state"], false);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn min_interval() {
+        let (sensor, _client, mut rx) = sensor(None, Duration::from_secs(60)).await;
+
+        // The retained state is restored before any readings come in
+        sensor
+            .on_mqtt(Publish::new(
+                "automation/darkness",
+                QoS::AtLeastOnce,
+                r#"{"state": true, "updated": 0}"#,
+            ))
+            .await;
+        assert_eq!(darkness_events(&mut rx), vec![true]);
+
+        illuminance(&sensor, 300).await;
+        assert_eq!(darkness_events(&mut rx), vec![false]);
+
+        illuminance(&sensor, 50).await;
+        assert!(darkness_events(&mut rx).is_empty());
+
+        tokio::time::advance(Duration::from_secs(61)).await;
+        illuminance(&sensor, 50).await;
+        assert_eq!(darkness_events(&mut rx), vec![true]);
     }
 }
@@ -0,0 +1,500 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use aes::cipher::block_padding::Pkcs7;
+use aes::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use bytes::{Buf, BufMut};
+use google_home::errors::{self, DeviceError};
+use md5::{Digest, Md5};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::{debug, trace};
+
+pub const PORT: u16 = 54321;
+
+const MAGIC: u16 = 0x2131;
+const HEADER_LENGTH: usize = 32;
+
+type Encryptor = cbc::Encryptor<aes::Aes128>;
+type Decryptor = cbc::Decryptor<aes::Aes128>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Token needs to be 32 hexadecimal characters")]
+    InvalidToken,
+    #[error("Device did not respond in time")]
+    Timeout,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Invalid packet: {0}")]
+    InvalidPacket(&'static str),
+    #[error("Checksum does not match, is the token correct?")]
+    Checksum,
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("Device responded with error {code}: {message}")]
+    Device { code: i64, message: String },
+}
+
+impl From<Error> for errors::ErrorCode {
+    fn from(value: Error) -> Self {
+        match value {
+            // If the device does not answer it is most likely offline
+            Error::Timeout | Error::Io(_) => DeviceError::DeviceOffline.into(),
+            _ => DeviceError::TransientError.into(),
+        }
+    }
+}
+
+// The token is used to derive the key and iv for the AES-128-CBC encryption of the payload, it
+// also replaces the checksum when calculating the checksum
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Token([u8; 16]);
+
+impl Token {
+    pub fn parse(token: &str) -> Result<Self, Error> {
+        let mut bytes = [0; 16];
+        hex::decode_to_slice(token.trim(), &mut bytes).or(Err(Error::InvalidToken))?;
+
+        Ok(Self(bytes))
+    }
+
+    fn key(&self) -> [u8; 16] {
+        Md5::digest(self.0).into()
+    }
+
+    fn iv(&self) -> [u8; 16] {
+        let mut hasher = Md5::new();
+        hasher.update(self.key());
+        hasher.update(self.0);
+        hasher.finalize().into()
+    }
+
+    fn encrypt(&self, data: &[u8]) -> Vec<u8> {
+        Encryptor::new(&self.key().into(), &self.iv().into()).encrypt_padded_vec_mut::<Pkcs7>(data)
+    }
+
+    fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        Decryptor::new(&self.key().into(), &self.iv().into())
+            .decrypt_padded_vec_mut::<Pkcs7>(data)
+            .or(Err(Error::InvalidPacket("Payload can not be decrypted")))
+    }
+
+    fn checksum(&self, header: &[u8], data: &[u8]) -> [u8; 16] {
+        let mut hasher = Md5::new();
+        hasher.update(&header[..16]);
+        hasher.update(self.0);
+        hasher.update(data);
+        hasher.finalize().into()
+    }
+}
+
+impl std::fmt::Debug for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "***")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub device_id: u32,
+    // Seconds since the device booted, requests need to have a stamp that is not in the past
+    pub stamp: u32,
+}
+
+// Sent to get the device id and stamp, before the first request can be made
+pub fn hello() -> [u8; HEADER_LENGTH] {
+    let mut packet = [0xff; HEADER_LENGTH];
+    packet[..2].copy_from_slice(&MAGIC.to_be_bytes());
+    packet[2..4].copy_from_slice(&(HEADER_LENGTH as u16).to_be_bytes());
+
+    packet
+}
+
+fn parse_header(mut packet: &[u8]) -> Result<(Header, &[u8]), Error> {
+    if packet.len() < HEADER_LENGTH {
+        return Err(Error::InvalidPacket("Packet is shorter than the header"));
+    }
+
+    if packet.get_u16() != MAGIC {
+        return Err(Error::InvalidPacket("Magic number does not match"));
+    }
+
+    let length = packet.get_u16() as usize;
+    if length < HEADER_LENGTH || length - 4 > packet.len() {
+        return Err(Error::InvalidPacket("Length does not match"));
+    }
+
+    // Unknown, always 0 except for the hello packet
+    packet.advance(4);
+    let header = Header {
+        device_id: packet.get_u32(),
+        stamp: packet.get_u32(),
+    };
+
+    Ok((header, &packet[..length - 16]))
+}
+
+pub fn parse_hello(packet: &[u8]) -> Result<Header, Error> {
+    parse_header(packet).map(|(header, _)| header)
+}
+
+pub fn encode(token: &Token, header: Header, payload: &[u8]) -> Vec<u8> {
+    let data = token.encrypt(payload);
+
+    let mut packet = Vec::with_capacity(HEADER_LENGTH + data.len());
+    packet.put_u16(MAGIC);
+    packet.put_u16((HEADER_LENGTH + data.len()) as u16);
+    packet.put_u32(0);
+    packet.put_u32(header.device_id);
+    packet.put_u32(header.stamp);
+    let checksum = token.checksum(&packet, &data);
+    packet.put_slice(&checksum);
+    packet.put_slice(&data);
+
+    packet
+}
+
+pub fn decode(token: &Token, packet: &[u8]) -> Result<(Header, Vec<u8>), Error> {
+    let (header, rest) = parse_header(packet)?;
+    let (checksum, data) = rest.split_at(16);
+
+    if token.checksum(packet, data) != checksum {
+        return Err(Error::Checksum);
+    }
+
+    let mut payload = token.decrypt(data)?;
+    // Some devices terminate the payload with a null byte
+    while payload.last() == Some(&0) {
+        payload.pop();
+    }
+
+    Ok((header, payload))
+}
+
+#[derive(Debug, Serialize)]
+struct Request<'a, P> {
+    id: u32,
+    method: &'a str,
+    params: P,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResponseError {
+    code: i64,
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Response<T> {
+    id: u32,
+    result: Option<T>,
+    error: Option<ResponseError>,
+}
+
+#[derive(Debug)]
+struct Session {
+    socket: UdpSocket,
+    header: Header,
+    // Used to keep the stamp moving forward
+    started: Instant,
+}
+
+#[derive(Debug)]
+struct ClientState {
+    session: Option<Session>,
+    next_id: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    addr: SocketAddr,
+    token: Token,
+    timeout: Duration,
+    state: Arc<Mutex<ClientState>>,
+}
+
+impl Client {
+    pub fn new(addr: SocketAddr, token: Token, timeout: Duration) -> Self {
+        Self {
+            addr,
+            token,
+            timeout,
+            state: Arc::new(Mutex::new(ClientState {
+                session: None,
+                next_id: 1,
+            })),
+        }
+    }
+
+    async fn receive(&self, socket: &UdpSocket, buffer: &mut [u8]) -> Result<usize, Error> {
+        tokio::time::timeout(self.timeout, socket.recv(buffer))
+            .await
+            .or(Err(Error::Timeout))?
+            .map_err(Error::from)
+    }
+
+    async fn handshake(&self) -> Result<Session, Error> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        socket.connect(self.addr).await?;
+        socket.send(&hello()).await?;
+
+        let mut buffer = [0; HEADER_LENGTH];
+        let read = self.receive(&socket, &mut buffer).await?;
+        let header = parse_hello(&buffer[..read])?;
+        trace!("Handshake with {}: {header:?}", self.addr);
+
+        Ok(Session {
+            socket,
+            header,
+            started: Instant::now(),
+        })
+    }
+
+    pub async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: impl Serialize,
+    ) -> Result<T, Error> {
+        let mut state = self.state.lock().await;
+        let id = state.next_id;
+        state.next_id = state.next_id.wrapping_add(1).max(1);
+
+        let payload = serde_json::to_vec(&Request { id, method, params })?;
+        let result = self.exchange(&mut state.session, id, &payload).await;
+        if let Err(err) = &result {
+            // Start over with a new handshake, the device might have rebooted
+            debug!("Call to {} failed: {err}", self.addr);
+            state.session = None;
+        }
+
+        result
+    }
+
+    async fn exchange<T: DeserializeOwned>(
+        &self,
+        session: &mut Option<Session>,
+        id: u32,
+        payload: &[u8],
+    ) -> Result<T, Error> {
+        if session.is_none() {
+            *session = Some(self.handshake().await?);
+        }
+        let session = session.as_ref().expect("Handshake should be done");
+
+        let header = Header {
+            device_id: session.header.device_id,
+            stamp: session
+                .header
+                .stamp
+                .wrapping_add(session.started.elapsed().as_secs() as u32),
+        };
+        session
+            .socket
+            .send(&encode(&self.token, header, payload))
+            .await?;
+
+        let mut buffer = [0; 4096];
+        loop {
+            let read = self.receive(&session.socket, &mut buffer).await?;
+            let (_, payload) = decode(&self.token, &buffer[..read])?;
+            let response: Response<T> = serde_json::from_slice(&payload)?;
+
+            // Late responses to earlier requests that timed out are skipped
+            if response.id != id {
+                trace!("Skipping response with id {}", response.id);
+                continue;
+            }
+
+            if let Some(error) = response.error {
+                return Err(Error::Device {
+                    code: error.code,
+                    message: error.message,
+                });
+            }
+
+            return response
+                .result
+                .ok_or(Error::InvalidPacket("Response does not contain a result"));
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+
+    pub const TOKEN: &str = "3f8e1b2c4d5a69788796a5b4c3d2e1f0";
+    pub const DEVICE_ID: u32 = 0x0123abcd;
+
+    // Answers the hello and every request with the result given by the handler
+    pub async fn device(
+        handler: impl Fn(&str, serde_json::Value) -> serde_json::Value + Send + 'static,
+    ) -> SocketAddr {
+        let token = Token::parse(TOKEN).unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buffer = [0; 4096];
+            let header = Header {
+                device_id: DEVICE_ID,
+                stamp: 0x1a2b,
+            };
+
+            loop {
+                let (read, from) = socket.recv_from(&mut buffer).await.unwrap();
+                if buffer[..read] == hello() {
+                    let mut response = hello();
+                    response[4..8].fill(0);
+                    response[8..12].copy_from_slice(&header.device_id.to_be_bytes());
+                    response[12..16].copy_from_slice(&header.stamp.to_be_bytes());
+                    socket.send_to(&response, from).await.unwrap();
+                    continue;
+                }
+
+                let (_, payload) = decode(&token, &buffer[..read]).unwrap();
+                let request: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+                let result = handler(
+                    request["method"].as_str().unwrap(),
+                    request["params"].clone(),
+                );
+                let response = serde_json::json!({"result": result, "id": request["id"]});
+
+                socket
+                    .send_to(
+                        &encode(&token, header, response.to_string().as_bytes()),
+                        from,
+                    )
+                    .await
+                    .unwrap();
+            }
+        });
+
+        addr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::*;
+    use super::*;
+
+    // get_prop request and response for the test token, created independently of this module
+    // using python's cryptography package
+    const REQUEST: &str = "
+        21310070000000000123abcd00001a2b537628443d36bc9baf76a03224a49bba
+        8b02c677cf93f5c2fcdbb81cf83a7c9713295bd51a52776d3a6dbec5300a31b0
+        8e15c3507e841c320436da2f74e8cefc3023301e46f39a3e1258614ae4c3ae3b
+        d7a07c87b095b32f3fec862517e20c7a";
+    const RESPONSE: &str = "
+        21310050000000000123abcd00001a2c5bacd6a916aee521bd6d8e92bcce5c47
+        ee4b3cff45544db2b33907558cdabebf0f9d02390b852b8a211de77502d950ea
+        25ba55d75cd26184c99bf9822c71b47a";
+    const HELLO_RESPONSE: &str = "
+        21310020000000000123abcd00001a2bffffffffffffffffffffffffffffffff";
+
+    fn packet(hex: &str) -> Vec<u8> {
+        hex::decode(hex.split_whitespace().collect::<String>()).unwrap()
+    }
+
+    fn token() -> Token {
+        Token::parse(TOKEN).unwrap()
+    }
+
+    #[test]
+    fn parse_token() {
+        assert!(Token::parse("3f8e1b2c4d5a6978").is_err());
+        assert!(Token::parse("not a token, but it is the right length").is_err());
+        assert_eq!(format!("{:?}", token()), "***");
+    }
+
+    #[test]
+    fn handshake() {
+        assert_eq!(hello()[..4], [0x21, 0x31, 0x00, 0x20]);
+        assert!(hello()[4..].iter().all(|&byte| byte == 0xff));
+
+        assert_eq!(
+            parse_hello(&packet(HELLO_RESPONSE)).unwrap(),
+            Header {
+                device_id: DEVICE_ID,
+                stamp: 0x1a2b
+            }
+        );
+        assert!(parse_hello(&packet(HELLO_RESPONSE)[..16]).is_err());
+    }
+
+    #[test]
+    fn encode_request() {
+        let payload = r#"{"id":1,"method":"get_prop","params":["power","aqi","humidity","mode"]}"#;
+        let header = Header {
+            device_id: DEVICE_ID,
+            stamp: 0x1a2b,
+        };
+
+        assert_eq!(
+            encode(&token(), header, payload.as_bytes()),
+            packet(REQUEST)
+        );
+    }
+
+    #[test]
+    fn decode_response() {
+        let (header, payload) = decode(&token(), &packet(RESPONSE)).unwrap();
+
+        assert_eq!(header.stamp, 0x1a2c);
+        assert_eq!(payload, br#"{"result":["on",17,42,"auto"],"id":1}"#);
+
+        let mut corrupted = packet(RESPONSE);
+        corrupted[40] ^= 0xff;
+        assert!(matches!(decode(&token(), &corrupted), Err(Error::Checksum)));
+
+        let other = Token::parse("00000000000000000000000000000000").unwrap();
+        assert!(matches!(
+            decode(&other, &packet(RESPONSE)),
+            Err(Error::Checksum)
+        ));
+    }
+
+    #[tokio::test]
+    async fn call() {
+        let addr = device(|method, params| {
+            assert_eq!(method, "get_prop");
+            assert_eq!(params, serde_json::json!(["power", "aqi"]));
+            serde_json::json!(["on", 17])
+        })
+        .await;
+        let client = Client::new(addr, token(), Duration::from_millis(200));
+
+        let (power, aqi): (String, u32) = client.call("get_prop", ["power", "aqi"]).await.unwrap();
+        assert_eq!(power, "on");
+        assert_eq!(aqi, 17);
+
+        // The session is reused for the next call
+        let _: (String, u32) = client.call("get_prop", ["power", "aqi"]).await.unwrap();
+        assert_eq!(client.state.lock().await.next_id, 3);
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        // Never answers
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = Client::new(
+            socket.local_addr().unwrap(),
+            token(),
+            Duration::from_millis(50),
+        );
+
+        let result = client.call::<Vec<String>>("get_prop", ["power"]).await;
+        assert!(matches!(result, Err(Error::Timeout)));
+        assert_eq!(
+            errors::ErrorCode::from(result.unwrap_err()),
+            DeviceError::DeviceOffline.into()
+        );
+    }
+}
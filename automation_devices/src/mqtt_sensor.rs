@@ -0,0 +1,298 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use automation_lib::action_callback::ActionCallback;
+use automation_lib::config::MqttDeviceConfig;
+use automation_lib::device::{Device, LuaDeviceCreate};
+use automation_lib::event::OnMqtt;
+use automation_lib::mqtt::WrappedAsyncClient;
+use automation_macro::LuaDeviceConfig;
+use mlua::{FromLua, LuaSerdeExt};
+use rumqttc::{matches, Publish, QoS};
+use tokio::sync::RwLock;
+use tracing::{debug, trace, warn};
+
+// Payloads can be large, only the start is included in the logs
+const MAX_LOGGED_PAYLOAD: usize = 200;
+
+fn truncate(payload: &str) -> String {
+    match payload.char_indices().nth(MAX_LOGGED_PAYLOAD) {
+        Some((end, _)) => format!("{}...", &payload[..end]),
+        None => payload.to_owned(),
+    }
+}
+
+// Lua function that extracts the value from a payload
+#[derive(Debug, Clone)]
+pub struct ParseFunction {
+    lua: mlua::Lua,
+    f: mlua::Function,
+}
+
+impl ParseFunction {
+    // Json payloads are passed to the function as a table, anything else as a string
+    async fn parse(&self, payload: &str) -> mlua::Result<Option<serde_json::Value>> {
+        let payload = match serde_json::from_str::<serde_json::Value>(payload) {
+            Ok(payload) => self.lua.to_value(&payload)?,
+            Err(_) => mlua::Value::String(self.lua.create_string(payload)?),
+        };
+
+        let value = self.f.call_async::<mlua::Value>(payload).await?;
+        if value.is_nil() {
+            return Ok(None);
+        }
+
+        self.lua.from_value(value).map(Some)
+    }
+}
+
+impl FromLua for ParseFunction {
+    fn from_lua(value: mlua::Value, lua: &mlua::Lua) -> mlua::Result<Self> {
+        Ok(Self {
+            lua: lua.clone(),
+            f: mlua::Function::from_lua(value, lua)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, LuaDeviceConfig)]
+pub struct Config {
+    pub identifier: String,
+    #[device_config(flatten)]
+    pub mqtt: MqttDeviceConfig,
+    // Returns the value contained in the payload, or nil if the payload does not contain one. If
+    // not set the (json) payload itself is used as the value
+    #[device_config(from_lua, default)]
+    pub parse: Option<ParseFunction>,
+    // The value is republished (retained) to this topic every time it changes
+    #[device_config(default)]
+    pub normalized_topic: Option<String>,
+
+    // Called when the value changes
+    #[device_config(from_lua, default)]
+    pub callback: ActionCallback<GenericMqttSensor, serde_json::Value>,
+
+    #[device_config(from_lua)]
+    pub client: WrappedAsyncClient,
+}
+
+#[derive(Debug, Clone)]
+pub struct GenericMqttSensor {
+    config: Config,
+    value: Arc<RwLock<Option<serde_json::Value>>>,
+}
+
+impl GenericMqttSensor {
+    pub async fn value(&self) -> Option<serde_json::Value> {
+        self.value.read().await.clone()
+    }
+
+    async fn parse(&self, payload: &str) -> Option<serde_json::Value> {
+        let Some(parse) = &self.config.parse else {
+            return serde_json::from_str(payload)
+                .map_err(|err| {
+                    warn!(
+                        id = self.get_id(),
+                        "Failed to parse payload '{}': {err}",
+                        truncate(payload)
+                    );
+                })
+                .ok();
+        };
+
+        parse
+            .parse(payload)
+            .await
+            .map_err(|err| {
+                warn!(
+                    id = self.get_id(),
+                    "Failed to parse payload '{}': {err}",
+                    truncate(payload)
+                );
+            })
+            .ok()
+            .flatten()
+    }
+
+    async fn republish(&self, value: &serde_json::Value) {
+        let Some(topic) = &self.config.normalized_topic else {
+            return;
+        };
+
+        if let Err(err) = self
+            .config
+            .client
+            .publish(
+                topic,
+                QoS::AtLeastOnce,
+                true,
+                serde_json::to_string(value).expect("Serialization should not fail"),
+            )
+            .await
+        {
+            warn!(
+                id = self.get_id(),
+                "Failed to publish value on {topic}: {err}"
+            );
+        }
+    }
+}
+
+#[async_trait]
+impl LuaDeviceCreate for GenericMqttSensor {
+    type Config = Config;
+    type Error = rumqttc::ClientError;
+
+    async fn create(config: Self::Config) -> Result<Self, Self::Error> {
+        trace!(id = config.identifier, "Setting up GenericMqttSensor");
+
+        config
+            .client
+            .subscribe(&config.mqtt.topic, QoS::AtLeastOnce)
+            .await?;
+
+        Ok(Self {
+            config,
+            value: Default::default(),
+        })
+    }
+}
+
+impl Device for GenericMqttSensor {
+    fn get_id(&self) -> String {
+        self.config.identifier.clone()
+    }
+}
+
+#[async_trait]
+impl OnMqtt for GenericMqttSensor {
+    async fn on_mqtt(&self, message: Publish) {
+        if !matches(&message.topic, &self.config.mqtt.topic) {
+            return;
+        }
+
+        let payload = String::from_utf8_lossy(&message.payload);
+        let Some(value) = self.parse(&payload).await else {
+            return;
+        };
+
+        {
+            let mut current = self.value.write().await;
+            if current.as_ref() == Some(&value) {
+                return;
+            }
+            *current = Some(value.clone());
+        }
+
+        debug!(id = self.get_id(), "Value = {value}");
+        self.republish(&value).await;
+        self.config.callback.call(self, &value).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use automation_lib::event::EventChannel;
+    use automation_lib::lua::testing::MockMqttClient;
+
+    use super::*;
+
+    async fn sensor(
+        lua: &mlua::Lua,
+        client: &MockMqttClient,
+        parse: &str,
+        callback: ActionCallback<GenericMqttSensor, serde_json::Value>,
+    ) -> GenericMqttSensor {
+        let parse = ParseFunction::from_lua(lua.load(parse).eval().unwrap(), lua).unwrap();
+
+        GenericMqttSensor::create(Config {
+            identifier: "sensor".into(),
+            mqtt: MqttDeviceConfig {
+                topic: "tele/sensor/SENSOR".into(),
+                availability: None,
+            },
+            parse: Some(parse),
+            normalized_topic: Some("automation/sensor/temperature".into()),
+            callback,
+            client: client.client(),
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn publish(sensor: &GenericMqttSensor, payload: &str) {
+        sensor
+            .on_mqtt(Publish::new(
+                "tele/sensor/SENSOR",
+                QoS::AtLeastOnce,
+                payload,
+            ))
+            .await;
+    }
+
+    #[test]
+    fn truncate_payload() {
+        assert_eq!(truncate("short"), "short");
+
+        let long = "é".repeat(MAX_LOGGED_PAYLOAD + 10);
+        let truncated = truncate(&long);
+        assert_eq!(truncated.chars().count(), MAX_LOGGED_PAYLOAD + 3);
+        assert!(truncated.ends_with("..."));
+    }
+
+    #[tokio::test]
+    async fn parse_and_republish() {
+        let lua = mlua::Lua::new();
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let f = lua
+            .create_function({
+                let count = count.clone();
+                move |_lua, _: mlua::MultiValue| {
+                    count.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                }
+            })
+            .unwrap();
+        let callback = ActionCallback::from_lua(mlua::Value::Function(f), &lua).unwrap();
+        let sensor = sensor(
+            &lua,
+            &client,
+            "function(payload) return payload.DS18B20.Temperature end",
+            callback,
+        )
+        .await;
+
+        publish(
+            &sensor,
+            r#"{"Time":"2024-12-06T20:00:00","DS18B20":{"Temperature":21.5}}"#,
+        )
+        .await;
+        // Unchanged values do not trigger the callback
+        publish(
+            &sensor,
+            r#"{"Time":"2024-12-06T20:05:00","DS18B20":{"Temperature":21.5}}"#,
+        )
+        .await;
+        // Errors in the parse function are logged and ignored
+        publish(&sensor, "not json").await;
+        publish(&sensor, r#"{"DS18B20":{"Temperature":22}}"#).await;
+
+        assert_eq!(sensor.value().await, Some(serde_json::json!(22)));
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(
+            client.published(),
+            [
+                ("automation/sensor/temperature".into(), "21.5".into()),
+                ("automation/sensor/temperature".into(), "22".into())
+            ]
+        );
+    }
+}
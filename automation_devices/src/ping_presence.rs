@@ -0,0 +1,402 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use automation_lib::action_callback::ActionCallback;
+use automation_lib::device::{Device, LuaDeviceCreate};
+use automation_lib::helpers::weak::spawn_weak_interval;
+use automation_lib::messages::PresenceMessage;
+use automation_lib::mqtt::WrappedAsyncClient;
+use automation_macro::LuaDeviceConfig;
+use eui48::MacAddress;
+use rumqttc::QoS;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tokio::sync::RwLock;
+use tracing::{debug, info, trace, warn};
+
+use crate::icmp;
+
+const ARP_TABLE: &str = "/proc/net/arp";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Target {
+    // Appended to the topic, e.g. 'alice/phone' so the Presence device counts it towards alice
+    pub name: String,
+    #[serde(default)]
+    pub ip: Option<IpAddr>,
+    // Looked up in the ARP table every time, for devices that do not have a fixed ip
+    #[serde(default)]
+    pub mac: Option<MacAddress>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeMethod {
+    // Sends an ICMP echo request, requires CAP_NET_RAW
+    Icmp,
+    // Connects to the port, a refused connection also means the device is there
+    Tcp,
+}
+
+impl Display for ProbeMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProbeMethod::Icmp => write!(f, "icmp"),
+            ProbeMethod::Tcp => write!(f, "tcp"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, LuaDeviceConfig)]
+pub struct Config {
+    pub identifier: String,
+    pub targets: Vec<Target>,
+    // Presence messages are published (retained) on '<topic>/<name>'
+    pub topic: String,
+    #[device_config(rename("interval_seconds"), default(30), with(Duration::from_secs))]
+    pub interval: Duration,
+    #[device_config(rename("timeout_seconds"), default(1), with(Duration::from_secs))]
+    pub timeout: Duration,
+    // Number of probes in a row that need to fail before a target is away, phones regularly miss
+    // a probe while they are sleeping
+    #[device_config(default(3))]
+    pub miss_count: usize,
+    // Falls back to tcp when ICMP can not be used
+    #[device_config(default(ProbeMethod::Icmp))]
+    pub method: ProbeMethod,
+    // Port used by the tcp probe, iPhones listen on 62078
+    #[device_config(default(62078))]
+    pub port: u16,
+
+    // Called when a target arrives or leaves
+    #[device_config(from_lua, default)]
+    pub callback: ActionCallback<PingPresence, TargetPresence>,
+
+    #[device_config(from_lua)]
+    pub client: WrappedAsyncClient,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TargetPresence {
+    pub name: String,
+    pub present: bool,
+}
+
+// Finds the ip that belongs to the mac address, incomplete entries are skipped
+fn parse_arp(table: &str, mac: &MacAddress) -> Option<IpAddr> {
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        let [ip, _, flags, hw_address, ..] = fields[..] else {
+            return None;
+        };
+
+        if flags == "0x0" || MacAddress::parse_str(hw_address).ok()? != *mac {
+            return None;
+        }
+
+        ip.parse().ok()
+    })
+}
+
+#[derive(Debug, Default)]
+struct TargetState {
+    present: Option<bool>,
+    misses: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct PingPresence {
+    config: Config,
+    // Method that is actually used, after falling back
+    method: ProbeMethod,
+    state: Arc<RwLock<HashMap<String, TargetState>>>,
+}
+
+impl PingPresence {
+    pub async fn is_present(&self, name: &str) -> Option<bool> {
+        self.state
+            .read()
+            .await
+            .get(name)
+            .and_then(|state| state.present)
+    }
+
+    async fn resolve(&self, target: &Target) -> Option<IpAddr> {
+        if let Some(ip) = target.ip {
+            return Some(ip);
+        }
+
+        let mac = target.mac.as_ref()?;
+        match tokio::fs::read_to_string(ARP_TABLE).await {
+            Ok(table) => parse_arp(&table, mac),
+            Err(err) => {
+                warn!(id = self.get_id(), "Failed to read ARP table: {err}");
+                None
+            }
+        }
+    }
+
+    async fn probe(&self, ip: IpAddr) -> bool {
+        if self.method == ProbeMethod::Icmp {
+            match icmp::ping(ip, self.config.timeout).await {
+                Ok(reachable) => return reachable,
+                // IPv6 targets end up here
+                Err(err) => trace!(id = self.get_id(), "Failed to ping {ip}, using tcp: {err}"),
+            }
+        }
+
+        let connect = TcpStream::connect(SocketAddr::new(ip, self.config.port));
+        match tokio::time::timeout(self.config.timeout, connect).await {
+            Ok(Ok(_)) => true,
+            Ok(Err(err)) => err.kind() == ErrorKind::ConnectionRefused,
+            Err(_) => false,
+        }
+    }
+
+    async fn update(&self, name: &str, reachable: bool) {
+        let present = {
+            let mut state = self.state.write().await;
+            let state = state.entry(name.to_owned()).or_default();
+
+            if reachable {
+                state.misses = 0;
+            } else {
+                state.misses += 1;
+                trace!(id = self.get_id(), "{name} missed {} probes", state.misses);
+            }
+
+            let present = if reachable {
+                true
+            } else if state.misses >= self.config.miss_count {
+                false
+            } else {
+                return;
+            };
+
+            if state.present == Some(present) {
+                return;
+            }
+            state.present = Some(present);
+
+            present
+        };
+
+        debug!(id = self.get_id(), "{name} present: {present}");
+
+        let message = PresenceMessage::new(present);
+        if let Err(err) = self
+            .config
+            .client
+            .publish(
+                format!("{}/{name}", self.config.topic),
+                QoS::AtLeastOnce,
+                true,
+                serde_json::to_string(&message).expect("Serialization should not fail"),
+            )
+            .await
+        {
+            warn!(
+                id = self.get_id(),
+                "Failed to publish presence of {name}: {err}"
+            );
+        }
+
+        self.config
+            .callback
+            .call(
+                self,
+                &TargetPresence {
+                    name: name.to_owned(),
+                    present,
+                },
+            )
+            .await;
+    }
+
+    // Rebuilds the device in the background task, see automation_lib::helpers::weak
+    fn downgrade(&self) -> impl Fn() -> Option<Self> + Send + 'static {
+        let config = self.config.clone();
+        let method = self.method;
+        let state = Arc::downgrade(&self.state);
+
+        move || {
+            Some(Self {
+                config: config.clone(),
+                method,
+                state: state.upgrade()?,
+            })
+        }
+    }
+
+    async fn poll(&self) {
+        for target in &self.config.targets {
+            let reachable = match self.resolve(target).await {
+                Some(ip) => self.probe(ip).await,
+                // Devices drop out of the ARP table when they leave
+                None => false,
+            };
+
+            self.update(&target.name, reachable).await;
+        }
+    }
+}
+
+#[async_trait]
+impl LuaDeviceCreate for PingPresence {
+    type Config = Config;
+    type Error = std::convert::Infallible;
+
+    async fn create(config: Self::Config) -> Result<Self, Self::Error> {
+        trace!(id = config.identifier, "Setting up PingPresence");
+
+        let mut method = config.method;
+        if method == ProbeMethod::Icmp {
+            if let Err(err) = icmp::ping(Ipv4Addr::LOCALHOST.into(), config.timeout).await {
+                warn!(
+                    id = config.identifier,
+                    "Ping is not available, falling back to tcp on port {}: {err}", config.port
+                );
+                method = ProbeMethod::Tcp;
+            }
+        }
+        info!(id = config.identifier, "Probing targets using {method}");
+
+        let device = Self {
+            config,
+            method,
+            state: Default::default(),
+        };
+
+        spawn_weak_interval(
+            device.downgrade(),
+            device.config.interval,
+            |device| async move { device.poll().await },
+        );
+
+        Ok(device)
+    }
+}
+
+impl Device for PingPresence {
+    fn get_id(&self) -> String {
+        self.config.identifier.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automation_lib::event::EventChannel;
+    use automation_lib::lua::testing::MockMqttClient;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn presence(client: &MockMqttClient) -> PingPresence {
+        PingPresence {
+            config: Config {
+                identifier: "ping".into(),
+                targets: Vec::new(),
+                topic: "automation/presence/ping".into(),
+                interval: Duration::from_secs(3600),
+                timeout: Duration::from_millis(200),
+                miss_count: 3,
+                method: ProbeMethod::Tcp,
+                port: 62078,
+                callback: Default::default(),
+                client: client.client(),
+            },
+            method: ProbeMethod::Tcp,
+            state: Default::default(),
+        }
+    }
+
+    #[test]
+    fn arp() {
+        let table = "IP address       HW type     Flags       HW address            Mask     Device
+192.168.1.1      0x1         0x2         aa:bb:cc:dd:ee:01     *        eth0
+192.168.1.20     0x1         0x0         00:00:00:00:00:00     *        eth0
+192.168.1.42     0x1         0x2         AA:BB:CC:DD:EE:42     *        eth0
+";
+
+        let mac = |mac: &str| MacAddress::parse_str(mac).unwrap();
+        assert_eq!(
+            parse_arp(table, &mac("aa:bb:cc:dd:ee:42")),
+            Some([192, 168, 1, 42].into())
+        );
+        assert_eq!(parse_arp(table, &mac("00:00:00:00:00:00")), None);
+        assert_eq!(parse_arp(table, &mac("aa:bb:cc:dd:ee:99")), None);
+    }
+
+    #[tokio::test]
+    async fn tcp_probe() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap().port();
+        let mut presence = presence(&client);
+
+        presence.config.port = open;
+        assert!(presence.probe([127, 0, 0, 1].into()).await);
+
+        // Something answered, so the device is there
+        drop(listener);
+        assert!(presence.probe([127, 0, 0, 1].into()).await);
+    }
+
+    #[tokio::test]
+    async fn miss_count() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let presence = presence(&client);
+
+        presence.update("alice/phone", true).await;
+        presence.update("alice/phone", false).await;
+        presence.update("alice/phone", false).await;
+        assert_eq!(presence.is_present("alice/phone").await, Some(true));
+
+        presence.update("alice/phone", false).await;
+        assert_eq!(presence.is_present("alice/phone").await, Some(false));
+
+        // A target that was never seen is only away after the misses as well
+        presence.update("bob/phone", false).await;
+        assert_eq!(presence.is_present("bob/phone").await, None);
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let states: Vec<_> = client
+            .published()
+            .into_iter()
+            .map(|(topic, payload)| {
+                let message: serde_json::Value = serde_json::from_str(&payload).unwrap();
+                (topic, message["state"].as_bool().unwrap())
+            })
+            .collect();
+        assert_eq!(
+            states,
+            [
+                ("automation/presence/ping/alice/phone".into(), true),
+                ("automation/presence/ping/alice/phone".into(), false)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn poll_stops_with_device() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let mut config = presence(&client).config;
+        config.interval = Duration::from_millis(10);
+
+        let device = PingPresence::create(config).await.unwrap();
+        let state = Arc::downgrade(&device.state);
+
+        drop(device);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(state.upgrade().is_none());
+    }
+}
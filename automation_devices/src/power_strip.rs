@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use automation_lib::action_callback::ActionCallback;
+use automation_lib::config::{InfoConfig, MqttDeviceConfig};
+use automation_lib::device::{Device, LuaDeviceCreate};
+use automation_lib::event::OnMqtt;
+use automation_lib::mqtt::WrappedAsyncClient;
+use automation_macro::LuaDeviceConfig;
+use google_home::device;
+use google_home::errors::{DeviceError, ErrorCode};
+use google_home::traits::{OnOff, Toggle, ToggleValue, Toggles};
+use google_home::types::Type;
+use rumqttc::{matches, Publish, QoS};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tracing::{debug, trace, warn};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SocketConfig {
+    // Key used in the mqtt payload, e.g. 'socket_1'
+    pub key: String,
+    // Name of the socket in Google Home, e.g. 'Desk lamp'
+    pub name: String,
+}
+
+#[derive(Debug, Clone, LuaDeviceConfig)]
+pub struct Config {
+    #[device_config(flatten)]
+    pub info: InfoConfig,
+    #[device_config(flatten)]
+    pub mqtt: MqttDeviceConfig,
+    pub sockets: Vec<SocketConfig>,
+
+    // Called when one of the sockets changes state
+    #[device_config(from_lua, default)]
+    pub callback: ActionCallback<PowerStrip, SocketState>,
+
+    #[device_config(from_lua)]
+    pub client: WrappedAsyncClient,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SocketState {
+    pub key: String,
+    pub on: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct PowerStrip {
+    config: Config,
+    state: Arc<RwLock<HashMap<String, bool>>>,
+}
+
+impl PowerStrip {
+    async fn state(&self) -> RwLockReadGuard<HashMap<String, bool>> {
+        self.state.read().await
+    }
+
+    async fn state_mut(&self) -> RwLockWriteGuard<HashMap<String, bool>> {
+        self.state.write().await
+    }
+
+    fn has_socket(&self, key: &str) -> bool {
+        self.config.sockets.iter().any(|socket| socket.key == key)
+    }
+
+    pub async fn socket(&self, key: &str) -> Option<bool> {
+        self.state().await.get(key).copied()
+    }
+
+    pub async fn set_sockets(&self, sockets: HashMap<String, bool>) -> Result<(), ErrorCode> {
+        if let Some(key) = sockets.keys().find(|key| !self.has_socket(key)) {
+            warn!(id = Device::get_id(self), "Unknown socket '{key}'");
+            return Err(DeviceError::ActionNotAvailable.into());
+        }
+
+        let message = serde_json::to_string(&sockets).expect("Serialization should not fail");
+        debug!(id = Device::get_id(self), "{message}");
+
+        let topic = format!("{}/set", self.config.mqtt.topic);
+        self.config
+            .client
+            .publish(&topic, QoS::AtLeastOnce, false, message)
+            .await
+            .map_err(|err| {
+                warn!("Failed to update state on {topic}: {err}");
+                DeviceError::TransientError
+            })?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl LuaDeviceCreate for PowerStrip {
+    type Config = Config;
+    type Error = rumqttc::ClientError;
+
+    async fn create(config: Self::Config) -> Result<Self, Self::Error> {
+        trace!(id = config.info.identifier(), "Setting up PowerStrip");
+
+        config
+            .client
+            .subscribe(&config.mqtt.topic, QoS::AtLeastOnce)
+            .await?;
+
+        Ok(Self {
+            config,
+            state: Default::default(),
+        })
+    }
+}
+
+impl Device for PowerStrip {
+    fn get_id(&self) -> String {
+        self.config.info.identifier()
+    }
+
+    fn get_info(&self) -> Option<&InfoConfig> {
+        Some(&self.config.info)
+    }
+}
+
+// Sockets report either a boolean or "ON"/"OFF"
+fn parse_socket(value: &serde_json::Value) -> Option<bool> {
+    match value {
+        serde_json::Value::Bool(on) => Some(*on),
+        serde_json::Value::String(state) => match state.as_str() {
+            "ON" => Some(true),
+            "OFF" => Some(false),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[async_trait]
+impl OnMqtt for PowerStrip {
+    async fn on_mqtt(&self, message: Publish) {
+        if !matches(&message.topic, &self.config.mqtt.topic) {
+            return;
+        }
+
+        let payload = match serde_json::from_slice::<serde_json::Map<String, serde_json::Value>>(
+            &message.payload,
+        ) {
+            Ok(payload) => payload,
+            Err(err) => {
+                warn!(id = Device::get_id(self), "Failed to parse message: {err}");
+                return;
+            }
+        };
+
+        for socket in &self.config.sockets {
+            let Some(on) = payload.get(&socket.key).and_then(parse_socket) else {
+                continue;
+            };
+
+            let previous = self.state_mut().await.insert(socket.key.clone(), on);
+            if previous != Some(on) {
+                debug!(id = Device::get_id(self), "{} = {on}", socket.key);
+                let state = SocketState {
+                    key: socket.key.clone(),
+                    on,
+                };
+                self.config.callback.call(self, &state).await;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl google_home::Device for PowerStrip {
+    fn get_device_type(&self) -> Type {
+        Type::Outlet
+    }
+
+    fn get_device_name(&self) -> device::Name {
+        self.config.info.device_name()
+    }
+
+    fn get_id(&self) -> String {
+        Device::get_id(self)
+    }
+
+    async fn is_online(&self) -> bool {
+        true
+    }
+
+    fn get_room_hint(&self) -> Option<&str> {
+        self.config.info.room.as_deref()
+    }
+
+    fn get_custom_data(&self) -> Option<serde_json::Value> {
+        self.config.info.custom_data()
+    }
+}
+
+// Controls all sockets at once
+#[async_trait]
+impl OnOff for PowerStrip {
+    async fn on(&self) -> Result<bool, ErrorCode> {
+        Ok(self.state().await.values().any(|on| *on))
+    }
+
+    async fn set_on(&self, on: bool) -> Result<(), ErrorCode> {
+        let sockets = self
+            .config
+            .sockets
+            .iter()
+            .map(|socket| (socket.key.clone(), on))
+            .collect();
+
+        self.set_sockets(sockets).await
+    }
+}
+
+#[async_trait]
+impl Toggles for PowerStrip {
+    fn available_toggles(&self) -> Vec<Toggle> {
+        self.config
+            .sockets
+            .iter()
+            .map(|socket| Toggle {
+                name: socket.key.clone(),
+                name_values: vec![ToggleValue {
+                    name_synonym: vec![socket.name.clone()],
+                    lang: "en".into(),
+                }],
+            })
+            .collect()
+    }
+
+    async fn current_toggle_settings(&self) -> Result<HashMap<String, bool>, ErrorCode> {
+        let state = self.state().await;
+
+        Ok(self
+            .config
+            .sockets
+            .iter()
+            .map(|socket| {
+                let on = state.get(&socket.key).copied().unwrap_or_default();
+                (socket.key.clone(), on)
+            })
+            .collect())
+    }
+
+    async fn set_toggle(&self, toggle: String, enable: bool) -> Result<(), ErrorCode> {
+        self.set_sockets(HashMap::from([(toggle, enable)])).await
+    }
+
+    // Send all changes in a single message
+    async fn set_toggles(
+        &self,
+        update_toggle_settings: HashMap<String, bool>,
+    ) -> Result<(), ErrorCode> {
+        self.set_sockets(update_toggle_settings).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automation_lib::event::EventChannel;
+    use automation_lib::lua::testing::MockMqttClient;
+    use google_home::traits::Command;
+    use serde_json::json;
+
+    use super::*;
+
+    async fn power_strip(client: &MockMqttClient) -> PowerStrip {
+        PowerStrip::create(Config {
+            info: InfoConfig::new("Power strip"),
+            mqtt: MqttDeviceConfig {
+                topic: "automation/power_strip".into(),
+                availability: None,
+            },
+            sockets: vec![
+                SocketConfig {
+                    key: "socket_1".into(),
+                    name: "Desk lamp".into(),
+                },
+                SocketConfig {
+                    key: "socket_2".into(),
+                    name: "Monitor".into(),
+                },
+            ],
+            callback: Default::default(),
+            client: client.client(),
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn toggles() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let strip = power_strip(&client).await;
+
+        strip
+            .on_mqtt(Publish::new(
+                "automation/power_strip",
+                QoS::AtLeastOnce,
+                r#"{"socket_1":"ON","socket_2":false,"power":12.5}"#,
+            ))
+            .await;
+        assert_eq!(strip.socket("socket_1").await, Some(true));
+        assert!(strip.on().await.unwrap());
+        assert_eq!(
+            strip.current_toggle_settings().await.unwrap(),
+            HashMap::from([("socket_1".into(), true), ("socket_2".into(), false)])
+        );
+
+        google_home::Device::execute(
+            &strip,
+            Command::SetToggles {
+                update_toggle_settings: HashMap::from([("socket_2".into(), true)]),
+            },
+        )
+        .await
+        .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        let (topic, payload) = client.published().pop().unwrap();
+        assert_eq!(topic, "automation/power_strip/set");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&payload).unwrap(),
+            json!({ "socket_2": true })
+        );
+
+        assert!(strip.set_toggle("socket_3".into(), true).await.is_err());
+    }
+}
@@ -0,0 +1,821 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use automation_lib::action_callback::ActionCallback;
+use automation_lib::config::{InfoConfig, Secret};
+use automation_lib::device::{Device, LuaDeviceCreate};
+use automation_lib::event::OnMqtt;
+use automation_lib::metrics;
+use automation_lib::mqtt::WrappedAsyncClient;
+use automation_macro::LuaDeviceConfig;
+use google_home::device;
+use google_home::errors::{DeviceError, ErrorCode};
+use google_home::traits::OnOff;
+use google_home::types::Type;
+use reqwest::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use reqwest::StatusCode;
+use rumqttc::{Publish, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::sync::{oneshot, RwLock};
+use tracing::{debug, trace, warn};
+
+use crate::zigbee::outlet::OutletType;
+
+// Gen2 devices always use admin as the username
+const USERNAME: &str = "admin";
+
+#[derive(Debug, Clone, LuaDeviceConfig)]
+pub struct Config {
+    #[device_config(flatten)]
+    pub info: InfoConfig,
+    // Hostname or ip address of the device, used for RPC over HTTP when no topic is set
+    #[device_config(default)]
+    pub host: Option<String>,
+    // MQTT topic prefix configured on the device, e.g. 'shellyplus1pm-a8032ab12345'
+    #[device_config(default)]
+    pub topic: Option<String>,
+    #[device_config(from_lua, default)]
+    pub client: Option<WrappedAsyncClient>,
+    // Switch to control on devices with more than one, the first switch is 0
+    #[device_config(default(0))]
+    pub switch_id: u8,
+    #[device_config(default(OutletType::Outlet))]
+    pub outlet_type: OutletType,
+    // Only needed when authentication is enabled on the device
+    #[device_config(secret, default)]
+    pub password: Secret<Option<String>>,
+    #[device_config(rename("timeout_seconds"), default(5), with(Duration::from_secs))]
+    pub timeout: Duration,
+
+    // Called when the switch is turned on or off
+    #[device_config(from_lua, default)]
+    pub callback: ActionCallback<ShellySwitch, bool>,
+    // Called when the active power changes
+    #[device_config(from_lua, default)]
+    pub power_callback: ActionCallback<ShellySwitch, f64>,
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Either host or topic needs to be set")]
+    MissingHost,
+    #[error("A client is required when topic is set")]
+    MissingClient,
+    #[error(transparent)]
+    Mqtt(#[from] rumqttc::ClientError),
+    #[error(transparent)]
+    Request(#[from] reqwest::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("RPC call failed ({code}): {message}")]
+    Rpc { code: i32, message: String },
+    #[error("Device requires authentication, check the password")]
+    Unauthorized,
+    #[error("Timed out waiting for a response")]
+    Timeout,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct EnergyCounter {
+    // Wh
+    pub total: f64,
+}
+
+// Notifications only contain the fields that changed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct SwitchStatus {
+    pub output: Option<bool>,
+    // Watt
+    pub apower: Option<f64>,
+    pub voltage: Option<f64>,
+    pub current: Option<f64>,
+    pub aenergy: Option<EnergyCounter>,
+}
+
+impl SwitchStatus {
+    fn merge(&mut self, update: SwitchStatus) {
+        self.output = update.output.or(self.output);
+        self.apower = update.apower.or(self.apower);
+        self.voltage = update.voltage.or(self.voltage);
+        self.current = update.current.or(self.current);
+        self.aenergy = update.aenergy.or(self.aenergy);
+    }
+}
+
+fn sha256(input: &str) -> String {
+    format!("{:x}", Sha256::digest(input))
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct AuthChallenge {
+    realm: String,
+    // Numeric when sent in an RPC error, a hex string in the HTTP header
+    nonce: serde_json::Value,
+}
+
+impl AuthChallenge {
+    // Parses the WWW-Authenticate header, e.g.
+    // 'Digest qop="auth", realm="shellyplus1pm-a8032ab12345", nonce="60dc59c6", algorithm=SHA-256'
+    fn from_header(header: &str) -> Option<Self> {
+        let mut realm = None;
+        let mut nonce = None;
+        for param in header.strip_prefix("Digest ")?.split(',') {
+            let Some((key, value)) = param.trim().split_once('=') else {
+                continue;
+            };
+
+            let value = value.trim_matches('"');
+            match key {
+                "realm" => realm = Some(value.to_owned()),
+                "nonce" => nonce = Some(value.into()),
+                _ => {}
+            }
+        }
+
+        Some(Self {
+            realm: realm?,
+            nonce: nonce?,
+        })
+    }
+
+    fn nonce(&self) -> String {
+        match &self.nonce {
+            serde_json::Value::String(nonce) => nonce.clone(),
+            nonce => nonce.to_string(),
+        }
+    }
+
+    // SHA-256 digest as described in RFC 7616
+    fn response(&self, password: &str, nc: &str, cnonce: u64, ha2: &str) -> String {
+        let ha1 = sha256(&format!("{USERNAME}:{}:{password}", self.realm));
+        let ha2 = sha256(ha2);
+
+        sha256(&format!("{ha1}:{}:{nc}:{cnonce}:auth:{ha2}", self.nonce()))
+    }
+}
+
+// Authentication that is included in the RPC request itself, used over MQTT
+#[derive(Debug, Serialize)]
+struct Auth {
+    realm: String,
+    username: &'static str,
+    nonce: serde_json::Value,
+    cnonce: u64,
+    response: String,
+    algorithm: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcRequest<'a> {
+    id: u32,
+    src: &'a str,
+    method: &'a str,
+    params: &'a serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auth: Option<Auth>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcError {
+    code: i32,
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcResponse {
+    id: u32,
+    #[serde(default)]
+    result: Option<serde_json::Value>,
+    #[serde(default)]
+    error: Option<RpcError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcNotification {
+    method: String,
+    #[serde(default)]
+    params: serde_json::Value,
+}
+
+enum Reply {
+    Result(serde_json::Value),
+    Unauthorized(AuthChallenge),
+}
+
+impl RpcResponse {
+    fn into_reply(self) -> Result<Reply, Error> {
+        match self.error {
+            // The message contains the challenge as json
+            Some(error) if error.code == 401 => {
+                Ok(Reply::Unauthorized(serde_json::from_str(&error.message)?))
+            }
+            Some(error) => Err(Error::Rpc {
+                code: error.code,
+                message: error.message,
+            }),
+            None => Ok(Reply::Result(self.result.unwrap_or_default())),
+        }
+    }
+}
+
+fn cnonce() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Clone)]
+pub struct ShellySwitch {
+    config: Config,
+    http: reqwest::Client,
+
+    next_id: Arc<AtomicU32>,
+    // Requests sent over MQTT that are waiting for a response
+    pending: Arc<Mutex<HashMap<u32, oneshot::Sender<RpcResponse>>>>,
+    status: Arc<RwLock<Option<SwitchStatus>>>,
+}
+
+impl ShellySwitch {
+    // Used as the source of requests, the device publishes responses to '<src>/rpc'
+    fn src(&self) -> String {
+        format!("automation_rs-{}", Device::get_id(self))
+    }
+
+    // Key of the switch in status notifications
+    fn component(&self) -> String {
+        format!("switch:{}", self.config.switch_id)
+    }
+
+    fn mqtt(&self) -> Option<(&str, &WrappedAsyncClient)> {
+        self.config
+            .topic
+            .as_deref()
+            .zip(self.config.client.as_ref())
+    }
+
+    async fn send_mqtt(
+        &self,
+        topic: &str,
+        client: &WrappedAsyncClient,
+        mut request: RpcRequest<'_>,
+        challenge: Option<&AuthChallenge>,
+    ) -> Result<Reply, Error> {
+        if let (Some(challenge), Some(password)) = (challenge, self.config.password.as_deref()) {
+            let cnonce = cnonce();
+            request.auth = Some(Auth {
+                realm: challenge.realm.clone(),
+                username: USERNAME,
+                nonce: challenge.nonce.clone(),
+                cnonce,
+                response: challenge.response(password, "1", cnonce, "dummy_method:dummy_uri"),
+                algorithm: "SHA-256",
+            });
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(request.id, tx);
+
+        let response = async {
+            client
+                .publish(
+                    format!("{topic}/rpc"),
+                    QoS::AtLeastOnce,
+                    false,
+                    serde_json::to_vec(&request)?,
+                )
+                .await?;
+
+            tokio::time::timeout(self.config.timeout, rx)
+                .await
+                .map_err(|_| Error::Timeout)?
+                .map_err(|_| Error::Timeout)
+        }
+        .await;
+
+        self.pending.lock().unwrap().remove(&request.id);
+
+        response?.into_reply()
+    }
+
+    async fn send_http(
+        &self,
+        host: &str,
+        request: RpcRequest<'_>,
+        challenge: Option<&AuthChallenge>,
+    ) -> Result<Reply, Error> {
+        let mut builder = self
+            .http
+            .post(format!("http://{host}/rpc"))
+            .timeout(self.config.timeout)
+            .json(&request);
+
+        if let (Some(challenge), Some(password)) = (challenge, self.config.password.as_deref()) {
+            let cnonce = cnonce();
+            let nc = "00000001";
+            builder = builder.header(
+                AUTHORIZATION,
+                format!(
+                    r#"Digest username="{USERNAME}", realm="{}", nonce="{}", uri="/rpc", algorithm=SHA-256, response="{}", qop=auth, nc={nc}, cnonce="{cnonce}""#,
+                    challenge.realm,
+                    challenge.nonce(),
+                    challenge.response(password, nc, cnonce, "POST:/rpc"),
+                ),
+            );
+        }
+
+        let response = builder.send().await?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            return response
+                .headers()
+                .get(WWW_AUTHENTICATE)
+                .and_then(|header| header.to_str().ok())
+                .and_then(AuthChallenge::from_header)
+                .map(Reply::Unauthorized)
+                .ok_or(Error::Unauthorized);
+        }
+
+        response
+            .error_for_status()?
+            .json::<RpcResponse>()
+            .await?
+            .into_reply()
+    }
+
+    async fn send(
+        &self,
+        method: &str,
+        params: &serde_json::Value,
+        challenge: Option<&AuthChallenge>,
+    ) -> Result<Reply, Error> {
+        let src = self.src();
+        let request = RpcRequest {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            src: &src,
+            method,
+            params,
+            auth: None,
+        };
+
+        match (self.mqtt(), &self.config.host) {
+            (Some((topic, client)), _) => self.send_mqtt(topic, client, request, challenge).await,
+            (None, Some(host)) => self.send_http(host, request, challenge).await,
+            (None, None) => Err(Error::MissingHost),
+        }
+    }
+
+    async fn call(
+        &self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> Result<serde_json::Value, Error> {
+        trace!(id = Device::get_id(self), "Calling {method}: {params}");
+
+        let challenge = match self.send(method, &params, None).await? {
+            Reply::Result(result) => return Ok(result),
+            Reply::Unauthorized(challenge) => challenge,
+        };
+
+        if self.config.password.is_none() {
+            return Err(Error::Unauthorized);
+        }
+
+        match self.send(method, &params, Some(&challenge)).await? {
+            Reply::Result(result) => Ok(result),
+            Reply::Unauthorized(_) => Err(Error::Unauthorized),
+        }
+    }
+
+    async fn update_status(&self, update: SwitchStatus) {
+        let (previous, current) = {
+            let mut status = self.status.write().await;
+            let previous = status.unwrap_or_default();
+            let current = status.get_or_insert_with(Default::default);
+            current.merge(update);
+
+            (previous, *current)
+        };
+
+        if let Some(energy) = current.aenergy {
+            metrics::set_outlet_energy(&Device::get_id(self), energy.total);
+        }
+
+        if previous.output != current.output {
+            if let Some(on) = current.output {
+                debug!(id = Device::get_id(self), "On = {on}");
+                self.config.callback.call(self, &on).await;
+            }
+        }
+
+        if previous.apower != current.apower {
+            if let Some(power) = current.apower {
+                trace!(id = Device::get_id(self), "Power = {power}");
+                self.config.power_callback.call(self, &power).await;
+            }
+        }
+    }
+
+    // Over MQTT the status is kept up to date using notifications, over HTTP it is requested
+    // every time
+    pub async fn status(&self) -> Result<SwitchStatus, Error> {
+        if self.mqtt().is_some() {
+            if let Some(status) = *self.status.read().await {
+                return Ok(status);
+            }
+        }
+
+        let status = self
+            .call("Switch.GetStatus", json!({ "id": self.config.switch_id }))
+            .await?;
+        self.update_status(serde_json::from_value(status)?).await;
+
+        Ok(self.status.read().await.unwrap_or_default())
+    }
+
+    pub async fn power(&self) -> Result<Option<f64>, Error> {
+        Ok(self.status().await?.apower)
+    }
+}
+
+#[async_trait]
+impl LuaDeviceCreate for ShellySwitch {
+    type Config = Config;
+    type Error = Error;
+
+    async fn create(config: Self::Config) -> Result<Self, Self::Error> {
+        trace!(id = config.info.identifier(), "Setting up ShellySwitch");
+
+        let switch = Self {
+            config,
+            http: reqwest::Client::builder().build()?,
+            next_id: Arc::new(AtomicU32::new(1)),
+            pending: Default::default(),
+            status: Default::default(),
+        };
+
+        match (&switch.config.topic, &switch.config.client) {
+            (Some(topic), Some(client)) => {
+                client
+                    .subscribe(format!("{}/rpc", switch.src()), QoS::AtLeastOnce)
+                    .await?;
+                client
+                    .subscribe(format!("{topic}/events/rpc"), QoS::AtLeastOnce)
+                    .await?;
+            }
+            (Some(_), None) => return Err(Error::MissingClient),
+            (None, _) if switch.config.host.is_none() => return Err(Error::MissingHost),
+            (None, _) => {}
+        }
+
+        Ok(switch)
+    }
+}
+
+impl Device for ShellySwitch {
+    fn get_id(&self) -> String {
+        self.config.info.identifier()
+    }
+
+    fn get_info(&self) -> Option<&InfoConfig> {
+        Some(&self.config.info)
+    }
+}
+
+#[async_trait]
+impl OnMqtt for ShellySwitch {
+    async fn on_mqtt(&self, message: Publish) {
+        let Some((topic, _)) = self.mqtt() else {
+            return;
+        };
+
+        if message.topic == format!("{}/rpc", self.src()) {
+            let response: RpcResponse = match serde_json::from_slice(&message.payload) {
+                Ok(response) => response,
+                Err(err) => {
+                    warn!(id = Device::get_id(self), "Failed to parse response: {err}");
+                    return;
+                }
+            };
+
+            match self.pending.lock().unwrap().remove(&response.id) {
+                Some(tx) => {
+                    tx.send(response).ok();
+                }
+                None => debug!(
+                    id = Device::get_id(self),
+                    "Received response to unknown request {}", response.id
+                ),
+            }
+        } else if message.topic == format!("{topic}/events/rpc") {
+            let notification: RpcNotification = match serde_json::from_slice(&message.payload) {
+                Ok(notification) => notification,
+                Err(err) => {
+                    warn!(
+                        id = Device::get_id(self),
+                        "Failed to parse notification: {err}"
+                    );
+                    return;
+                }
+            };
+
+            if notification.method != "NotifyStatus" {
+                return;
+            }
+
+            let Some(status) = notification.params.get(self.component()) else {
+                return;
+            };
+
+            match serde_json::from_value(status.clone()) {
+                Ok(status) => self.update_status(status).await,
+                Err(err) => warn!(id = Device::get_id(self), "Failed to parse status: {err}"),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl google_home::Device for ShellySwitch {
+    fn get_device_type(&self) -> Type {
+        self.config.outlet_type.into()
+    }
+
+    fn get_device_name(&self) -> device::Name {
+        self.config.info.device_name()
+    }
+
+    fn get_id(&self) -> String {
+        Device::get_id(self)
+    }
+
+    async fn is_online(&self) -> bool {
+        true
+    }
+
+    fn get_room_hint(&self) -> Option<&str> {
+        self.config.info.room.as_deref()
+    }
+
+    fn get_custom_data(&self) -> Option<serde_json::Value> {
+        self.config.info.custom_data()
+    }
+
+    fn will_report_state(&self) -> bool {
+        false
+    }
+}
+
+#[async_trait]
+impl OnOff for ShellySwitch {
+    async fn on(&self) -> Result<bool, ErrorCode> {
+        match self.status().await {
+            Ok(status) => status.output.ok_or(DeviceError::TransientError.into()),
+            Err(err) => {
+                warn!(id = Device::get_id(self), "Failed to get state: {err}");
+                Err(DeviceError::DeviceOffline.into())
+            }
+        }
+    }
+
+    async fn set_on(&self, on: bool) -> Result<(), ErrorCode> {
+        let params = json!({ "id": self.config.switch_id, "on": on });
+        if let Err(err) = self.call("Switch.Set", params).await {
+            warn!(id = Device::get_id(self), "Failed to set state: {err}");
+            return Err(DeviceError::DeviceOffline.into());
+        }
+
+        self.update_status(SwitchStatus {
+            output: Some(on),
+            ..Default::default()
+        })
+        .await;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use automation_lib::event::EventChannel;
+    use automation_lib::lua::testing::{MockHttpServer, MockMqttClient};
+
+    use super::*;
+
+    const TOPIC: &str = "shellyplus1pm-a8032ab12345";
+
+    fn fixture(name: &str) -> serde_json::Value {
+        let fixture = match name {
+            "switch_get_status" => {
+                include_str!("../tests/fixtures/shelly/switch_get_status.json")
+            }
+            "switch_set" => include_str!("../tests/fixtures/shelly/switch_set.json"),
+            "notify_status" => include_str!("../tests/fixtures/shelly/notify_status.json"),
+            "unauthorized" => include_str!("../tests/fixtures/shelly/unauthorized.json"),
+            _ => panic!("Unknown fixture {name}"),
+        };
+
+        serde_json::from_str(fixture).unwrap()
+    }
+
+    async fn switch(
+        host: Option<String>,
+        client: Option<&MockMqttClient>,
+        password: Option<&str>,
+    ) -> ShellySwitch {
+        ShellySwitch::create(Config {
+            info: InfoConfig {
+                room: Some("Living Room".into()),
+                ..InfoConfig::new("Heater")
+            },
+            host,
+            topic: client.map(|_| TOPIC.into()),
+            client: client.map(MockMqttClient::client),
+            switch_id: 0,
+            outlet_type: OutletType::Outlet,
+            password: password.map(str::to_owned).into(),
+            timeout: Duration::from_secs(1),
+            callback: Default::default(),
+            power_callback: Default::default(),
+        })
+        .await
+        .unwrap()
+    }
+
+    // Waits for the n-th request that is sent over MQTT
+    async fn next_request(client: &MockMqttClient, n: usize) -> serde_json::Value {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        loop {
+            let requests: Vec<_> = client
+                .published()
+                .into_iter()
+                .filter(|(topic, _)| *topic == format!("{TOPIC}/rpc"))
+                .collect();
+            if let Some((_, payload)) = requests.get(n) {
+                return serde_json::from_str(payload).unwrap();
+            }
+
+            assert!(Instant::now() < deadline, "No request was sent");
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    async fn respond(switch: &ShellySwitch, request: &serde_json::Value, fixture_name: &str) {
+        let mut response = fixture(fixture_name);
+        response["id"] = request["id"].clone();
+
+        switch
+            .on_mqtt(Publish::new(
+                format!("{}/rpc", request["src"].as_str().unwrap()),
+                QoS::AtLeastOnce,
+                serde_json::to_vec(&response).unwrap(),
+            ))
+            .await;
+    }
+
+    #[test]
+    fn digest() {
+        let challenge = AuthChallenge::from_header(
+            r#"Digest qop="auth", realm="shellyplus1pm-a8032ab12345", nonce="60dc59c6", algorithm=SHA-256"#,
+        )
+        .unwrap();
+        assert_eq!(
+            challenge,
+            AuthChallenge {
+                realm: TOPIC.into(),
+                nonce: "60dc59c6".into()
+            }
+        );
+        assert_eq!(
+            challenge.response("hunter2", "00000001", 42, "POST:/rpc"),
+            "83eaf207bfb7b64156348eef42a7cb1a457d148066161221dbc2c4d6b233fa91"
+        );
+
+        let Ok(Reply::Unauthorized(challenge)) =
+            serde_json::from_value::<RpcResponse>(fixture("unauthorized"))
+                .unwrap()
+                .into_reply()
+        else {
+            panic!("Expected a challenge");
+        };
+        assert_eq!(
+            challenge.response("hunter2", "1", 42, "dummy_method:dummy_uri"),
+            "31cc9c4be23806deef459063ff770f88a2f2d98d940adb997e0cc1708e83083c"
+        );
+    }
+
+    #[tokio::test]
+    async fn http() {
+        let server = MockHttpServer::start().await;
+        server
+            .respond_json("POST", "/rpc", fixture("switch_get_status"))
+            .await;
+        let host = server.url().trim_start_matches("http://").to_owned();
+        let switch = switch(Some(host), None, None).await;
+
+        assert_eq!(switch.on().await, Ok(true));
+        assert_eq!(switch.power().await.unwrap(), Some(12.3));
+
+        let request = &server.received_json("/rpc").await[0];
+        assert_eq!(request["method"], "Switch.GetStatus");
+        assert_eq!(request["params"], json!({ "id": 0 }));
+        assert_eq!(request["src"], "automation_rs-living_room_heater");
+    }
+
+    #[tokio::test]
+    async fn mqtt_authentication() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let switch = switch(None, Some(&client), Some("hunter2")).await;
+
+        let set = tokio::spawn({
+            let switch = switch.clone();
+            async move { switch.set_on(true).await }
+        });
+
+        let request = next_request(&client, 0).await;
+        assert_eq!(request["method"], "Switch.Set");
+        assert_eq!(request["params"], json!({ "id": 0, "on": true }));
+        assert!(request.get("auth").is_none());
+        respond(&switch, &request, "unauthorized").await;
+
+        // The request is sent again with the response to the challenge
+        let request = next_request(&client, 1).await;
+        let auth = &request["auth"];
+        assert_eq!(auth["username"], "admin");
+        assert_eq!(auth["nonce"], 1733515200);
+        let challenge = AuthChallenge {
+            realm: TOPIC.into(),
+            nonce: 1733515200.into(),
+        };
+        assert_eq!(
+            auth["response"],
+            challenge.response(
+                "hunter2",
+                "1",
+                auth["cnonce"].as_u64().unwrap(),
+                "dummy_method:dummy_uri"
+            )
+        );
+        respond(&switch, &request, "switch_set").await;
+
+        assert_eq!(set.await.unwrap(), Ok(()));
+        assert_eq!(switch.on().await, Ok(true));
+    }
+
+    #[tokio::test]
+    async fn mqtt_unauthorized() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let switch = switch(None, Some(&client), None).await;
+
+        let set = tokio::spawn({
+            let switch = switch.clone();
+            async move { switch.set_on(true).await }
+        });
+        respond(&switch, &next_request(&client, 0).await, "unauthorized").await;
+
+        assert_eq!(set.await.unwrap(), Err(DeviceError::DeviceOffline.into()));
+    }
+
+    #[tokio::test]
+    async fn notify_status() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let switch = switch(None, Some(&client), None).await;
+
+        let status = tokio::spawn({
+            let switch = switch.clone();
+            async move { switch.status().await.unwrap() }
+        });
+        respond(
+            &switch,
+            &next_request(&client, 0).await,
+            "switch_get_status",
+        )
+        .await;
+        assert_eq!(status.await.unwrap().output, Some(true));
+
+        // Notifications only contain the fields that changed
+        switch
+            .on_mqtt(Publish::new(
+                format!("{TOPIC}/events/rpc"),
+                QoS::AtLeastOnce,
+                serde_json::to_vec(&fixture("notify_status")).unwrap(),
+            ))
+            .await;
+
+        let status = switch.status().await.unwrap();
+        assert_eq!(status.output, Some(true));
+        assert_eq!(status.apower, Some(1850.5));
+        assert_eq!(status.voltage, Some(230.1));
+        assert_eq!(status.aenergy, Some(EnergyCounter { total: 1522.114 }));
+    }
+}
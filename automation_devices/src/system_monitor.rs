@@ -0,0 +1,475 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use automation_lib::action_callback::ActionCallback;
+use automation_lib::config::InfoConfig;
+use automation_lib::device::{Device, LuaDeviceCreate};
+use automation_lib::helpers::weak::spawn_weak_interval;
+use automation_lib::mqtt::WrappedAsyncClient;
+use automation_macro::LuaDeviceConfig;
+use google_home::device;
+use google_home::errors::{DeviceError, ErrorCode};
+use google_home::traits::{TemperatureSetting, TemperatureUnit};
+use google_home::types::Type;
+use rumqttc::QoS;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::RwLock;
+use tracing::{debug, trace, warn};
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct Thresholds {
+    // Celsius
+    pub temperature: Option<f64>,
+    // 1 minute load average
+    pub load: Option<f64>,
+    // Percentage in use
+    pub memory: Option<f64>,
+    pub disk: Option<f64>,
+}
+
+#[derive(Debug, Clone, LuaDeviceConfig)]
+pub struct Config {
+    #[device_config(flatten)]
+    pub info: InfoConfig,
+    #[device_config(rename("interval_seconds"), default(30), with(Duration::from_secs))]
+    pub interval: Duration,
+    #[device_config(default(PathBuf::from("/sys/class/thermal/thermal_zone0")))]
+    pub thermal_zone: PathBuf,
+    // Any path on the filesystem to report the usage of
+    #[device_config(default(PathBuf::from("/")))]
+    pub disk_path: PathBuf,
+    #[device_config(default(PathBuf::from("/proc")))]
+    pub proc_path: PathBuf,
+    #[device_config(default)]
+    pub thresholds: Thresholds,
+
+    // Every reading is published to this topic
+    #[device_config(default)]
+    pub mqtt_topic: Option<String>,
+    #[device_config(from_lua, default)]
+    pub client: Option<WrappedAsyncClient>,
+
+    // Called with true when the value goes above the threshold and with false when it drops
+    // below it again
+    #[device_config(from_lua, default)]
+    pub on_high_temperature: ActionCallback<SystemMonitor, bool>,
+    #[device_config(from_lua, default)]
+    pub on_high_load: ActionCallback<SystemMonitor, bool>,
+    #[device_config(from_lua, default)]
+    pub on_high_memory: ActionCallback<SystemMonitor, bool>,
+    #[device_config(from_lua, default)]
+    pub on_high_disk: ActionCallback<SystemMonitor, bool>,
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("A client is required when mqtt_topic is set")]
+    MissingClient,
+}
+
+// Values that could not be read, e.g. because the system has no thermal zone, are left empty
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct SystemReading {
+    pub load: Option<f64>,
+    pub temperature: Option<f64>,
+    pub memory: Option<f64>,
+    pub disk: Option<f64>,
+}
+
+async fn read_load(proc_path: &Path) -> Option<f64> {
+    tokio::fs::read_to_string(proc_path.join("loadavg"))
+        .await
+        .ok()?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+async fn read_temperature(thermal_zone: &Path) -> Option<f64> {
+    let millidegrees: f64 = tokio::fs::read_to_string(thermal_zone.join("temp"))
+        .await
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+
+    Some(millidegrees / 1000.0)
+}
+
+fn parse_meminfo(meminfo: &str) -> Option<f64> {
+    let field = |name: &str| -> Option<f64> {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))?
+            .split_whitespace()
+            .next()?
+            .parse()
+            .ok()
+    };
+
+    let total = field("MemTotal")?;
+    let available = field("MemAvailable")?;
+
+    Some(100.0 * (total - available) / total)
+}
+
+async fn read_memory(proc_path: &Path) -> Option<f64> {
+    parse_meminfo(
+        &tokio::fs::read_to_string(proc_path.join("meminfo"))
+            .await
+            .ok()?,
+    )
+}
+
+// Same calculation as df, blocks reserved for root do not count as available
+fn read_disk(path: &Path) -> Option<f64> {
+    let stat = rustix::fs::statvfs(path).ok()?;
+    let used = stat.f_blocks.checked_sub(stat.f_bfree)?;
+    let total = used + stat.f_bavail;
+    if total == 0 {
+        return None;
+    }
+
+    Some(100.0 * used as f64 / total as f64)
+}
+
+#[derive(Debug, Default)]
+struct Alerts {
+    temperature: bool,
+    load: bool,
+    memory: bool,
+    disk: bool,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    reading: Option<SystemReading>,
+    alerts: Alerts,
+}
+
+#[derive(Debug, Clone)]
+pub struct SystemMonitor {
+    config: Config,
+    state: Arc<RwLock<State>>,
+}
+
+impl SystemMonitor {
+    pub async fn reading(&self) -> Option<SystemReading> {
+        self.state.read().await.reading
+    }
+
+    pub async fn temperature(&self) -> Option<f64> {
+        self.reading().await.and_then(|reading| reading.temperature)
+    }
+
+    pub async fn load(&self) -> Option<f64> {
+        self.reading().await.and_then(|reading| reading.load)
+    }
+
+    pub async fn memory(&self) -> Option<f64> {
+        self.reading().await.and_then(|reading| reading.memory)
+    }
+
+    pub async fn disk(&self) -> Option<f64> {
+        self.reading().await.and_then(|reading| reading.disk)
+    }
+
+    async fn sample(&self) -> SystemReading {
+        SystemReading {
+            load: read_load(&self.config.proc_path).await,
+            temperature: read_temperature(&self.config.thermal_zone).await,
+            memory: read_memory(&self.config.proc_path).await,
+            disk: read_disk(&self.config.disk_path),
+        }
+    }
+
+    async fn process(&self, reading: SystemReading) {
+        trace!(id = Device::get_id(self), "Reading = {reading:?}");
+
+        let thresholds = self.config.thresholds;
+        let changed = {
+            let mut guard = self.state.write().await;
+            let state = &mut *guard;
+            state.reading = Some(reading);
+
+            let mut changed = Vec::new();
+            for (name, value, threshold, alert, callback) in [
+                (
+                    "temperature",
+                    reading.temperature,
+                    thresholds.temperature,
+                    &mut state.alerts.temperature,
+                    &self.config.on_high_temperature,
+                ),
+                (
+                    "load",
+                    reading.load,
+                    thresholds.load,
+                    &mut state.alerts.load,
+                    &self.config.on_high_load,
+                ),
+                (
+                    "memory",
+                    reading.memory,
+                    thresholds.memory,
+                    &mut state.alerts.memory,
+                    &self.config.on_high_memory,
+                ),
+                (
+                    "disk",
+                    reading.disk,
+                    thresholds.disk,
+                    &mut state.alerts.disk,
+                    &self.config.on_high_disk,
+                ),
+            ] {
+                let (Some(value), Some(threshold)) = (value, threshold) else {
+                    continue;
+                };
+
+                let high = value > threshold;
+                if high != *alert {
+                    debug!(
+                        id = Device::get_id(self),
+                        "{name} is {value}, high = {high}"
+                    );
+                    *alert = high;
+                    changed.push((callback, high));
+                }
+            }
+
+            changed
+        };
+
+        for (callback, high) in changed {
+            callback.call(self, &high).await;
+        }
+
+        if let (Some(topic), Some(client)) = (&self.config.mqtt_topic, &self.config.client) {
+            let payload = serde_json::to_string(&reading).expect("Serialization should not fail");
+            if let Err(err) = client.publish(topic, QoS::AtMostOnce, false, payload).await {
+                warn!(
+                    id = Device::get_id(self),
+                    "Failed to publish reading: {err}"
+                );
+            }
+        }
+    }
+
+    // Rebuilds the device in the background task, see automation_lib::helpers::weak
+    fn downgrade(&self) -> impl Fn() -> Option<Self> + Send + 'static {
+        let config = self.config.clone();
+        let state = Arc::downgrade(&self.state);
+
+        move || {
+            Some(Self {
+                config: config.clone(),
+                state: state.upgrade()?,
+            })
+        }
+    }
+}
+
+#[async_trait]
+impl LuaDeviceCreate for SystemMonitor {
+    type Config = Config;
+    type Error = Error;
+
+    async fn create(config: Self::Config) -> Result<Self, Self::Error> {
+        trace!(id = config.info.identifier(), "Setting up SystemMonitor");
+
+        if config.mqtt_topic.is_some() && config.client.is_none() {
+            return Err(Error::MissingClient);
+        }
+
+        let monitor = Self {
+            config,
+            state: Default::default(),
+        };
+
+        spawn_weak_interval(
+            monitor.downgrade(),
+            monitor.config.interval,
+            |monitor| async move {
+                let reading = monitor.sample().await;
+                monitor.process(reading).await;
+            },
+        );
+
+        Ok(monitor)
+    }
+}
+
+impl Device for SystemMonitor {
+    fn get_id(&self) -> String {
+        self.config.info.identifier()
+    }
+
+    fn get_info(&self) -> Option<&InfoConfig> {
+        Some(&self.config.info)
+    }
+}
+
+#[async_trait]
+impl google_home::Device for SystemMonitor {
+    fn get_device_type(&self) -> Type {
+        Type::Sensor
+    }
+
+    fn get_device_name(&self) -> device::Name {
+        self.config.info.device_name()
+    }
+
+    fn get_id(&self) -> String {
+        Device::get_id(self)
+    }
+
+    async fn is_online(&self) -> bool {
+        true
+    }
+
+    fn get_room_hint(&self) -> Option<&str> {
+        self.config.info.room.as_deref()
+    }
+
+    fn get_custom_data(&self) -> Option<serde_json::Value> {
+        self.config.info.custom_data()
+    }
+
+    fn will_report_state(&self) -> bool {
+        false
+    }
+}
+
+#[async_trait]
+impl TemperatureSetting for SystemMonitor {
+    fn query_only_temperature_control(&self) -> Option<bool> {
+        Some(true)
+    }
+
+    #[allow(non_snake_case)]
+    fn temperatureUnitForUX(&self) -> TemperatureUnit {
+        TemperatureUnit::Celsius
+    }
+
+    async fn temperature_ambient_celsius(&self) -> Result<f32, ErrorCode> {
+        self.temperature()
+            .await
+            .map(|temperature| ((10.0 * temperature).round() / 10.0) as f32)
+            .ok_or(DeviceError::TransientError.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automation_lib::event::EventChannel;
+    use automation_lib::lua::testing::MockMqttClient;
+    use mlua::FromLua;
+
+    use super::*;
+
+    const MEMINFO: &str = "MemTotal:        4000000 kB
+MemFree:          500000 kB
+MemAvailable:    3000000 kB
+Buffers:          100000 kB
+";
+
+    fn config(dir: &Path, client: Option<&MockMqttClient>) -> Config {
+        Config {
+            info: InfoConfig::new("Server"),
+            interval: Duration::from_secs(3600),
+            thermal_zone: dir.to_owned(),
+            disk_path: dir.to_owned(),
+            proc_path: dir.to_owned(),
+            thresholds: Thresholds {
+                temperature: Some(70.0),
+                ..Default::default()
+            },
+            mqtt_topic: client.map(|_| "automation/server".into()),
+            client: client.map(MockMqttClient::client),
+            on_high_temperature: Default::default(),
+            on_high_load: Default::default(),
+            on_high_memory: Default::default(),
+            on_high_disk: Default::default(),
+        }
+    }
+
+    #[test]
+    fn meminfo() {
+        assert_eq!(parse_meminfo(MEMINFO), Some(25.0));
+        assert_eq!(parse_meminfo("MemTotal: 4000000 kB"), None);
+    }
+
+    #[tokio::test]
+    async fn sample() {
+        let dir = std::env::temp_dir().join(format!("system_monitor_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("loadavg"), "0.52 0.58 0.59 1/467 12345\n").unwrap();
+        std::fs::write(dir.join("meminfo"), MEMINFO).unwrap();
+        std::fs::write(dir.join("temp"), "48312\n").unwrap();
+
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let monitor = SystemMonitor::create(config(&dir, Some(&client)))
+            .await
+            .unwrap();
+
+        // The first sample is taken right away
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let reading = monitor.reading().await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(reading.load, Some(0.52));
+        assert_eq!(reading.temperature, Some(48.312));
+        assert_eq!(reading.memory, Some(25.0));
+        assert!(reading
+            .disk
+            .is_some_and(|disk| (0.0..=100.0).contains(&disk)));
+        assert_eq!(monitor.temperature_ambient_celsius().await, Ok(48.3));
+
+        let published = client.published();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].0, "automation/server");
+    }
+
+    #[tokio::test]
+    async fn threshold() {
+        let lua = mlua::Lua::new();
+        let calls = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let f = lua
+            .create_function({
+                let calls = calls.clone();
+                move |_lua, (_, high): (mlua::Value, bool)| {
+                    calls.lock().unwrap().push(high);
+                    Ok(())
+                }
+            })
+            .unwrap();
+
+        // Created directly so the readings do not come from the sample loop
+        let monitor = SystemMonitor {
+            config: Config {
+                on_high_temperature: ActionCallback::from_lua(mlua::Value::Function(f), &lua)
+                    .unwrap(),
+                ..config(Path::new("/nonexistent"), None)
+            },
+            state: Default::default(),
+        };
+
+        for temperature in [65.0, 71.0, 75.0, 69.0, 72.0] {
+            monitor
+                .process(SystemReading {
+                    temperature: Some(temperature),
+                    ..Default::default()
+                })
+                .await;
+        }
+
+        assert_eq!(*calls.lock().unwrap(), [true, false, true]);
+        assert_eq!(monitor.temperature().await, Some(72.0));
+    }
+}
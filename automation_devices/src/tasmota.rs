@@ -0,0 +1,422 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use automation_lib::action_callback::ActionCallback;
+use automation_lib::availability::Availability;
+use automation_lib::config::InfoConfig;
+use automation_lib::device::{Device, DeviceAvailability, LuaDeviceCreate};
+use automation_lib::event::OnMqtt;
+use automation_lib::metrics;
+use automation_lib::mqtt::WrappedAsyncClient;
+use automation_macro::LuaDeviceConfig;
+use google_home::device;
+use google_home::errors::{DeviceError, ErrorCode};
+use google_home::traits::OnOff;
+use google_home::types::Type;
+use rumqttc::{Publish, QoS};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tracing::{debug, trace, warn};
+
+use crate::zigbee::outlet::OutletType;
+
+#[derive(Debug, Clone, LuaDeviceConfig)]
+pub struct Config {
+    #[device_config(flatten)]
+    pub info: InfoConfig,
+    // The topic configured in Tasmota, e.g. 'tasmota_A1B2C3'
+    pub topic: String,
+    // Relay to control on devices with more than one, the first relay is 1
+    #[device_config(default)]
+    pub relay: Option<u8>,
+    #[device_config(default(OutletType::Outlet))]
+    pub outlet_type: OutletType,
+
+    // Called when the relay is turned on or off
+    #[device_config(from_lua, default)]
+    pub callback: ActionCallback<TasmotaOutlet, bool>,
+    // Called with the ENERGY block of every telemetry message
+    #[device_config(from_lua, default)]
+    pub power_callback: ActionCallback<TasmotaOutlet, TasmotaEnergy>,
+    // Called when the device becomes available or unavailable
+    #[device_config(from_lua, default)]
+    pub availability_callback: ActionCallback<TasmotaOutlet, bool>,
+
+    #[device_config(from_lua)]
+    pub client: WrappedAsyncClient,
+}
+
+// Tasmota sends the state as a bare string, e.g. 'ON'
+fn parse_power(payload: &str) -> Option<bool> {
+    match payload.trim().to_uppercase().as_str() {
+        "ON" | "1" | "TRUE" => Some(true),
+        "OFF" | "0" | "FALSE" => Some(false),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "PascalCase"))]
+pub struct TasmotaEnergy {
+    // Watt
+    pub power: f64,
+    pub voltage: Option<f64>,
+    pub current: Option<f64>,
+    // kWh
+    pub today: Option<f64>,
+    pub total: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SensorMessage {
+    #[serde(rename = "ENERGY")]
+    energy: Option<TasmotaEnergy>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    on: Option<bool>,
+    energy: Option<TasmotaEnergy>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TasmotaOutlet {
+    config: Config,
+
+    state: Arc<RwLock<State>>,
+    availability: Availability,
+}
+
+impl TasmotaOutlet {
+    fn topic(&self, prefix: &str, topic: &str) -> String {
+        format!("{prefix}/{}/{topic}", self.config.topic)
+    }
+
+    // Name of the relay in commands and results, e.g. 'POWER' or 'POWER2'
+    fn power_key(&self) -> String {
+        match self.config.relay {
+            Some(relay) => format!("POWER{relay}"),
+            None => "POWER".into(),
+        }
+    }
+
+    pub async fn power(&self) -> Option<f64> {
+        self.state.read().await.energy.map(|energy| energy.power)
+    }
+
+    pub async fn energy(&self) -> Option<TasmotaEnergy> {
+        self.state.read().await.energy
+    }
+
+    async fn update_on(&self, on: bool) {
+        if self.state.read().await.on == Some(on) {
+            return;
+        }
+
+        debug!(id = Device::get_id(self), "On = {on}");
+        self.state.write().await.on = Some(on);
+        self.config.callback.call(self, &on).await;
+    }
+
+    async fn update_available(&self, available: bool) {
+        if !self.availability.set(available) {
+            return;
+        }
+
+        self.config
+            .availability_callback
+            .call(self, &available)
+            .await;
+    }
+
+    async fn update_energy(&self, energy: TasmotaEnergy) {
+        trace!(id = Device::get_id(self), "Energy = {energy:?}");
+        self.state.write().await.energy = Some(energy);
+
+        if let Some(total) = energy.total {
+            metrics::set_outlet_energy(&Device::get_id(self), total * 1000.0);
+        }
+
+        self.config.power_callback.call(self, &energy).await;
+    }
+
+    async fn publish_power(&self, payload: &str) -> Result<(), rumqttc::ClientError> {
+        self.config
+            .client
+            .publish(
+                self.topic("cmnd", &self.power_key()),
+                QoS::AtLeastOnce,
+                false,
+                payload,
+            )
+            .await
+    }
+}
+
+#[async_trait]
+impl LuaDeviceCreate for TasmotaOutlet {
+    type Config = Config;
+    type Error = rumqttc::ClientError;
+
+    async fn create(config: Self::Config) -> Result<Self, Self::Error> {
+        trace!(id = config.info.identifier(), "Setting up TasmotaOutlet");
+
+        let outlet = Self {
+            availability: Availability::new(config.info.identifier()),
+            config,
+            state: Default::default(),
+        };
+
+        for topic in [outlet.topic("stat", "#"), outlet.topic("tele", "#")] {
+            outlet
+                .config
+                .client
+                .subscribe(topic, QoS::AtLeastOnce)
+                .await?;
+        }
+
+        // A command without a payload makes Tasmota respond with the current state
+        outlet.publish_power("").await?;
+
+        Ok(outlet)
+    }
+}
+
+impl Device for TasmotaOutlet {
+    fn get_id(&self) -> String {
+        self.config.info.identifier()
+    }
+
+    fn get_info(&self) -> Option<&InfoConfig> {
+        Some(&self.config.info)
+    }
+}
+
+impl DeviceAvailability for TasmotaOutlet {
+    fn availability(&self) -> &Availability {
+        &self.availability
+    }
+}
+
+#[async_trait]
+impl OnMqtt for TasmotaOutlet {
+    async fn on_mqtt(&self, message: Publish) {
+        let payload = String::from_utf8_lossy(&message.payload);
+
+        if message.topic == self.topic("tele", "LWT") {
+            self.update_available(payload.eq_ignore_ascii_case("Online"))
+                .await;
+        } else if message.topic == self.topic("stat", &self.power_key()) {
+            match parse_power(&payload) {
+                Some(on) => self.update_on(on).await,
+                None => warn!(id = Device::get_id(self), "Unknown power state: {payload}"),
+            }
+        } else if message.topic == self.topic("stat", "RESULT")
+            || message.topic == self.topic("tele", "STATE")
+        {
+            let result: serde_json::Value = match serde_json::from_str(&payload) {
+                Ok(result) => result,
+                Err(err) => {
+                    warn!(id = Device::get_id(self), "Failed to parse message: {err}");
+                    return;
+                }
+            };
+
+            // Results of other commands do not contain the power state
+            if let Some(on) = result
+                .get(self.power_key())
+                .and_then(|power| power.as_str())
+                .and_then(parse_power)
+            {
+                self.update_on(on).await;
+            }
+        } else if message.topic == self.topic("tele", "SENSOR") {
+            match serde_json::from_str::<SensorMessage>(&payload) {
+                Ok(SensorMessage {
+                    energy: Some(energy),
+                }) => self.update_energy(energy).await,
+                Ok(_) => {}
+                Err(err) => warn!(id = Device::get_id(self), "Failed to parse message: {err}"),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl google_home::Device for TasmotaOutlet {
+    fn get_device_type(&self) -> Type {
+        self.config.outlet_type.into()
+    }
+
+    fn get_device_name(&self) -> device::Name {
+        self.config.info.device_name()
+    }
+
+    fn get_id(&self) -> String {
+        Device::get_id(self)
+    }
+
+    async fn is_online(&self) -> bool {
+        self.availability.get()
+    }
+
+    fn get_room_hint(&self) -> Option<&str> {
+        self.config.info.room.as_deref()
+    }
+
+    fn get_custom_data(&self) -> Option<serde_json::Value> {
+        self.config.info.custom_data()
+    }
+
+    fn will_report_state(&self) -> bool {
+        false
+    }
+}
+
+#[async_trait]
+impl OnOff for TasmotaOutlet {
+    async fn on(&self) -> Result<bool, ErrorCode> {
+        // The state is unknown until the device has responded
+        self.state
+            .read()
+            .await
+            .on
+            .ok_or(DeviceError::TransientError.into())
+    }
+
+    async fn set_on(&self, on: bool) -> Result<(), ErrorCode> {
+        self.publish_power(if on { "ON" } else { "OFF" })
+            .await
+            .map_err(|err| {
+                warn!(id = Device::get_id(self), "Failed to set state: {err}");
+                DeviceError::TransientError.into()
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use automation_lib::event::EventChannel;
+    use automation_lib::lua::testing::MockMqttClient;
+
+    use super::*;
+
+    async fn outlet(client: &MockMqttClient, relay: Option<u8>) -> TasmotaOutlet {
+        TasmotaOutlet::create(Config {
+            info: InfoConfig::new("Heater"),
+            topic: "tasmota_8C4F00".into(),
+            relay,
+            outlet_type: OutletType::Outlet,
+            callback: Default::default(),
+            power_callback: Default::default(),
+            availability_callback: Default::default(),
+            client: client.client(),
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn publish(outlet: &TasmotaOutlet, topic: &str, payload: &str) {
+        outlet
+            .on_mqtt(Publish::new(topic, QoS::AtLeastOnce, payload))
+            .await;
+    }
+
+    #[test]
+    fn power() {
+        assert_eq!(parse_power("ON"), Some(true));
+        assert_eq!(parse_power("off"), Some(false));
+        assert_eq!(parse_power("1"), Some(true));
+        assert_eq!(parse_power("TOGGLE"), None);
+    }
+
+    #[tokio::test]
+    async fn state() {
+        let client = MockMqttClient::new(EventChannel::new().0);
+        let outlet = outlet(&client, None).await;
+
+        assert!(outlet.on().await.is_err());
+
+        publish(&outlet, "stat/tasmota_8C4F00/RESULT", r#"{"POWER":"ON"}"#).await;
+        assert_eq!(outlet.on().await, Ok(true));
+
+        publish(&outlet, "stat/tasmota_8C4F00/POWER", "OFF").await;
+        assert_eq!(outlet.on().await, Ok(false));
+
+        publish(
+            &outlet,
+            "tele/tasmota_8C4F00/STATE",
+            r#"{"Time":"2024-12-06T21:08:12","Uptime":"0T00:15:11","UptimeSec":911,"Heap":26,"SleepMode":"Dynamic","Sleep":50,"LoadAvg":19,"MqttCount":1,"POWER":"ON","Wifi":{"AP":1,"SSId":"iot","BSSId":"AA:BB:CC:DD:EE:FF","Channel":6,"Mode":"11n","RSSI":62,"Signal":-69,"LinkCount":1,"Downtime":"0T00:00:04"}}"#,
+        )
+        .await;
+        assert_eq!(outlet.on().await, Ok(true));
+
+        outlet.set_on(false).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(
+            client.published(),
+            [
+                ("cmnd/tasmota_8C4F00/POWER".into(), "".into()),
+                ("cmnd/tasmota_8C4F00/POWER".into(), "OFF".into())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn relay() {
+        let client = MockMqttClient::new(EventChannel::new().0);
+        let outlet = outlet(&client, Some(2)).await;
+
+        publish(
+            &outlet,
+            "stat/tasmota_8C4F00/RESULT",
+            r#"{"POWER1":"OFF","POWER2":"ON"}"#,
+        )
+        .await;
+        assert_eq!(outlet.on().await, Ok(true));
+
+        // Only the configured relay is used
+        publish(&outlet, "stat/tasmota_8C4F00/POWER1", "ON").await;
+        publish(&outlet, "stat/tasmota_8C4F00/POWER2", "OFF").await;
+        assert_eq!(outlet.on().await, Ok(false));
+    }
+
+    #[tokio::test]
+    async fn energy() {
+        let client = MockMqttClient::new(EventChannel::new().0);
+        let outlet = outlet(&client, None).await;
+
+        publish(
+            &outlet,
+            "tele/tasmota_8C4F00/SENSOR",
+            r#"{"Time":"2024-12-06T21:08:12","ENERGY":{"TotalStartTime":"2024-01-12T19:21:33","Total":12.345,"Yesterday":0.512,"Today":0.213,"Period":2,"Power":1843,"ApparentPower":1851,"ReactivePower":172,"Factor":1.00,"Voltage":231,"Current":8.013}}"#,
+        )
+        .await;
+
+        assert_eq!(outlet.power().await, Some(1843.0));
+        assert_eq!(
+            outlet.energy().await,
+            Some(TasmotaEnergy {
+                power: 1843.0,
+                voltage: Some(231.0),
+                current: Some(8.013),
+                today: Some(0.213),
+                total: Some(12.345),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn availability() {
+        let client = MockMqttClient::new(EventChannel::new().0);
+        let outlet = outlet(&client, None).await;
+
+        publish(&outlet, "tele/tasmota_8C4F00/LWT", "Offline").await;
+        assert!(!google_home::Device::is_online(&outlet).await);
+
+        publish(&outlet, "tele/tasmota_8C4F00/LWT", "Online").await;
+        assert!(google_home::Device::is_online(&outlet).await);
+    }
+}
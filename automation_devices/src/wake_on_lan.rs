@@ -1,19 +1,111 @@
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
 
 use async_trait::async_trait;
+use automation_lib::action_callback::ActionCallback;
+use automation_lib::availability::Availability;
 use automation_lib::config::{InfoConfig, MqttDeviceConfig};
-use automation_lib::device::{Device, LuaDeviceCreate};
-use automation_lib::event::OnMqtt;
+use automation_lib::device::{Device, DeviceAvailability, LuaDeviceCreate};
+use automation_lib::event::{OnMqtt, OnPresence};
 use automation_lib::messages::ActivateMessage;
 use automation_lib::mqtt::WrappedAsyncClient;
 use automation_macro::LuaDeviceConfig;
 use eui48::MacAddress;
 use google_home::device;
-use google_home::errors::ErrorCode;
+use google_home::errors::{DeviceError, ErrorCode};
 use google_home::traits::{self, Scene};
 use google_home::types::Type;
-use rumqttc::Publish;
-use tracing::{debug, error, trace};
+use rumqttc::{Publish, QoS};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::sync::{Mutex, RwLock};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{debug, error, trace, warn};
+
+use crate::icmp;
+
+// Checks if the computer is reachable by connecting to a port on it
+#[derive(Debug, Clone, Deserialize)]
+pub struct CheckConfig {
+    pub ip: IpAddr,
+    pub port: u16,
+    // How long to wait for the computer to come up after sending the packet
+    #[serde(default = "default_check_timeout_seconds")]
+    pub timeout_seconds: u64,
+    #[serde(default = "default_check_interval_seconds")]
+    pub interval_seconds: u64,
+}
+
+impl CheckConfig {
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_seconds)
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_seconds)
+    }
+
+    async fn is_reachable(&self) -> bool {
+        let connect = TcpStream::connect(SocketAddr::new(self.ip, self.port));
+        matches!(
+            tokio::time::timeout(self.interval(), connect).await,
+            Ok(Ok(_))
+        )
+    }
+
+    async fn wait_until_reachable(&self) -> bool {
+        let deadline = Instant::now() + self.timeout();
+        loop {
+            if self.is_reachable().await {
+                return true;
+            }
+
+            if Instant::now() + self.interval() > deadline {
+                return false;
+            }
+
+            tokio::time::sleep(self.interval()).await;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ShutdownConfig {
+    // Publish a message for an agent running on the computer, by default '{"action":"shutdown"}'
+    Mqtt {
+        mqtt_topic: String,
+        #[serde(default)]
+        payload: Option<serde_json::Value>,
+    },
+    // POST to the url
+    Http {
+        url: String,
+    },
+    // Log in and run the shutdown command, the user needs to be allowed to run it without a
+    // password
+    Ssh {
+        host: String,
+        user: String,
+        #[serde(default)]
+        key_path: Option<PathBuf>,
+    },
+}
+
+const SSH_SHUTDOWN_COMMAND: &str = "sudo shutdown -h now";
+
+fn default_check_timeout_seconds() -> u64 {
+    60
+}
+
+fn default_check_interval_seconds() -> u64 {
+    5
+}
 
 #[derive(Debug, Clone, LuaDeviceConfig)]
 pub struct Config {
@@ -24,13 +116,167 @@ pub struct Config {
     pub mac_address: MacAddress,
     #[device_config(default(Ipv4Addr::new(255, 255, 255, 255)))]
     pub broadcast_ip: Ipv4Addr,
+    // Verify that the computer actually woke up
+    #[device_config(default)]
+    pub check: Option<CheckConfig>,
+    // Ping the ip of the check at this interval to track if the computer is online, falls back to
+    // connecting to the port of the check when ICMP can not be used
+    #[device_config(rename("ping_interval_seconds"), default, with(|s: Option<u64>| s.map(Duration::from_secs)))]
+    pub ping_interval: Option<Duration>,
+    // Called when the computer comes online or goes offline, only used with ping_interval
+    #[device_config(from_lua, default)]
+    pub availability_callback: ActionCallback<WakeOnLAN, bool>,
+    // Called with the outcome of the check after trying to wake the computer
+    #[device_config(from_lua, default)]
+    pub callback: ActionCallback<WakeOnLAN, bool>,
+    // How to shut down the computer, shutting down is not available if not set
+    #[device_config(default)]
+    pub shutdown: Option<ShutdownConfig>,
+    // Shut down the computer when nobody has been home for this long
+    #[device_config(rename("presence_shutdown_seconds"), default, with(|s: Option<u64>| s.map(Duration::from_secs)))]
+    pub presence_shutdown: Option<Duration>,
     #[device_config(from_lua)]
     pub client: WrappedAsyncClient,
 }
 
+#[derive(Debug)]
+struct State {
+    last_known_state: RwLock<bool>,
+    presence_handle: Mutex<Option<JoinHandle<()>>>,
+    // Waits for the computer to come up after it was woken
+    check_handle: Mutex<Option<JoinHandle<()>>>,
+    // Only changes when the computer is pinged
+    availability: Availability,
+}
+
 #[derive(Debug, Clone)]
 pub struct WakeOnLAN {
     config: Config,
+    state: Arc<State>,
+}
+
+impl WakeOnLAN {
+    // Uses the check if it is configured, otherwise the result of the last wake attempt
+    pub async fn on(&self) -> bool {
+        if let Some(check) = &self.config.check {
+            let reachable = check.is_reachable().await;
+            *self.state.last_known_state.write().await = reachable;
+        }
+
+        *self.state.last_known_state.read().await
+    }
+
+    pub async fn shutdown(&self) -> Result<(), ErrorCode> {
+        let Some(shutdown) = &self.config.shutdown else {
+            debug!(
+                id = Device::get_id(self),
+                "Trying to shut down computer, but no shutdown is configured"
+            );
+            return Err(DeviceError::ActionNotAvailable.into());
+        };
+
+        debug!(id = Device::get_id(self), "Shutting down computer");
+        match shutdown {
+            ShutdownConfig::Mqtt {
+                mqtt_topic,
+                payload,
+            } => self
+                .config
+                .client
+                .publish(
+                    mqtt_topic,
+                    QoS::AtLeastOnce,
+                    false,
+                    payload
+                        .clone()
+                        .unwrap_or_else(|| json!({ "action": "shutdown" }))
+                        .to_string(),
+                )
+                .await
+                .map_err(|err| {
+                    error!(id = Device::get_id(self), "Failed to shut down: {err}");
+                    DeviceError::TransientError
+                })?,
+            ShutdownConfig::Http { url } => {
+                reqwest::Client::new()
+                    .post(url)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(|err| {
+                        error!(id = Device::get_id(self), "Failed to shut down: {err}");
+                        DeviceError::TransientError
+                    })?;
+            }
+            ShutdownConfig::Ssh {
+                host,
+                user,
+                key_path,
+            } => {
+                let mut command = Command::new("ssh");
+                command.args(["-o", "BatchMode=yes"]);
+                if let Some(key_path) = key_path {
+                    command.arg("-i").arg(key_path);
+                }
+                command
+                    .arg(format!("{user}@{host}"))
+                    .arg(SSH_SHUTDOWN_COMMAND);
+
+                // The connection is usually dropped while the computer shuts down, so we only
+                // check that ssh itself could be started
+                command.status().await.map_err(|err| {
+                    error!(id = Device::get_id(self), "Failed to shut down: {err}");
+                    DeviceError::TransientError
+                })?;
+            }
+        }
+
+        *self.state.last_known_state.write().await = false;
+
+        Ok(())
+    }
+
+    async fn set_online(&self, online: bool) {
+        *self.state.last_known_state.write().await = online;
+        if self.state.availability.set(online) {
+            self.config.availability_callback.call(self, &online).await;
+        }
+    }
+
+    // Only holds on to a weak reference, see automation_lib::helpers::weak. Whether to fall back
+    // to tcp is kept across ticks, so this can not use the helpers directly.
+    async fn ping_loop(config: Config, state: Weak<State>, check: CheckConfig, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        let mut use_tcp = false;
+        loop {
+            interval.tick().await;
+
+            let Some(state) = state.upgrade() else {
+                break;
+            };
+            let device = Self {
+                config: config.clone(),
+                state,
+            };
+
+            let online = if use_tcp {
+                check.is_reachable().await
+            } else {
+                match icmp::ping(check.ip, check.interval()).await {
+                    Ok(online) => online,
+                    Err(err) => {
+                        warn!(
+                            id = Device::get_id(&device),
+                            "Ping is not available, falling back to tcp: {err}"
+                        );
+                        use_tcp = true;
+                        check.is_reachable().await
+                    }
+                }
+            };
+            device.set_online(online).await;
+        }
+    }
 }
 
 #[async_trait]
@@ -46,7 +292,30 @@ impl LuaDeviceCreate for WakeOnLAN {
             .subscribe(&config.mqtt.topic, rumqttc::QoS::AtLeastOnce)
             .await?;
 
-        Ok(Self { config })
+        let state = Arc::new(State {
+            last_known_state: Default::default(),
+            presence_handle: Default::default(),
+            check_handle: Default::default(),
+            availability: Availability::new(config.info.identifier()),
+        });
+
+        match (&config.check, config.ping_interval) {
+            (Some(check), Some(ping_interval)) => {
+                tokio::spawn(Self::ping_loop(
+                    config.clone(),
+                    Arc::downgrade(&state),
+                    check.clone(),
+                    ping_interval,
+                ));
+            }
+            (None, Some(_)) => warn!(
+                id = config.info.identifier(),
+                "ping_interval requires check to be configured"
+            ),
+            _ => {}
+        }
+
+        Ok(Self { config, state })
     }
 }
 
@@ -54,6 +323,16 @@ impl Device for WakeOnLAN {
     fn get_id(&self) -> String {
         self.config.info.identifier()
     }
+
+    fn get_info(&self) -> Option<&InfoConfig> {
+        Some(&self.config.info)
+    }
+}
+
+impl DeviceAvailability for WakeOnLAN {
+    fn availability(&self) -> &Availability {
+        &self.state.availability
+    }
 }
 
 #[async_trait]
@@ -82,7 +361,7 @@ impl google_home::Device for WakeOnLAN {
     }
 
     fn get_device_name(&self) -> device::Name {
-        let mut name = device::Name::new(&self.config.info.name);
+        let mut name = self.config.info.device_name();
         name.add_default_name("Computer");
 
         name
@@ -92,6 +371,9 @@ impl google_home::Device for WakeOnLAN {
         Device::get_id(self)
     }
 
+    // Google Home does not send commands to offline devices, so reporting the ping result here
+    // would make it impossible to wake the computer. The ping result is only reported through the
+    // availability.
     async fn is_online(&self) -> bool {
         true
     }
@@ -99,20 +381,47 @@ impl google_home::Device for WakeOnLAN {
     fn get_room_hint(&self) -> Option<&str> {
         self.config.info.room.as_deref()
     }
+
+    fn get_custom_data(&self) -> Option<serde_json::Value> {
+        self.config.info.custom_data()
+    }
 }
 
 #[async_trait]
-impl traits::Scene for WakeOnLAN {
-    async fn set_active(&self, deactivate: bool) -> Result<(), ErrorCode> {
-        if deactivate {
+impl OnPresence for WakeOnLAN {
+    async fn on_presence(&self, presence: bool) {
+        let mut handle = self.state.presence_handle.lock().await;
+        if let Some(handle) = handle.take() {
+            handle.abort();
+        }
+
+        let Some(delay) = self.config.presence_shutdown else {
+            return;
+        };
+
+        if !presence && self.config.shutdown.is_some() {
             debug!(
                 id = Device::get_id(self),
-                "Trying to deactivate computer, this is not currently supported"
+                "Shutting down computer in {delay:?}"
             );
-            // We do not support deactivating this scene
-            Err(ErrorCode::DeviceError(
-                google_home::errors::DeviceError::ActionNotAvailable,
-            ))
+            let device = self.clone();
+            *handle = Some(tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                device.shutdown().await.ok();
+            }));
+        }
+    }
+}
+
+#[async_trait]
+impl traits::Scene for WakeOnLAN {
+    fn scene_reversible(&self) -> Option<bool> {
+        Some(self.config.shutdown.is_some())
+    }
+
+    async fn set_active(&self, deactivate: bool) -> Result<(), ErrorCode> {
+        if deactivate {
+            self.shutdown().await
         } else {
             debug!(
                 id = Device::get_id(self),
@@ -137,9 +446,303 @@ impl traits::Scene for WakeOnLAN {
                     id = Device::get_id(self),
                     "Failed to activate computer: {err}"
                 );
-                google_home::errors::DeviceError::TransientError.into()
-            })
-            .map(|_| debug!(id = Device::get_id(self), "Success!"))
+                google_home::errors::DeviceError::TransientError
+            })?;
+
+            let Some(check) = self.config.check.clone() else {
+                debug!(id = Device::get_id(self), "Success!");
+                return Ok(());
+            };
+
+            // Waiting for the computer can take up to a minute, so this happens in the background
+            // instead of blocking the mqtt handler and the Google Home request. The outcome shows
+            // up in the next query through the last known state.
+            let mut handle = self.state.check_handle.lock().await;
+            if let Some(handle) = handle.take() {
+                handle.abort();
+            }
+
+            let device = self.clone();
+            *handle = Some(tokio::spawn(async move {
+                let awake = check.wait_until_reachable().await;
+                *device.state.last_known_state.write().await = awake;
+
+                if awake {
+                    debug!(id = Device::get_id(&device), "Computer is awake");
+                } else {
+                    warn!(
+                        id = Device::get_id(&device),
+                        "Computer did not wake up within {:?}",
+                        check.timeout()
+                    );
+                }
+
+                device.config.callback.call(&device, &awake).await;
+            }));
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use automation_lib::event::EventChannel;
+    use automation_lib::lua::testing::{MockHttpServer, MockMqttClient};
+    use google_home::{GoogleHome, Request};
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    async fn wake_on_lan(
+        client: &MockMqttClient,
+        shutdown: Option<ShutdownConfig>,
+        presence_shutdown: Option<Duration>,
+    ) -> WakeOnLAN {
+        WakeOnLAN::create(Config {
+            info: InfoConfig::new("Computer"),
+            mqtt: MqttDeviceConfig {
+                topic: "automation/computer".into(),
+                availability: None,
+            },
+            mac_address: MacAddress::nil(),
+            broadcast_ip: Ipv4Addr::BROADCAST,
+            check: None,
+            ping_interval: None,
+            availability_callback: Default::default(),
+            callback: Default::default(),
+            shutdown,
+            presence_shutdown,
+            client: client.client(),
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn shutdown_not_configured() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let device = wake_on_lan(&client, None, None).await;
+
+        assert_eq!(
+            device.set_active(true).await,
+            Err(DeviceError::ActionNotAvailable.into())
+        );
+    }
+
+    #[tokio::test]
+    async fn shutdown_mqtt() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let shutdown = ShutdownConfig::Mqtt {
+            mqtt_topic: "computer/shutdown".into(),
+            payload: None,
+        };
+        let device = wake_on_lan(&client, Some(shutdown), None).await;
+
+        device.set_active(true).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(
+            client.published().pop(),
+            Some((
+                "computer/shutdown".to_string(),
+                json!({ "action": "shutdown" }).to_string()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn shutdown_http() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let server = MockHttpServer::start().await;
+        server.respond_json("POST", "/shutdown", json!({})).await;
+        let shutdown = ShutdownConfig::Http {
+            url: format!("{}/shutdown", server.url()),
+        };
+        let device = wake_on_lan(&client, Some(shutdown), None).await;
+
+        device.set_active(true).await.unwrap();
+
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn presence_shutdown() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let shutdown = ShutdownConfig::Mqtt {
+            mqtt_topic: "computer/shutdown".into(),
+            payload: None,
+        };
+        let device = wake_on_lan(&client, Some(shutdown), Some(Duration::from_millis(50))).await;
+
+        // Coming back home cancels the shutdown
+        device.on_presence(false).await;
+        device.on_presence(true).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(client.published().is_empty());
+
+        device.on_presence(false).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(client.published().len(), 1);
+    }
+
+    fn check(port: u16) -> CheckConfig {
+        CheckConfig {
+            ip: Ipv4Addr::LOCALHOST.into(),
+            port,
+            timeout_seconds: 1,
+            interval_seconds: 1,
         }
     }
+
+    #[tokio::test]
+    async fn reachable() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let check = check(listener.local_addr().unwrap().port());
+
+        assert!(check.wait_until_reachable().await);
+    }
+
+    #[tokio::test]
+    async fn unreachable() {
+        // Find a port that nothing is listening on
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let check = check(port);
+
+        assert!(!check.is_reachable().await);
+        assert!(!check.wait_until_reachable().await);
+    }
+
+    #[tokio::test]
+    async fn shutdown_config() {
+        let shutdown: ShutdownConfig = serde_json::from_value(json!({
+            "host": "desktop.local",
+            "user": "automation",
+            "key_path": "/keys/id_ed25519",
+        }))
+        .unwrap();
+        assert!(matches!(
+            shutdown,
+            ShutdownConfig::Ssh {
+                key_path: Some(_),
+                ..
+            }
+        ));
+
+        let shutdown: ShutdownConfig = serde_json::from_value(json!({
+            "mqtt_topic": "computer/shutdown",
+            "payload": { "command": "poweroff" },
+        }))
+        .unwrap();
+        assert!(matches!(
+            shutdown,
+            ShutdownConfig::Mqtt {
+                payload: Some(_),
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn activate_while_offline() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let device = WakeOnLAN::create(Config {
+            broadcast_ip: Ipv4Addr::LOCALHOST,
+            ..wake_on_lan(&client, None, None).await.config
+        })
+        .await
+        .unwrap();
+
+        // The last ping did not get a reply
+        device.set_online(false).await;
+        assert!(!device.availability().get());
+
+        let devices = HashMap::from([(Device::get_id(&device), Box::new(device))]);
+        let request: Request = serde_json::from_value(json!({
+            "requestId": "ff36a3cc-ec34-11e6-b1a0-64510650abcf",
+            "inputs": [{
+                "intent": "action.devices.EXECUTE",
+                "payload": {
+                    "commands": [{
+                        "devices": [{ "id": "computer" }],
+                        "execution": [{
+                            "command": "action.devices.commands.ActivateScene",
+                            "params": { "deactivate": false }
+                        }]
+                    }]
+                }
+            }]
+        }))
+        .unwrap();
+
+        let response = GoogleHome::new("user")
+            .handle_request(request, &devices)
+            .await
+            .unwrap();
+        let response = serde_json::to_value(response).unwrap();
+
+        assert_eq!(
+            response["payload"]["commands"],
+            json!([{ "ids": ["computer"], "status": "SUCCESS", "states": { "online": true } }])
+        );
+    }
+
+    #[tokio::test]
+    async fn activate_does_not_wait_for_check() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        // Find a port that nothing is listening on
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let device = WakeOnLAN::create(Config {
+            broadcast_ip: Ipv4Addr::LOCALHOST,
+            check: Some(CheckConfig {
+                timeout_seconds: 60,
+                ..check(port)
+            }),
+            ..wake_on_lan(&client, None, None).await.config
+        })
+        .await
+        .unwrap();
+
+        let result = tokio::time::timeout(Duration::from_secs(1), device.set_active(false)).await;
+        assert_eq!(result, Ok(Ok(())));
+    }
+
+    #[tokio::test]
+    async fn ping_stops_with_device() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+
+        let device = WakeOnLAN::create(Config {
+            check: Some(check(0)),
+            ping_interval: Some(Duration::from_millis(50)),
+            ..wake_on_lan(&client, None, None).await.config
+        })
+        .await
+        .unwrap();
+        let mut availability = device.availability().watch();
+
+        // The availability is dropped together with the device, which only happens if the ping
+        // task does not keep it alive
+        drop(device);
+        let changed = tokio::time::timeout(Duration::from_secs(2), async {
+            while availability.changed().await.is_ok() {}
+        })
+        .await;
+        assert!(changed.is_ok());
+    }
 }
@@ -1,6 +1,8 @@
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
+use automation_lib::action_callback::ActionCallback;
 use automation_lib::config::MqttDeviceConfig;
 use automation_lib::device::{Device, LuaDeviceCreate};
 use automation_lib::event::{self, Event, EventChannel, OnMqtt};
@@ -9,7 +11,9 @@ use automation_lib::mqtt::WrappedAsyncClient;
 use automation_lib::ntfy::{Notification, Priority};
 use automation_macro::LuaDeviceConfig;
 use rumqttc::Publish;
+use serde::Serialize;
 use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::task::JoinHandle;
 use tracing::{debug, error, trace, warn};
 
 #[derive(Debug, Clone, LuaDeviceConfig)]
@@ -17,17 +21,200 @@ pub struct Config {
     pub identifier: String,
     #[device_config(flatten)]
     pub mqtt: MqttDeviceConfig,
-    // Power in Watt
+    // Power in Watt, anything below this is considered idle
     pub threshold: f32,
+    // Power in Watt, anything above this is considered spinning
+    #[device_config(default(250.0))]
+    pub spinning_threshold: f32,
+    // Power in Watt, anything above this is considered filling/heating
+    #[device_config(default(1000.0))]
+    pub heating_threshold: f32,
+    // Time the power needs to stay in a new band before the phase changes
+    #[device_config(rename("min_dwell_seconds"), default(60), with(Duration::from_secs))]
+    pub min_dwell: Duration,
+    // Time the power needs to stay below the threshold before the washer is done
+    #[device_config(rename("done_after_seconds"), default(180), with(Duration::from_secs))]
+    pub done_after: Duration,
+    #[device_config(from_lua, default)]
+    pub callback: ActionCallback<Washer, Status>,
     #[device_config(rename("event_channel"), from_lua, with(|ec: EventChannel| ec.get_tx()))]
     pub tx: event::Sender,
     #[device_config(from_lua)]
     pub client: WrappedAsyncClient,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Idle,
+    Heating,
+    Washing,
+    Spinning,
+    Done,
+}
+
+impl Phase {
+    fn is_running(&self) -> bool {
+        !matches!(self, Phase::Idle | Phase::Done)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+    pub phase: Phase,
+    // Seconds since the start of the cycle
+    pub elapsed: Option<u64>,
+    // Estimated seconds until the cycle is done, based on the duration of the previous cycle
+    pub remaining: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bands {
+    threshold: f32,
+    spinning_threshold: f32,
+    heating_threshold: f32,
+    min_dwell: Duration,
+    done_after: Duration,
+}
+
+impl From<&Config> for Bands {
+    fn from(config: &Config) -> Self {
+        Self {
+            threshold: config.threshold,
+            spinning_threshold: config.spinning_threshold,
+            heating_threshold: config.heating_threshold,
+            min_dwell: config.min_dwell,
+            done_after: config.done_after,
+        }
+    }
+}
+
+impl Bands {
+    fn phase(&self, power: f32) -> Phase {
+        if power < self.threshold {
+            Phase::Idle
+        } else if power < self.spinning_threshold {
+            Phase::Washing
+        } else if power < self.heating_threshold {
+            Phase::Spinning
+        } else {
+            Phase::Heating
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Cycle {
+    bands: Bands,
+    phase: Phase,
+    // Phase the power draw currently corresponds to and since when
+    candidate: Option<(Phase, Instant)>,
+    started: Option<Instant>,
+    finished: Option<Instant>,
+    last_duration: Option<Duration>,
+}
+
+impl Cycle {
+    fn new(bands: Bands) -> Self {
+        Self {
+            bands,
+            phase: Phase::Idle,
+            candidate: None,
+            started: None,
+            finished: None,
+            last_duration: None,
+        }
+    }
+
+    fn dwell(&self, band: Phase) -> Duration {
+        if band == Phase::Idle {
+            self.bands.done_after
+        } else {
+            self.bands.min_dwell
+        }
+    }
+
+    // Returns the new phase if the phase has changed
+    fn update(&mut self, power: f32, now: Instant) -> Option<Phase> {
+        let band = self.bands.phase(power);
+
+        if band == self.phase || (band == Phase::Idle && !self.phase.is_running()) {
+            // Short dips or spikes between phases should not cause a transition
+            self.candidate = None;
+            return None;
+        }
+
+        if self.candidate.is_none_or(|(phase, _)| phase != band) {
+            self.candidate = Some((band, now));
+        }
+
+        self.check(now)
+    }
+
+    // Time left before the pending transition happens if no other reading is received
+    fn pending(&self, now: Instant) -> Option<Duration> {
+        let (band, since) = self.candidate?;
+
+        Some(self.dwell(band).saturating_sub(now.duration_since(since)))
+    }
+
+    // Completes the pending transition if the power has been in the new band for long enough,
+    // returns the new phase if the phase has changed
+    fn check(&mut self, now: Instant) -> Option<Phase> {
+        let (band, since) = self.candidate?;
+
+        if now.duration_since(since) < self.dwell(band) {
+            return None;
+        }
+
+        self.candidate = None;
+
+        if !self.phase.is_running() {
+            self.started = Some(since);
+            self.finished = None;
+        }
+
+        self.phase = if band == Phase::Idle {
+            self.finished = Some(since);
+            self.last_duration = self.elapsed(since);
+
+            Phase::Done
+        } else {
+            band
+        };
+
+        Some(self.phase)
+    }
+
+    fn elapsed(&self, now: Instant) -> Option<Duration> {
+        let started = self.started?;
+
+        Some(self.finished.unwrap_or(now).duration_since(started))
+    }
+
+    fn remaining(&self, now: Instant) -> Option<Duration> {
+        if !self.phase.is_running() {
+            return None;
+        }
+
+        Some(self.last_duration?.saturating_sub(self.elapsed(now)?))
+    }
+
+    fn status(&self, now: Instant) -> Status {
+        Status {
+            phase: self.phase,
+            elapsed: self.elapsed(now).map(|elapsed| elapsed.as_secs()),
+            remaining: self.remaining(now).map(|remaining| remaining.as_secs()),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct State {
-    running: isize,
+    cycle: Cycle,
+    // Completes the pending transition when no new readings are received, some plugs only
+    // report changes so they go quiet once the washer is done
+    handle: Option<JoinHandle<()>>,
 }
 
 // TODO: Add google home integration
@@ -45,6 +232,18 @@ impl Washer {
     async fn state_mut(&self) -> RwLockWriteGuard<State> {
         self.state.write().await
     }
+
+    pub async fn current_phase(&self) -> Phase {
+        self.state().await.cycle.phase
+    }
+
+    pub async fn elapsed(&self) -> Option<Duration> {
+        self.state().await.cycle.elapsed(Instant::now())
+    }
+
+    pub async fn status(&self) -> Status {
+        self.state().await.cycle.status(Instant::now())
+    }
 }
 
 #[async_trait]
@@ -60,7 +259,10 @@ impl LuaDeviceCreate for Washer {
             .subscribe(&config.mqtt.topic, rumqttc::QoS::AtLeastOnce)
             .await?;
 
-        let state = State { running: 0 };
+        let state = State {
+            cycle: Cycle::new(Bands::from(&config)),
+            handle: None,
+        };
         let state = Arc::new(RwLock::new(state));
 
         Ok(Self { config, state })
@@ -73,11 +275,6 @@ impl Device for Washer {
     }
 }
 
-// The washer needs to have a power draw above the threshold multiple times before the washer is
-// actually marked as running
-// This helps prevent false positives
-const HYSTERESIS: isize = 10;
-
 #[async_trait]
 impl OnMqtt for Washer {
     async fn on_mqtt(&self, message: Publish) {
@@ -96,18 +293,60 @@ impl OnMqtt for Washer {
             }
         };
 
-        // debug!(id = self.identifier, power, "Washer state update");
+        let phase = {
+            let mut state = self.state_mut().await;
+            let candidate = state.cycle.candidate;
+            let phase = state.cycle.update(power, Instant::now());
+
+            // Restart the timer whenever the pending transition changes
+            if state.cycle.candidate != candidate {
+                if let Some(handle) = state.handle.take() {
+                    handle.abort();
+                }
 
-        if power < self.config.threshold && self.state().await.running >= HYSTERESIS {
-            // The washer is done running
-            debug!(
-                id = self.config.identifier,
-                power,
-                threshold = self.config.threshold,
-                "Washer is done"
-            );
+                if let Some(timeout) = state.cycle.pending(Instant::now()) {
+                    let device = self.clone();
+                    state.handle = Some(tokio::spawn(async move {
+                        tokio::time::sleep(timeout).await;
+                        device.check_pending().await;
+                    }));
+                }
+            }
+
+            phase
+        };
+
+        if let Some(phase) = phase {
+            self.phase_changed(phase).await;
+        }
+    }
+}
+
+impl Washer {
+    async fn check_pending(&self) {
+        let phase = {
+            let mut state = self.state_mut().await;
+            // NOTE: We are running inside the pending task, so we can not abort it here
+            state.handle = None;
+            state.cycle.check(Instant::now())
+        };
+
+        if let Some(phase) = phase {
+            self.phase_changed(phase).await;
+        }
+    }
 
-            self.state_mut().await.running = 0;
+    async fn phase_changed(&self, phase: Phase) {
+        let status = self.status().await;
+        debug!(
+            id = self.config.identifier,
+            elapsed = status.elapsed,
+            "Washer is now {phase:?}"
+        );
+
+        self.config.callback.call(self, &status).await;
+
+        if phase == Phase::Done {
             let notification = Notification::new()
                 .set_title("Laundy is done")
                 .set_message("Don't forget to hang it!")
@@ -117,25 +356,166 @@ impl OnMqtt for Washer {
             if self
                 .config
                 .tx
-                .send(Event::Ntfy(notification))
+                .send(Event::Ntfy(Box::new(notification)))
                 .await
                 .is_err()
             {
                 warn!("There are no receivers on the event channel");
             }
-        } else if power < self.config.threshold {
-            // Prevent false positives
-            self.state_mut().await.running = 0;
-        } else if power >= self.config.threshold && self.state().await.running < HYSTERESIS {
-            // Washer could be starting
-            debug!(
-                id = self.config.identifier,
-                power,
-                threshold = self.config.threshold,
-                "Washer is starting"
-            );
-
-            self.state_mut().await.running += 1;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cycle() -> Cycle {
+        Cycle::new(Bands {
+            threshold: 1.0,
+            spinning_threshold: 250.0,
+            heating_threshold: 1000.0,
+            min_dwell: Duration::from_secs(60),
+            done_after: Duration::from_secs(180),
+        })
+    }
+
+    // Feeds a recorded sequence of (seconds since start, payload) and returns the phase transitions
+    fn run(cycle: &mut Cycle, start: Instant, messages: &[(u64, &str)]) -> Vec<(u64, Phase)> {
+        messages
+            .iter()
+            .filter_map(|(seconds, payload)| {
+                let message = Publish::new("washer", rumqttc::QoS::AtLeastOnce, *payload);
+                let power = PowerMessage::try_from(message).unwrap().power();
+
+                cycle
+                    .update(power, start + Duration::from_secs(*seconds))
+                    .map(|phase| (*seconds, phase))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn full_cycle() {
+        let start = Instant::now();
+        let mut cycle = cycle();
+
+        let transitions = run(
+            &mut cycle,
+            start,
+            &[
+                (0, r#"{"power": 0.5}"#),
+                (30, r#"{"power": 2000}"#),
+                (60, r#"{"power": 2100}"#),
+                (90, r#"{"power": 2050}"#),
+                (120, r#"{"power": 120}"#),
+                (150, r#"{"power": 0}"#),
+                (180, r#"{"power": 130}"#),
+                (240, r#"{"power": 110}"#),
+                (300, r#"{"power": 400}"#),
+                (330, r#"{"power": 450}"#),
+                (360, r#"{"power": 420}"#),
+                (390, r#"{"power": 0.3}"#),
+                (450, r#"{"power": 0.3}"#),
+                (570, r#"{"power": 0.3}"#),
+            ],
+        );
+
+        assert_eq!(
+            transitions,
+            vec![
+                (90, Phase::Heating),
+                (240, Phase::Washing),
+                (360, Phase::Spinning),
+                (570, Phase::Done),
+            ]
+        );
+
+        // The cycle ran from the first reading above the threshold until the power dropped
+        assert_eq!(
+            cycle.elapsed(start + Duration::from_secs(600)),
+            Some(Duration::from_secs(360))
+        );
+        assert_eq!(cycle.status(start).remaining, None);
+    }
+
+    #[test]
+    fn done_without_new_readings() {
+        let start = Instant::now();
+        let mut cycle = cycle();
+
+        let transitions = run(
+            &mut cycle,
+            start,
+            &[
+                (0, r#"{"power": 150}"#),
+                (60, r#"{"power": 150}"#),
+                (300, r#"{"power": 0}"#),
+            ],
+        );
+        assert_eq!(transitions, vec![(60, Phase::Washing)]);
+
+        // The plug goes quiet after the last reading, so the timer has to complete the cycle
+        assert_eq!(
+            cycle.pending(start + Duration::from_secs(300)),
+            Some(Duration::from_secs(180))
+        );
+        assert_eq!(cycle.check(start + Duration::from_secs(400)), None);
+        assert_eq!(
+            cycle.check(start + Duration::from_secs(480)),
+            Some(Phase::Done)
+        );
+        assert_eq!(cycle.pending(start + Duration::from_secs(480)), None);
+        assert_eq!(
+            cycle.elapsed(start + Duration::from_secs(480)),
+            Some(Duration::from_secs(300))
+        );
+    }
+
+    #[test]
+    fn short_dip_is_ignored() {
+        let start = Instant::now();
+        let mut cycle = cycle();
+
+        let transitions = run(
+            &mut cycle,
+            start,
+            &[
+                (0, r#"{"power": 150}"#),
+                (60, r#"{"power": 150}"#),
+                (90, r#"{"power": 0}"#),
+                (120, r#"{"power": 0}"#),
+                (150, r#"{"power": 150}"#),
+                (400, r#"{"power": 0}"#),
+                (500, r#"{"power": 150}"#),
+            ],
+        );
+
+        assert_eq!(transitions, vec![(60, Phase::Washing)]);
+        assert_eq!(cycle.phase, Phase::Washing);
+    }
+
+    #[test]
+    fn remaining_uses_previous_cycle() {
+        let start = Instant::now();
+        let mut cycle = cycle();
+
+        run(
+            &mut cycle,
+            start,
+            &[
+                (0, r#"{"power": 150}"#),
+                (60, r#"{"power": 150}"#),
+                (600, r#"{"power": 0}"#),
+                (780, r#"{"power": 0}"#),
+                (1000, r#"{"power": 150}"#),
+                (1060, r#"{"power": 150}"#),
+            ],
+        );
+
+        assert_eq!(cycle.phase, Phase::Washing);
+        let status = cycle.status(start + Duration::from_secs(1100));
+        assert_eq!(status.elapsed, Some(100));
+        assert_eq!(status.remaining, Some(500));
+    }
+}
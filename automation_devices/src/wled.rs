@@ -0,0 +1,495 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use automation_lib::config::InfoConfig;
+use automation_lib::device::{Device, LuaDeviceCreate};
+use automation_lib::event::OnMqtt;
+use automation_lib::helpers::weak::spawn_weak_interval;
+use automation_lib::mqtt::WrappedAsyncClient;
+use automation_macro::LuaDeviceConfig;
+use google_home::device::Name;
+use google_home::errors::{DeviceError, ErrorCode};
+use google_home::traits::{
+    Brightness, Mode, ModeSetting, ModeSettingValue, ModeValue, Modes, OnOff,
+};
+use google_home::types::Type;
+use rumqttc::{Publish, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use thiserror::Error;
+use tokio::sync::RwLock;
+use tracing::{debug, trace, warn};
+
+const MAX_BRIGHTNESS: u8 = 255;
+const PRESET_MODE: &str = "preset";
+
+#[derive(Debug, Clone, LuaDeviceConfig)]
+pub struct Config {
+    #[device_config(flatten)]
+    pub info: InfoConfig,
+    pub url: String,
+    // Topic prefix that WLED publishes to, the state is kept up to date using MQTT instead of
+    // polling when this is set
+    #[device_config(default)]
+    pub mqtt_topic: Option<String>,
+    #[device_config(from_lua, default)]
+    pub client: Option<WrappedAsyncClient>,
+    #[device_config(
+        rename("poll_interval_seconds"),
+        default(30),
+        with(Duration::from_secs)
+    )]
+    pub poll_interval: Duration,
+    #[device_config(rename("timeout_seconds"), default(5), with(Duration::from_secs))]
+    pub timeout: Duration,
+    // Maps the name of a preset to its id, the named presets can be selected from Google Home
+    #[device_config(default)]
+    pub presets: HashMap<String, u8>,
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("A client is required when mqtt_topic is set")]
+    MissingClient,
+    #[error(transparent)]
+    Mqtt(#[from] rumqttc::ClientError),
+    #[error(transparent)]
+    Request(#[from] reqwest::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WledState {
+    pub on: bool,
+    pub bri: u8,
+    // Id of the active preset, -1 if no preset is active
+    #[serde(default = "no_preset")]
+    pub ps: i16,
+}
+
+fn no_preset() -> i16 {
+    -1
+}
+
+#[derive(Debug, Clone)]
+pub struct Wled {
+    config: Config,
+    client: reqwest::Client,
+    // Last known state of the controller
+    state: Arc<RwLock<Option<WledState>>>,
+}
+
+impl Wled {
+    fn state_url(&self) -> String {
+        format!("{}/json/state", self.config.url)
+    }
+
+    async fn fetch_state(&self) -> Result<WledState, reqwest::Error> {
+        let state: WledState = self
+            .client
+            .get(self.state_url())
+            .timeout(self.config.timeout)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        *self.state.write().await = Some(state);
+
+        Ok(state)
+    }
+
+    // Uses the cached state if we have one
+    async fn state(&self) -> Result<WledState, ErrorCode> {
+        if let Some(state) = *self.state.read().await {
+            return Ok(state);
+        }
+
+        self.fetch_state().await.map_err(|err| {
+            warn!(id = Device::get_id(self), "Failed to get state: {err}");
+            DeviceError::DeviceOffline.into()
+        })
+    }
+
+    async fn update(&self, mut update: serde_json::Value) -> Result<(), ErrorCode> {
+        // Makes WLED respond with the new state
+        update["v"] = true.into();
+
+        let result = async {
+            self.client
+                .post(self.state_url())
+                .timeout(self.config.timeout)
+                .json(&update)
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<WledState>()
+                .await
+        }
+        .await;
+
+        match result {
+            Ok(state) => {
+                *self.state.write().await = Some(state);
+                Ok(())
+            }
+            Err(err) => {
+                warn!(id = Device::get_id(self), "Failed to update state: {err}");
+                Err(DeviceError::DeviceOffline.into())
+            }
+        }
+    }
+
+    pub async fn set_preset(&self, id: u8) -> Result<(), ErrorCode> {
+        debug!(id = Device::get_id(self), "Activating preset {id}");
+        self.update(json!({ "ps": id })).await
+    }
+
+    pub async fn preset(&self) -> Result<Option<u8>, ErrorCode> {
+        Ok(self.state().await?.ps.try_into().ok())
+    }
+
+    fn mqtt_topic(&self, topic: &str) -> Option<String> {
+        self.config
+            .mqtt_topic
+            .as_ref()
+            .map(|prefix| format!("{prefix}/{topic}"))
+    }
+
+    // Rebuilds the device in the background task, see automation_lib::helpers::weak
+    fn downgrade(&self) -> impl Fn() -> Option<Self> + Send + 'static {
+        let config = self.config.clone();
+        let client = self.client.clone();
+        let state = Arc::downgrade(&self.state);
+
+        move || {
+            Some(Self {
+                config: config.clone(),
+                client: client.clone(),
+                state: state.upgrade()?,
+            })
+        }
+    }
+
+    async fn poll(&self) {
+        match self.fetch_state().await {
+            Ok(state) => trace!(id = self.get_id(), "State: {state:?}"),
+            Err(err) => {
+                warn!(id = self.get_id(), "Failed to get state: {err}");
+                // Make sure queries do not report a stale state
+                *self.state.write().await = None;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl LuaDeviceCreate for Wled {
+    type Config = Config;
+    type Error = Error;
+
+    async fn create(config: Self::Config) -> Result<Self, Self::Error> {
+        trace!(id = config.info.identifier(), "Setting up Wled");
+
+        let wled = Self {
+            config,
+            client: reqwest::Client::builder().build()?,
+            state: Default::default(),
+        };
+
+        match (wled.mqtt_topic("g"), &wled.config.client) {
+            (Some(topic), Some(client)) => {
+                client.subscribe(topic, QoS::AtLeastOnce).await?;
+                // Last will of the controller
+                if let Some(topic) = wled.mqtt_topic("status") {
+                    client.subscribe(topic, QoS::AtLeastOnce).await?;
+                }
+            }
+            (Some(_), None) => return Err(Error::MissingClient),
+            (None, _) => {
+                spawn_weak_interval(
+                    wled.downgrade(),
+                    wled.config.poll_interval,
+                    |wled| async move { wled.poll().await },
+                );
+            }
+        }
+
+        Ok(wled)
+    }
+}
+
+impl Device for Wled {
+    fn get_id(&self) -> String {
+        self.config.info.identifier()
+    }
+
+    fn get_info(&self) -> Option<&InfoConfig> {
+        Some(&self.config.info)
+    }
+}
+
+#[async_trait]
+impl OnMqtt for Wled {
+    async fn on_mqtt(&self, message: Publish) {
+        if Some(&message.topic) == self.mqtt_topic("status").as_ref() {
+            if &message.payload[..] == b"offline" {
+                debug!(id = Device::get_id(self), "Controller is offline");
+                // The next query will try to reach the controller and report it as offline
+                *self.state.write().await = None;
+            }
+            return;
+        }
+
+        if Some(&message.topic) != self.mqtt_topic("g").as_ref() {
+            return;
+        }
+
+        let bri = match String::from_utf8_lossy(&message.payload).parse::<u8>() {
+            Ok(bri) => bri,
+            Err(err) => {
+                warn!(id = Device::get_id(self), "Failed to parse message: {err}");
+                return;
+            }
+        };
+
+        // WLED reports a brightness of 0 when it is turned off
+        let mut state = self.state.write().await;
+        let previous = state.unwrap_or(WledState {
+            on: false,
+            bri: MAX_BRIGHTNESS,
+            ps: no_preset(),
+        });
+        *state = Some(WledState {
+            on: bri > 0,
+            bri: if bri > 0 { bri } else { previous.bri },
+            ..previous
+        });
+
+        debug!(id = Device::get_id(self), "State = {:?}", *state);
+    }
+}
+
+#[async_trait]
+impl google_home::Device for Wled {
+    fn get_device_type(&self) -> Type {
+        Type::Light
+    }
+
+    fn get_device_name(&self) -> Name {
+        self.config.info.device_name()
+    }
+
+    fn get_id(&self) -> String {
+        Device::get_id(self)
+    }
+
+    async fn is_online(&self) -> bool {
+        self.state().await.is_ok()
+    }
+
+    fn get_room_hint(&self) -> Option<&str> {
+        self.config.info.room.as_deref()
+    }
+
+    fn get_custom_data(&self) -> Option<serde_json::Value> {
+        self.config.info.custom_data()
+    }
+
+    fn will_report_state(&self) -> bool {
+        false
+    }
+}
+
+#[async_trait]
+impl OnOff for Wled {
+    async fn on(&self) -> Result<bool, ErrorCode> {
+        Ok(self.state().await?.on)
+    }
+
+    async fn set_on(&self, on: bool) -> Result<(), ErrorCode> {
+        self.update(json!({ "on": on })).await
+    }
+}
+
+#[async_trait]
+impl Brightness for Wled {
+    async fn brightness(&self) -> Result<u8, ErrorCode> {
+        let bri = self.state().await?.bri as f32;
+        Ok((bri * 100.0 / MAX_BRIGHTNESS as f32).round() as u8)
+    }
+
+    async fn set_brightness(&self, brightness: u8) -> Result<(), ErrorCode> {
+        let bri = (brightness.min(100) as f32 * MAX_BRIGHTNESS as f32 / 100.0).round() as u8;
+        self.update(json!({ "bri": bri })).await
+    }
+}
+
+#[async_trait]
+impl Modes for Wled {
+    fn available_modes(&self) -> Vec<Mode> {
+        let mut presets: Vec<_> = self.config.presets.iter().collect();
+        presets.sort_by_key(|(_, id)| **id);
+
+        vec![Mode {
+            name: PRESET_MODE.into(),
+            name_values: vec![ModeValue {
+                name_synonym: vec!["Preset".into(), "Effect".into()],
+                lang: "en".into(),
+            }],
+            settings: presets
+                .into_iter()
+                .map(|(name, _)| ModeSetting {
+                    setting_name: name.clone(),
+                    setting_values: vec![ModeSettingValue {
+                        setting_synonym: vec![name.clone()],
+                        lang: "en".into(),
+                    }],
+                })
+                .collect(),
+            ordered: false,
+        }]
+    }
+
+    async fn current_mode_settings(&self) -> Result<HashMap<String, String>, ErrorCode> {
+        let preset = self.preset().await?;
+
+        Ok(self
+            .config
+            .presets
+            .iter()
+            .find(|(_, id)| Some(**id) == preset)
+            .map(|(name, _)| HashMap::from([(PRESET_MODE.into(), name.clone())]))
+            .unwrap_or_default())
+    }
+
+    async fn set_mode(&self, mode: String, setting: String) -> Result<(), ErrorCode> {
+        match self.config.presets.get(&setting) {
+            Some(id) if mode == PRESET_MODE => self.set_preset(*id).await,
+            _ => Err(DeviceError::ActionNotAvailable.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automation_lib::event::EventChannel;
+    use automation_lib::lua::testing::{MockHttpServer, MockMqttClient};
+
+    use super::*;
+
+    async fn wled(server: &MockHttpServer, client: Option<&MockMqttClient>) -> Wled {
+        Wled::create(Config {
+            info: InfoConfig::new("Shelf"),
+            url: server.url(),
+            mqtt_topic: client.map(|_| "wled/shelf".into()),
+            client: client.map(MockMqttClient::client),
+            poll_interval: Duration::from_secs(3600),
+            timeout: Duration::from_secs(1),
+            presets: HashMap::from([("Rainbow".into(), 2), ("Candle".into(), 1)]),
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn control() {
+        let server = MockHttpServer::start().await;
+        server
+            .respond_json(
+                "POST",
+                "/json/state",
+                json!({ "on": true, "bri": 255, "ps": 2 }),
+            )
+            .await;
+        let wled = wled(&server, Some(&MockMqttClient::new(EventChannel::new().0))).await;
+
+        wled.set_brightness(50).await.unwrap();
+        wled.set_modes(HashMap::from([(PRESET_MODE.into(), "Rainbow".into())]))
+            .await
+            .unwrap();
+        assert_eq!(
+            server.received_json("/json/state").await,
+            [
+                json!({ "bri": 128, "v": true }),
+                json!({ "ps": 2, "v": true })
+            ]
+        );
+
+        // The state from the response is used
+        assert_eq!(wled.brightness().await, Ok(100));
+        assert_eq!(
+            wled.current_mode_settings().await,
+            Ok(HashMap::from([(PRESET_MODE.into(), "Rainbow".into())]))
+        );
+
+        let presets: Vec<_> = wled.available_modes()[0]
+            .settings
+            .iter()
+            .map(|setting| setting.setting_name.clone())
+            .collect();
+        assert_eq!(presets, ["Candle", "Rainbow"]);
+    }
+
+    #[tokio::test]
+    async fn mqtt_state() {
+        let server = MockHttpServer::start().await;
+        server
+            .respond_json(
+                "GET",
+                "/json/state",
+                json!({ "on": true, "bri": 51, "ps": -1 }),
+            )
+            .await;
+        let client = MockMqttClient::new(EventChannel::new().0);
+        let wled = wled(&server, Some(&client)).await;
+
+        assert_eq!(wled.brightness().await, Ok(20));
+        assert_eq!(wled.preset().await, Ok(None));
+
+        let brightness = |bri: &str| Publish::new("wled/shelf/g", QoS::AtLeastOnce, bri.to_owned());
+        wled.on_mqtt(brightness("0")).await;
+        assert_eq!(wled.on().await, Ok(false));
+        // The brightness is remembered while turned off
+        assert_eq!(wled.brightness().await, Ok(20));
+
+        wled.on_mqtt(brightness("255")).await;
+        assert_eq!(wled.on().await, Ok(true));
+        assert_eq!(wled.brightness().await, Ok(100));
+
+        // The cached state is dropped when the controller goes offline
+        wled.on_mqtt(Publish::new(
+            "wled/shelf/status",
+            QoS::AtLeastOnce,
+            "offline",
+        ))
+        .await;
+        assert_eq!(wled.brightness().await, Ok(20));
+    }
+
+    #[tokio::test]
+    async fn poll_stops_with_device() {
+        let server = MockHttpServer::start().await;
+        let wled = wled(&server, None).await;
+        let state = Arc::downgrade(&wled.state);
+
+        drop(wled);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(state.upgrade().is_none());
+    }
+
+    #[tokio::test]
+    async fn offline() {
+        // Unmatched requests get a 404
+        let server = MockHttpServer::start().await;
+        let wled = wled(&server, Some(&MockMqttClient::new(EventChannel::new().0))).await;
+
+        assert_eq!(wled.on().await, Err(DeviceError::DeviceOffline.into()));
+        assert_eq!(
+            wled.set_on(true).await,
+            Err(DeviceError::DeviceOffline.into())
+        );
+    }
+}
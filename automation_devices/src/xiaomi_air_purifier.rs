@@ -0,0 +1,326 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use automation_lib::config::{InfoConfig, Secret};
+use automation_lib::device::{Device, LuaDeviceCreate};
+use automation_macro::LuaDeviceConfig;
+use google_home::device::Name;
+use google_home::errors::{DeviceError, ErrorCode};
+use google_home::traits::{
+    AvailableSpeeds, CurrentSensorState, DescriptiveCapabilities, FanSpeed, HumiditySetting,
+    NumericCapabilities, OnOff, SensorState, Speed, SpeedValue, SupportedSensorState,
+};
+use google_home::types::Type;
+use serde_json::json;
+use tracing::{debug, trace};
+
+use crate::miio::{self, Client, Token};
+
+const SENSOR_STATE_NAME: &str = "PM2.5";
+// Upper limit of each state in μg/m³, following the US EPA air quality index
+const PM25_STATES: [(f32, &str); 5] = [
+    (12.0, "healthy"),
+    (35.4, "moderate"),
+    (55.4, "unhealthy sensitive"),
+    (150.4, "unhealthy"),
+    (250.4, "very unhealthy"),
+];
+const PM25_HAZARDOUS: &str = "hazardous";
+
+// Modes of the purifier, these are exposed as fan speeds
+const MODES: [&str; 3] = ["auto", "silent", "favorite"];
+
+#[derive(Debug, Clone, LuaDeviceConfig)]
+pub struct Config {
+    #[device_config(flatten)]
+    pub info: InfoConfig,
+    #[device_config(rename("ip"), with(|ip: IpAddr| SocketAddr::new(ip, miio::PORT)))]
+    pub addr: SocketAddr,
+    // Can be extracted from the Mi Home app, see python-miio for instructions
+    #[device_config(secret)]
+    pub token: Secret<String>,
+    #[device_config(rename("timeout_seconds"), default(3), with(Duration::from_secs))]
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Status {
+    on: bool,
+    // The purifier reports the PM2.5 in μg/m³ as 'aqi'
+    pm25: u16,
+    humidity: u8,
+    mode: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct XiaomiAirPurifier {
+    config: Config,
+    client: Client,
+}
+
+impl XiaomiAirPurifier {
+    async fn status(&self) -> Result<Status, miio::Error> {
+        let (power, pm25, humidity, mode): (String, u16, u8, String) = self
+            .client
+            .call("get_prop", ["power", "aqi", "humidity", "mode"])
+            .await?;
+
+        let status = Status {
+            on: power == "on",
+            pm25,
+            humidity,
+            mode,
+        };
+        trace!(id = Device::get_id(self), "Status: {status:?}");
+
+        Ok(status)
+    }
+
+    async fn set(&self, method: &str, params: serde_json::Value) -> Result<(), miio::Error> {
+        debug!(id = Device::get_id(self), "{method}: {params}");
+
+        let result: Vec<String> = self.client.call(method, params).await?;
+        if result != ["ok"] {
+            return Err(miio::Error::InvalidPacket("Result is not 'ok'"));
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl LuaDeviceCreate for XiaomiAirPurifier {
+    type Config = Config;
+    type Error = miio::Error;
+
+    async fn create(config: Self::Config) -> Result<Self, Self::Error> {
+        trace!(
+            id = config.info.identifier(),
+            "Setting up XiaomiAirPurifier"
+        );
+
+        let token = Token::parse(&config.token)?;
+        let client = Client::new(config.addr, token, config.timeout);
+
+        Ok(Self { config, client })
+    }
+}
+
+impl Device for XiaomiAirPurifier {
+    fn get_id(&self) -> String {
+        self.config.info.identifier()
+    }
+
+    fn get_info(&self) -> Option<&InfoConfig> {
+        Some(&self.config.info)
+    }
+}
+
+#[async_trait]
+impl google_home::Device for XiaomiAirPurifier {
+    fn get_device_type(&self) -> Type {
+        Type::AirPurifier
+    }
+
+    fn get_device_name(&self) -> Name {
+        self.config.info.device_name()
+    }
+
+    fn get_id(&self) -> String {
+        Device::get_id(self)
+    }
+
+    async fn is_online(&self) -> bool {
+        self.status().await.is_ok()
+    }
+
+    fn get_room_hint(&self) -> Option<&str> {
+        self.config.info.room.as_deref()
+    }
+
+    fn get_custom_data(&self) -> Option<serde_json::Value> {
+        self.config.info.custom_data()
+    }
+
+    fn will_report_state(&self) -> bool {
+        false
+    }
+}
+
+#[async_trait]
+impl OnOff for XiaomiAirPurifier {
+    async fn on(&self) -> Result<bool, ErrorCode> {
+        Ok(self.status().await?.on)
+    }
+
+    async fn set_on(&self, on: bool) -> Result<(), ErrorCode> {
+        let power = if on { "on" } else { "off" };
+        self.set("set_power", json!([power])).await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl FanSpeed for XiaomiAirPurifier {
+    fn available_fan_speeds(&self) -> AvailableSpeeds {
+        AvailableSpeeds {
+            speeds: MODES
+                .iter()
+                .map(|mode| Speed {
+                    speed_name: (*mode).into(),
+                    speed_values: vec![SpeedValue {
+                        speed_synonym: vec![mode[..1].to_uppercase() + &mode[1..]],
+                        lang: "en".into(),
+                    }],
+                })
+                .collect(),
+            ordered: false,
+        }
+    }
+
+    async fn current_fan_speed_setting(&self) -> Result<String, ErrorCode> {
+        Ok(self.status().await?.mode)
+    }
+
+    async fn set_fan_speed(&self, fan_speed: String) -> Result<(), ErrorCode> {
+        if !MODES.contains(&fan_speed.as_str()) {
+            return Err(DeviceError::TransientError.into());
+        }
+
+        self.set("set_mode", json!([fan_speed])).await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl HumiditySetting for XiaomiAirPurifier {
+    fn query_only_humidity_setting(&self) -> Option<bool> {
+        Some(true)
+    }
+
+    async fn humidity_ambient_percent(&self) -> Result<isize, ErrorCode> {
+        Ok(self.status().await?.humidity as isize)
+    }
+}
+
+fn pm25_state(pm25: f32) -> &'static str {
+    PM25_STATES
+        .iter()
+        .find(|(limit, _)| pm25 <= *limit)
+        .map_or(PM25_HAZARDOUS, |(_, state)| state)
+}
+
+#[async_trait]
+impl SensorState for XiaomiAirPurifier {
+    fn sensor_states_supported(&self) -> Vec<SupportedSensorState> {
+        let mut available_states: Vec<String> = PM25_STATES
+            .iter()
+            .map(|(_, state)| (*state).into())
+            .collect();
+        available_states.push(PM25_HAZARDOUS.into());
+
+        vec![SupportedSensorState {
+            name: SENSOR_STATE_NAME.into(),
+            descriptive_capabilities: DescriptiveCapabilities { available_states },
+            numeric_capabilities: Some(NumericCapabilities {
+                raw_value_unit: "MICROGRAMS_PER_CUBIC_METER".into(),
+            }),
+        }]
+    }
+
+    async fn current_sensor_state_data(&self) -> Result<Vec<CurrentSensorState>, ErrorCode> {
+        let pm25 = self.status().await?.pm25 as f32;
+
+        Ok(vec![CurrentSensorState {
+            name: SENSOR_STATE_NAME.into(),
+            current_sensor_state: pm25_state(pm25).into(),
+            raw_value: Some(pm25),
+        }])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::miio::testing::{device, TOKEN};
+
+    async fn purifier(addr: SocketAddr) -> XiaomiAirPurifier {
+        XiaomiAirPurifier::create(Config {
+            info: InfoConfig {
+                room: Some("Bedroom".into()),
+                ..InfoConfig::new("Air purifier")
+            },
+            addr,
+            token: Secret::new(TOKEN.into()),
+            timeout: Duration::from_millis(200),
+        })
+        .await
+        .unwrap()
+    }
+
+    #[test]
+    fn pm25_states() {
+        assert_eq!(pm25_state(3.0), "healthy");
+        assert_eq!(pm25_state(12.0), "healthy");
+        assert_eq!(pm25_state(40.0), "unhealthy sensitive");
+        assert_eq!(pm25_state(500.0), "hazardous");
+    }
+
+    #[tokio::test]
+    async fn purifier_state() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let addr = device({
+            let calls = calls.clone();
+            move |method, params| {
+                calls.lock().unwrap().push((method.to_owned(), params));
+                match method {
+                    "get_prop" => json!(["on", 42, 55, "auto"]),
+                    _ => json!(["ok"]),
+                }
+            }
+        })
+        .await;
+        let purifier = purifier(addr).await;
+
+        assert!(purifier.on().await.unwrap());
+        assert_eq!(purifier.current_fan_speed_setting().await.unwrap(), "auto");
+        assert_eq!(purifier.humidity_ambient_percent().await.unwrap(), 55);
+        assert_eq!(
+            purifier.current_sensor_state_data().await.unwrap(),
+            [CurrentSensorState {
+                name: "PM2.5".into(),
+                current_sensor_state: "unhealthy sensitive".into(),
+                raw_value: Some(42.0),
+            }]
+        );
+
+        purifier.set_on(false).await.unwrap();
+        purifier.set_fan_speed("silent".into()).await.unwrap();
+        assert!(purifier.set_fan_speed("turbo".into()).await.is_err());
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(
+            calls[4..],
+            [
+                ("set_power".into(), json!(["off"])),
+                ("set_mode".into(), json!(["silent"]))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_token() {
+        let result = XiaomiAirPurifier::create(Config {
+            token: Secret::new("not a token".into()),
+            ..purifier("127.0.0.1:54321".parse().unwrap()).await.config
+        })
+        .await;
+
+        assert!(matches!(result, Err(miio::Error::InvalidToken)));
+    }
+}
@@ -0,0 +1,264 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use automation_lib::action_callback::ActionCallback;
+use automation_lib::availability::{Availability, MqttDeviceStatus};
+use automation_lib::config::{InfoConfig, MqttDeviceConfig};
+use automation_lib::device::{Device, DeviceAvailability, LuaDeviceCreate};
+use automation_lib::event::OnMqtt;
+use automation_lib::mqtt::WrappedAsyncClient;
+use automation_macro::LuaDeviceConfig;
+use google_home::device;
+use google_home::errors::ErrorCode;
+use google_home::traits::OpenClose;
+use google_home::types::Type;
+use rumqttc::{matches, Publish};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tracing::{debug, trace, warn};
+
+#[derive(Debug, Clone, LuaDeviceConfig)]
+pub struct Config {
+    #[device_config(flatten)]
+    pub info: InfoConfig,
+    #[device_config(flatten)]
+    pub mqtt: MqttDeviceConfig,
+
+    #[device_config(from_lua, default)]
+    pub callback: ActionCallback<Blind, BlindState>,
+
+    // Called when the device becomes available or unavailable
+    #[device_config(from_lua, default)]
+    pub availability_callback: ActionCallback<Blind, bool>,
+
+    #[device_config(from_lua)]
+    pub client: WrappedAsyncClient,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum BlindMoving {
+    Up,
+    Down,
+    Stop,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlindState {
+    // 0 is fully closed, 100 is fully open
+    position: u8,
+    #[serde(default)]
+    moving: Option<BlindMoving>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Blind {
+    config: Config,
+
+    state: Arc<RwLock<BlindState>>,
+    status: MqttDeviceStatus,
+}
+
+impl Blind {
+    async fn state(&self) -> RwLockReadGuard<BlindState> {
+        self.state.read().await
+    }
+
+    async fn state_mut(&self) -> RwLockWriteGuard<BlindState> {
+        self.state.write().await
+    }
+}
+
+#[async_trait]
+impl LuaDeviceCreate for Blind {
+    type Config = Config;
+    type Error = rumqttc::ClientError;
+
+    async fn create(config: Self::Config) -> Result<Self, Self::Error> {
+        trace!(id = config.info.identifier(), "Setting up Blind");
+
+        config
+            .client
+            .subscribe(&config.mqtt.topic, rumqttc::QoS::AtLeastOnce)
+            .await?;
+        let status = MqttDeviceStatus::new(config.info.identifier(), &config.mqtt);
+        status.subscribe(&config.client).await?;
+
+        Ok(Self {
+            config,
+            state: Default::default(),
+            status,
+        })
+    }
+}
+
+impl Device for Blind {
+    fn get_id(&self) -> String {
+        self.config.info.identifier()
+    }
+
+    fn get_info(&self) -> Option<&InfoConfig> {
+        Some(&self.config.info)
+    }
+}
+
+impl DeviceAvailability for Blind {
+    fn availability(&self) -> &Availability {
+        self.status.availability()
+    }
+}
+
+#[async_trait]
+impl OnMqtt for Blind {
+    async fn on_mqtt(&self, message: Publish) {
+        if self
+            .status
+            .handle_with_callback(self, &message, &self.config.availability_callback)
+            .await
+        {
+            return;
+        }
+
+        if !matches(&message.topic, &self.config.mqtt.topic) {
+            return;
+        }
+
+        let state = match serde_json::from_slice::<BlindState>(&message.payload) {
+            Ok(state) => state,
+            Err(err) => {
+                warn!(id = Device::get_id(self), "Failed to parse message: {err}");
+                return;
+            }
+        };
+
+        // No need to do anything if the state has not changed
+        if state == *self.state().await {
+            return;
+        }
+
+        debug!(id = Device::get_id(self), "Updating state to {state:?}");
+        *self.state_mut().await = state.clone();
+
+        self.config.callback.call(self, &state).await;
+    }
+}
+
+#[async_trait]
+impl google_home::Device for Blind {
+    fn get_device_type(&self) -> Type {
+        Type::Blinds
+    }
+
+    fn get_device_name(&self) -> device::Name {
+        self.config.info.device_name()
+    }
+
+    fn get_id(&self) -> String {
+        Device::get_id(self)
+    }
+
+    async fn is_online(&self) -> bool {
+        self.status.is_available()
+    }
+
+    fn get_room_hint(&self) -> Option<&str> {
+        self.config.info.room.as_deref()
+    }
+
+    fn get_device_info(&self) -> Option<device::Info> {
+        self.status.device_info()
+    }
+
+    fn get_custom_data(&self) -> Option<serde_json::Value> {
+        self.config.info.custom_data()
+    }
+
+    fn will_report_state(&self) -> bool {
+        // TODO: Implement state reporting
+        false
+    }
+}
+
+#[async_trait]
+impl OpenClose for Blind {
+    fn discrete_only_open_close(&self) -> Option<bool> {
+        Some(false)
+    }
+
+    async fn open_percent(&self) -> Result<u8, ErrorCode> {
+        Ok(self.state().await.position)
+    }
+
+    async fn set_open_percent(&self, open_percent: u8) -> Result<(), ErrorCode> {
+        let message = json!({ "position": open_percent.min(100) });
+
+        debug!(id = Device::get_id(self), "{message}");
+
+        let topic = format!("{}/set", self.config.mqtt.topic);
+        // TODO: Handle potential errors here
+        self.config
+            .client
+            .publish(
+                &topic,
+                rumqttc::QoS::AtLeastOnce,
+                false,
+                serde_json::to_string(&message).unwrap(),
+            )
+            .await
+            .map_err(|err| warn!("Failed to update state on {topic}: {err}"))
+            .ok();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use automation_lib::event::EventChannel;
+    use automation_lib::lua::testing::MockMqttClient;
+    use rumqttc::QoS;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn position() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let blind = Blind::create(Config {
+            info: InfoConfig::new("Curtains"),
+            mqtt: MqttDeviceConfig {
+                topic: "zigbee2mqtt/curtains".into(),
+                availability: None,
+            },
+            callback: Default::default(),
+            availability_callback: Default::default(),
+            client: client.client(),
+        })
+        .await
+        .unwrap();
+
+        blind
+            .on_mqtt(Publish::new(
+                "zigbee2mqtt/curtains",
+                QoS::AtLeastOnce,
+                r#"{"position":40,"moving":"UP","state":"OPEN","linkquality":120}"#,
+            ))
+            .await;
+
+        assert_eq!(blind.open_percent().await.unwrap(), 40);
+        assert_eq!(blind.state().await.moving, Some(BlindMoving::Up));
+
+        blind.set_open_percent(75).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(
+            client.published().pop(),
+            Some((
+                "zigbee2mqtt/curtains/set".to_string(),
+                r#"{"position":75}"#.to_string()
+            ))
+        );
+    }
+}
@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use automation_lib::action_callback::ActionCallback;
+use automation_lib::config::MqttDeviceConfig;
+use automation_lib::device::{Device, LuaDeviceCreate};
+use automation_lib::event::OnMqtt;
+use automation_lib::messages::AvailabilityMessage;
+use automation_lib::mqtt::WrappedAsyncClient;
+use automation_macro::LuaDeviceConfig;
+use rumqttc::{Publish, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use thiserror::Error;
+use tokio::sync::{oneshot, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tracing::{debug, trace, warn};
+
+#[derive(Debug, Clone, LuaDeviceConfig)]
+pub struct Config {
+    pub identifier: String,
+    // Base topic of zigbee2mqtt, e.g. 'zigbee2mqtt'
+    #[device_config(flatten)]
+    pub mqtt: MqttDeviceConfig,
+    // How long to wait for zigbee2mqtt to respond to a request
+    #[device_config(rename("timeout_seconds"), default(5), with(Duration::from_secs))]
+    pub timeout: Duration,
+
+    // Called when the bridge goes online or offline
+    #[device_config(from_lua, default)]
+    pub state_callback: ActionCallback<Zigbee2MqttBridge, bool>,
+    // Called when a device joins or leaves the network
+    #[device_config(from_lua, default)]
+    pub device_callback: ActionCallback<Zigbee2MqttBridge, DeviceEvent>,
+
+    #[device_config(from_lua)]
+    pub client: WrappedAsyncClient,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceEventType {
+    DeviceJoined,
+    DeviceLeave,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeviceEvent {
+    event: DeviceEventType,
+    friendly_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct BridgeEvent {
+    #[serde(rename = "type")]
+    event: String,
+    data: BridgeEventData,
+}
+
+#[derive(Debug, Deserialize)]
+struct BridgeEventData {
+    friendly_name: String,
+}
+
+impl BridgeEvent {
+    fn device_event(self) -> Option<DeviceEvent> {
+        let event = match self.event.as_str() {
+            "device_joined" => DeviceEventType::DeviceJoined,
+            "device_leave" => DeviceEventType::DeviceLeave,
+            _ => return None,
+        };
+
+        Some(DeviceEvent {
+            event,
+            friendly_name: self.data.friendly_name,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct BridgeResponse {
+    status: String,
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    transaction: Option<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Mqtt(#[from] rumqttc::ClientError),
+    #[error("Zigbee2MQTT did not respond within {0:?}")]
+    Timeout(Duration),
+    #[error("Zigbee2MQTT returned an error: {0}")]
+    RequestFailed(String),
+}
+
+#[derive(Debug, Default)]
+pub struct State {
+    online: bool,
+    transaction: u64,
+    pending: HashMap<String, oneshot::Sender<BridgeResponse>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Zigbee2MqttBridge {
+    config: Config,
+    state: Arc<RwLock<State>>,
+}
+
+impl Zigbee2MqttBridge {
+    async fn state(&self) -> RwLockReadGuard<State> {
+        self.state.read().await
+    }
+
+    async fn state_mut(&self) -> RwLockWriteGuard<State> {
+        self.state.write().await
+    }
+
+    fn topic(&self, topic: &str) -> String {
+        format!("{}/bridge/{topic}", self.config.mqtt.topic)
+    }
+
+    pub async fn is_online(&self) -> bool {
+        self.state().await.online
+    }
+
+    // Sends a request to the bridge and waits for the matching response
+    async fn request(&self, request: &str, mut payload: serde_json::Value) -> Result<(), Error> {
+        let (tx, rx) = oneshot::channel();
+        let transaction = {
+            let mut state = self.state_mut().await;
+            state.transaction += 1;
+            let transaction = format!("automation-{}", state.transaction);
+            state.pending.insert(transaction.clone(), tx);
+
+            transaction
+        };
+
+        payload["transaction"] = transaction.clone().into();
+        let result = self
+            .config
+            .client
+            .publish(
+                self.topic(&format!("request/{request}")),
+                QoS::AtLeastOnce,
+                false,
+                serde_json::to_string(&payload).expect("Serialization should not fail"),
+            )
+            .await;
+
+        if let Err(err) = result {
+            self.state_mut().await.pending.remove(&transaction);
+            return Err(err.into());
+        }
+
+        let Ok(Ok(response)) = tokio::time::timeout(self.config.timeout, rx).await else {
+            self.state_mut().await.pending.remove(&transaction);
+            return Err(Error::Timeout(self.config.timeout));
+        };
+
+        if response.status == "ok" {
+            Ok(())
+        } else {
+            Err(Error::RequestFailed(
+                response.error.unwrap_or(response.status),
+            ))
+        }
+    }
+
+    pub async fn permit_join(&self, seconds: u32) -> Result<(), Error> {
+        self.request(
+            "permit_join",
+            json!({ "value": seconds > 0, "time": seconds }),
+        )
+        .await
+    }
+
+    pub async fn restart(&self) -> Result<(), Error> {
+        self.request("restart", json!({})).await
+    }
+
+    pub async fn log_level(&self, level: &str) -> Result<(), Error> {
+        self.request(
+            "options",
+            json!({ "options": { "advanced": { "log_level": level } } }),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl LuaDeviceCreate for Zigbee2MqttBridge {
+    type Config = Config;
+    type Error = rumqttc::ClientError;
+
+    async fn create(config: Self::Config) -> Result<Self, Self::Error> {
+        trace!(id = config.identifier, "Setting up Zigbee2MqttBridge");
+
+        let bridge = Self {
+            config,
+            state: Default::default(),
+        };
+
+        for topic in ["state", "event", "response/#"] {
+            bridge
+                .config
+                .client
+                .subscribe(bridge.topic(topic), QoS::AtLeastOnce)
+                .await?;
+        }
+
+        Ok(bridge)
+    }
+}
+
+impl Device for Zigbee2MqttBridge {
+    fn get_id(&self) -> String {
+        self.config.identifier.clone()
+    }
+}
+
+#[async_trait]
+impl OnMqtt for Zigbee2MqttBridge {
+    async fn on_mqtt(&self, message: Publish) {
+        if message.topic == self.topic("state") {
+            let online = match AvailabilityMessage::try_from(message) {
+                Ok(message) => message.available(),
+                Err(err) => {
+                    warn!(id = self.get_id(), "Failed to parse message: {err}");
+                    return;
+                }
+            };
+
+            if online != self.state().await.online {
+                debug!(id = self.get_id(), "Bridge online: {online}");
+                self.state_mut().await.online = online;
+                self.config.state_callback.call(self, &online).await;
+            }
+        } else if message.topic == self.topic("event") {
+            let event: BridgeEvent = match serde_json::from_slice(&message.payload) {
+                Ok(event) => event,
+                Err(err) => {
+                    warn!(id = self.get_id(), "Failed to parse message: {err}");
+                    return;
+                }
+            };
+
+            if let Some(event) = event.device_event() {
+                debug!(id = self.get_id(), "Device event: {event:?}");
+                self.config.device_callback.call(self, &event).await;
+            }
+        } else if message.topic.starts_with(&self.topic("response/")) {
+            let response: BridgeResponse = match serde_json::from_slice(&message.payload) {
+                Ok(response) => response,
+                Err(err) => {
+                    warn!(id = self.get_id(), "Failed to parse message: {err}");
+                    return;
+                }
+            };
+
+            // Responses to requests that were not made by us do not have a (known) transaction
+            let Some(transaction) = &response.transaction else {
+                return;
+            };
+
+            if let Some(tx) = self.state_mut().await.pending.remove(transaction) {
+                tx.send(response).ok();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automation_lib::event::EventChannel;
+    use automation_lib::lua::testing::MockMqttClient;
+
+    use super::*;
+
+    async fn bridge(client: &MockMqttClient) -> Zigbee2MqttBridge {
+        Zigbee2MqttBridge::create(Config {
+            identifier: "bridge".into(),
+            mqtt: MqttDeviceConfig {
+                topic: "zigbee2mqtt".into(),
+                availability: None,
+            },
+            timeout: Duration::from_millis(100),
+            state_callback: Default::default(),
+            device_callback: Default::default(),
+            client: client.client(),
+        })
+        .await
+        .unwrap()
+    }
+
+    fn publish(topic: &str, payload: &str) -> Publish {
+        Publish::new(topic, QoS::AtLeastOnce, payload)
+    }
+
+    #[test]
+    fn device_event() {
+        let event: BridgeEvent = serde_json::from_str(
+            r#"{"type":"device_joined","data":{"friendly_name":"0x90fd9ffffe6494fc","ieee_address":"0x90fd9ffffe6494fc"}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            event.device_event(),
+            Some(DeviceEvent {
+                event: DeviceEventType::DeviceJoined,
+                friendly_name: "0x90fd9ffffe6494fc".into()
+            })
+        );
+
+        let event: BridgeEvent = serde_json::from_str(
+            r#"{"type":"device_interview","data":{"friendly_name":"0x90fd9ffffe6494fc","status":"started"}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(event.device_event(), None);
+    }
+
+    #[tokio::test]
+    async fn request_response() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let bridge = bridge(&client).await;
+
+        let request = tokio::spawn({
+            let bridge = bridge.clone();
+            async move { bridge.permit_join(60).await }
+        });
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let (topic, payload) = client.published().pop().unwrap();
+        assert_eq!(topic, "zigbee2mqtt/bridge/request/permit_join");
+        let payload: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(payload["time"], 60);
+
+        bridge
+            .on_mqtt(publish(
+                "zigbee2mqtt/bridge/response/permit_join",
+                &json!({
+                    "data": { "time": 60 },
+                    "status": "ok",
+                    "transaction": payload["transaction"]
+                })
+                .to_string(),
+            ))
+            .await;
+
+        assert!(request.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn request_timeout() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let bridge = bridge(&client).await;
+
+        assert!(matches!(bridge.restart().await, Err(Error::Timeout(_))));
+        assert!(bridge.state().await.pending.is_empty());
+    }
+}
@@ -0,0 +1,364 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use automation_lib::action_callback::ActionCallback;
+use automation_lib::availability::{Availability, MqttDeviceStatus};
+use automation_lib::config::{InfoConfig, MqttDeviceConfig};
+use automation_lib::device::{Device, DeviceAvailability, LuaDeviceCreate};
+use automation_lib::event::OnMqtt;
+use automation_lib::mqtt::WrappedAsyncClient;
+use automation_macro::LuaDeviceConfig;
+use google_home::device;
+use google_home::errors::{DeviceError, ErrorCode};
+use google_home::traits::{HumiditySetting, TemperatureSetting, TemperatureUnit};
+use google_home::types::Type;
+use rumqttc::{matches, Publish};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tracing::{debug, trace, warn};
+
+#[derive(Debug, Clone, LuaDeviceConfig)]
+pub struct Config {
+    #[device_config(flatten)]
+    pub info: InfoConfig,
+    #[device_config(flatten)]
+    pub mqtt: MqttDeviceConfig,
+
+    // The callback is only called when a value changed by at least this much since the last call,
+    // this prevents the callback from being spammed by sensor jitter
+    #[device_config(default(0.1))]
+    pub min_delta: f64,
+
+    // Called when the reading changes
+    #[device_config(from_lua, default)]
+    pub callback: ActionCallback<ClimateSensor, ClimateReading>,
+    // Called when the sensor becomes available or unavailable
+    #[device_config(from_lua, default)]
+    pub availability_callback: ActionCallback<ClimateSensor, bool>,
+
+    #[device_config(from_lua)]
+    pub client: WrappedAsyncClient,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ClimateReading {
+    pub temperature: Option<f64>,
+    pub humidity: Option<f64>,
+    pub pressure: Option<f64>,
+    pub battery: Option<f64>,
+}
+
+impl ClimateReading {
+    // Values that are missing from the message keep their previous value
+    fn merge(self, other: ClimateReading) -> Self {
+        Self {
+            temperature: other.temperature.or(self.temperature),
+            humidity: other.humidity.or(self.humidity),
+            pressure: other.pressure.or(self.pressure),
+            battery: other.battery.or(self.battery),
+        }
+    }
+
+    fn differs(&self, other: &ClimateReading, min_delta: f64) -> bool {
+        let differs = |a: Option<f64>, b: Option<f64>| match (a, b) {
+            (Some(a), Some(b)) => (a - b).abs() >= min_delta,
+            (a, b) => a.is_some() != b.is_some(),
+        };
+
+        differs(self.temperature, other.temperature)
+            || differs(self.humidity, other.humidity)
+            || differs(self.pressure, other.pressure)
+            || differs(self.battery, other.battery)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct State {
+    current: ClimateReading,
+    // Reading that was last passed to the callback
+    reported: ClimateReading,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClimateSensor {
+    config: Config,
+
+    state: Arc<RwLock<State>>,
+    status: MqttDeviceStatus,
+}
+
+impl ClimateSensor {
+    async fn state(&self) -> RwLockReadGuard<State> {
+        self.state.read().await
+    }
+
+    async fn state_mut(&self) -> RwLockWriteGuard<State> {
+        self.state.write().await
+    }
+
+    pub async fn reading(&self) -> ClimateReading {
+        self.state().await.current
+    }
+
+    pub async fn temperature(&self) -> Option<f64> {
+        self.state().await.current.temperature
+    }
+
+    pub async fn humidity(&self) -> Option<f64> {
+        self.state().await.current.humidity
+    }
+}
+
+#[async_trait]
+impl LuaDeviceCreate for ClimateSensor {
+    type Config = Config;
+    type Error = rumqttc::ClientError;
+
+    async fn create(config: Self::Config) -> Result<Self, Self::Error> {
+        trace!(id = config.info.identifier(), "Setting up ClimateSensor");
+
+        config
+            .client
+            .subscribe(&config.mqtt.topic, rumqttc::QoS::AtLeastOnce)
+            .await?;
+        let status = MqttDeviceStatus::new(config.info.identifier(), &config.mqtt);
+        status.subscribe(&config.client).await?;
+
+        Ok(Self {
+            config,
+            state: Default::default(),
+            status,
+        })
+    }
+}
+
+impl Device for ClimateSensor {
+    fn get_id(&self) -> String {
+        self.config.info.identifier()
+    }
+
+    fn get_info(&self) -> Option<&InfoConfig> {
+        Some(&self.config.info)
+    }
+}
+
+impl DeviceAvailability for ClimateSensor {
+    fn availability(&self) -> &Availability {
+        self.status.availability()
+    }
+}
+
+#[async_trait]
+impl OnMqtt for ClimateSensor {
+    async fn on_mqtt(&self, message: Publish) {
+        if self
+            .status
+            .handle_with_callback(self, &message, &self.config.availability_callback)
+            .await
+        {
+            return;
+        }
+
+        if !matches(&message.topic, &self.config.mqtt.topic) {
+            return;
+        }
+
+        let reading = match serde_json::from_slice::<ClimateReading>(&message.payload) {
+            Ok(reading) => reading,
+            Err(err) => {
+                warn!(id = Device::get_id(self), "Failed to parse message: {err}");
+                return;
+            }
+        };
+
+        let mut state = self.state_mut().await;
+        state.current = state.current.merge(reading);
+        if !state
+            .current
+            .differs(&state.reported, self.config.min_delta)
+        {
+            return;
+        }
+
+        let reading = state.current;
+        state.reported = reading;
+        drop(state);
+
+        debug!(id = Device::get_id(self), "Reading = {reading:?}");
+        self.config.callback.call(self, &reading).await;
+    }
+}
+
+#[async_trait]
+impl google_home::Device for ClimateSensor {
+    fn get_device_type(&self) -> Type {
+        Type::Sensor
+    }
+
+    fn get_device_name(&self) -> device::Name {
+        self.config.info.device_name()
+    }
+
+    fn get_id(&self) -> String {
+        Device::get_id(self)
+    }
+
+    async fn is_online(&self) -> bool {
+        self.status.is_available()
+    }
+
+    fn get_room_hint(&self) -> Option<&str> {
+        self.config.info.room.as_deref()
+    }
+
+    fn get_device_info(&self) -> Option<device::Info> {
+        self.status.device_info()
+    }
+
+    fn get_custom_data(&self) -> Option<serde_json::Value> {
+        self.config.info.custom_data()
+    }
+}
+
+#[async_trait]
+impl HumiditySetting for ClimateSensor {
+    fn query_only_humidity_setting(&self) -> Option<bool> {
+        Some(true)
+    }
+
+    async fn humidity_ambient_percent(&self) -> Result<isize, ErrorCode> {
+        self.humidity()
+            .await
+            .map(|humidity| humidity.round() as isize)
+            .ok_or(DeviceError::TransientError.into())
+    }
+}
+
+#[async_trait]
+impl TemperatureSetting for ClimateSensor {
+    fn query_only_temperature_control(&self) -> Option<bool> {
+        Some(true)
+    }
+
+    #[allow(non_snake_case)]
+    fn temperatureUnitForUX(&self) -> TemperatureUnit {
+        TemperatureUnit::Celsius
+    }
+
+    async fn temperature_ambient_celsius(&self) -> Result<f32, ErrorCode> {
+        self.temperature()
+            .await
+            .map(|temperature| ((10.0 * temperature).round() / 10.0) as f32)
+            .ok_or(DeviceError::TransientError.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use automation_lib::event::EventChannel;
+    use automation_lib::lua::testing::MockMqttClient;
+    use mlua::FromLua;
+    use rumqttc::QoS;
+
+    use super::*;
+
+    async fn sensor(
+        client: &MockMqttClient,
+        callback: ActionCallback<ClimateSensor, ClimateReading>,
+    ) -> ClimateSensor {
+        ClimateSensor::create(Config {
+            info: InfoConfig {
+                room: Some("Bedroom".into()),
+                ..InfoConfig::new("Climate")
+            },
+            mqtt: MqttDeviceConfig {
+                topic: "zigbee2mqtt/climate".into(),
+                availability: None,
+            },
+            min_delta: 0.1,
+            callback,
+            availability_callback: Default::default(),
+            client: client.client(),
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn publish(sensor: &ClimateSensor, payload: &str) {
+        sensor
+            .on_mqtt(Publish::new(
+                "zigbee2mqtt/climate",
+                QoS::AtLeastOnce,
+                payload,
+            ))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn reading() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let sensor = sensor(&client, Default::default()).await;
+
+        assert!(sensor.temperature_ambient_celsius().await.is_err());
+
+        publish(
+            &sensor,
+            r#"{"temperature":21.37,"humidity":48.6,"pressure":1012.3,"battery":87,"linkquality":120}"#,
+        )
+        .await;
+        assert_eq!(
+            sensor.reading().await,
+            ClimateReading {
+                temperature: Some(21.37),
+                humidity: Some(48.6),
+                pressure: Some(1012.3),
+                battery: Some(87.0),
+            }
+        );
+        assert_eq!(sensor.temperature_ambient_celsius().await, Ok(21.4));
+        assert_eq!(sensor.humidity_ambient_percent().await, Ok(49));
+
+        // Values that are not part of the message are kept
+        publish(&sensor, r#"{"temperature":20.5}"#).await;
+        assert_eq!(sensor.temperature().await, Some(20.5));
+        assert_eq!(sensor.humidity().await, Some(48.6));
+    }
+
+    #[tokio::test]
+    async fn min_delta() {
+        let lua = mlua::Lua::new();
+        let count = Arc::new(AtomicUsize::new(0));
+        let f = lua
+            .create_function({
+                let count = count.clone();
+                move |_lua, _: mlua::MultiValue| {
+                    count.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                }
+            })
+            .unwrap();
+        let callback = ActionCallback::from_lua(mlua::Value::Function(f), &lua).unwrap();
+
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let sensor = sensor(&client, callback).await;
+
+        publish(&sensor, r#"{"temperature":21.0}"#).await;
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+
+        // Jitter below the delta is ignored, even if it adds up over multiple messages
+        publish(&sensor, r#"{"temperature":21.04}"#).await;
+        publish(&sensor, r#"{"temperature":21.08}"#).await;
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+
+        publish(&sensor, r#"{"temperature":21.12}"#).await;
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+
+        // A new value always counts as a change
+        publish(&sensor, r#"{"humidity":50}"#).await;
+        assert_eq!(count.load(Ordering::Relaxed), 3);
+    }
+}
@@ -0,0 +1,335 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use automation_lib::action_callback::ActionCallback;
+use automation_lib::availability::{Availability, MqttDeviceStatus};
+use automation_lib::config::{InfoConfig, MqttDeviceConfig};
+use automation_lib::device::{Device, DeviceAvailability, LuaDeviceCreate};
+use automation_lib::event::OnMqtt;
+use automation_lib::mqtt::WrappedAsyncClient;
+use automation_macro::LuaDeviceConfig;
+use google_home::device;
+use google_home::errors::{DeviceError, ErrorCode};
+use google_home::traits::{HumiditySetpointRange, HumiditySetting, OnOff};
+use google_home::types::Type;
+use rumqttc::{matches, Publish, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tracing::{debug, trace, warn};
+
+#[derive(Debug, Clone, LuaDeviceConfig)]
+pub struct Config {
+    #[device_config(flatten)]
+    pub info: InfoConfig,
+    #[device_config(flatten)]
+    pub mqtt: MqttDeviceConfig,
+
+    // Range the target humidity can be set to, requests outside of it are clamped
+    #[device_config(default(30))]
+    pub min_humidity: isize,
+    #[device_config(default(80))]
+    pub max_humidity: isize,
+
+    // Called when the state of the dehumidifier changes
+    #[device_config(from_lua, default)]
+    pub callback: ActionCallback<SmartDehumidifier, DehumidifierState>,
+
+    #[device_config(from_lua)]
+    pub client: WrappedAsyncClient,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct DehumidifierMessage {
+    state: Option<String>,
+    humidity: Option<f64>,
+    target_humidity: Option<isize>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub struct DehumidifierState {
+    pub on: bool,
+    pub humidity: Option<f64>,
+    pub target_humidity: Option<isize>,
+}
+
+impl DehumidifierState {
+    // Values that are missing from the message keep their previous value
+    fn merge(self, message: DehumidifierMessage) -> Self {
+        Self {
+            on: message
+                .state
+                .map(|state| state.eq_ignore_ascii_case("ON"))
+                .unwrap_or(self.on),
+            humidity: message.humidity.or(self.humidity),
+            target_humidity: message.target_humidity.or(self.target_humidity),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SmartDehumidifier {
+    config: Config,
+
+    state: Arc<RwLock<DehumidifierState>>,
+    status: MqttDeviceStatus,
+}
+
+impl SmartDehumidifier {
+    async fn state(&self) -> RwLockReadGuard<DehumidifierState> {
+        self.state.read().await
+    }
+
+    async fn state_mut(&self) -> RwLockWriteGuard<DehumidifierState> {
+        self.state.write().await
+    }
+
+    pub async fn humidity(&self) -> Option<f64> {
+        self.state().await.humidity
+    }
+
+    pub async fn target_humidity(&self) -> Option<isize> {
+        self.state().await.target_humidity
+    }
+
+    fn setpoint_range(&self) -> HumiditySetpointRange {
+        HumiditySetpointRange {
+            min_percent: self.config.min_humidity,
+            max_percent: self.config.max_humidity,
+        }
+    }
+
+    async fn publish_set(&self, message: serde_json::Value) -> Result<(), ErrorCode> {
+        let topic = format!("{}/set", self.config.mqtt.topic);
+        self.config
+            .client
+            .publish(
+                &topic,
+                QoS::AtLeastOnce,
+                false,
+                serde_json::to_string(&message).expect("Serialization should not fail"),
+            )
+            .await
+            .map_err(|err| {
+                warn!(
+                    id = Device::get_id(self),
+                    "Failed to update state on {topic}: {err}"
+                );
+                DeviceError::TransientError.into()
+            })
+    }
+}
+
+#[async_trait]
+impl LuaDeviceCreate for SmartDehumidifier {
+    type Config = Config;
+    type Error = rumqttc::ClientError;
+
+    async fn create(config: Self::Config) -> Result<Self, Self::Error> {
+        trace!(
+            id = config.info.identifier(),
+            "Setting up SmartDehumidifier"
+        );
+
+        config
+            .client
+            .subscribe(&config.mqtt.topic, QoS::AtLeastOnce)
+            .await?;
+        let status = MqttDeviceStatus::new(config.info.identifier(), &config.mqtt);
+        status.subscribe(&config.client).await?;
+
+        Ok(Self {
+            config,
+            state: Default::default(),
+            status,
+        })
+    }
+}
+
+impl Device for SmartDehumidifier {
+    fn get_id(&self) -> String {
+        self.config.info.identifier()
+    }
+
+    fn get_info(&self) -> Option<&InfoConfig> {
+        Some(&self.config.info)
+    }
+}
+
+impl DeviceAvailability for SmartDehumidifier {
+    fn availability(&self) -> &Availability {
+        self.status.availability()
+    }
+}
+
+#[async_trait]
+impl OnMqtt for SmartDehumidifier {
+    async fn on_mqtt(&self, message: Publish) {
+        if self.status.handle(&message) {
+            return;
+        }
+
+        if !matches(&message.topic, &self.config.mqtt.topic) {
+            return;
+        }
+
+        let message = match serde_json::from_slice::<DehumidifierMessage>(&message.payload) {
+            Ok(message) => message,
+            Err(err) => {
+                warn!(id = Device::get_id(self), "Failed to parse message: {err}");
+                return;
+            }
+        };
+
+        let mut state = self.state_mut().await;
+        let previous = *state;
+        *state = state.merge(message);
+        let current = *state;
+        drop(state);
+
+        if current != previous {
+            debug!(id = Device::get_id(self), "State = {current:?}");
+            self.config.callback.call(self, &current).await;
+        }
+    }
+}
+
+#[async_trait]
+impl google_home::Device for SmartDehumidifier {
+    fn get_device_type(&self) -> Type {
+        Type::Dehumidifier
+    }
+
+    fn get_device_name(&self) -> device::Name {
+        self.config.info.device_name()
+    }
+
+    fn get_id(&self) -> String {
+        Device::get_id(self)
+    }
+
+    async fn is_online(&self) -> bool {
+        self.status.is_available()
+    }
+
+    fn get_room_hint(&self) -> Option<&str> {
+        self.config.info.room.as_deref()
+    }
+
+    fn get_device_info(&self) -> Option<device::Info> {
+        self.status.device_info()
+    }
+
+    fn get_custom_data(&self) -> Option<serde_json::Value> {
+        self.config.info.custom_data()
+    }
+}
+
+#[async_trait]
+impl OnOff for SmartDehumidifier {
+    async fn on(&self) -> Result<bool, ErrorCode> {
+        Ok(self.state().await.on)
+    }
+
+    async fn set_on(&self, on: bool) -> Result<(), ErrorCode> {
+        self.publish_set(json!({ "state": if on { "ON" } else { "OFF" } }))
+            .await
+    }
+}
+
+#[async_trait]
+impl HumiditySetting for SmartDehumidifier {
+    fn humidity_setpoint_range(&self) -> Option<HumiditySetpointRange> {
+        Some(self.setpoint_range())
+    }
+
+    async fn humidity_ambient_percent(&self) -> Result<isize, ErrorCode> {
+        self.humidity()
+            .await
+            .map(|humidity| humidity.round() as isize)
+            .ok_or(DeviceError::TransientError.into())
+    }
+
+    async fn humidity_setpoint_percent(&self) -> Result<Option<isize>, ErrorCode> {
+        Ok(self.target_humidity().await)
+    }
+
+    async fn set_humidity_setpoint(&self, humidity: isize) -> Result<(), ErrorCode> {
+        let humidity = self.setpoint_range().clamp(humidity);
+        debug!(
+            id = Device::get_id(self),
+            "Setting target humidity to {humidity}%"
+        );
+
+        self.publish_set(json!({ "target_humidity": humidity }))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automation_lib::event::EventChannel;
+    use automation_lib::lua::testing::MockMqttClient;
+
+    use super::*;
+
+    async fn dehumidifier(client: &MockMqttClient) -> SmartDehumidifier {
+        SmartDehumidifier::create(Config {
+            info: InfoConfig {
+                room: Some("Basement".into()),
+                ..InfoConfig::new("Dehumidifier")
+            },
+            mqtt: MqttDeviceConfig {
+                topic: "zigbee2mqtt/dehumidifier".into(),
+                availability: None,
+            },
+            min_humidity: 35,
+            max_humidity: 70,
+            callback: Default::default(),
+            client: client.client(),
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn setpoint() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let dehumidifier = dehumidifier(&client).await;
+
+        assert_eq!(dehumidifier.humidity_setpoint_percent().await, Ok(None));
+
+        dehumidifier
+            .on_mqtt(Publish::new(
+                "zigbee2mqtt/dehumidifier",
+                QoS::AtLeastOnce,
+                r#"{"state":"ON","humidity":62.4,"target_humidity":50,"linkquality":96}"#,
+            ))
+            .await;
+        assert_eq!(dehumidifier.on().await, Ok(true));
+        assert_eq!(dehumidifier.humidity_ambient_percent().await, Ok(62));
+        assert_eq!(dehumidifier.humidity_setpoint_percent().await, Ok(Some(50)));
+
+        dehumidifier.set_humidity_setpoint(45).await.unwrap();
+        // Values outside of the supported range are clamped
+        dehumidifier.set_humidity_setpoint(20).await.unwrap();
+
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        let published: Vec<_> = client
+            .published()
+            .into_iter()
+            .map(|(topic, payload)| {
+                assert_eq!(topic, "zigbee2mqtt/dehumidifier/set");
+                serde_json::from_str::<serde_json::Value>(&payload).unwrap()
+            })
+            .collect();
+        assert_eq!(
+            published,
+            [
+                json!({ "target_humidity": 45 }),
+                json!({ "target_humidity": 35 })
+            ]
+        );
+    }
+}
@@ -0,0 +1,346 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use automation_lib::action_callback::ActionCallback;
+use automation_lib::availability::{Availability, MqttDeviceStatus};
+use automation_lib::config::{InfoConfig, MqttDeviceConfig};
+use automation_lib::device::{Device, DeviceAvailability, LuaDeviceCreate};
+use automation_lib::event::OnMqtt;
+use automation_lib::mqtt::WrappedAsyncClient;
+use automation_macro::LuaDeviceConfig;
+use google_home::device;
+use google_home::errors::ErrorCode;
+use google_home::traits::{
+    CurrentSensorState, DescriptiveCapabilities, SensorState, SupportedSensorState,
+};
+use google_home::types::Type;
+use rumqttc::{matches, Publish};
+use serde::Deserialize;
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::task::JoinHandle;
+use tracing::{debug, trace, warn};
+
+const SENSOR_STATE_NAME: &str = "WaterLeak";
+
+#[derive(Debug, Clone, LuaDeviceConfig)]
+pub struct Config {
+    #[device_config(flatten)]
+    pub info: InfoConfig,
+    #[device_config(flatten)]
+    pub mqtt: MqttDeviceConfig,
+
+    // Keep calling the callback at this interval for as long as the leak persists, that way a
+    // single missed notification does not go unnoticed
+    #[device_config(rename("repeat_interval_seconds"), default(5 * 60), with(Duration::from_secs))]
+    pub repeat_interval: Duration,
+
+    // Called when a leak is detected or cleared
+    #[device_config(from_lua, default)]
+    pub callback: ActionCallback<LeakSensor, bool>,
+    // Called when the battery level changes
+    #[device_config(from_lua, default)]
+    pub battery_callback: ActionCallback<LeakSensor, f64>,
+    // Called when the sensor is tampered with
+    #[device_config(from_lua, default)]
+    pub tamper_callback: ActionCallback<LeakSensor, bool>,
+    // Called when the sensor becomes available or unavailable
+    #[device_config(from_lua, default)]
+    pub availability_callback: ActionCallback<LeakSensor, bool>,
+
+    #[device_config(from_lua)]
+    pub client: WrappedAsyncClient,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct LeakMessage {
+    water_leak: Option<bool>,
+    battery: Option<f64>,
+    tamper: Option<bool>,
+}
+
+#[derive(Debug, Default)]
+pub struct State {
+    leak: bool,
+    battery: Option<f64>,
+    tamper: Option<bool>,
+    // Repeats the callback while the leak persists
+    repeat_handle: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LeakSensor {
+    config: Config,
+
+    state: Arc<RwLock<State>>,
+    status: MqttDeviceStatus,
+}
+
+impl LeakSensor {
+    async fn state(&self) -> RwLockReadGuard<State> {
+        self.state.read().await
+    }
+
+    async fn state_mut(&self) -> RwLockWriteGuard<State> {
+        self.state.write().await
+    }
+
+    pub async fn leak(&self) -> bool {
+        self.state().await.leak
+    }
+
+    pub async fn battery(&self) -> Option<f64> {
+        self.state().await.battery
+    }
+
+    async fn set_leak(&self, leak: bool) {
+        let mut state = self.state_mut().await;
+        if leak == state.leak {
+            return;
+        }
+
+        state.leak = leak;
+        if let Some(handle) = state.repeat_handle.take() {
+            handle.abort();
+        }
+
+        if leak {
+            let device = self.clone();
+            state.repeat_handle = Some(tokio::spawn(async move {
+                let mut interval = tokio::time::interval(device.config.repeat_interval);
+                // The first tick completes immediately, the initial call is made below
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    debug!(id = Device::get_id(&device), "Leak persists");
+                    device.config.callback.call(&device, &true).await;
+                }
+            }));
+        }
+        drop(state);
+
+        debug!(id = Device::get_id(self), "Leak = {leak}");
+        self.config.callback.call(self, &leak).await;
+    }
+}
+
+#[async_trait]
+impl LuaDeviceCreate for LeakSensor {
+    type Config = Config;
+    type Error = rumqttc::ClientError;
+
+    async fn create(config: Self::Config) -> Result<Self, Self::Error> {
+        trace!(id = config.info.identifier(), "Setting up LeakSensor");
+
+        config
+            .client
+            .subscribe(&config.mqtt.topic, rumqttc::QoS::AtLeastOnce)
+            .await?;
+        let status = MqttDeviceStatus::new(config.info.identifier(), &config.mqtt);
+        status.subscribe(&config.client).await?;
+
+        Ok(Self {
+            config,
+            state: Default::default(),
+            status,
+        })
+    }
+}
+
+impl Device for LeakSensor {
+    fn get_id(&self) -> String {
+        self.config.info.identifier()
+    }
+
+    fn get_info(&self) -> Option<&InfoConfig> {
+        Some(&self.config.info)
+    }
+}
+
+impl DeviceAvailability for LeakSensor {
+    fn availability(&self) -> &Availability {
+        self.status.availability()
+    }
+}
+
+#[async_trait]
+impl OnMqtt for LeakSensor {
+    async fn on_mqtt(&self, message: Publish) {
+        if self
+            .status
+            .handle_with_callback(self, &message, &self.config.availability_callback)
+            .await
+        {
+            return;
+        }
+
+        if !matches(&message.topic, &self.config.mqtt.topic) {
+            return;
+        }
+
+        let message = match serde_json::from_slice::<LeakMessage>(&message.payload) {
+            Ok(message) => message,
+            Err(err) => {
+                warn!(id = Device::get_id(self), "Failed to parse message: {err}");
+                return;
+            }
+        };
+
+        if let Some(leak) = message.water_leak {
+            self.set_leak(leak).await;
+        }
+
+        if let Some(battery) = message.battery {
+            let previous = self.state_mut().await.battery.replace(battery);
+            if previous != Some(battery) {
+                debug!(id = Device::get_id(self), "Battery = {battery}");
+                self.config.battery_callback.call(self, &battery).await;
+            }
+        }
+
+        if let Some(tamper) = message.tamper {
+            let previous = self.state_mut().await.tamper.replace(tamper);
+            if previous != Some(tamper) {
+                debug!(id = Device::get_id(self), "Tamper = {tamper}");
+                self.config.tamper_callback.call(self, &tamper).await;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl google_home::Device for LeakSensor {
+    fn get_device_type(&self) -> Type {
+        Type::Sensor
+    }
+
+    fn get_device_name(&self) -> device::Name {
+        self.config.info.device_name()
+    }
+
+    fn get_id(&self) -> String {
+        Device::get_id(self)
+    }
+
+    async fn is_online(&self) -> bool {
+        self.status.is_available()
+    }
+
+    fn get_room_hint(&self) -> Option<&str> {
+        self.config.info.room.as_deref()
+    }
+
+    fn get_device_info(&self) -> Option<device::Info> {
+        self.status.device_info()
+    }
+
+    fn get_custom_data(&self) -> Option<serde_json::Value> {
+        self.config.info.custom_data()
+    }
+}
+
+#[async_trait]
+impl SensorState for LeakSensor {
+    fn sensor_states_supported(&self) -> Vec<SupportedSensorState> {
+        vec![SupportedSensorState {
+            name: SENSOR_STATE_NAME.into(),
+            descriptive_capabilities: DescriptiveCapabilities {
+                available_states: vec!["leak".into(), "no leak".into()],
+            },
+            numeric_capabilities: None,
+        }]
+    }
+
+    async fn current_sensor_state_data(&self) -> Result<Vec<CurrentSensorState>, ErrorCode> {
+        let state = if self.leak().await { "leak" } else { "no leak" };
+
+        Ok(vec![CurrentSensorState {
+            name: SENSOR_STATE_NAME.into(),
+            current_sensor_state: state.into(),
+            raw_value: None,
+        }])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use automation_lib::event::EventChannel;
+    use automation_lib::lua::testing::MockMqttClient;
+    use mlua::FromLua;
+    use rumqttc::QoS;
+
+    use super::*;
+
+    fn counter<S>(lua: &mlua::Lua, count: Arc<AtomicUsize>) -> ActionCallback<LeakSensor, S> {
+        let f = lua
+            .create_function(move |_lua, _: mlua::MultiValue| {
+                count.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            })
+            .unwrap();
+
+        ActionCallback::from_lua(mlua::Value::Function(f), lua).unwrap()
+    }
+
+    async fn publish(sensor: &LeakSensor, payload: &str) {
+        sensor
+            .on_mqtt(Publish::new("zigbee2mqtt/leak", QoS::AtLeastOnce, payload))
+            .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn repeat() {
+        let lua = mlua::Lua::new();
+        let leaks = Arc::new(AtomicUsize::new(0));
+        let batteries = Arc::new(AtomicUsize::new(0));
+
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let sensor = LeakSensor::create(Config {
+            info: InfoConfig {
+                room: Some("Kitchen".into()),
+                ..InfoConfig::new("Leak")
+            },
+            mqtt: MqttDeviceConfig {
+                topic: "zigbee2mqtt/leak".into(),
+                availability: None,
+            },
+            repeat_interval: Duration::from_secs(60),
+            callback: counter(&lua, leaks.clone()),
+            battery_callback: counter(&lua, batteries.clone()),
+            tamper_callback: Default::default(),
+            availability_callback: Default::default(),
+            client: client.client(),
+        })
+        .await
+        .unwrap();
+
+        publish(
+            &sensor,
+            r#"{"water_leak":true,"battery":90,"tamper":false}"#,
+        )
+        .await;
+        assert!(sensor.leak().await);
+        assert_eq!(leaks.load(Ordering::Relaxed), 1);
+        assert_eq!(batteries.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            sensor.current_sensor_state_data().await.unwrap()[0].current_sensor_state,
+            "leak"
+        );
+
+        // Unchanged values do not trigger the callbacks
+        publish(&sensor, r#"{"water_leak":true,"battery":90}"#).await;
+        assert_eq!(leaks.load(Ordering::Relaxed), 1);
+        assert_eq!(batteries.load(Ordering::Relaxed), 1);
+
+        tokio::time::sleep(Duration::from_secs(150)).await;
+        assert_eq!(leaks.load(Ordering::Relaxed), 3);
+
+        publish(&sensor, r#"{"water_leak":false}"#).await;
+        assert_eq!(leaks.load(Ordering::Relaxed), 4);
+        tokio::time::sleep(Duration::from_secs(150)).await;
+        assert_eq!(leaks.load(Ordering::Relaxed), 4);
+    }
+}
@@ -1,29 +1,41 @@
 use std::fmt::Debug;
 use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Result;
 use async_trait::async_trait;
 use automation_lib::action_callback::ActionCallback;
+use automation_lib::availability::{Availability, MqttDeviceStatus};
 use automation_lib::config::{InfoConfig, MqttDeviceConfig};
-use automation_lib::device::{Device, LuaDeviceCreate};
+use automation_lib::device::{Device, DeviceAvailability, LuaDeviceCreate};
+use automation_lib::error::DeviceConfigError;
 use automation_lib::event::{OnMqtt, OnPresence};
 use automation_lib::helpers::serialization::state_deserializer;
+use automation_lib::lua::traits::{Effect, LightEffect, Timeout};
 use automation_lib::mqtt::WrappedAsyncClient;
 use automation_macro::LuaDeviceConfig;
 use google_home::device;
 use google_home::errors::ErrorCode;
-use google_home::traits::{Brightness, OnOff};
+use google_home::traits::{
+    Brightness, Color, ColorModel, ColorRGB, ColorSetting, ColorTemperatureRange, OnOff,
+};
 use google_home::types::Type;
 use rumqttc::{matches, Publish};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::json;
-use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::task::JoinHandle;
 use tracing::{debug, trace, warn};
 
 pub trait LightState:
     Debug + Clone + Default + Sync + Send + Serialize + Into<StateOnOff> + 'static
 {
+    // Message published on the get topic to make the light report the current state
+    fn get_message() -> serde_json::Value {
+        json!({ "state": "" })
+    }
 }
 
 #[derive(Debug, Clone, LuaDeviceConfig)]
@@ -32,10 +44,33 @@ pub struct Config<T: LightState> {
     pub info: InfoConfig,
     #[device_config(flatten)]
     pub mqtt: MqttDeviceConfig,
+    // Default transition time in seconds for all changes made to the light
+    #[device_config(default)]
+    pub transition: Option<f32>,
+    // Configures what the light does when power is restored, e.g. after a power cut
+    #[device_config(default)]
+    pub power_on_behavior: Option<PowerOnConfig>,
+    // Range of color temperatures in Kelvin supported by the light
+    #[device_config(default)]
+    pub color_temp_range: ColorTempRange,
+    // How brightness percentages map to the brightness levels of the light
+    #[device_config(default)]
+    pub brightness_curve: BrightnessCurve,
+    // Lights only report their state when it changes, so ask for it after a restart
+    #[device_config(default(true))]
+    pub request_state_on_start: bool,
 
     #[device_config(from_lua, default)]
     pub callback: ActionCallback<Light<T>, T>,
 
+    // Called when the device becomes available or unavailable
+    #[device_config(from_lua, default)]
+    pub availability_callback: ActionCallback<Light<T>, bool>,
+
+    // Called every time the mqtt client (re)connects to the broker
+    #[device_config(from_lua, default)]
+    pub on_connect: ActionCallback<WrappedAsyncClient, ()>,
+
     #[device_config(from_lua)]
     pub client: WrappedAsyncClient,
 }
@@ -55,7 +90,11 @@ pub struct StateBrightness {
     brightness: f64,
 }
 
-impl LightState for StateBrightness {}
+impl LightState for StateBrightness {
+    fn get_message() -> serde_json::Value {
+        json!({ "state": "", "brightness": "" })
+    }
+}
 
 impl From<StateBrightness> for StateOnOff {
     fn from(state: StateBrightness) -> Self {
@@ -63,15 +102,215 @@ impl From<StateBrightness> for StateOnOff {
     }
 }
 
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StateColor {
+    #[serde(deserialize_with = "state_deserializer")]
+    state: bool,
+    brightness: f64,
+    // Only set once the light has reported a color we understand
+    #[serde(default, deserialize_with = "reported_color")]
+    color: Option<ColorRGB>,
+}
+
+// Zigbee2MQTT reports the color in the color space of the light, for most bulbs this is xy and
+// sometimes also includes hue and saturation
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ReportedColor {
+    Rgb { r: u8, g: u8, b: u8 },
+    Xy { x: f64, y: f64 },
+    Hs { hue: f64, saturation: f64 },
+}
+
+impl ReportedColor {
+    fn to_rgb(&self) -> Option<ColorRGB> {
+        let (r, g, b) = match *self {
+            Self::Rgb { r, g, b } => return Some(ColorRGB { r, g, b }),
+            Self::Xy { x, y } => xy_to_rgb(x, y)?,
+            Self::Hs { hue, saturation } => hs_to_rgb(hue, saturation / 100.0),
+        };
+
+        let to_u8 = |c: f64| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+        Some(ColorRGB {
+            r: to_u8(r),
+            g: to_u8(g),
+            b: to_u8(b),
+        })
+    }
+}
+
+// Converts CIE xy at full brightness to sRGB, the brightness is reported separately
+fn xy_to_rgb(x: f64, y: f64) -> Option<(f64, f64, f64)> {
+    if y <= 0.0 {
+        return None;
+    }
+
+    let (big_x, big_y, big_z) = (x / y, 1.0, (1.0 - x - y) / y);
+    let r = big_x * 1.656492 - big_y * 0.354851 - big_z * 0.255038;
+    let g = -big_x * 0.707196 + big_y * 1.655397 + big_z * 0.036152;
+    let b = big_x * 0.051713 - big_y * 0.121364 + big_z * 1.011530;
+
+    // Scale the color back into range instead of clipping it, so the hue stays the same
+    let max = r.max(g).max(b);
+    let (r, g, b) = if max > 1.0 {
+        (r / max, g / max, b / max)
+    } else {
+        (r, g, b)
+    };
+
+    let gamma = |c: f64| {
+        if c <= 0.0031308 {
+            12.92 * c
+        } else {
+            1.055 * c.powf(1.0 / 2.4) - 0.055
+        }
+    };
+
+    Some((gamma(r), gamma(g), gamma(b)))
+}
+
+// Hue in degrees and saturation between 0 and 1
+fn hs_to_rgb(hue: f64, saturation: f64) -> (f64, f64, f64) {
+    let sector = hue.rem_euclid(360.0) / 60.0;
+    let chroma = saturation.clamp(0.0, 1.0);
+    let x = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+
+    let (r, g, b) = match sector as u8 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+
+    let m = 1.0 - chroma;
+    (r + m, g + m, b + m)
+}
+
+// A color in a format we do not understand should not prevent the rest of the state from updating
+fn reported_color<'de, D>(deserializer: D) -> Result<Option<ColorRGB>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let color = Option::<serde_json::Value>::deserialize(deserializer)?;
+
+    Ok(color
+        .and_then(|color| serde_json::from_value::<ReportedColor>(color).ok())
+        .and_then(|color| color.to_rgb()))
+}
+
+impl LightState for StateColor {
+    fn get_message() -> serde_json::Value {
+        json!({ "state": "", "brightness": "", "color": "", "color_temp": "" })
+    }
+}
+
+impl From<StateColor> for StateOnOff {
+    fn from(state: StateColor) -> Self {
+        StateOnOff { state: state.state }
+    }
+}
+
+impl From<StateColor> for StateBrightness {
+    fn from(state: StateColor) -> Self {
+        StateBrightness {
+            state: state.state,
+            brightness: state.brightness,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct ColorTempRange {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl Default for ColorTempRange {
+    fn default() -> Self {
+        Self {
+            min: 2200,
+            max: 4000,
+        }
+    }
+}
+
+impl ColorTempRange {
+    // Zigbee2MQTT expects the color temperature in mireds
+    fn to_mireds(self, kelvin: u32) -> u32 {
+        1_000_000 / kelvin.clamp(self.min, self.max)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerOnBehavior {
+    Off,
+    On,
+    Previous,
+    Toggle,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PowerOnConfig {
+    pub behavior: PowerOnBehavior,
+    // Brightness in percent, only supported by Hue bulbs
+    #[serde(default)]
+    pub brightness: Option<u8>,
+    // Color temperature in mireds
+    #[serde(default)]
+    pub color_temp: Option<u16>,
+}
+
+impl PowerOnConfig {
+    fn message(&self, curve: BrightnessCurve) -> serde_json::Value {
+        let mut message = json!({ "power_on_behavior": self.behavior });
+        if let Some(brightness) = self.brightness {
+            message["hue_power_on_brightness"] = curve.to_raw(brightness).into();
+        }
+        if let Some(color_temp) = self.color_temp {
+            message["color_temp_startup"] = color_temp.into();
+        }
+
+        message
+    }
+}
+
+// How often and how long to wait for the light to become available before giving up on setting the
+// power on behavior
+const POWER_ON_ATTEMPTS: u32 = 3;
+const POWER_ON_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Deserialize)]
+struct PowerOnMessage {
+    power_on_behavior: Option<PowerOnBehavior>,
+}
+
+// Combined change to the light, e.g. to slowly fade in from lua
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SetState {
+    pub state: Option<bool>,
+    // Brightness in percent, same as Google Home
+    pub brightness: Option<u8>,
+    // Overrides the default transition time
+    pub transition: Option<f32>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Light<T: LightState> {
     config: Config<T>,
 
     state: Arc<RwLock<T>>,
+    status: MqttDeviceStatus,
+    // Only warn once about the power on behavior not being applied
+    power_on_warned: Arc<AtomicBool>,
+    timeout: Arc<Mutex<Option<JoinHandle<()>>>>,
 }
 
 pub type LightOnOff = Light<StateOnOff>;
 pub type LightBrightness = Light<StateBrightness>;
+pub type LightColor = Light<StateColor>;
 
 impl<T: LightState> Light<T> {
     async fn state(&self) -> RwLockReadGuard<T> {
@@ -81,25 +320,149 @@ impl<T: LightState> Light<T> {
     async fn state_mut(&self) -> RwLockWriteGuard<T> {
         self.state.write().await
     }
+
+    // Publishes the message on the set topic, with the transition time added
+    async fn publish_set(&self, mut message: serde_json::Value, transition: Option<f32>) {
+        if let Some(transition) = transition.or(self.config.transition) {
+            message["transition"] = transition.into();
+        }
+
+        debug!(id = Device::get_id(self), "{message}");
+
+        let topic = format!("{}/set", self.config.mqtt.topic);
+        // TODO: Handle potential errors here
+        self.config
+            .client
+            .publish(
+                &topic,
+                rumqttc::QoS::AtLeastOnce,
+                false,
+                serde_json::to_string(&message).unwrap(),
+            )
+            .await
+            .map_err(|err| warn!("Failed to update state on {topic}: {err}"))
+            .ok();
+    }
+
+    pub async fn set_state(&self, state: SetState) {
+        let mut message = json!({});
+        if let Some(on) = state.state {
+            message["state"] = if on { "ON" } else { "OFF" }.into();
+        }
+        if let Some(brightness) = state.brightness {
+            message["brightness"] = self.config.brightness_curve.to_raw(brightness).into();
+        }
+
+        self.publish_set(message, state.transition).await;
+    }
+
+    // Push the power on behavior to the light, waiting for it to become available if needed
+    async fn apply_power_on_behavior(&self) {
+        let Some(power_on) = &self.config.power_on_behavior else {
+            return;
+        };
+
+        for attempt in 1..=POWER_ON_ATTEMPTS {
+            // Give the retained availability message a chance to arrive first
+            tokio::time::sleep(POWER_ON_RETRY_DELAY).await;
+
+            if self.status.is_available() {
+                debug!(id = Device::get_id(self), "Setting power on behavior");
+                self.publish_set(power_on.message(self.config.brightness_curve), None)
+                    .await;
+                return;
+            }
+
+            debug!(
+                id = Device::get_id(self),
+                "Light is unavailable, not setting power on behavior ({attempt}/{POWER_ON_ATTEMPTS})"
+            );
+        }
+
+        warn!(
+            id = Device::get_id(self),
+            "Failed to set power on behavior, light is unavailable"
+        );
+    }
+
+    // The light reports the power on behavior as part of its state, if it does not match it
+    // probably does not support it
+    fn check_power_on_behavior(&self, message: &Publish) {
+        let Some(power_on) = &self.config.power_on_behavior else {
+            return;
+        };
+
+        let Ok(PowerOnMessage {
+            power_on_behavior: Some(behavior),
+        }) = serde_json::from_slice(&message.payload)
+        else {
+            return;
+        };
+
+        if behavior != power_on.behavior && !self.power_on_warned.swap(true, Ordering::Relaxed) {
+            warn!(
+                id = Device::get_id(self),
+                "Light reports power on behavior {behavior:?} instead of {:?}, it might not support it",
+                power_on.behavior
+            );
+        }
+    }
 }
 
 #[async_trait]
 impl<T: LightState> LuaDeviceCreate for Light<T> {
     type Config = Config<T>;
-    type Error = rumqttc::ClientError;
+    type Error = DeviceConfigError;
 
     async fn create(config: Self::Config) -> Result<Self, Self::Error> {
         trace!(id = config.info.identifier(), "Setting up IkeaOutlet");
 
+        let ColorTempRange { min, max } = config.color_temp_range;
+        if min == 0 || min >= max {
+            return Err(DeviceConfigError::InvalidValue(
+                "color_temp_range".into(),
+                format!("min ({min}) should be above 0 and less than max ({max})"),
+            ));
+        }
+
         config
             .client
             .subscribe(&config.mqtt.topic, rumqttc::QoS::AtLeastOnce)
             .await?;
+        let status = MqttDeviceStatus::new(config.info.identifier(), &config.mqtt);
+        status.subscribe(&config.client).await?;
+
+        config.client.on_connect(config.on_connect.clone());
+
+        if config.request_state_on_start {
+            let topic = format!("{}/get", config.mqtt.topic);
+            config
+                .client
+                .publish(
+                    &topic,
+                    rumqttc::QoS::AtLeastOnce,
+                    false,
+                    serde_json::to_string(&T::get_message()).unwrap(),
+                )
+                .await
+                .map_err(|err| warn!("Failed to request state on {topic}: {err}"))
+                .ok();
+        }
 
-        Ok(Self {
+        let light = Self {
             config,
             state: Default::default(),
-        })
+            status,
+            power_on_warned: Default::default(),
+            timeout: Default::default(),
+        };
+
+        tokio::spawn({
+            let light = light.clone();
+            async move { light.apply_power_on_behavior().await }
+        });
+
+        Ok(light)
     }
 }
 
@@ -107,13 +470,33 @@ impl<T: LightState> Device for Light<T> {
     fn get_id(&self) -> String {
         self.config.info.identifier()
     }
+
+    fn get_info(&self) -> Option<&InfoConfig> {
+        Some(&self.config.info)
+    }
+}
+
+impl<T: LightState> DeviceAvailability for Light<T> {
+    fn availability(&self) -> &Availability {
+        self.status.availability()
+    }
 }
 
 #[async_trait]
 impl OnMqtt for Light<StateOnOff> {
     async fn on_mqtt(&self, message: Publish) {
+        if self
+            .status
+            .handle_with_callback(self, &message, &self.config.availability_callback)
+            .await
+        {
+            return;
+        }
+
         // Check if the message is from the device itself or from a remote
         if matches(&message.topic, &self.config.mqtt.topic) {
+            self.check_power_on_behavior(&message);
+
             let state = match serde_json::from_slice::<StateOnOff>(&message.payload) {
                 Ok(state) => state,
                 Err(err) => {
@@ -122,6 +505,11 @@ impl OnMqtt for Light<StateOnOff> {
                 }
             };
 
+            // The light was turned off some other way, so the timeout is no longer needed
+            if !state.state {
+                self.stop_timeout().await;
+            }
+
             // No need to do anything if the state has not changed
             if state.state == self.state().await.state {
                 return;
@@ -145,8 +533,18 @@ impl OnMqtt for Light<StateOnOff> {
 #[async_trait]
 impl OnMqtt for Light<StateBrightness> {
     async fn on_mqtt(&self, message: Publish) {
+        if self
+            .status
+            .handle_with_callback(self, &message, &self.config.availability_callback)
+            .await
+        {
+            return;
+        }
+
         // Check if the message is from the deviec itself or from a remote
         if matches(&message.topic, &self.config.mqtt.topic) {
+            self.check_power_on_behavior(&message);
+
             let state = match serde_json::from_slice::<StateBrightness>(&message.payload) {
                 Ok(state) => state,
                 Err(err) => {
@@ -155,6 +553,11 @@ impl OnMqtt for Light<StateBrightness> {
                 }
             };
 
+            // The light was turned off some other way, so the timeout is no longer needed
+            if !state.state {
+                self.stop_timeout().await;
+            }
+
             {
                 let current_state = self.state().await;
                 // No need to do anything if the state has not changed
@@ -198,7 +601,7 @@ impl<T: LightState> google_home::Device for Light<T> {
     }
 
     fn get_device_name(&self) -> device::Name {
-        device::Name::new(&self.config.info.name)
+        self.config.info.device_name()
     }
 
     fn get_id(&self) -> String {
@@ -206,19 +609,81 @@ impl<T: LightState> google_home::Device for Light<T> {
     }
 
     async fn is_online(&self) -> bool {
-        true
+        self.status.is_available()
     }
 
     fn get_room_hint(&self) -> Option<&str> {
         self.config.info.room.as_deref()
     }
 
+    fn get_device_info(&self) -> Option<device::Info> {
+        self.status.device_info()
+    }
+
+    fn get_custom_data(&self) -> Option<serde_json::Value> {
+        self.config.info.custom_data()
+    }
+
     fn will_report_state(&self) -> bool {
         // TODO: Implement state reporting
         false
     }
 }
 
+#[async_trait]
+impl OnMqtt for Light<StateColor> {
+    async fn on_mqtt(&self, message: Publish) {
+        if self
+            .status
+            .handle_with_callback(self, &message, &self.config.availability_callback)
+            .await
+        {
+            return;
+        }
+
+        // Check if the message is from the deviec itself or from a remote
+        if matches(&message.topic, &self.config.mqtt.topic) {
+            self.check_power_on_behavior(&message);
+
+            let state = match serde_json::from_slice::<StateColor>(&message.payload) {
+                Ok(state) => state,
+                Err(err) => {
+                    warn!(id = Device::get_id(self), "Failed to parse message: {err}");
+                    return;
+                }
+            };
+
+            // The light was turned off some other way, so the timeout is no longer needed
+            if !state.state {
+                self.stop_timeout().await;
+            }
+
+            {
+                let current_state = self.state().await;
+                // No need to do anything if the state has not changed
+                if state.state == current_state.state
+                    && state.brightness == current_state.brightness
+                    && state.color == current_state.color
+                {
+                    return;
+                }
+            }
+
+            *self.state_mut().await = state;
+            debug!(
+                id = Device::get_id(self),
+                "Updating state to {:?}",
+                self.state().await
+            );
+
+            self.config
+                .callback
+                .call(self, self.state().await.deref())
+                .await;
+        }
+    }
+}
+
 #[async_trait]
 impl<T> OnOff for Light<T>
 where
@@ -235,27 +700,97 @@ where
             "state": if on { "ON" } else { "OFF"}
         });
 
-        debug!(id = Device::get_id(self), "{message}");
-
-        let topic = format!("{}/set", self.config.mqtt.topic);
-        // TODO: Handle potential errors here
-        self.config
-            .client
-            .publish(
-                &topic,
-                rumqttc::QoS::AtLeastOnce,
-                false,
-                serde_json::to_string(&message).unwrap(),
-            )
-            .await
-            .map_err(|err| warn!("Failed to update state on {topic}: {err}"))
-            .ok();
+        self.publish_set(message, None).await;
 
         Ok(())
     }
 }
 
 const FACTOR: f64 = 30.0;
+const MAX_RAW_BRIGHTNESS: u8 = 254;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(try_from = "RawBrightnessCurve")]
+pub enum BrightnessCurve {
+    // Makes low brightness levels easier to select
+    #[default]
+    Logarithmic,
+    Linear,
+    Gamma(f64),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RawBrightnessCurve {
+    Name(String),
+    Gamma { gamma: f64 },
+}
+
+impl TryFrom<RawBrightnessCurve> for BrightnessCurve {
+    type Error = String;
+
+    fn try_from(curve: RawBrightnessCurve) -> Result<Self, Self::Error> {
+        match curve {
+            RawBrightnessCurve::Name(name) => match name.as_str() {
+                "logarithmic" => Ok(Self::Logarithmic),
+                "linear" => Ok(Self::Linear),
+                _ => Err(format!("Unknown brightness curve '{name}'")),
+            },
+            RawBrightnessCurve::Gamma { gamma } if gamma > 0.0 => Ok(Self::Gamma(gamma)),
+            RawBrightnessCurve::Gamma { gamma } => {
+                Err(format!("Gamma should be larger than 0, got {gamma}"))
+            }
+        }
+    }
+}
+
+impl BrightnessCurve {
+    fn curve(&self, brightness: u8) -> f64 {
+        let brightness = brightness as f64 / 100.0;
+        let max = MAX_RAW_BRIGHTNESS as f64;
+
+        match self {
+            Self::Logarithmic => FACTOR * ((FACTOR / (FACTOR + max)).powf(-brightness) - 1.0),
+            Self::Linear => max * brightness,
+            Self::Gamma(gamma) => max * brightness.powf(*gamma),
+        }
+    }
+
+    // Brightness used by zigbee2mqtt for every percentage, each percentage gets its own level so
+    // that converting back and forth always gives the same percentage
+    fn levels(&self) -> [u8; 101] {
+        let mut levels = [0; 101];
+        for brightness in 1..=100u8 {
+            let previous = levels[brightness as usize - 1];
+            // Leave room for the remaining percentages at the top of the range
+            let max = MAX_RAW_BRIGHTNESS - (100 - brightness);
+            let level = self.curve(brightness).clamp(0.0, max as f64).round() as u8;
+
+            levels[brightness as usize] = level.max(previous + 1);
+        }
+
+        levels
+    }
+
+    // Converts a percentage to the brightness used by zigbee2mqtt
+    fn to_raw(self, brightness: u8) -> u8 {
+        self.levels()[brightness.min(100) as usize]
+    }
+
+    // Converts the brightness reported by zigbee2mqtt to the closest percentage
+    fn to_percentage(self, raw: f64) -> u8 {
+        self.levels()
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| {
+                (**a as f64 - raw)
+                    .abs()
+                    .total_cmp(&(**b as f64 - raw).abs())
+            })
+            .map(|(brightness, _)| brightness as u8)
+            .unwrap_or_default()
+    }
+}
 
 #[async_trait]
 impl<T> Brightness for Light<T>
@@ -266,34 +801,415 @@ where
     async fn brightness(&self) -> Result<u8, ErrorCode> {
         let state = self.state().await;
         let state: StateBrightness = state.deref().clone().into();
-        let brightness =
-            100.0 * f64::log10(state.brightness / FACTOR + 1.0) / f64::log10(254.0 / FACTOR + 1.0);
 
-        Ok(brightness.clamp(0.0, 100.0).round() as u8)
+        Ok(self.config.brightness_curve.to_percentage(state.brightness))
     }
 
     async fn set_brightness(&self, brightness: u8) -> Result<(), ErrorCode> {
-        let brightness =
-            FACTOR * ((FACTOR / (FACTOR + 254.0)).powf(-(brightness as f64) / 100.0) - 1.0);
-
         let message = json!({
-            "brightness": brightness.clamp(0.0, 254.0).round() as u8
+            "brightness": self.config.brightness_curve.to_raw(brightness)
         });
 
-        let topic = format!("{}/set", self.config.mqtt.topic);
-        // TODO: Handle potential errors here
-        self.config
-            .client
-            .publish(
-                &topic,
-                rumqttc::QoS::AtLeastOnce,
-                false,
-                serde_json::to_string(&message).unwrap(),
-            )
-            .await
-            .map_err(|err| warn!("Failed to update state on {topic}: {err}"))
-            .ok();
+        self.publish_set(message, None).await;
 
         Ok(())
     }
 }
+
+#[async_trait]
+impl ColorSetting for Light<StateColor> {
+    fn color_model(&self) -> Option<ColorModel> {
+        Some(ColorModel::Rgb)
+    }
+
+    fn color_temperature_range(&self) -> Option<ColorTemperatureRange> {
+        let ColorTempRange { min, max } = self.config.color_temp_range;
+
+        Some(ColorTemperatureRange {
+            temperature_min_k: min,
+            temperature_max_k: max,
+        })
+    }
+
+    async fn color(&self) -> Result<Color, ErrorCode> {
+        Ok(Color::Rgb(self.state().await.color.unwrap_or_default()))
+    }
+
+    async fn set_color(&self, color: Color) -> Result<(), ErrorCode> {
+        let message = match color {
+            Color::Rgb(color) => json!({ "color": color }),
+            Color::Temperature(kelvin) => {
+                json!({ "color_temp": self.config.color_temp_range.to_mireds(kelvin) })
+            }
+        };
+
+        self.publish_set(message, None).await;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<T: LightState> Timeout for Light<T> {
+    async fn start_timeout(&self, timeout: Duration) {
+        let mut handle = self.timeout.lock().await;
+        if let Some(handle) = handle.take() {
+            handle.abort();
+        }
+
+        debug!(id = Device::get_id(self), "Turning off in {timeout:?}");
+        let light = self.clone();
+        *handle = Some(tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            debug!(id = Device::get_id(&light), "Timeout elapsed, turning off");
+            light.set_on(false).await.ok();
+        }));
+    }
+
+    async fn stop_timeout(&self) {
+        if let Some(handle) = self.timeout.lock().await.take() {
+            handle.abort();
+        }
+    }
+}
+
+#[async_trait]
+impl<T> LightEffect for Light<T>
+where
+    T: LightState,
+{
+    async fn effect(&self, effect: Effect) -> Result<(), ErrorCode> {
+        let message = json!({ "effect": effect });
+
+        self.publish_set(message, None).await;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automation_lib::event::EventChannel;
+    use automation_lib::lua::testing::MockMqttClient;
+    use rumqttc::QoS;
+
+    use super::*;
+
+    fn config<T: LightState>(client: &MockMqttClient) -> Config<T> {
+        Config {
+            info: InfoConfig::new("Light"),
+            mqtt: MqttDeviceConfig {
+                topic: "zigbee2mqtt/light".into(),
+                availability: None,
+            },
+            transition: None,
+            power_on_behavior: None,
+            color_temp_range: Default::default(),
+            brightness_curve: Default::default(),
+            request_state_on_start: false,
+            callback: Default::default(),
+            availability_callback: Default::default(),
+            on_connect: Default::default(),
+            client: client.client(),
+        }
+    }
+
+    async fn light(client: &MockMqttClient) -> LightOnOff {
+        LightOnOff::create(config(client)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn availability() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let light = light(&client).await;
+
+        assert!(google_home::Device::is_online(&light).await);
+
+        light
+            .on_mqtt(Publish::new(
+                "zigbee2mqtt/light/availability",
+                QoS::AtLeastOnce,
+                "offline",
+            ))
+            .await;
+        assert!(!google_home::Device::is_online(&light).await);
+
+        light
+            .on_mqtt(Publish::new(
+                "zigbee2mqtt/light/availability",
+                QoS::AtLeastOnce,
+                r#"{"state":"online"}"#,
+            ))
+            .await;
+        assert!(google_home::Device::is_online(&light).await);
+
+        // Availability messages should not be treated as state updates
+        assert!(!light.state().await.state);
+    }
+
+    #[tokio::test]
+    async fn color() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let light = LightColor::create(config(&client)).await.unwrap();
+
+        light
+            .on_mqtt(Publish::new(
+                "zigbee2mqtt/light",
+                QoS::AtLeastOnce,
+                r#"{"brightness":254,"color":{"hue":0,"saturation":100,"x":0.7006,"y":0.2993},"color_mode":"xy","color_temp":500,"linkquality":120,"state":"ON","update":{"installed_version":16777231,"latest_version":16777231,"state":"idle"}}"#,
+            ))
+            .await;
+
+        assert_eq!(
+            light.color().await.unwrap(),
+            Color::Rgb(ColorRGB { r: 255, g: 0, b: 0 })
+        );
+        assert!(light.on().await.unwrap());
+
+        // Lights in hs mode only report hue and saturation
+        light
+            .on_mqtt(Publish::new(
+                "zigbee2mqtt/light",
+                QoS::AtLeastOnce,
+                r#"{"brightness":254,"color":{"hue":240,"saturation":100},"color_mode":"hs","linkquality":87,"state":"ON"}"#,
+            ))
+            .await;
+        assert_eq!(
+            light.color().await.unwrap(),
+            Color::Rgb(ColorRGB { r: 0, g: 0, b: 255 })
+        );
+
+        // An unknown color format does not prevent the state from updating
+        light
+            .on_mqtt(Publish::new(
+                "zigbee2mqtt/light",
+                QoS::AtLeastOnce,
+                r#"{"brightness":254,"color":"unknown","state":"OFF"}"#,
+            ))
+            .await;
+        assert!(!light.on().await.unwrap());
+
+        light.set_color(Color::Rgb(0x0000FF.into())).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        let (topic, payload) = client.published().pop().unwrap();
+        assert_eq!(topic, "zigbee2mqtt/light/set");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&payload).unwrap(),
+            json!({ "color": { "r": 0, "g": 0, "b": 255 } })
+        );
+
+        light.set_color(Color::Temperature(2500)).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        let (_, payload) = client.published().pop().unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&payload).unwrap(),
+            json!({ "color_temp": 400 })
+        );
+
+        // Clamped to the supported range
+        light.set_color(Color::Temperature(6500)).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        let (_, payload) = client.published().pop().unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&payload).unwrap(),
+            json!({ "color_temp": 250 })
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_color_temp_range() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let light = LightColor::create(Config {
+            color_temp_range: ColorTempRange {
+                min: 6500,
+                max: 2700,
+            },
+            ..config(&client)
+        })
+        .await;
+
+        assert!(matches!(
+            light,
+            Err(DeviceConfigError::InvalidValue(field, _)) if field == "color_temp_range"
+        ));
+    }
+
+    #[tokio::test]
+    async fn device_info() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let light = light(&client).await;
+
+        assert!(google_home::Device::get_device_info(&light).is_none());
+
+        light
+            .on_mqtt(Publish::new(
+                "zigbee2mqtt/light/$info",
+                QoS::AtLeastOnce,
+                r#"{"ieee_address":"0x90fd9ffffe6494fc","manufacturer":"IKEA of Sweden","model_id":"LED1836G9","software_build_id":"2.3.093","date_code":"20190925"}"#,
+            ))
+            .await;
+
+        // Only the first message is used
+        light
+            .on_mqtt(Publish::new(
+                "zigbee2mqtt/light/$info",
+                QoS::AtLeastOnce,
+                r#"{"manufacturer":"Other"}"#,
+            ))
+            .await;
+
+        let info = google_home::Device::get_device_info(&light).unwrap();
+        assert_eq!(info.manufacturer.as_deref(), Some("IKEA of Sweden"));
+        assert_eq!(info.model.as_deref(), Some("LED1836G9"));
+        assert_eq!(info.sw_version.as_deref(), Some("2.3.093"));
+        assert_eq!(info.serial_number.as_deref(), Some("0x90fd9ffffe6494fc"));
+    }
+
+    #[tokio::test]
+    async fn transition() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let light = LightBrightness::create(Config {
+            transition: Some(0.5),
+            ..config(&client)
+        })
+        .await
+        .unwrap();
+
+        let published = || {
+            let (_, payload) = client.published().pop().unwrap();
+            serde_json::from_str::<serde_json::Value>(&payload).unwrap()
+        };
+
+        light.set_on(true).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        assert_eq!(published(), json!({ "state": "ON", "transition": 0.5 }));
+
+        light
+            .set_state(SetState {
+                state: Some(true),
+                brightness: Some(100),
+                transition: Some(5.0),
+            })
+            .await;
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        assert_eq!(
+            published(),
+            json!({ "state": "ON", "brightness": 254, "transition": 5.0 })
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn power_on_behavior() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let light = LightOnOff::create(Config {
+            power_on_behavior: Some(PowerOnConfig {
+                behavior: PowerOnBehavior::Previous,
+                brightness: None,
+                color_temp: Some(370),
+            }),
+            ..config(&client)
+        })
+        .await
+        .unwrap();
+
+        // Not sent while the light is unavailable
+        light
+            .on_mqtt(Publish::new(
+                "zigbee2mqtt/light/availability",
+                QoS::AtLeastOnce,
+                "offline",
+            ))
+            .await;
+        tokio::time::sleep(POWER_ON_RETRY_DELAY + Duration::from_millis(10)).await;
+        assert!(client.published().is_empty());
+
+        light
+            .on_mqtt(Publish::new(
+                "zigbee2mqtt/light/availability",
+                QoS::AtLeastOnce,
+                "online",
+            ))
+            .await;
+        tokio::time::sleep(POWER_ON_RETRY_DELAY).await;
+        let (topic, payload) = client.published().pop().unwrap();
+        assert_eq!(topic, "zigbee2mqtt/light/set");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&payload).unwrap(),
+            json!({ "power_on_behavior": "previous", "color_temp_startup": 370 })
+        );
+
+        light
+            .on_mqtt(Publish::new(
+                "zigbee2mqtt/light",
+                QoS::AtLeastOnce,
+                r#"{"state":"ON","power_on_behavior":"on"}"#,
+            ))
+            .await;
+        assert!(light.power_on_warned.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn request_state_on_start() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        LightColor::create(Config {
+            request_state_on_start: true,
+            ..config(&client)
+        })
+        .await
+        .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let (topic, payload) = client.published().pop().unwrap();
+        assert_eq!(topic, "zigbee2mqtt/light/get");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&payload).unwrap(),
+            json!({ "state": "", "brightness": "", "color": "", "color_temp": "" })
+        );
+
+        // Nothing is requested when disabled
+        let client = MockMqttClient::new(EventChannel::new().0);
+        light(&client).await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(client.published().is_empty());
+    }
+
+    #[test]
+    fn brightness_curve() {
+        let curves: Vec<BrightnessCurve> = serde_json::from_value(json!([
+            "logarithmic",
+            "linear",
+            { "gamma": 2.2 },
+            { "gamma": 0.5 },
+        ]))
+        .unwrap();
+        assert_eq!(
+            curves,
+            vec![
+                BrightnessCurve::Logarithmic,
+                BrightnessCurve::Linear,
+                BrightnessCurve::Gamma(2.2),
+                BrightnessCurve::Gamma(0.5),
+            ]
+        );
+
+        for curve in curves {
+            assert_eq!(curve.to_raw(0), 0);
+            assert_eq!(curve.to_raw(100), MAX_RAW_BRIGHTNESS);
+
+            for brightness in 0..=100 {
+                let raw = curve.to_raw(brightness);
+                assert_eq!(curve.to_percentage(raw as f64), brightness, "{curve:?}");
+            }
+        }
+
+        assert!(serde_json::from_value::<BrightnessCurve>(json!("cubic")).is_err());
+        assert!(serde_json::from_value::<BrightnessCurve>(json!({ "gamma": 0.0 })).is_err());
+    }
+}
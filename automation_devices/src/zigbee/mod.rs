@@ -1,2 +1,11 @@
+pub mod blind;
+pub mod bridge;
+pub mod climate;
+pub mod dehumidifier;
+pub mod leak;
 pub mod light;
+pub mod motion;
 pub mod outlet;
+pub mod remote;
+pub mod smoke;
+pub mod valve;
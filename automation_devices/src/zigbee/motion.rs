@@ -0,0 +1,337 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use automation_lib::action_callback::ActionCallback;
+use automation_lib::availability::{Availability, MqttDeviceStatus};
+use automation_lib::config::{InfoConfig, MqttDeviceConfig};
+use automation_lib::device::{Device, DeviceAvailability, LuaDeviceCreate};
+use automation_lib::event::OnMqtt;
+use automation_lib::mqtt::WrappedAsyncClient;
+use automation_macro::LuaDeviceConfig;
+use google_home::device;
+use google_home::errors::ErrorCode;
+use google_home::traits::{
+    Occupancy, OccupancySensing, OccupancySensorConfiguration, OccupancySensorType,
+};
+use google_home::types::Type;
+use rumqttc::{matches, Publish};
+use serde::Deserialize;
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::task::JoinHandle;
+use tracing::{debug, trace, warn};
+
+#[derive(Debug, Clone, LuaDeviceConfig)]
+pub struct Config {
+    #[device_config(flatten)]
+    pub info: InfoConfig,
+    #[device_config(flatten)]
+    pub mqtt: MqttDeviceConfig,
+
+    // Only report that the room is clear after it has been clear for this long, many sensors
+    // briefly report no occupancy while somebody is still there
+    #[device_config(rename("clear_delay_seconds"), default, with(|s: Option<u64>| s.map(Duration::from_secs)))]
+    pub clear_delay: Option<Duration>,
+
+    // Called when the occupancy changes
+    #[device_config(from_lua, default)]
+    pub callback: ActionCallback<MotionSensor, bool>,
+    // Called when the sensor becomes available or unavailable
+    #[device_config(from_lua, default)]
+    pub availability_callback: ActionCallback<MotionSensor, bool>,
+
+    #[device_config(from_lua)]
+    pub client: WrappedAsyncClient,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct MotionMessage {
+    occupancy: Option<bool>,
+    illuminance: Option<f64>,
+    battery: Option<f64>,
+}
+
+#[derive(Debug, Default)]
+pub struct State {
+    occupancy: bool,
+    illuminance: Option<f64>,
+    battery: Option<f64>,
+    // Pending transition to unoccupied
+    clear_handle: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MotionSensor {
+    config: Config,
+
+    state: Arc<RwLock<State>>,
+    status: MqttDeviceStatus,
+}
+
+impl MotionSensor {
+    async fn state(&self) -> RwLockReadGuard<State> {
+        self.state.read().await
+    }
+
+    async fn state_mut(&self) -> RwLockWriteGuard<State> {
+        self.state.write().await
+    }
+
+    pub async fn occupancy(&self) -> bool {
+        self.state().await.occupancy
+    }
+
+    pub async fn illuminance(&self) -> Option<f64> {
+        self.state().await.illuminance
+    }
+
+    pub async fn battery(&self) -> Option<f64> {
+        self.state().await.battery
+    }
+
+    async fn set_occupancy(&self, occupancy: bool) {
+        let mut state = self.state_mut().await;
+        if let Some(handle) = state.clear_handle.take() {
+            handle.abort();
+        }
+
+        if occupancy == state.occupancy {
+            return;
+        }
+
+        match (occupancy, self.config.clear_delay) {
+            (false, Some(delay)) => {
+                trace!(id = Device::get_id(self), "Clearing occupancy in {delay:?}");
+                let device = self.clone();
+                state.clear_handle = Some(tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+
+                    let mut state = device.state_mut().await;
+                    state.clear_handle = None;
+                    state.occupancy = false;
+                    drop(state);
+
+                    debug!(id = Device::get_id(&device), "Occupancy = false");
+                    device.config.callback.call(&device, &false).await;
+                }));
+            }
+            (occupancy, _) => {
+                state.occupancy = occupancy;
+                drop(state);
+
+                debug!(id = Device::get_id(self), "Occupancy = {occupancy}");
+                self.config.callback.call(self, &occupancy).await;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl LuaDeviceCreate for MotionSensor {
+    type Config = Config;
+    type Error = rumqttc::ClientError;
+
+    async fn create(config: Self::Config) -> Result<Self, Self::Error> {
+        trace!(id = config.info.identifier(), "Setting up MotionSensor");
+
+        config
+            .client
+            .subscribe(&config.mqtt.topic, rumqttc::QoS::AtLeastOnce)
+            .await?;
+        let status = MqttDeviceStatus::new(config.info.identifier(), &config.mqtt);
+        status.subscribe(&config.client).await?;
+
+        Ok(Self {
+            config,
+            state: Default::default(),
+            status,
+        })
+    }
+}
+
+impl Device for MotionSensor {
+    fn get_id(&self) -> String {
+        self.config.info.identifier()
+    }
+
+    fn get_info(&self) -> Option<&InfoConfig> {
+        Some(&self.config.info)
+    }
+}
+
+impl DeviceAvailability for MotionSensor {
+    fn availability(&self) -> &Availability {
+        self.status.availability()
+    }
+}
+
+#[async_trait]
+impl OnMqtt for MotionSensor {
+    async fn on_mqtt(&self, message: Publish) {
+        if self
+            .status
+            .handle_with_callback(self, &message, &self.config.availability_callback)
+            .await
+        {
+            return;
+        }
+
+        if !matches(&message.topic, &self.config.mqtt.topic) {
+            return;
+        }
+
+        let message = match serde_json::from_slice::<MotionMessage>(&message.payload) {
+            Ok(message) => message,
+            Err(err) => {
+                warn!(id = Device::get_id(self), "Failed to parse message: {err}");
+                return;
+            }
+        };
+
+        {
+            let mut state = self.state_mut().await;
+            if message.illuminance.is_some() {
+                state.illuminance = message.illuminance;
+            }
+            if message.battery.is_some() {
+                state.battery = message.battery;
+            }
+        }
+
+        if let Some(occupancy) = message.occupancy {
+            self.set_occupancy(occupancy).await;
+        }
+    }
+}
+
+#[async_trait]
+impl google_home::Device for MotionSensor {
+    fn get_device_type(&self) -> Type {
+        Type::Sensor
+    }
+
+    fn get_device_name(&self) -> device::Name {
+        self.config.info.device_name()
+    }
+
+    fn get_id(&self) -> String {
+        Device::get_id(self)
+    }
+
+    async fn is_online(&self) -> bool {
+        self.status.is_available()
+    }
+
+    fn get_room_hint(&self) -> Option<&str> {
+        self.config.info.room.as_deref()
+    }
+
+    fn get_device_info(&self) -> Option<device::Info> {
+        self.status.device_info()
+    }
+
+    fn get_custom_data(&self) -> Option<serde_json::Value> {
+        self.config.info.custom_data()
+    }
+}
+
+#[async_trait]
+impl OccupancySensing for MotionSensor {
+    fn occupancy_sensor_configuration(&self) -> Vec<OccupancySensorConfiguration> {
+        vec![OccupancySensorConfiguration {
+            occupancy_sensor_type: OccupancySensorType::Pir,
+            occupied_to_unoccupied_delay_sec: self
+                .config
+                .clear_delay
+                .map(|delay| delay.as_secs() as u32),
+        }]
+    }
+
+    async fn occupancy(&self) -> Result<Occupancy, ErrorCode> {
+        Ok(if self.occupancy().await {
+            Occupancy::Occupied
+        } else {
+            Occupancy::Unoccupied
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automation_lib::event::EventChannel;
+    use automation_lib::lua::testing::MockMqttClient;
+    use rumqttc::QoS;
+
+    use super::*;
+
+    async fn sensor(client: &MockMqttClient, clear_delay: Option<Duration>) -> MotionSensor {
+        MotionSensor::create(Config {
+            info: InfoConfig::new("Motion"),
+            mqtt: MqttDeviceConfig {
+                topic: "zigbee2mqtt/motion".into(),
+                availability: None,
+            },
+            clear_delay,
+            callback: Default::default(),
+            availability_callback: Default::default(),
+            client: client.client(),
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn publish(sensor: &MotionSensor, payload: &str) {
+        sensor
+            .on_mqtt(Publish::new(
+                "zigbee2mqtt/motion",
+                QoS::AtLeastOnce,
+                payload,
+            ))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn occupancy() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let sensor = sensor(&client, None).await;
+
+        publish(
+            &sensor,
+            r#"{"occupancy":true,"illuminance":120,"battery":100,"linkquality":80}"#,
+        )
+        .await;
+        assert!(sensor.occupancy().await);
+        assert_eq!(sensor.illuminance().await, Some(120.0));
+        assert_eq!(sensor.battery().await, Some(100.0));
+        assert_eq!(
+            OccupancySensing::occupancy(&sensor).await,
+            Ok(Occupancy::Occupied)
+        );
+
+        publish(&sensor, r#"{"occupancy":false}"#).await;
+        assert!(!sensor.occupancy().await);
+        // Values that are not part of the message are kept
+        assert_eq!(sensor.illuminance().await, Some(120.0));
+    }
+
+    #[tokio::test]
+    async fn clear_delay() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let sensor = sensor(&client, Some(Duration::from_millis(50))).await;
+
+        publish(&sensor, r#"{"occupancy":true}"#).await;
+        publish(&sensor, r#"{"occupancy":false}"#).await;
+        assert!(sensor.occupancy().await);
+
+        // Motion within the delay keeps the room occupied
+        publish(&sensor, r#"{"occupancy":true}"#).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(sensor.occupancy().await);
+
+        publish(&sensor, r#"{"occupancy":false}"#).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!sensor.occupancy().await);
+    }
+}
@@ -1,16 +1,23 @@
 use std::fmt::Debug;
 use std::ops::Deref;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Result;
 use async_trait::async_trait;
 use automation_lib::action_callback::ActionCallback;
+use automation_lib::availability::{Availability, MqttDeviceStatus};
 use automation_lib::config::{InfoConfig, MqttDeviceConfig};
-use automation_lib::device::{Device, LuaDeviceCreate};
+use automation_lib::device::{Device, DeviceAvailability, LuaDeviceCreate};
 use automation_lib::event::{OnMqtt, OnPresence};
 use automation_lib::helpers::serialization::state_deserializer;
+use automation_lib::helpers::weak::spawn_weak;
+use automation_lib::lua::traits::Timeout;
+use automation_lib::metrics;
 use automation_lib::mqtt::WrappedAsyncClient;
 use automation_macro::LuaDeviceConfig;
+use chrono::{DateTime, Local, TimeDelta, TimeZone};
+use futures::StreamExt;
 use google_home::device;
 use google_home::errors::ErrorCode;
 use google_home::traits::OnOff;
@@ -18,12 +25,16 @@ use google_home::types::Type;
 use rumqttc::{matches, Publish};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
 use tracing::{debug, trace, warn};
 
 pub trait OutletState:
     Debug + Clone + Default + Sync + Send + Serialize + Into<StateOnOff> + 'static
 {
+    // Outlets that report their power usage also keep track of the energy used
+    const TRACKS_ENERGY: bool = false;
 }
 
 #[derive(Debug, Clone, Deserialize, PartialEq, Eq, Copy)]
@@ -54,9 +65,57 @@ pub struct Config<T: OutletState> {
     #[device_config(default(true))]
     pub presence_auto_off: bool,
 
+    // Turn the outlet off again after it has been on for this long, e.g. for a kettle
+    #[device_config(rename("timeout_seconds"), default, with(|t: Option<u64>| t.map(Duration::from_secs)))]
+    pub timeout: Option<Duration>,
+
+    // Local hour at which the energy used today is reset
+    #[device_config(default)]
+    pub energy_rollover_hour: u32,
+
+    // Power in Watt, the appliance is considered running above this, detection is disabled when
+    // not set
+    #[device_config(default)]
+    pub on_threshold_w: Option<f64>,
+    // Power in Watt, the appliance is considered finished below this, defaults to the on threshold
+    #[device_config(default)]
+    pub off_threshold_w: Option<f64>,
+    // Time the power needs to stay above the on threshold before the appliance has started
+    #[device_config(
+        rename("min_on_duration_seconds"),
+        default(30),
+        with(Duration::from_secs)
+    )]
+    pub min_on_duration: Duration,
+    // Time the power needs to stay below the off threshold before the appliance has finished
+    #[device_config(
+        rename("min_off_duration_seconds"),
+        default(180),
+        with(Duration::from_secs)
+    )]
+    pub min_off_duration: Duration,
+
     #[device_config(from_lua, default)]
     pub callback: ActionCallback<Outlet<T>, T>,
 
+    // Called when the device becomes available or unavailable
+    #[device_config(from_lua, default)]
+    pub availability_callback: ActionCallback<Outlet<T>, bool>,
+
+    // Called at the daily rollover with the energy used in kWh during the past day
+    #[device_config(from_lua, default)]
+    pub daily_report_callback: ActionCallback<Outlet<T>, f64>,
+
+    // Called with the current power when the appliance connected to the outlet starts or finishes
+    #[device_config(from_lua, default)]
+    pub appliance_started: ActionCallback<Outlet<T>, f64>,
+    #[device_config(from_lua, default)]
+    pub appliance_finished: ActionCallback<Outlet<T>, f64>,
+
+    // Called every time the mqtt client (re)connects to the broker
+    #[device_config(from_lua, default)]
+    pub on_connect: ActionCallback<WrappedAsyncClient, ()>,
+
     #[device_config(from_lua)]
     pub client: WrappedAsyncClient,
 }
@@ -74,9 +133,165 @@ pub struct StatePower {
     #[serde(deserialize_with = "state_deserializer")]
     state: bool,
     power: f64,
+    // Energy meter in kWh, only reported by some outlets
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    energy: Option<f64>,
+}
+
+impl OutletState for StatePower {
+    const TRACKS_ENERGY: bool = true;
+}
+
+// Energy usage is only kept in memory, so the counters start from zero after a restart
+#[derive(Debug, Default)]
+struct Energy {
+    total_kwh: f64,
+    today_kwh: f64,
+    // Power in W at the time of the previous message
+    last_sample: Option<(Instant, f64)>,
+    last_meter: Option<f64>,
 }
 
-impl OutletState for StatePower {}
+impl Energy {
+    fn add(&mut self, kwh: f64) {
+        self.total_kwh += kwh;
+        self.today_kwh += kwh;
+    }
+
+    fn update(&mut self, power: f64, meter: Option<f64>, now: Instant) {
+        if let Some(meter) = meter {
+            // Prefer the meter of the outlet itself, a decrease means the meter was reset
+            match self.last_meter {
+                Some(last_meter) if meter >= last_meter => self.add(meter - last_meter),
+                _ => {}
+            }
+            self.last_meter = Some(meter);
+        } else if let Some((last_time, last_power)) = self.last_sample {
+            let hours = (now - last_time).as_secs_f64() / 3600.0;
+            self.add((last_power + power) / 2.0 * hours / 1000.0);
+        }
+
+        self.last_sample = Some((now, power));
+    }
+
+    // Resets the energy used today and returns the previous value
+    fn rollover(&mut self) -> f64 {
+        std::mem::take(&mut self.today_kwh)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Thresholds {
+    on: f64,
+    off: f64,
+    min_on_duration: Duration,
+    min_off_duration: Duration,
+}
+
+impl<T: OutletState> From<&Config<T>> for Option<Thresholds> {
+    fn from(config: &Config<T>) -> Self {
+        let on = config.on_threshold_w?;
+
+        Some(Thresholds {
+            on,
+            off: config.off_threshold_w.unwrap_or(on),
+            min_on_duration: config.min_on_duration,
+            min_off_duration: config.min_off_duration,
+        })
+    }
+}
+
+// Detects when an appliance starts and finishes based on its power usage, the power needs to stay
+// beyond the threshold for a while so short spikes and dips are ignored
+#[derive(Debug)]
+struct Appliance {
+    thresholds: Thresholds,
+    running: bool,
+    // Since when the power has been beyond the threshold for the opposite state
+    candidate: Option<Instant>,
+    power: f64,
+}
+
+impl Appliance {
+    fn new(thresholds: Thresholds) -> Self {
+        Self {
+            thresholds,
+            running: false,
+            candidate: None,
+            power: 0.0,
+        }
+    }
+
+    fn dwell(&self) -> Duration {
+        if self.running {
+            self.thresholds.min_off_duration
+        } else {
+            self.thresholds.min_on_duration
+        }
+    }
+
+    // Returns the new state if the appliance started or finished
+    fn update(&mut self, power: f64, now: Instant) -> Option<bool> {
+        self.power = power;
+
+        let beyond = if self.running {
+            power < self.thresholds.off
+        } else {
+            power > self.thresholds.on
+        };
+
+        if !beyond {
+            self.candidate = None;
+            return None;
+        }
+
+        self.candidate.get_or_insert(now);
+
+        self.check(now)
+    }
+
+    // Time left before the pending change happens if no other reading is received
+    fn pending(&self, now: Instant) -> Option<Duration> {
+        let since = self.candidate?;
+
+        Some(self.dwell().saturating_sub(now.duration_since(since)))
+    }
+
+    fn check(&mut self, now: Instant) -> Option<bool> {
+        let since = self.candidate?;
+
+        if now.duration_since(since) < self.dwell() {
+            return None;
+        }
+
+        self.candidate = None;
+        self.running = !self.running;
+
+        Some(self.running)
+    }
+}
+
+#[derive(Debug)]
+struct ApplianceState {
+    appliance: Appliance,
+    // Completes the pending change when no new readings are received, some plugs only report
+    // changes so they go quiet once the appliance is done
+    handle: Option<JoinHandle<()>>,
+}
+
+// Time until the next rollover at the given local hour
+fn until_rollover(hour: u32, now: DateTime<Local>) -> Duration {
+    let next = (0..=1)
+        .filter_map(|days| {
+            let date = now.date_naive() + TimeDelta::days(days);
+            let time = date.and_hms_opt(hour, 0, 0)?;
+            Local.from_local_datetime(&time).earliest()
+        })
+        .find(|next| *next > now);
+
+    next.and_then(|next| (next - now).to_std().ok())
+        .unwrap_or(Duration::from_secs(24 * 60 * 60))
+}
 
 impl From<StatePower> for StateOnOff {
     fn from(state: StatePower) -> Self {
@@ -84,11 +299,19 @@ impl From<StatePower> for StateOnOff {
     }
 }
 
+#[derive(Debug)]
+struct State<T: OutletState> {
+    current: RwLock<T>,
+    status: MqttDeviceStatus,
+    energy: RwLock<Energy>,
+    appliance: Option<RwLock<ApplianceState>>,
+    timeout: Mutex<Option<JoinHandle<()>>>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Outlet<T: OutletState> {
     config: Config<T>,
-
-    state: Arc<RwLock<T>>,
+    state: Arc<State<T>>,
 }
 
 pub type OutletOnOff = Outlet<StateOnOff>;
@@ -96,16 +319,66 @@ pub type OutletPower = Outlet<StatePower>;
 
 impl<T: OutletState> Outlet<T> {
     async fn state(&self) -> RwLockReadGuard<T> {
-        self.state.read().await
+        self.state.current.read().await
     }
 
     async fn state_mut(&self) -> RwLockWriteGuard<T> {
-        self.state.write().await
+        self.state.current.write().await
+    }
+
+    // Starts the configured timeout when the outlet turns on and stops it when it turns off
+    async fn update_timeout(&self, on: bool) {
+        match (on, self.config.timeout) {
+            (true, Some(timeout)) => self.start_timeout(timeout).await,
+            (false, _) => self.stop_timeout().await,
+            _ => {}
+        }
+    }
+
+    // Energy used since the last rollover in kWh
+    pub async fn energy_today(&self) -> f64 {
+        self.state.energy.read().await.today_kwh
+    }
+
+    // Energy used since the automation system was started in kWh
+    pub async fn energy_total(&self) -> f64 {
+        self.state.energy.read().await.total_kwh
+    }
+
+    // Rebuilds the device in the background task, see automation_lib::helpers::weak
+    fn downgrade(&self) -> impl Fn() -> Option<Self> + Send + 'static {
+        let config = self.config.clone();
+        let state = Arc::downgrade(&self.state);
+
+        move || {
+            Some(Self {
+                config: config.clone(),
+                state: state.upgrade()?,
+            })
+        }
+    }
+
+    async fn rollover(&self)
+    where
+        Self: mlua::IntoLua,
+    {
+        let yesterday = self.state.energy.write().await.rollover();
+        debug!(
+            id = Device::get_id(self),
+            "Energy used today: {yesterday}kWh"
+        );
+        self.config
+            .daily_report_callback
+            .call(self, &yesterday)
+            .await;
     }
 }
 
 #[async_trait]
-impl<T: OutletState> LuaDeviceCreate for Outlet<T> {
+impl<T: OutletState> LuaDeviceCreate for Outlet<T>
+where
+    Self: mlua::IntoLua,
+{
     type Config = Config<T>;
     type Error = rumqttc::ClientError;
 
@@ -116,11 +389,41 @@ impl<T: OutletState> LuaDeviceCreate for Outlet<T> {
             .client
             .subscribe(&config.mqtt.topic, rumqttc::QoS::AtLeastOnce)
             .await?;
+        let status = MqttDeviceStatus::new(config.info.identifier(), &config.mqtt);
+        status.subscribe(&config.client).await?;
+
+        config.client.on_connect(config.on_connect.clone());
+
+        let appliance = Option::<Thresholds>::from(&config)
+            .filter(|_| T::TRACKS_ENERGY)
+            .map(|thresholds| {
+                RwLock::new(ApplianceState {
+                    appliance: Appliance::new(thresholds),
+                    handle: None,
+                })
+            });
+
+        let state = Arc::new(State {
+            current: Default::default(),
+            status,
+            energy: Default::default(),
+            appliance,
+            timeout: Default::default(),
+        });
 
-        Ok(Self {
-            config,
-            state: Default::default(),
-        })
+        let outlet = Self { config, state };
+
+        if T::TRACKS_ENERGY {
+            let hour = outlet.config.energy_rollover_hour;
+            let rollovers = futures::stream::repeat(())
+                .then(move |_| tokio::time::sleep(until_rollover(hour, Local::now())));
+
+            spawn_weak(outlet.downgrade(), rollovers, |outlet, _| async move {
+                outlet.rollover().await
+            });
+        }
+
+        Ok(outlet)
     }
 }
 
@@ -128,11 +431,30 @@ impl<T: OutletState> Device for Outlet<T> {
     fn get_id(&self) -> String {
         self.config.info.identifier()
     }
+
+    fn get_info(&self) -> Option<&InfoConfig> {
+        Some(&self.config.info)
+    }
+}
+
+impl<T: OutletState> DeviceAvailability for Outlet<T> {
+    fn availability(&self) -> &Availability {
+        self.state.status.availability()
+    }
 }
 
 #[async_trait]
 impl OnMqtt for Outlet<StateOnOff> {
     async fn on_mqtt(&self, message: Publish) {
+        if self
+            .state
+            .status
+            .handle_with_callback(self, &message, &self.config.availability_callback)
+            .await
+        {
+            return;
+        }
+
         // Check if the message is from the device itself or from a remote
         if matches(&message.topic, &self.config.mqtt.topic) {
             let state = match serde_json::from_slice::<StateOnOff>(&message.payload) {
@@ -149,6 +471,7 @@ impl OnMqtt for Outlet<StateOnOff> {
             }
 
             self.state_mut().await.state = state.state;
+            self.update_timeout(state.state).await;
             debug!(
                 id = Device::get_id(self),
                 "Updating state to {:?}",
@@ -166,6 +489,15 @@ impl OnMqtt for Outlet<StateOnOff> {
 #[async_trait]
 impl OnMqtt for Outlet<StatePower> {
     async fn on_mqtt(&self, message: Publish) {
+        if self
+            .state
+            .status
+            .handle_with_callback(self, &message, &self.config.availability_callback)
+            .await
+        {
+            return;
+        }
+
         // Check if the message is from the deviec itself or from a remote
         if matches(&message.topic, &self.config.mqtt.topic) {
             let state = match serde_json::from_slice::<StatePower>(&message.payload) {
@@ -176,6 +508,15 @@ impl OnMqtt for Outlet<StatePower> {
                 }
             };
 
+            let total_kwh = {
+                let mut energy = self.state.energy.write().await;
+                energy.update(state.power, state.energy, Instant::now());
+                energy.total_kwh
+            };
+            metrics::set_outlet_energy(&Device::get_id(self), total_kwh * 1000.0);
+
+            self.update_appliance(state.power).await;
+
             {
                 let current_state = self.state().await;
                 // No need to do anything if the state has not changed
@@ -184,6 +525,9 @@ impl OnMqtt for Outlet<StatePower> {
                 }
             }
 
+            if state.state != self.state().await.state {
+                self.update_timeout(state.state).await;
+            }
             self.state_mut().await.state = state.state;
             self.state_mut().await.power = state.power;
             debug!(
@@ -200,6 +544,68 @@ impl OnMqtt for Outlet<StatePower> {
     }
 }
 
+impl OutletPower {
+    async fn update_appliance(&self, power: f64) {
+        let Some(appliance) = &self.state.appliance else {
+            return;
+        };
+
+        let running = {
+            let mut state = appliance.write().await;
+            let candidate = state.appliance.candidate;
+            let running = state.appliance.update(power, Instant::now());
+
+            // Restart the timer whenever the pending change starts or stops
+            if state.appliance.candidate != candidate {
+                if let Some(handle) = state.handle.take() {
+                    handle.abort();
+                }
+
+                if let Some(timeout) = state.appliance.pending(Instant::now()) {
+                    let device = self.clone();
+                    state.handle = Some(tokio::spawn(async move {
+                        tokio::time::sleep(timeout).await;
+                        device.check_appliance().await;
+                    }));
+                }
+            }
+
+            running
+        };
+
+        if let Some(running) = running {
+            self.appliance_changed(running, power).await;
+        }
+    }
+
+    async fn check_appliance(&self) {
+        let Some(appliance) = &self.state.appliance else {
+            return;
+        };
+
+        let (running, power) = {
+            let mut state = appliance.write().await;
+            // NOTE: We are running inside the pending task, so we can not abort it here
+            state.handle = None;
+            (state.appliance.check(Instant::now()), state.appliance.power)
+        };
+
+        if let Some(running) = running {
+            self.appliance_changed(running, power).await;
+        }
+    }
+
+    async fn appliance_changed(&self, running: bool, power: f64) {
+        if running {
+            debug!(id = Device::get_id(self), "Appliance started");
+            self.config.appliance_started.call(self, &power).await;
+        } else {
+            debug!(id = Device::get_id(self), "Appliance finished");
+            self.config.appliance_finished.call(self, &power).await;
+        }
+    }
+}
+
 #[async_trait]
 impl<T: OutletState> OnPresence for Outlet<T> {
     async fn on_presence(&self, presence: bool) {
@@ -217,7 +623,7 @@ impl<T: OutletState> google_home::Device for Outlet<T> {
     }
 
     fn get_device_name(&self) -> device::Name {
-        device::Name::new(&self.config.info.name)
+        self.config.info.device_name()
     }
 
     fn get_id(&self) -> String {
@@ -225,19 +631,51 @@ impl<T: OutletState> google_home::Device for Outlet<T> {
     }
 
     async fn is_online(&self) -> bool {
-        true
+        self.state.status.is_available()
     }
 
     fn get_room_hint(&self) -> Option<&str> {
         self.config.info.room.as_deref()
     }
 
+    fn get_device_info(&self) -> Option<device::Info> {
+        self.state.status.device_info()
+    }
+
+    fn get_custom_data(&self) -> Option<serde_json::Value> {
+        self.config.info.custom_data()
+    }
+
     fn will_report_state(&self) -> bool {
         // TODO: Implement state reporting
         false
     }
 }
 
+#[async_trait]
+impl<T: OutletState> Timeout for Outlet<T> {
+    async fn start_timeout(&self, timeout: Duration) {
+        let mut handle = self.state.timeout.lock().await;
+        if let Some(handle) = handle.take() {
+            handle.abort();
+        }
+
+        debug!(id = Device::get_id(self), "Turning off in {timeout:?}");
+        let outlet = self.clone();
+        *handle = Some(tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            debug!(id = Device::get_id(&outlet), "Timeout elapsed, turning off");
+            outlet.set_on(false).await.ok();
+        }));
+    }
+
+    async fn stop_timeout(&self) {
+        if let Some(handle) = self.state.timeout.lock().await.take() {
+            handle.abort();
+        }
+    }
+}
+
 #[async_trait]
 impl<T> OnOff for Outlet<T>
 where
@@ -273,3 +711,263 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use automation_lib::event::EventChannel;
+    use automation_lib::lua::testing::MockMqttClient;
+    use chrono::Timelike;
+    use mlua::FromLua;
+    use rumqttc::QoS;
+
+    use super::*;
+
+    fn config<T: OutletState>(client: &MockMqttClient) -> Config<T> {
+        Config {
+            info: InfoConfig::new("Outlet"),
+            mqtt: MqttDeviceConfig {
+                topic: "zigbee2mqtt/outlet".into(),
+                availability: None,
+            },
+            outlet_type: OutletType::Outlet,
+            presence_auto_off: false,
+            timeout: None,
+            energy_rollover_hour: 0,
+            on_threshold_w: None,
+            off_threshold_w: None,
+            min_on_duration: Duration::ZERO,
+            min_off_duration: Duration::ZERO,
+            callback: Default::default(),
+            availability_callback: Default::default(),
+            daily_report_callback: Default::default(),
+            appliance_started: Default::default(),
+            appliance_finished: Default::default(),
+            on_connect: Default::default(),
+            client: client.client(),
+        }
+    }
+
+    #[test]
+    fn energy_from_power() {
+        let mut energy = Energy::default();
+        let start = Instant::now();
+
+        energy.update(100.0, None, start);
+        energy.update(300.0, None, start + Duration::from_secs(3600));
+        energy.update(300.0, None, start + Duration::from_secs(2 * 3600));
+
+        assert!((energy.today_kwh - 0.5).abs() < 1e-9);
+        assert!((energy.total_kwh - 0.5).abs() < 1e-9);
+
+        assert!((energy.rollover() - 0.5).abs() < 1e-9);
+        assert_eq!(energy.today_kwh, 0.0);
+        assert!((energy.total_kwh - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn energy_from_meter() {
+        let mut energy = Energy::default();
+        let start = Instant::now();
+
+        energy.update(1000.0, Some(12.0), start);
+        energy.update(1000.0, Some(12.25), start + Duration::from_secs(60));
+        // The meter was reset
+        energy.update(1000.0, Some(0.5), start + Duration::from_secs(120));
+        energy.update(1000.0, Some(0.75), start + Duration::from_secs(180));
+
+        assert!((energy.today_kwh - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rollover_time() {
+        let now = Local::now();
+        let next_hour = (now.hour() + 1) % 24;
+
+        let until = until_rollover(next_hour, now);
+        assert!(until > Duration::ZERO);
+        assert!(until <= Duration::from_secs(3600));
+
+        let until = until_rollover(now.hour(), now);
+        assert!(until > Duration::from_secs(22 * 3600));
+        assert!(until <= Duration::from_secs(25 * 3600));
+    }
+
+    #[tokio::test]
+    async fn energy_meter_message() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let outlet = OutletPower::create(config(&client)).await.unwrap();
+
+        for payload in [
+            r#"{"state":"ON","power":2000,"energy":1.5}"#,
+            r#"{"state":"ON","power":2000,"energy":1.75}"#,
+        ] {
+            outlet
+                .on_mqtt(Publish::new(
+                    "zigbee2mqtt/outlet",
+                    QoS::AtLeastOnce,
+                    payload,
+                ))
+                .await;
+        }
+
+        assert!((outlet.energy_today().await - 0.25).abs() < 1e-9);
+        assert!((outlet.energy_total().await - 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn appliance_cycle() {
+        let mut appliance = Appliance::new(Thresholds {
+            on: 10.0,
+            off: 5.0,
+            min_on_duration: Duration::from_secs(60),
+            min_off_duration: Duration::from_secs(300),
+        });
+        let start = Instant::now();
+
+        let sequence = [
+            // A short spike should be ignored
+            (0, 1500.0),
+            (30, 2.0),
+            (60, 1500.0),
+            (120, 1500.0),
+            (180, 200.0),
+            // Short dips during the cycle should be ignored as well
+            (240, 1.0),
+            (300, 200.0),
+            (400, 7.0),
+            (500, 1.0),
+            (700, 1.0),
+            (800, 0.5),
+            (900, 0.5),
+        ];
+
+        let changes: Vec<_> = sequence
+            .into_iter()
+            .filter_map(|(seconds, power)| {
+                let now = start + Duration::from_secs(seconds);
+                appliance
+                    .update(power, now)
+                    .map(|running| (seconds, running))
+            })
+            .collect();
+
+        assert_eq!(changes, vec![(120, true), (800, false)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn appliance_callbacks() {
+        let lua = mlua::Lua::new();
+        lua.load("started = 0 finished = 0").exec().unwrap();
+        let started = lua
+            .load("function(_, power) started = started + 1 last_power = power end")
+            .eval()
+            .unwrap();
+        let finished = lua
+            .load("function(_, power) finished = finished + 1 end")
+            .eval()
+            .unwrap();
+
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let outlet = OutletPower::create(Config {
+            info: InfoConfig::new("Dishwasher"),
+            mqtt: MqttDeviceConfig {
+                topic: "zigbee2mqtt/dishwasher".into(),
+                availability: None,
+            },
+            on_threshold_w: Some(10.0),
+            off_threshold_w: Some(5.0),
+            min_on_duration: Duration::from_secs(60),
+            min_off_duration: Duration::from_secs(300),
+            appliance_started: ActionCallback::from_lua(mlua::Value::Function(started), &lua)
+                .unwrap(),
+            appliance_finished: ActionCallback::from_lua(mlua::Value::Function(finished), &lua)
+                .unwrap(),
+            ..config(&client)
+        })
+        .await
+        .unwrap();
+
+        let power = |power: f64| {
+            Publish::new(
+                "zigbee2mqtt/dishwasher",
+                QoS::AtLeastOnce,
+                json!({ "state": "ON", "power": power }).to_string(),
+            )
+        };
+
+        for _ in 0..2 {
+            outlet.on_mqtt(power(1800.0)).await;
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            outlet.on_mqtt(power(1800.0)).await;
+            tokio::time::sleep(Duration::from_secs(40)).await;
+            // The plug only reports changes, so the timer has to complete the transition
+            outlet.on_mqtt(power(0.3)).await;
+            tokio::time::sleep(Duration::from_secs(400)).await;
+        }
+
+        let started: u32 = lua.globals().get("started").unwrap();
+        let finished: u32 = lua.globals().get("finished").unwrap();
+        let last_power: f64 = lua.globals().get("last_power").unwrap();
+        assert_eq!(started, 2);
+        assert_eq!(finished, 2);
+        assert_eq!(last_power, 1800.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn kettle_timeout() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let kettle = OutletOnOff::create(Config {
+            info: InfoConfig::new("Kettle"),
+            mqtt: MqttDeviceConfig {
+                topic: "zigbee2mqtt/kettle".into(),
+                availability: None,
+            },
+            outlet_type: OutletType::Kettle,
+            timeout: Some(Duration::from_secs(5 * 60)),
+            ..config(&client)
+        })
+        .await
+        .unwrap();
+
+        let state = |on: bool| {
+            Publish::new(
+                "zigbee2mqtt/kettle",
+                QoS::AtLeastOnce,
+                json!({ "state": if on { "ON" } else { "OFF" } }).to_string(),
+            )
+        };
+
+        // Turning the kettle off manually cancels the timeout
+        kettle.on_mqtt(state(true)).await;
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        kettle.on_mqtt(state(false)).await;
+        tokio::time::sleep(Duration::from_secs(10 * 60)).await;
+        assert!(client.published().is_empty());
+
+        kettle.on_mqtt(state(true)).await;
+        tokio::time::sleep(Duration::from_secs(5 * 60 + 1)).await;
+        assert_eq!(
+            client.published(),
+            vec![(
+                "zigbee2mqtt/kettle/set".to_string(),
+                r#"{"state":"OFF"}"#.to_string()
+            )]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rollover_stops_with_outlet() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let outlet = OutletPower::create(config(&client)).await.unwrap();
+        let state = Arc::downgrade(&outlet.state);
+
+        drop(outlet);
+        // The task only notices at the next rollover
+        tokio::time::sleep(Duration::from_secs(25 * 3600)).await;
+        assert!(state.upgrade().is_none());
+    }
+}
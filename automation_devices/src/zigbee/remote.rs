@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use automation_lib::action_callback::ActionCallback;
+use automation_lib::config::{InfoConfig, MqttDeviceConfig};
+use automation_lib::device::{Device, LuaDeviceCreate};
+use automation_lib::event::OnMqtt;
+use automation_lib::mqtt::WrappedAsyncClient;
+use automation_macro::LuaDeviceConfig;
+use rumqttc::{Publish, QoS};
+use serde::Deserialize;
+use tokio::sync::RwLock;
+use tracing::{debug, trace, warn};
+
+#[derive(Debug, Clone, LuaDeviceConfig)]
+pub struct Config {
+    #[device_config(flatten)]
+    pub info: InfoConfig,
+    #[device_config(flatten)]
+    pub mqtt: MqttDeviceConfig,
+
+    // Maps the action reported by zigbee2mqtt to a callback, e.g. { single = function(remote) end }
+    #[device_config(from_lua, default)]
+    pub actions: HashMap<String, ActionCallback<ActionRemote, ()>>,
+    // Called with the battery percentage whenever it changes
+    #[device_config(from_lua, default)]
+    pub battery_callback: ActionCallback<ActionRemote, f64>,
+
+    #[device_config(from_lua)]
+    pub client: WrappedAsyncClient,
+}
+
+#[derive(Debug, Deserialize)]
+struct ActionMessage {
+    #[serde(default)]
+    action: Option<String>,
+    #[serde(default)]
+    battery: Option<f64>,
+}
+
+// Remote that routes the actions to callbacks defined in lua, this works for any remote that
+// reports an 'action' through zigbee2mqtt
+#[derive(Debug, Clone)]
+pub struct ActionRemote {
+    config: Config,
+    battery: Arc<RwLock<Option<f64>>>,
+}
+
+impl Device for ActionRemote {
+    fn get_id(&self) -> String {
+        self.config.info.identifier()
+    }
+
+    fn get_info(&self) -> Option<&InfoConfig> {
+        Some(&self.config.info)
+    }
+}
+
+#[async_trait]
+impl LuaDeviceCreate for ActionRemote {
+    type Config = Config;
+    type Error = rumqttc::ClientError;
+
+    async fn create(config: Self::Config) -> Result<Self, Self::Error> {
+        trace!(id = config.info.identifier(), "Setting up ActionRemote");
+
+        config
+            .client
+            .subscribe(&config.mqtt.topic, QoS::AtLeastOnce)
+            .await?;
+
+        Ok(Self {
+            config,
+            battery: Default::default(),
+        })
+    }
+}
+
+#[async_trait]
+impl OnMqtt for ActionRemote {
+    async fn on_mqtt(&self, message: Publish) {
+        if message.topic != self.config.mqtt.topic {
+            return;
+        }
+
+        let message: ActionMessage = match serde_json::from_slice(&message.payload) {
+            Ok(message) => message,
+            Err(err) => {
+                warn!(id = self.get_id(), "Failed to parse message: {err}");
+                return;
+            }
+        };
+
+        if let Some(battery) = message.battery {
+            let previous = self.battery.write().await.replace(battery);
+            if previous != Some(battery) {
+                debug!(id = self.get_id(), "Battery = {battery}%");
+                self.config.battery_callback.call(self, &battery).await;
+            }
+        }
+
+        // zigbee2mqtt clears the action by sending an empty string
+        let Some(action) = message.action.filter(|action| !action.is_empty()) else {
+            return;
+        };
+
+        match self.config.actions.get(&action) {
+            Some(callback) => {
+                debug!(id = self.get_id(), "Remote action = {action}");
+                callback.call(self, &()).await;
+            }
+            None => debug!(id = self.get_id(), "Unknown action: {action}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automation_lib::event::EventChannel;
+    use automation_lib::lua::testing::MockMqttClient;
+    use mlua::FromLua;
+
+    use super::*;
+
+    // Callback that counts how often it is called in a lua global
+    fn counter<S>(lua: &mlua::Lua, name: &str) -> ActionCallback<ActionRemote, S> {
+        let f: mlua::Function = lua
+            .load(format!("return function() {name} = ({name} or 0) + 1 end"))
+            .eval()
+            .unwrap();
+
+        ActionCallback::from_lua(mlua::Value::Function(f), lua).unwrap()
+    }
+
+    fn count(lua: &mlua::Lua, name: &str) -> u32 {
+        lua.globals().get::<Option<u32>>(name).unwrap().unwrap_or(0)
+    }
+
+    #[tokio::test]
+    async fn actions() {
+        let lua = mlua::Lua::new();
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let remote = ActionRemote::create(Config {
+            info: InfoConfig::new("Remote"),
+            mqtt: MqttDeviceConfig {
+                topic: "zigbee2mqtt/remote".into(),
+                availability: None,
+            },
+            actions: HashMap::from([
+                ("single".into(), counter(&lua, "single")),
+                ("double".into(), counter(&lua, "double")),
+            ]),
+            battery_callback: counter(&lua, "battery"),
+            client: client.client(),
+        })
+        .await
+        .unwrap();
+
+        for payload in [
+            r#"{"action":"single","battery":90}"#,
+            r#"{"action":"","battery":90}"#,
+            r#"{"action":"double","battery":90}"#,
+            r#"{"action":"hold","battery":85}"#,
+        ] {
+            remote
+                .on_mqtt(Publish::new(
+                    "zigbee2mqtt/remote",
+                    QoS::AtLeastOnce,
+                    payload,
+                ))
+                .await;
+        }
+
+        assert_eq!(count(&lua, "single"), 1);
+        assert_eq!(count(&lua, "double"), 1);
+        // Only called when the battery level changes
+        assert_eq!(count(&lua, "battery"), 2);
+    }
+}
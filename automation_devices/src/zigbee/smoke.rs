@@ -0,0 +1,348 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use automation_lib::action_callback::ActionCallback;
+use automation_lib::availability::{Availability, MqttDeviceStatus};
+use automation_lib::config::{InfoConfig, MqttDeviceConfig};
+use automation_lib::device::{Device, DeviceAvailability, LuaDeviceCreate};
+use automation_lib::event::{self, Alarm, AlarmKind, Event, EventChannel, OnMqtt};
+use automation_lib::mqtt::WrappedAsyncClient;
+use automation_macro::LuaDeviceConfig;
+use google_home::device;
+use google_home::errors::ErrorCode;
+use google_home::traits::{
+    CurrentSensorState, DescriptiveCapabilities, SensorState, SupportedSensorState,
+};
+use google_home::types::Type;
+use rumqttc::{matches, Publish, QoS};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tracing::{debug, trace, warn};
+
+const SENSOR_STATE_NAME: &str = "SmokeLevel";
+const SMOKE_DETECTED: &str = "smoke detected";
+const NO_SMOKE_DETECTED: &str = "no smoke detected";
+
+// How long the siren sounds during a self-test
+const TEST_DURATION_SECONDS: u32 = 3;
+
+#[derive(Debug, Clone, LuaDeviceConfig)]
+pub struct Config {
+    #[device_config(flatten)]
+    pub info: InfoConfig,
+    #[device_config(flatten)]
+    pub mqtt: MqttDeviceConfig,
+
+    // Called when smoke is detected or cleared
+    #[device_config(from_lua, default)]
+    pub callback: ActionCallback<SmokeDetector, bool>,
+    // Called when the battery level changes
+    #[device_config(from_lua, default)]
+    pub battery_callback: ActionCallback<SmokeDetector, f64>,
+    // Called when the detector becomes available or unavailable
+    #[device_config(from_lua, default)]
+    pub availability_callback: ActionCallback<SmokeDetector, bool>,
+
+    #[device_config(rename("event_channel"), from_lua, with(|ec: EventChannel| ec.get_tx()))]
+    pub tx: event::Sender,
+    #[device_config(from_lua)]
+    pub client: WrappedAsyncClient,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SmokeMessage {
+    smoke: Option<bool>,
+    battery: Option<f64>,
+}
+
+#[derive(Debug, Default)]
+pub struct State {
+    smoke: bool,
+    battery: Option<f64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SmokeDetector {
+    config: Config,
+
+    state: Arc<RwLock<State>>,
+    status: MqttDeviceStatus,
+}
+
+impl SmokeDetector {
+    async fn state(&self) -> RwLockReadGuard<State> {
+        self.state.read().await
+    }
+
+    async fn state_mut(&self) -> RwLockWriteGuard<State> {
+        self.state.write().await
+    }
+
+    pub async fn smoke(&self) -> bool {
+        self.state().await.smoke
+    }
+
+    pub async fn battery(&self) -> Option<f64> {
+        self.state().await.battery
+    }
+
+    async fn warning(&self, warning: serde_json::Value) -> Result<(), rumqttc::ClientError> {
+        let topic = format!("{}/set", self.config.mqtt.topic);
+        self.config
+            .client
+            .publish(
+                &topic,
+                QoS::AtLeastOnce,
+                false,
+                json!({ "warning": warning }).to_string(),
+            )
+            .await
+    }
+
+    // Silence the siren, the detector keeps reporting smoke while it is present
+    pub async fn hush(&self) -> Result<(), rumqttc::ClientError> {
+        debug!(id = Device::get_id(self), "Silencing alarm");
+        self.warning(json!({ "mode": "stop" })).await
+    }
+
+    // Briefly sound the siren
+    pub async fn test(&self) -> Result<(), rumqttc::ClientError> {
+        debug!(id = Device::get_id(self), "Testing alarm");
+        self.warning(json!({
+            "mode": "emergency",
+            "level": "low",
+            "strobe": false,
+            "duration": TEST_DURATION_SECONDS,
+        }))
+        .await
+    }
+
+    async fn set_smoke(&self, smoke: bool) {
+        {
+            let mut state = self.state_mut().await;
+            if smoke == state.smoke {
+                return;
+            }
+            state.smoke = smoke;
+        }
+
+        if smoke {
+            warn!(id = Device::get_id(self), "Smoke detected");
+        } else {
+            debug!(id = Device::get_id(self), "Smoke cleared");
+        }
+
+        let alarm = Alarm {
+            source: Device::get_id(self),
+            kind: AlarmKind::Smoke,
+            active: smoke,
+        };
+        if self.config.tx.send(Event::Alarm(alarm)).await.is_err() {
+            warn!("There are no receivers on the event channel");
+        }
+
+        self.config.callback.call(self, &smoke).await;
+    }
+}
+
+#[async_trait]
+impl LuaDeviceCreate for SmokeDetector {
+    type Config = Config;
+    type Error = rumqttc::ClientError;
+
+    async fn create(config: Self::Config) -> Result<Self, Self::Error> {
+        trace!(id = config.info.identifier(), "Setting up SmokeDetector");
+
+        config
+            .client
+            .subscribe(&config.mqtt.topic, QoS::AtLeastOnce)
+            .await?;
+        let status = MqttDeviceStatus::new(config.info.identifier(), &config.mqtt);
+        status.subscribe(&config.client).await?;
+
+        Ok(Self {
+            config,
+            state: Default::default(),
+            status,
+        })
+    }
+}
+
+impl Device for SmokeDetector {
+    fn get_id(&self) -> String {
+        self.config.info.identifier()
+    }
+
+    fn get_info(&self) -> Option<&InfoConfig> {
+        Some(&self.config.info)
+    }
+}
+
+impl DeviceAvailability for SmokeDetector {
+    fn availability(&self) -> &Availability {
+        self.status.availability()
+    }
+}
+
+#[async_trait]
+impl OnMqtt for SmokeDetector {
+    async fn on_mqtt(&self, message: Publish) {
+        if self
+            .status
+            .handle_with_callback(self, &message, &self.config.availability_callback)
+            .await
+        {
+            return;
+        }
+
+        if !matches(&message.topic, &self.config.mqtt.topic) {
+            return;
+        }
+
+        let message = match serde_json::from_slice::<SmokeMessage>(&message.payload) {
+            Ok(message) => message,
+            Err(err) => {
+                warn!(id = Device::get_id(self), "Failed to parse message: {err}");
+                return;
+            }
+        };
+
+        if let Some(smoke) = message.smoke {
+            self.set_smoke(smoke).await;
+        }
+
+        if let Some(battery) = message.battery {
+            let previous = self.state_mut().await.battery.replace(battery);
+            if previous != Some(battery) {
+                debug!(id = Device::get_id(self), "Battery = {battery}");
+                self.config.battery_callback.call(self, &battery).await;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl google_home::Device for SmokeDetector {
+    fn get_device_type(&self) -> Type {
+        Type::SmokeDetector
+    }
+
+    fn get_device_name(&self) -> device::Name {
+        self.config.info.device_name()
+    }
+
+    fn get_id(&self) -> String {
+        Device::get_id(self)
+    }
+
+    async fn is_online(&self) -> bool {
+        self.status.is_available()
+    }
+
+    fn get_room_hint(&self) -> Option<&str> {
+        self.config.info.room.as_deref()
+    }
+
+    fn get_device_info(&self) -> Option<device::Info> {
+        self.status.device_info()
+    }
+
+    fn get_custom_data(&self) -> Option<serde_json::Value> {
+        self.config.info.custom_data()
+    }
+}
+
+#[async_trait]
+impl SensorState for SmokeDetector {
+    fn sensor_states_supported(&self) -> Vec<SupportedSensorState> {
+        vec![SupportedSensorState {
+            name: SENSOR_STATE_NAME.into(),
+            descriptive_capabilities: DescriptiveCapabilities {
+                available_states: vec![SMOKE_DETECTED.into(), NO_SMOKE_DETECTED.into()],
+            },
+            numeric_capabilities: None,
+        }]
+    }
+
+    async fn current_sensor_state_data(&self) -> Result<Vec<CurrentSensorState>, ErrorCode> {
+        let state = if self.smoke().await {
+            SMOKE_DETECTED
+        } else {
+            NO_SMOKE_DETECTED
+        };
+
+        Ok(vec![CurrentSensorState {
+            name: SENSOR_STATE_NAME.into(),
+            current_sensor_state: state.into(),
+            raw_value: None,
+        }])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automation_lib::lua::testing::MockMqttClient;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn smoke() {
+        let (event_channel, mut rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel.clone());
+        let detector = SmokeDetector::create(Config {
+            info: InfoConfig {
+                room: Some("Hallway".into()),
+                ..InfoConfig::new("Smoke")
+            },
+            mqtt: MqttDeviceConfig {
+                topic: "zigbee2mqtt/smoke".into(),
+                availability: None,
+            },
+            callback: Default::default(),
+            battery_callback: Default::default(),
+            availability_callback: Default::default(),
+            tx: event_channel.get_tx(),
+            client: client.client(),
+        })
+        .await
+        .unwrap();
+
+        detector
+            .on_mqtt(Publish::new(
+                "zigbee2mqtt/smoke",
+                QoS::AtLeastOnce,
+                r#"{"smoke":true,"battery":100,"battery_low":false}"#,
+            ))
+            .await;
+        assert!(detector.smoke().await);
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(Event::Alarm(Alarm {
+                kind: AlarmKind::Smoke,
+                active: true,
+                ..
+            }))
+        ));
+
+        // The alarm is only raised when the state changes
+        detector
+            .on_mqtt(Publish::new(
+                "zigbee2mqtt/smoke",
+                QoS::AtLeastOnce,
+                r#"{"smoke":true}"#,
+            ))
+            .await;
+        assert!(rx.try_recv().is_err());
+
+        detector.hush().await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        assert_eq!(
+            client.published().pop(),
+            Some((
+                "zigbee2mqtt/smoke/set".to_string(),
+                json!({ "warning": { "mode": "stop" } }).to_string()
+            ))
+        );
+    }
+}
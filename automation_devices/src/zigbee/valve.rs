@@ -0,0 +1,364 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use automation_lib::action_callback::ActionCallback;
+use automation_lib::availability::{Availability, MqttDeviceStatus};
+use automation_lib::config::{InfoConfig, MqttDeviceConfig};
+use automation_lib::device::{Device, DeviceAvailability, LuaDeviceCreate};
+use automation_lib::event::OnMqtt;
+use automation_lib::helpers::serialization::state_deserializer;
+use automation_lib::mqtt::WrappedAsyncClient;
+use automation_macro::LuaDeviceConfig;
+use google_home::device;
+use google_home::errors::ErrorCode;
+use google_home::traits::OnOff;
+use google_home::types::Type;
+use rumqttc::{matches, Publish};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::{Mutex, RwLock};
+use tokio::task::JoinHandle;
+use tracing::{debug, trace, warn};
+
+#[derive(Debug, Clone, LuaDeviceConfig)]
+pub struct Config {
+    #[device_config(flatten)]
+    pub info: InfoConfig,
+    #[device_config(flatten)]
+    pub mqtt: MqttDeviceConfig,
+    // The valve is always turned off after being open for this long, no matter what turned it on
+    #[device_config(rename("max_runtime_seconds"), with(Duration::from_secs))]
+    pub max_runtime: Duration,
+
+    #[device_config(from_lua, default)]
+    pub callback: ActionCallback<Valve, bool>,
+    // Called with the maximum runtime in seconds when the watchdog had to turn the valve off
+    #[device_config(from_lua, default)]
+    pub watchdog_callback: ActionCallback<Valve, u64>,
+
+    #[device_config(from_lua)]
+    pub client: WrappedAsyncClient,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct State {
+    #[serde(deserialize_with = "state_deserializer")]
+    state: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Valve {
+    config: Config,
+
+    on: Arc<RwLock<bool>>,
+    status: MqttDeviceStatus,
+    // Forces the valve off after the maximum runtime
+    watchdog: Arc<Mutex<Option<JoinHandle<()>>>>,
+    // Turns the valve off at the end of run_for
+    run: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl Valve {
+    async fn publish_state(&self, on: bool) {
+        let message = json!({
+            "state": if on { "ON" } else { "OFF" }
+        });
+
+        debug!(id = Device::get_id(self), "{message}");
+
+        let topic = format!("{}/set", self.config.mqtt.topic);
+        self.config
+            .client
+            .publish(
+                &topic,
+                rumqttc::QoS::AtLeastOnce,
+                false,
+                serde_json::to_string(&message).unwrap(),
+            )
+            .await
+            .map_err(|err| warn!("Failed to update state on {topic}: {err}"))
+            .ok();
+    }
+
+    // Only arms the watchdog if it is not already running, turning the valve on again should not
+    // extend the maximum runtime
+    async fn arm_watchdog(&self) {
+        let mut watchdog = self.watchdog.lock().await;
+        if watchdog
+            .as_ref()
+            .is_some_and(|handle| !handle.is_finished())
+        {
+            return;
+        }
+
+        let max_runtime = self.config.max_runtime;
+        trace!(
+            id = Device::get_id(self),
+            "Arming watchdog for {max_runtime:?}"
+        );
+        let valve = self.clone();
+        *watchdog = Some(tokio::spawn(async move {
+            tokio::time::sleep(max_runtime).await;
+            warn!(
+                id = Device::get_id(&valve),
+                "Valve has been on for {max_runtime:?}, forcing it off"
+            );
+            // NOTE: We are running inside the watchdog task, so we can not use set_on here as
+            // that would abort it
+            valve.publish_state(false).await;
+            valve
+                .config
+                .watchdog_callback
+                .call(&valve, &max_runtime.as_secs())
+                .await;
+        }));
+    }
+
+    async fn disarm(&self) {
+        if let Some(handle) = self.watchdog.lock().await.take() {
+            handle.abort();
+        }
+        if let Some(handle) = self.run.lock().await.take() {
+            handle.abort();
+        }
+    }
+
+    // Turns the valve on and off again after the given duration, limited by the maximum runtime
+    pub async fn run_for(&self, duration: Duration) {
+        self.set_on(true).await.ok();
+
+        let mut run = self.run.lock().await;
+        if let Some(handle) = run.take() {
+            handle.abort();
+        }
+
+        debug!(id = Device::get_id(self), "Turning off in {duration:?}");
+        let valve = self.clone();
+        *run = Some(tokio::spawn(async move {
+            tokio::time::sleep(duration).await;
+            // Make sure turning off does not abort this task
+            valve.run.lock().await.take();
+            valve.set_on(false).await.ok();
+        }));
+    }
+}
+
+#[async_trait]
+impl LuaDeviceCreate for Valve {
+    type Config = Config;
+    type Error = rumqttc::ClientError;
+
+    async fn create(config: Self::Config) -> Result<Self, Self::Error> {
+        trace!(id = config.info.identifier(), "Setting up Valve");
+
+        config
+            .client
+            .subscribe(&config.mqtt.topic, rumqttc::QoS::AtLeastOnce)
+            .await?;
+        let status = MqttDeviceStatus::new(config.info.identifier(), &config.mqtt);
+        status.subscribe(&config.client).await?;
+
+        Ok(Self {
+            config,
+            on: Default::default(),
+            status,
+            watchdog: Default::default(),
+            run: Default::default(),
+        })
+    }
+}
+
+impl Device for Valve {
+    fn get_id(&self) -> String {
+        self.config.info.identifier()
+    }
+
+    fn get_info(&self) -> Option<&InfoConfig> {
+        Some(&self.config.info)
+    }
+}
+
+impl DeviceAvailability for Valve {
+    fn availability(&self) -> &Availability {
+        self.status.availability()
+    }
+}
+
+#[async_trait]
+impl OnMqtt for Valve {
+    async fn on_mqtt(&self, message: Publish) {
+        if self.status.handle(&message) {
+            return;
+        }
+
+        if !matches(&message.topic, &self.config.mqtt.topic) {
+            return;
+        }
+
+        let state = match serde_json::from_slice::<State>(&message.payload) {
+            Ok(state) => state.state,
+            Err(err) => {
+                warn!(id = Device::get_id(self), "Failed to parse message: {err}");
+                return;
+            }
+        };
+
+        // The valve might have been turned on manually, so the watchdog is also armed here
+        if state {
+            self.arm_watchdog().await;
+        } else {
+            self.disarm().await;
+        }
+
+        if state == *self.on.read().await {
+            return;
+        }
+
+        debug!(id = Device::get_id(self), "Updating state to {state}");
+        *self.on.write().await = state;
+
+        self.config.callback.call(self, &state).await;
+    }
+}
+
+#[async_trait]
+impl google_home::Device for Valve {
+    fn get_device_type(&self) -> Type {
+        Type::Sprinkler
+    }
+
+    fn get_device_name(&self) -> device::Name {
+        self.config.info.device_name()
+    }
+
+    fn get_id(&self) -> String {
+        Device::get_id(self)
+    }
+
+    async fn is_online(&self) -> bool {
+        self.status.is_available()
+    }
+
+    fn get_room_hint(&self) -> Option<&str> {
+        self.config.info.room.as_deref()
+    }
+
+    fn get_device_info(&self) -> Option<device::Info> {
+        self.status.device_info()
+    }
+
+    fn get_custom_data(&self) -> Option<serde_json::Value> {
+        self.config.info.custom_data()
+    }
+
+    fn will_report_state(&self) -> bool {
+        // TODO: Implement state reporting
+        false
+    }
+}
+
+#[async_trait]
+impl OnOff for Valve {
+    async fn on(&self) -> Result<bool, ErrorCode> {
+        Ok(*self.on.read().await)
+    }
+
+    async fn set_on(&self, on: bool) -> Result<(), ErrorCode> {
+        // Do not wait for the valve to report its state before arming the watchdog
+        if on {
+            self.arm_watchdog().await;
+        } else {
+            self.disarm().await;
+        }
+
+        self.publish_state(on).await;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automation_lib::event::EventChannel;
+    use automation_lib::lua::testing::MockMqttClient;
+    use rumqttc::QoS;
+
+    use super::*;
+
+    async fn valve(client: &MockMqttClient) -> Valve {
+        Valve::create(Config {
+            info: InfoConfig::new("Garden"),
+            mqtt: MqttDeviceConfig {
+                topic: "zigbee2mqtt/valve".into(),
+                availability: None,
+            },
+            max_runtime: Duration::from_secs(600),
+            callback: Default::default(),
+            watchdog_callback: Default::default(),
+            client: client.client(),
+        })
+        .await
+        .unwrap()
+    }
+
+    fn states(client: &MockMqttClient) -> Vec<String> {
+        client
+            .published()
+            .into_iter()
+            .map(|(topic, payload)| {
+                assert_eq!(topic, "zigbee2mqtt/valve/set");
+                let message: serde_json::Value = serde_json::from_str(&payload).unwrap();
+                message["state"].as_str().unwrap().to_owned()
+            })
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn watchdog() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let valve = valve(&client).await;
+
+        // Turned on by hand
+        valve
+            .on_mqtt(Publish::new(
+                "zigbee2mqtt/valve",
+                QoS::AtLeastOnce,
+                r#"{"state":"ON"}"#,
+            ))
+            .await;
+        assert!(valve.on().await.unwrap());
+
+        // Turning it on again does not extend the runtime
+        tokio::time::sleep(Duration::from_secs(300)).await;
+        valve.set_on(true).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(301)).await;
+
+        assert_eq!(states(&client), ["ON", "OFF"]);
+        assert!(valve.watchdog.lock().await.as_ref().unwrap().is_finished());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_for() {
+        let (event_channel, _rx) = EventChannel::new();
+        let client = MockMqttClient::new(event_channel);
+        let valve = valve(&client).await;
+
+        valve.run_for(Duration::from_secs(60)).await;
+        // A second run replaces the first one
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        valve.run_for(Duration::from_secs(60)).await;
+
+        tokio::time::sleep(Duration::from_secs(45)).await;
+        assert_eq!(states(&client), ["ON", "ON"]);
+
+        tokio::time::sleep(Duration::from_secs(16)).await;
+        assert_eq!(states(&client), ["ON", "ON", "OFF"]);
+
+        // Turning off stops the watchdog, so nothing else is sent
+        assert!(valve.watchdog.lock().await.is_none());
+        tokio::time::sleep(Duration::from_secs(600)).await;
+        assert_eq!(states(&client).len(), 3);
+    }
+}
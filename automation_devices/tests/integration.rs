@@ -0,0 +1,304 @@
+//! Runs devices against an in-process MQTT broker, this covers the whole path from a message
+//! arriving at the broker up to the state of the device
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use automation_devices::{ContactSensor, LightBrightness, OutletOnOff};
+use automation_lib::device::{Device, LuaDeviceCreate};
+use automation_lib::device_manager::DeviceManager;
+use automation_lib::mqtt::{self, WrappedAsyncClient};
+use bytes::BytesMut;
+use google_home::traits::{Brightness, OnOff, OpenClose};
+use rumqttc::mqttbytes::v4::{self, Packet};
+use rumqttc::{
+    AsyncClient, ConnAck, ConnectReturnCode, MqttOptions, PingResp, PubAck, Publish, QoS, SubAck,
+    SubscribeReasonCode,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+// How long a test waits for the broker or a device before it fails
+const TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_PACKET_SIZE: usize = 1024 * 1024;
+
+type Subscriptions = Arc<Mutex<Vec<(String, mpsc::UnboundedSender<Packet>)>>>;
+
+/// Just enough of an MQTT 3.1.1 broker to connect, subscribe and publish, messages are always
+/// delivered with QoS 0 and nothing is retained
+#[derive(Clone)]
+struct MockBroker {
+    addr: SocketAddr,
+    subscriptions: Subscriptions,
+}
+
+impl MockBroker {
+    async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let broker = Self {
+            addr: listener.local_addr().unwrap(),
+            subscriptions: Default::default(),
+        };
+
+        tokio::spawn({
+            let broker = broker.clone();
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(broker.clone().connection(stream));
+                }
+            }
+        });
+
+        broker
+    }
+
+    async fn connection(self, stream: TcpStream) {
+        let (mut reader, mut writer) = stream.into_split();
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(packet) = rx.recv().await {
+                let mut buffer = BytesMut::new();
+                match packet {
+                    Packet::ConnAck(connack) => connack.write(&mut buffer),
+                    Packet::SubAck(suback) => suback.write(&mut buffer),
+                    Packet::PubAck(puback) => puback.write(&mut buffer),
+                    Packet::Publish(publish) => publish.write(&mut buffer),
+                    Packet::PingResp => PingResp.write(&mut buffer),
+                    // The broker below only ever queues the packets above
+                    packet => unreachable!("Broker does not send {packet:?}"),
+                }
+                .unwrap();
+
+                if writer.write_all(&buffer).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut buffer = BytesMut::new();
+        loop {
+            let packet = match v4::read(&mut buffer, MAX_PACKET_SIZE) {
+                Ok(packet) => packet,
+                Err(rumqttc::Error::InsufficientBytes(_)) => {
+                    match reader.read_buf(&mut buffer).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(_) => continue,
+                    }
+                }
+                Err(err) => panic!("Received invalid packet: {err}"),
+            };
+
+            let response = match packet {
+                Packet::Connect(_) => Some(Packet::ConnAck(ConnAck::new(
+                    ConnectReturnCode::Success,
+                    false,
+                ))),
+                Packet::Subscribe(subscribe) => {
+                    let mut subscriptions = self.subscriptions.lock().unwrap();
+                    let codes = subscribe
+                        .filters
+                        .into_iter()
+                        .map(|filter| {
+                            subscriptions.push((filter.path, tx.clone()));
+                            SubscribeReasonCode::Success(QoS::AtMostOnce)
+                        })
+                        .collect();
+
+                    Some(Packet::SubAck(SubAck::new(subscribe.pkid, codes)))
+                }
+                Packet::Publish(publish) => {
+                    let pkid = publish.pkid;
+                    let qos = publish.qos;
+                    self.route(publish);
+
+                    (qos == QoS::AtLeastOnce).then(|| Packet::PubAck(PubAck::new(pkid)))
+                }
+                Packet::PingReq => Some(Packet::PingResp),
+                Packet::Disconnect => break,
+                _ => None,
+            };
+
+            if let Some(response) = response {
+                if tx.send(response).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn route(&self, mut publish: Publish) {
+        publish.qos = QoS::AtMostOnce;
+        publish.pkid = 0;
+        publish.dup = false;
+
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        // Drop the subscriptions of clients that are gone
+        subscriptions.retain(|(filter, tx)| {
+            !rumqttc::matches(&publish.topic, filter)
+                || tx.send(Packet::Publish(publish.clone())).is_ok()
+        });
+    }
+
+    fn is_subscribed(&self, topic: &str) -> bool {
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(filter, _)| rumqttc::matches(topic, filter))
+    }
+
+    /// Publishes the message as soon as a client is subscribed to the topic
+    async fn publish(&self, topic: &str, payload: &str) {
+        eventually(|| async { self.is_subscribed(topic) }).await;
+
+        self.route(Publish::new(topic, QoS::AtMostOnce, payload));
+    }
+}
+
+async fn eventually<F, Fut>(mut check: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    tokio::time::timeout(TIMEOUT, async {
+        while !check().await {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Condition should become true before the timeout");
+}
+
+struct Harness {
+    broker: MockBroker,
+    lua: mlua::Lua,
+    client: WrappedAsyncClient,
+    device_manager: DeviceManager,
+}
+
+impl Harness {
+    async fn new() -> Self {
+        let broker = MockBroker::start().await;
+        let device_manager = DeviceManager::new().await;
+
+        let options = MqttOptions::new(
+            "automation-test",
+            broker.addr.ip().to_string(),
+            broker.addr.port(),
+        );
+        let (client, eventloop) = AsyncClient::new(options, 10);
+        let (status, _) = mqtt::start(eventloop, &device_manager.event_channel(), None, Vec::new());
+        let client = WrappedAsyncClient::new(client, status, mqtt::DEFAULT_OFFLINE_QUEUE_SIZE);
+
+        Self {
+            broker,
+            lua: mlua::Lua::new(),
+            client,
+            device_manager,
+        }
+    }
+
+    /// Creates the device from the lua config, in the same way config.lua does, and adds it to the
+    /// device manager so it receives the messages from the broker
+    async fn create<D>(&self, config: &str) -> D
+    where
+        D: LuaDeviceCreate + Device + Clone + 'static,
+        D::Config: mlua::FromLua,
+        D::Error: std::fmt::Debug,
+    {
+        let table: mlua::Table = self.lua.load(config).eval().unwrap();
+        table.set("client", self.client.clone()).unwrap();
+        let config = self.lua.unpack(mlua::Value::Table(table)).unwrap();
+
+        let device = D::create(config).await.unwrap();
+        self.device_manager.add(Box::new(device.clone())).await;
+
+        device
+    }
+}
+
+#[tokio::test]
+async fn outlet_on_off() {
+    let harness = Harness::new().await;
+    let outlet: OutletOnOff = harness
+        .create(r#"{ name = "Outlet", room = "Kitchen", topic = "zigbee2mqtt/kitchen/outlet" }"#)
+        .await;
+    assert!(!outlet.on().await.unwrap());
+
+    harness
+        .broker
+        .publish("zigbee2mqtt/kitchen/outlet", r#"{"state":"ON"}"#)
+        .await;
+
+    let outlet = &outlet;
+    eventually(move || async move { outlet.on().await.unwrap() }).await;
+}
+
+#[tokio::test]
+async fn light_brightness() {
+    let harness = Harness::new().await;
+    let light: LightBrightness = harness
+        .create(
+            r#"{
+                name = "Ceiling",
+                room = "Living room",
+                topic = "zigbee2mqtt/living/ceiling",
+                brightness_curve = "linear",
+            }"#,
+        )
+        .await;
+
+    harness
+        .broker
+        .publish(
+            "zigbee2mqtt/living/ceiling",
+            r#"{"state":"ON","brightness":127}"#,
+        )
+        .await;
+
+    let light = &light;
+    eventually(move || async move { light.brightness().await.unwrap() == 50 }).await;
+    assert!(light.on().await.unwrap());
+}
+
+#[tokio::test]
+async fn contact_sensor() {
+    let harness = Harness::new().await;
+    let sensor: ContactSensor = harness
+        .create(r#"{ name = "Door", room = "Hallway", topic = "zigbee2mqtt/hallway/door" }"#)
+        .await;
+    assert_eq!(sensor.open_percent().await.unwrap(), 0);
+
+    harness
+        .broker
+        .publish("zigbee2mqtt/hallway/door", r#"{"contact":false}"#)
+        .await;
+
+    let sensor = &sensor;
+    eventually(move || async move { sensor.open_percent().await.unwrap() == 100 }).await;
+}
+
+// The IKEA outlets are handled by the generic zigbee outlet
+#[tokio::test]
+async fn ikea_outlet_off() {
+    let harness = Harness::new().await;
+    let outlet: OutletOnOff = harness
+        .create(r#"{ name = "Kettle", room = "Kitchen", topic = "zigbee2mqtt/kitchen/kettle" }"#)
+        .await;
+
+    harness
+        .broker
+        .publish("zigbee2mqtt/kitchen/kettle", r#"{"state":"ON"}"#)
+        .await;
+    let outlet = &outlet;
+    eventually(move || async move { outlet.on().await.unwrap() }).await;
+
+    harness
+        .broker
+        .publish("zigbee2mqtt/kitchen/kettle", r#"{"state":"OFF"}"#)
+        .await;
+    eventually(move || async move { !outlet.on().await.unwrap() }).await;
+}
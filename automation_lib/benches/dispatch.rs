@@ -0,0 +1,71 @@
+//! Measures how long it takes to dispatch events to all devices that listen for them
+#![feature(test)]
+
+extern crate test;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use automation_lib::device::Device;
+use automation_lib::device_manager::DeviceManager;
+use automation_lib::event::{Event, OnPresence};
+use test::Bencher;
+use tokio::runtime::Runtime;
+
+const DEVICES: usize = 100;
+const EVENTS: usize = 100;
+
+#[derive(Debug, Clone)]
+struct CountingDevice {
+    id: String,
+    count: Arc<AtomicUsize>,
+}
+
+impl Device for CountingDevice {
+    fn get_id(&self) -> String {
+        self.id.clone()
+    }
+}
+
+#[async_trait]
+impl OnPresence for CountingDevice {
+    async fn on_presence(&self, _presence: bool) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[bench]
+fn dispatch(b: &mut Bencher) {
+    let runtime = Runtime::new().unwrap();
+    let count = Arc::new(AtomicUsize::new(0));
+    let device_manager = runtime.block_on(async {
+        let device_manager = DeviceManager::new().await;
+        for i in 0..DEVICES {
+            device_manager
+                .add(Box::new(CountingDevice {
+                    id: format!("device_{i}"),
+                    count: count.clone(),
+                }))
+                .await;
+        }
+
+        device_manager
+    });
+    let tx = device_manager.event_channel().get_tx();
+
+    b.iter(|| {
+        runtime.block_on(async {
+            count.store(0, Ordering::Relaxed);
+            for i in 0..EVENTS {
+                tx.send(Event::Presence(i % 2 == 0)).await.unwrap();
+                // Events for other traits are not dispatched to the devices
+                tx.send(Event::Darkness(i % 2 == 0)).await.unwrap();
+            }
+
+            while count.load(Ordering::Relaxed) < DEVICES * EVENTS {
+                tokio::task::yield_now().await;
+            }
+        })
+    });
+}
@@ -1,12 +1,25 @@
+use std::collections::BTreeMap;
 use std::marker::PhantomData;
+use std::sync::{Arc, RwLock};
 
+use automation_cast::Cast;
+use futures::future::{select, Either};
 use mlua::{FromLua, IntoLua, LuaSerdeExt};
 use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error};
+
+use crate::device::Device;
+
+// Functions are called in order of priority, lower priorities are called first. Functions with
+// the same priority are called in the order they were registered.
+type Functions = BTreeMap<i32, Vec<mlua::Function>>;
 
 #[derive(Debug, Clone)]
 struct Internal {
-    uuid: uuid::Uuid,
     lua: mlua::Lua,
+    // Shared, so functions registered after the callback was handed to a device are also called
+    functions: Arc<RwLock<Functions>>,
 }
 
 #[derive(Debug, Clone)]
@@ -26,19 +39,78 @@ impl<T, S> Default for ActionCallback<T, S> {
     }
 }
 
-impl<T, S> FromLua for ActionCallback<T, S> {
-    fn from_lua(value: mlua::Value, lua: &mlua::Lua) -> mlua::Result<Self> {
-        let uuid = uuid::Uuid::new_v4();
-        lua.set_named_registry_value(&uuid.to_string(), value)?;
-
-        Ok(ActionCallback {
+impl<T, S> ActionCallback<T, S> {
+    fn new(lua: &mlua::Lua) -> Self {
+        Self {
             internal: Some(Internal {
-                uuid,
                 lua: lua.clone(),
+                functions: Default::default(),
             }),
             _this: PhantomData::<T>,
             _state: PhantomData::<S>,
-        })
+        }
+    }
+
+    pub fn register(&self, f: mlua::Function, priority: i32) {
+        if let Some(internal) = &self.internal {
+            internal
+                .functions
+                .write()
+                .expect("Lock should not be poisoned")
+                .entry(priority)
+                .or_default()
+                .push(f);
+        }
+    }
+}
+
+impl<T, S> FromLua for ActionCallback<T, S> {
+    fn from_lua(value: mlua::Value, lua: &mlua::Lua) -> mlua::Result<Self> {
+        match value {
+            mlua::Value::Function(f) => {
+                let callback = Self::new(lua);
+                callback.register(f, 0);
+
+                Ok(callback)
+            }
+            // Created in lua using ActionCallback.new(), so more functions can be registered
+            mlua::Value::UserData(ud) => {
+                let callback = ud.borrow::<ActionCallback<mlua::Value, mlua::Value>>()?;
+
+                Ok(Self {
+                    internal: callback.internal.clone(),
+                    _this: PhantomData::<T>,
+                    _state: PhantomData::<S>,
+                })
+            }
+            value => Err(mlua::Error::FromLuaConversionError {
+                from: value.type_name(),
+                to: "ActionCallback".into(),
+                message: Some("expected a function or ActionCallback".into()),
+            }),
+        }
+    }
+}
+
+impl mlua::UserData for ActionCallback<mlua::Value, mlua::Value> {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_function("new", |lua, f: Option<mlua::Function>| {
+            let callback = Self::new(lua);
+            if let Some(f) = f {
+                callback.register(f, 0);
+            }
+
+            Ok(callback)
+        });
+
+        methods.add_method(
+            "register",
+            |_lua, this, (f, priority): (mlua::Function, Option<i32>)| {
+                this.register(f, priority.unwrap_or_default());
+
+                Ok(())
+            },
+        );
     }
 }
 
@@ -55,13 +127,22 @@ where
 
         let state = internal.lua.to_value(state).unwrap();
 
-        let callback: mlua::Value = internal
-            .lua
-            .named_registry_value(&internal.uuid.to_string())
-            .unwrap();
-        match callback {
-            mlua::Value::Function(f) => f.call_async::<()>((this.clone(), state)).await.unwrap(),
-            _ => todo!("Only functions are currently supported"),
+        // Not holding the lock while calling, so the functions can register more functions
+        let functions: Vec<_> = internal
+            .functions
+            .read()
+            .expect("Lock should not be poisoned")
+            .values()
+            .flatten()
+            .cloned()
+            .collect();
+
+        // A failing function should not prevent the others from being called
+        for f in functions {
+            if let Err(err) = f.call_async::<()>((this.clone(), state.clone())).await {
+                let id = Cast::<dyn Device>::cast(this).map(Device::get_id);
+                error!(id, "Callback failed: {err}");
+            }
         }
     }
 
@@ -69,3 +150,230 @@ where
         self.internal.is_some()
     }
 }
+
+#[derive(Debug, Clone, Default, FromLua)]
+pub struct CancelToken(CancellationToken);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.is_cancelled()
+    }
+}
+
+impl mlua::UserData for CancelToken {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("cancel", |_lua, this, ()| {
+            debug!("Canceling callback");
+            this.cancel();
+
+            Ok(())
+        });
+
+        methods.add_method("is_cancelled", |_lua, this, ()| Ok(this.is_cancelled()));
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CancellableCallback<T, S> {
+    callback: ActionCallback<T, S>,
+}
+
+impl<T, S> Default for CancellableCallback<T, S> {
+    fn default() -> Self {
+        Self {
+            callback: Default::default(),
+        }
+    }
+}
+
+impl<T, S> FromLua for CancellableCallback<T, S>
+where
+    T: Clone + 'static,
+    S: Clone + 'static,
+{
+    fn from_lua(value: mlua::Value, lua: &mlua::Lua) -> mlua::Result<Self> {
+        match value {
+            mlua::Value::UserData(ud) if ud.is::<Self>() => Ok(ud.borrow::<Self>()?.clone()),
+            value => Ok(Self {
+                callback: ActionCallback::from_lua(value, lua)?,
+            }),
+        }
+    }
+}
+
+impl<T, S> CancellableCallback<T, S>
+where
+    T: IntoLua + Sync + Send + Clone + 'static,
+    S: Serialize + Sync + Send + Clone + 'static,
+{
+    /// Run the callback in the background, the returned token can be used to abort the callback
+    /// before it completes
+    pub fn call_cancellable(&self, this: &T, state: &S) -> CancelToken {
+        let token = CancelToken::default();
+
+        if !self.is_set() {
+            return token;
+        }
+
+        tokio::spawn({
+            let token = token.clone();
+            let callback = self.callback.clone();
+            let this = this.clone();
+            let state = state.clone();
+
+            async move {
+                let cancelled = token.0.cancelled();
+                let call = callback.call(&this, &state);
+
+                if let Either::Left(_) = select(Box::pin(cancelled), Box::pin(call)).await {
+                    debug!("Callback was cancelled before it completed");
+                }
+            }
+        });
+
+        token
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.callback.is_set()
+    }
+}
+
+impl mlua::UserData for CancellableCallback<mlua::Value, ()> {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_function("new", |_lua, callback: ActionCallback<mlua::Value, ()>| {
+            Ok(Self { callback })
+        });
+
+        methods.add_method("call_async", |_lua, this, arg: mlua::Value| {
+            Ok(this.call_cancellable(&arg, &()))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn setup() -> mlua::Lua {
+        let lua = mlua::Lua::new();
+
+        let sleep = lua
+            .create_async_function(|_lua, ms: u64| async move {
+                tokio::time::sleep(Duration::from_millis(ms)).await;
+                Ok(())
+            })
+            .unwrap();
+        lua.globals().set("sleep", sleep).unwrap();
+        lua.globals().set("done", false).unwrap();
+
+        crate::helpers::register_with_lua(&lua).unwrap();
+
+        lua
+    }
+
+    fn is_done(lua: &mlua::Lua) -> bool {
+        lua.globals().get("done").unwrap()
+    }
+
+    #[tokio::test]
+    async fn priority() {
+        let lua = setup();
+        let callback: ActionCallback<mlua::Value, ()> = lua
+            .load(
+                r#"
+                order = {}
+                local callback = ActionCallback.new(function() table.insert(order, "default") end)
+                callback:register(function() table.insert(order, "cosmetic") end, 100)
+                callback:register(function() table.insert(order, "safety") end, -100)
+                callback:register(function() table.insert(order, "also default") end)
+                return callback
+                "#,
+            )
+            .eval()
+            .unwrap();
+
+        callback.call(&mlua::Value::Nil, &()).await;
+
+        let order: Vec<String> = lua.globals().get("order").unwrap();
+        assert_eq!(order, ["safety", "default", "also default", "cosmetic"]);
+    }
+
+    #[tokio::test]
+    async fn error_does_not_stop_callback() {
+        let lua = setup();
+        let callback: ActionCallback<mlua::Value, ()> = lua
+            .load(
+                r#"
+                local callback = ActionCallback.new(function() error("failed") end)
+                callback:register(function() done = true end, 100)
+                return callback
+                "#,
+            )
+            .eval()
+            .unwrap();
+
+        callback.call(&mlua::Value::Nil, &()).await;
+
+        assert!(is_done(&lua));
+    }
+
+    #[tokio::test]
+    async fn callback_completes() {
+        let lua = setup();
+        let callback: CancellableCallback<mlua::Value, ()> = lua
+            .load("function() sleep(10) done = true end")
+            .eval()
+            .unwrap();
+
+        let token = callback.call_cancellable(&mlua::Value::Nil, &());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert!(!token.is_cancelled());
+        assert!(is_done(&lua));
+    }
+
+    #[tokio::test]
+    async fn cancel_drops_in_flight_call() {
+        let lua = setup();
+        let callback: CancellableCallback<mlua::Value, ()> = lua
+            .load("function() sleep(50) done = true end")
+            .eval()
+            .unwrap();
+
+        let token = callback.call_cancellable(&mlua::Value::Nil, &());
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        token.cancel();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert!(token.is_cancelled());
+        assert!(!is_done(&lua));
+    }
+
+    #[tokio::test]
+    async fn call_async_from_lua() {
+        let lua = setup();
+        lua.load(
+            r#"
+            local callback = CancellableCallback.new(function() sleep(50) done = true end)
+            token = callback:call_async(nil)
+            "#,
+        )
+        .exec()
+        .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        lua.load("token:cancel()").exec().unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let cancelled: bool = lua.load("return token:is_cancelled()").eval().unwrap();
+        assert!(cancelled);
+        assert!(!is_done(&lua));
+    }
+}
@@ -0,0 +1,226 @@
+use std::sync::{Arc, RwLock};
+
+use google_home::device;
+use rumqttc::{ClientError, Publish, QoS};
+use tokio::sync::watch;
+use tracing::{debug, warn};
+
+use crate::action_callback::ActionCallback;
+use crate::config::MqttDeviceConfig;
+use crate::messages::{AvailabilityMessage, DeviceInfoMessage};
+use crate::mqtt::WrappedAsyncClient;
+
+// Keeps track of whether a device is reachable, changes can be followed through `watch`
+#[derive(Debug, Clone)]
+pub struct Availability {
+    id: String,
+    sender: watch::Sender<bool>,
+}
+
+impl Availability {
+    pub fn new(id: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            // Devices are assumed to be available until they report otherwise
+            sender: watch::Sender::new(true),
+        }
+    }
+
+    pub fn get(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    // Returns true if the availability changed
+    pub fn set(&self, available: bool) -> bool {
+        let changed = self.sender.send_if_modified(|current| {
+            if *current == available {
+                return false;
+            }
+
+            *current = available;
+            true
+        });
+
+        if changed {
+            debug!(id = self.id, "Available: {available}");
+        }
+
+        changed
+    }
+
+    pub fn watch(&self) -> watch::Receiver<bool> {
+        self.sender.subscribe()
+    }
+}
+
+enum Handled {
+    Ignored,
+    Info,
+    Availability { changed: bool },
+}
+
+// Availability and device info as published by zigbee2mqtt on the topics next to the device
+#[derive(Debug, Clone)]
+pub struct MqttDeviceStatus {
+    availability_topic: String,
+    info_topic: String,
+    availability: Availability,
+    // Populated from the first message received on the '$info' topic
+    device_info: Arc<RwLock<Option<device::Info>>>,
+}
+
+impl MqttDeviceStatus {
+    pub fn new(id: impl Into<String>, mqtt: &MqttDeviceConfig) -> Self {
+        Self {
+            availability_topic: mqtt.availability_topic(),
+            info_topic: mqtt.info_topic(),
+            availability: Availability::new(id),
+            device_info: Default::default(),
+        }
+    }
+
+    pub async fn subscribe(&self, client: &WrappedAsyncClient) -> Result<(), ClientError> {
+        for topic in [&self.availability_topic, &self.info_topic] {
+            client.subscribe(topic, QoS::AtLeastOnce).await?;
+        }
+
+        Ok(())
+    }
+
+    pub fn availability(&self) -> &Availability {
+        &self.availability
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.availability.get()
+    }
+
+    pub fn device_info(&self) -> Option<device::Info> {
+        self.device_info
+            .read()
+            .expect("Lock should not be poisoned")
+            .clone()
+    }
+
+    fn update(&self, message: &Publish) -> Handled {
+        if message.topic == self.availability_topic {
+            let changed = match AvailabilityMessage::try_from(message.clone()) {
+                Ok(message) => self.availability.set(message.available()),
+                Err(err) => {
+                    warn!(id = self.availability.id, "Failed to parse message: {err}");
+                    false
+                }
+            };
+
+            Handled::Availability { changed }
+        } else if message.topic == self.info_topic {
+            let mut device_info = self
+                .device_info
+                .write()
+                .expect("Lock should not be poisoned");
+            if device_info.is_none() {
+                match DeviceInfoMessage::try_from(message.clone()) {
+                    Ok(message) => {
+                        debug!(id = self.availability.id, "Device info: {message:?}");
+                        *device_info = Some(message.into());
+                    }
+                    Err(err) => warn!(id = self.availability.id, "Failed to parse message: {err}"),
+                }
+            }
+
+            Handled::Info
+        } else {
+            Handled::Ignored
+        }
+    }
+
+    // Returns true if the message was an availability or device info message
+    pub fn handle(&self, message: &Publish) -> bool {
+        !matches!(self.update(message), Handled::Ignored)
+    }
+
+    // Same as `handle`, but also calls the callback when the availability changed
+    pub async fn handle_with_callback<T>(
+        &self,
+        device: &T,
+        message: &Publish,
+        callback: &ActionCallback<T, bool>,
+    ) -> bool
+    where
+        T: mlua::IntoLua + Sync + Send + Clone + 'static,
+    {
+        match self.update(message) {
+            Handled::Ignored => false,
+            Handled::Info => true,
+            Handled::Availability { changed } => {
+                if changed {
+                    callback.call(device, &self.is_available()).await;
+                }
+
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status() -> MqttDeviceStatus {
+        MqttDeviceStatus::new(
+            "test",
+            &MqttDeviceConfig {
+                topic: "zigbee2mqtt/test".into(),
+                availability: None,
+            },
+        )
+    }
+
+    #[test]
+    fn availability() {
+        let status = status();
+        let mut rx = status.availability().watch();
+        assert!(status.is_available());
+
+        assert!(!status.handle(&Publish::new(
+            "zigbee2mqtt/test",
+            QoS::AtLeastOnce,
+            "offline"
+        )));
+        assert!(status.is_available());
+
+        assert!(status.handle(&Publish::new(
+            "zigbee2mqtt/test/availability",
+            QoS::AtLeastOnce,
+            r#"{"state":"offline"}"#
+        )));
+        assert!(!status.is_available());
+        assert!(rx.has_changed().unwrap());
+        rx.mark_unchanged();
+
+        // Repeated messages do not count as a change
+        assert!(!status.availability().set(false));
+        assert!(!rx.has_changed().unwrap());
+    }
+
+    #[test]
+    fn device_info() {
+        let status = status();
+        assert!(status.device_info().is_none());
+
+        for model in ["first", "second"] {
+            assert!(status.handle(&Publish::new(
+                "zigbee2mqtt/test/$info",
+                QoS::AtLeastOnce,
+                format!(r#"{{"model_id":"{model}"}}"#),
+            )));
+        }
+
+        // Only the first message is used
+        assert_eq!(
+            status.device_info().unwrap().model.as_deref(),
+            Some("first")
+        );
+    }
+}
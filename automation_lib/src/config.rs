@@ -1,8 +1,16 @@
+use std::collections::HashMap;
+use std::fmt;
 use std::net::{Ipv4Addr, SocketAddr};
+use std::ops::Deref;
+use std::path::PathBuf;
 use std::time::Duration;
 
+use google_home::device::Name;
 use rumqttc::{MqttOptions, Transport};
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
+use serde_json::json;
+
+use crate::{device_manager, mqtt};
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct MqttConfig {
@@ -10,23 +18,78 @@ pub struct MqttConfig {
     pub port: u16,
     pub client_name: String,
     pub username: String,
-    pub password: String,
+    pub password: Secret<String>,
     #[serde(default)]
     pub tls: bool,
+    // Maximum number of messages to hold on to while disconnected
+    #[serde(default = "default_offline_queue_size")]
+    pub offline_queue_size: usize,
+    // Skip retained messages that have already been processed when they are sent again after
+    // reconnecting
+    #[serde(default = "default_deduplicate_retained")]
+    pub deduplicate_retained: bool,
+    // Brokers to fail over to, in order, when the broker above can not be reached
+    #[serde(default)]
+    pub brokers: Vec<MqttBrokerConfig>,
 }
 
-impl From<MqttConfig> for MqttOptions {
-    fn from(value: MqttConfig) -> Self {
-        let mut mqtt_options = MqttOptions::new(value.client_name, value.host, value.port);
-        mqtt_options.set_credentials(value.username, value.password);
+#[derive(Debug, Clone, Deserialize)]
+pub struct MqttBrokerConfig {
+    pub host: String,
+    pub port: u16,
+    // Defaults to the credentials of the primary broker
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<Secret<String>>,
+    #[serde(default)]
+    pub tls: bool,
+}
+
+fn default_offline_queue_size() -> usize {
+    mqtt::DEFAULT_OFFLINE_QUEUE_SIZE
+}
+
+fn default_deduplicate_retained() -> bool {
+    true
+}
+
+impl MqttConfig {
+    fn options(&self, broker: &MqttBrokerConfig) -> MqttOptions {
+        let mut mqtt_options =
+            MqttOptions::new(self.client_name.clone(), broker.host.clone(), broker.port);
+        mqtt_options.set_credentials(
+            broker.username.as_ref().unwrap_or(&self.username),
+            broker.password.as_ref().unwrap_or(&self.password).deref(),
+        );
         mqtt_options.set_keep_alive(Duration::from_secs(5));
 
-        if value.tls {
+        if broker.tls {
             mqtt_options.set_transport(Transport::tls_with_default_config());
         }
 
         mqtt_options
     }
+
+    /// Options for the brokers to fail over to, in order
+    pub fn failover_options(&self) -> Vec<MqttOptions> {
+        self.brokers
+            .iter()
+            .map(|broker| self.options(broker))
+            .collect()
+    }
+}
+
+impl From<MqttConfig> for MqttOptions {
+    fn from(value: MqttConfig) -> Self {
+        value.options(&MqttBrokerConfig {
+            host: value.host.clone(),
+            port: value.port,
+            username: None,
+            password: None,
+            tls: value.tls,
+        })
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -36,6 +99,58 @@ pub struct FulfillmentConfig {
     pub ip: Ipv4Addr,
     #[serde(default = "default_fulfillment_port")]
     pub port: u16,
+    // TLS is enabled when both the certificate and key are set
+    #[serde(default)]
+    pub tls_cert_path: Option<PathBuf>,
+    #[serde(default)]
+    pub tls_key_path: Option<PathBuf>,
+    // Serve the Prometheus metrics on a separate address, disabled if not set
+    #[serde(default)]
+    pub metrics: Option<MetricsConfig>,
+    // Allow browsers on other origins to call the API, only the same origin is allowed if not set
+    #[serde(default)]
+    pub cors: Option<CorsConfig>,
+    // How often the devices are asked to check their health
+    #[serde(
+        default = "default_health_interval_seconds",
+        deserialize_with = "deserialize_health_interval_seconds"
+    )]
+    pub health_interval_seconds: u64,
+    // Number of recent responses that are kept to answer retried requests, 0 disables this
+    #[serde(default = "default_request_cache_size")]
+    pub request_cache_size: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CorsConfig {
+    // Use "*" to allow any origin
+    pub allowed_origins: Vec<String>,
+    #[serde(default = "default_cors_methods")]
+    pub allowed_methods: Vec<String>,
+    #[serde(default)]
+    pub allow_credentials: bool,
+}
+
+fn default_cors_methods() -> Vec<String> {
+    vec!["GET".into(), "POST".into()]
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MetricsConfig {
+    #[serde(default = "default_fulfillment_ip")]
+    pub ip: Ipv4Addr,
+    #[serde(default = "default_metrics_port")]
+    pub port: u16,
+}
+
+impl From<MetricsConfig> for SocketAddr {
+    fn from(metrics: MetricsConfig) -> Self {
+        (metrics.ip, metrics.port).into()
+    }
+}
+
+fn default_metrics_port() -> u16 {
+    9090
 }
 
 impl From<FulfillmentConfig> for SocketAddr {
@@ -52,13 +167,48 @@ fn default_fulfillment_port() -> u16 {
     7878
 }
 
-#[derive(Debug, Clone, Deserialize)]
+fn default_health_interval_seconds() -> u64 {
+    device_manager::DEFAULT_HEALTH_INTERVAL.as_secs()
+}
+
+// An interval of zero would check the health of the devices in a busy loop
+fn deserialize_health_interval_seconds<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match u64::deserialize(deserializer)? {
+        0 => Err(serde::de::Error::custom(
+            "health_interval_seconds needs to be at least 1",
+        )),
+        seconds => Ok(seconds),
+    }
+}
+
+fn default_request_cache_size() -> usize {
+    google_home::DEFAULT_REQUEST_CACHE_SIZE
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct InfoConfig {
     pub name: String,
     pub room: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // Arbitrary metadata, reported to Google Home as part of the custom data
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    // URL or Material icon name
+    pub icon: Option<String>,
 }
 
 impl InfoConfig {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            ..Default::default()
+        }
+    }
+
     pub fn identifier(&self) -> String {
         (if let Some(room) = &self.room {
             room.to_ascii_lowercase().replace(' ', "_") + "_"
@@ -66,9 +216,430 @@ impl InfoConfig {
             String::new()
         }) + &self.name.to_ascii_lowercase().replace(' ', "_")
     }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+
+    pub fn device_name(&self) -> Name {
+        let mut name = Name::new(&self.name);
+        for tag in &self.tags {
+            name.add_nickname(tag);
+        }
+
+        name
+    }
+
+    pub fn custom_data(&self) -> Option<serde_json::Value> {
+        if self.icon.is_none() && self.labels.is_empty() {
+            return None;
+        }
+
+        let mut data = json!({});
+        if let Some(icon) = &self.icon {
+            data["icon"] = json!(icon);
+        }
+        if !self.labels.is_empty() {
+            data["labels"] = json!(self.labels);
+        }
+
+        Some(data)
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct MqttDeviceConfig {
     pub topic: String,
+    // Topic on which the device reports if it is available, defaults to '{topic}/availability'
+    #[serde(default)]
+    pub availability: Option<String>,
+}
+
+impl MqttDeviceConfig {
+    pub fn availability_topic(&self) -> String {
+        self.availability
+            .clone()
+            .unwrap_or_else(|| format!("{}/availability", self.topic))
+    }
+
+    pub fn info_topic(&self) -> String {
+        format!("{}/$info", self.topic)
+    }
+}
+
+// Can be included in the config of any device to retry creating the device when it fails
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct RetryConfig {
+    #[serde(default)]
+    pub retry_count: u32,
+    #[serde(default = "default_retry_delay_ms")]
+    pub retry_delay_ms: u64,
+}
+
+impl RetryConfig {
+    pub fn delay(&self) -> Duration {
+        Duration::from_millis(self.retry_delay_ms)
+    }
+}
+
+fn default_retry_delay_ms() -> u64 {
+    1000
+}
+
+// Only used to identify a device in the logs before it has been created, devices either have an
+// explicit identifier or derive it from their name and room
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ConfigIdentifier {
+    identifier: Option<String>,
+    name: Option<String>,
+    room: Option<String>,
+}
+
+impl ConfigIdentifier {
+    pub fn identifier(&self) -> String {
+        match (&self.identifier, &self.name) {
+            (Some(identifier), _) => identifier.clone(),
+            (None, Some(name)) => InfoConfig {
+                name: name.clone(),
+                room: self.room.clone(),
+                ..Default::default()
+            }
+            .identifier(),
+            (None, None) => "unknown".into(),
+        }
+    }
+}
+
+// Wrapper that prevents the value from showing up in the logs
+#[derive(Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> From<T> for Secret<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T> Deref for Secret<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "***")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automation_macro::LuaDeviceConfig;
+    use mlua::LuaSerdeExt;
+
+    use super::*;
+    use crate::error::{ConfigContext, DeviceConfigError};
+
+    #[derive(Debug, LuaDeviceConfig)]
+    struct Config {
+        username: String,
+        #[device_config(secret)]
+        password: Secret<String>,
+    }
+
+    #[derive(Debug, LuaDeviceConfig)]
+    struct EnvConfig {
+        #[device_config(env("AUTOMATION_TEST_API_KEY"), secret)]
+        api_key: Secret<String>,
+        #[device_config(
+            env("AUTOMATION_TEST_TIMEOUT"),
+            rename("timeout_seconds"),
+            default(5),
+            with(Duration::from_secs)
+        )]
+        timeout: Duration,
+        #[device_config(env("AUTOMATION_TEST_PORT"), default(80))]
+        port: u16,
+    }
+
+    #[derive(Debug, LuaDeviceConfig)]
+    struct AliasConfig {
+        #[device_config(alias("min"))]
+        low: isize,
+        #[device_config(alias("max"), default(100))]
+        high: isize,
+        #[device_config(alias("old_mqtt"), from_lua)]
+        mqtt: InnerConfig,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Debug, LuaDeviceConfig)]
+    struct OuterConfig {
+        #[device_config(from_lua)]
+        mqtt: InnerConfig,
+    }
+
+    #[derive(Debug, LuaDeviceConfig)]
+    struct InnerConfig {
+        topic: String,
+    }
+
+    #[test]
+    fn custom_data() {
+        let mut info = InfoConfig::new("Lamp");
+        assert_eq!(info.custom_data(), None);
+
+        info.icon = Some("lightbulb".into());
+        assert_eq!(info.custom_data(), Some(json!({ "icon": "lightbulb" })));
+
+        info.labels.insert("floor".into(), "ground".into());
+        assert_eq!(
+            info.custom_data(),
+            Some(json!({ "icon": "lightbulb", "labels": { "floor": "ground" } }))
+        );
+    }
+
+    #[test]
+    fn config_identifier() {
+        let lua = mlua::Lua::new();
+        let identifier = |table: mlua::Table| -> String {
+            lua.from_value::<ConfigIdentifier>(mlua::Value::Table(table))
+                .unwrap()
+                .identifier()
+        };
+
+        let table = lua.create_table().unwrap();
+        table.set("name", "Ceiling Light").unwrap();
+        table.set("room", "Living Room").unwrap();
+        assert_eq!(identifier(table), "living_room_ceiling_light");
+
+        let table = lua.create_table().unwrap();
+        table.set("identifier", "ping").unwrap();
+        assert_eq!(identifier(table), "ping");
+
+        assert_eq!(identifier(lua.create_table().unwrap()), "unknown");
+    }
+
+    #[test]
+    fn fulfillment_with_tls() {
+        let lua = mlua::Lua::new();
+        let table = lua.create_table().unwrap();
+        table
+            .set("openid_url", "https://login.example.com")
+            .unwrap();
+        table.set("port", 8443).unwrap();
+        table
+            .set("tls_cert_path", "/etc/automation/cert.pem")
+            .unwrap();
+        table
+            .set("tls_key_path", "/etc/automation/key.pem")
+            .unwrap();
+
+        let config: FulfillmentConfig = lua.from_value(mlua::Value::Table(table)).unwrap();
+
+        assert_eq!(
+            config.tls_cert_path,
+            Some(PathBuf::from("/etc/automation/cert.pem"))
+        );
+        assert_eq!(
+            config.tls_key_path,
+            Some(PathBuf::from("/etc/automation/key.pem"))
+        );
+
+        let addr: SocketAddr = config.into();
+        assert_eq!(addr, SocketAddr::from(([0, 0, 0, 0], 8443)));
+    }
+
+    #[test]
+    fn health_interval_zero() {
+        let lua = mlua::Lua::new();
+        let table = lua.create_table().unwrap();
+        table
+            .set("openid_url", "https://login.example.com")
+            .unwrap();
+        table.set("health_interval_seconds", 0).unwrap();
+
+        let result: mlua::Result<FulfillmentConfig> = lua.from_value(mlua::Value::Table(table));
+        assert!(result.is_err());
+    }
+
+    // Marks the test binary as started by run_with_env
+    const ENV_CHILD: &str = "AUTOMATION_TEST_ENV_CHILD";
+
+    // Changing the environment races with other tests that run in parallel, so the test binary
+    // runs the given test again in a separate process with the environment set instead
+    fn run_with_env(test: &str, envs: &[(&str, &str)]) {
+        let module = module_path!().split_once("::").unwrap().1;
+
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .arg(format!("{module}::{test}"))
+            .arg("--exact")
+            .env(ENV_CHILD, "1")
+            .env_remove("AUTOMATION_TEST_API_KEY")
+            .env_remove("AUTOMATION_TEST_TIMEOUT")
+            .env_remove("AUTOMATION_TEST_PORT")
+            .envs(envs.iter().copied())
+            .output()
+            .unwrap();
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{stdout}");
+        assert!(stdout.contains("1 passed"), "{stdout}");
+    }
+
+    fn env_config() -> Option<mlua::Result<EnvConfig>> {
+        std::env::var_os(ENV_CHILD)?;
+
+        let lua = mlua::Lua::new();
+        let table = lua.create_table().unwrap();
+        table.set("api_key", "from_lua").unwrap();
+        table.set("timeout_seconds", 10).unwrap();
+
+        Some(lua.unpack(mlua::Value::Table(table)))
+    }
+
+    #[test]
+    fn env_fallback() {
+        run_with_env(
+            "env_set",
+            &[
+                ("AUTOMATION_TEST_API_KEY", "from_env"),
+                ("AUTOMATION_TEST_TIMEOUT", "30"),
+            ],
+        );
+        run_with_env("env_invalid", &[("AUTOMATION_TEST_PORT", "not a port")]);
+        run_with_env("env_unset", &[]);
+    }
+
+    #[test]
+    fn env_set() {
+        let Some(config) = env_config() else {
+            return;
+        };
+
+        let config = config.unwrap();
+        assert_eq!(*config.api_key, "from_env");
+        assert_eq!(config.timeout, Duration::from_secs(30));
+        assert_eq!(config.port, 80);
+    }
+
+    #[test]
+    fn env_invalid() {
+        let Some(config) = env_config() else {
+            return;
+        };
+
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn env_unset() {
+        let Some(config) = env_config() else {
+            return;
+        };
+
+        let config = config.unwrap();
+        assert_eq!(*config.api_key, "from_lua");
+        assert_eq!(config.timeout, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn alias() {
+        let lua = mlua::Lua::new();
+        let mqtt = lua.create_table().unwrap();
+        mqtt.set("topic", "old").unwrap();
+        let table = lua.create_table().unwrap();
+        table.set("min", 10).unwrap();
+        table.set("old_mqtt", mqtt).unwrap();
+
+        let config: AliasConfig = lua.unpack(mlua::Value::Table(table.clone())).unwrap();
+        assert_eq!(config.low, 10);
+        assert_eq!(config.high, 100);
+        assert_eq!(config.mqtt.topic, "old");
+
+        // The actual name takes precedence over the alias
+        let mqtt = lua.create_table().unwrap();
+        mqtt.set("topic", "new").unwrap();
+        table.set("low", 20).unwrap();
+        table.set("max", 200).unwrap();
+        table.set("mqtt", mqtt).unwrap();
+
+        let config: AliasConfig = lua.unpack(mlua::Value::Table(table)).unwrap();
+        assert_eq!(config.low, 20);
+        assert_eq!(config.high, 200);
+        assert_eq!(config.mqtt.topic, "new");
+    }
+
+    #[test]
+    fn secret_is_masked() {
+        let lua = mlua::Lua::new();
+        let table = lua.create_table().unwrap();
+        table.set("username", "user").unwrap();
+        table.set("password", "hunter2").unwrap();
+
+        let config: Config = lua.unpack(mlua::Value::Table(table)).unwrap();
+
+        assert_eq!(config.username, "user");
+        assert_eq!(*config.password, "hunter2");
+
+        let debug = format!("{config:?}");
+        assert!(debug.contains("user"));
+        assert!(debug.contains("***"));
+        assert!(!debug.contains("hunter2"));
+    }
+
+    #[test]
+    fn field_path() {
+        let lua = mlua::Lua::new();
+        let table = lua.create_table().unwrap();
+        table.set("username", "user").unwrap();
+
+        let err = lua.unpack::<Config>(mlua::Value::Table(table)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error in 'Config' at field 'password': Missing field 'password'"
+        );
+
+        // Devices replace the name of their config struct with their own name
+        let table = lua.create_table().unwrap();
+        table.set("mqtt", lua.create_table().unwrap()).unwrap();
+        let err = ConfigContext::with_device_type(
+            lua.unpack::<OuterConfig>(mlua::Value::Table(table)),
+            "IkeaOutlet",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error in 'IkeaOutlet' at field 'mqtt.topic': Missing field 'topic'"
+        );
+
+        let table = lua.create_table().unwrap();
+        table.set("mqtt", lua.create_table().unwrap()).unwrap();
+
+        let err = lua
+            .unpack::<OuterConfig>(mlua::Value::Table(table))
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(DeviceConfigError::ConfigParse { device_type, field_path, .. })
+                if device_type == "OuterConfig" && field_path == &["mqtt", "topic"]
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Error in 'OuterConfig' at field 'mqtt.topic': Missing field 'topic'"
+        );
+    }
 }
@@ -1,19 +1,45 @@
-use std::fmt::Debug;
+use std::fmt::{Debug, Display};
+use std::time::Duration;
 
+use async_trait::async_trait;
 use automation_cast::Cast;
 use dyn_clone::DynClone;
-use google_home::traits::OnOff;
-use mlua::ObjectLike;
+use google_home::traits::{Brightness, OnOff};
+use mlua::{ExternalError, ObjectLike};
+use serde::Serialize;
+use tracing::warn;
 
-use crate::event::{OnDarkness, OnMqtt, OnNotification, OnPresence};
+use crate::availability::Availability;
+use crate::config::InfoConfig;
+use crate::error::DeviceConfigError;
+use crate::event::{OnAlarm, OnDarkness, OnMqtt, OnNotification, OnPresence};
+use crate::lua::traits::Timeout;
 
 // TODO: Make this a proper macro
 macro_rules! impl_device {
     ($device:ty) => {
+        impl_device!($device, _methods => {});
+    };
+    ($device:ty, $methods:ident => $extra:block) => {
         impl mlua::UserData for $device {
             fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
-                methods.add_async_function("new", |_lua, config| async {
-                    let device: $device = LuaDeviceCreate::create(config)
+                methods.add_async_function("new", |lua, config: mlua::Value| async move {
+                    let retry: $crate::config::RetryConfig = mlua::LuaSerdeExt::from_value_with(
+                        &lua,
+                        config.clone(),
+                        mlua::DeserializeOptions::new().deny_unsupported_types(false),
+                    )?;
+                    let id: $crate::config::ConfigIdentifier = mlua::LuaSerdeExt::from_value_with(
+                        &lua,
+                        config.clone(),
+                        mlua::DeserializeOptions::new().deny_unsupported_types(false),
+                    )?;
+                    let config = $crate::error::ConfigContext::with_device_type(
+                        mlua::FromLua::from_lua(config, &lua),
+                        stringify!($device),
+                    )?;
+
+                    let device: $device = LuaDeviceCreate::create_with_retry(&id.identifier(), config, retry.retry_count, retry.delay())
                         .await
                         .map_err(mlua::ExternalError::into_lua_err)?;
 
@@ -27,6 +53,10 @@ macro_rules! impl_device {
 
                 methods.add_async_method("get_id", |_lua, this, _: ()| async move { Ok(this.get_id()) });
 
+                methods.add_method("implements", |_lua, this, name: String| {
+                    Ok(google_home::traits::has_trait(this, &name))
+                });
+
                 if impls::impls!($device: google_home::traits::OnOff) {
                     methods.add_async_method("set_on", |_lua, this, on: bool| async move {
                         (this.deref().cast() as Option<&dyn google_home::traits::OnOff>)
@@ -45,7 +75,17 @@ macro_rules! impl_device {
                             .await
                             .unwrap())
                     });
+
+                    methods.add_async_method("toggle", |_lua, this, _: ()| async move {
+                        $crate::lua::traits::on_off::toggle((this.deref().cast() as Option<&dyn google_home::traits::OnOff>)
+                            .expect("Cast should be valid"))
+                            .await
+                            .map_err(mlua::ExternalError::into_lua_err)
+                    });
                 }
+
+                let $methods = methods;
+                $extra
             }
         }
     };
@@ -60,6 +100,63 @@ pub trait LuaDeviceCreate {
     async fn create(config: Self::Config) -> Result<Self, Self::Error>
     where
         Self: Sized;
+
+    // Calls create until it succeeds, at most retries + 1 times
+    async fn create_with_retry(
+        id: &str,
+        config: Self::Config,
+        retries: u32,
+        delay: Duration,
+    ) -> Result<Self, Self::Error>
+    where
+        Self: Sized,
+        Self::Config: Clone + Send,
+        Self::Error: Display + Send,
+    {
+        let mut attempt = 0;
+        loop {
+            match Self::create(config.clone()).await {
+                Err(err) if attempt < retries => {
+                    attempt += 1;
+                    warn!(
+                        id,
+                        "Failed to create device ({attempt}/{retries}): {err}, retrying in {delay:?}"
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+// Hooks that are called when the device is added to the device manager and before the
+// application exits
+#[async_trait]
+pub trait DeviceLifecycle: Sync + Send {
+    async fn on_start(&self) {}
+
+    async fn on_stop(&self) {}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", content = "reason", rename_all = "snake_case")]
+pub enum HealthStatus {
+    Healthy,
+    // Still works, but something is off
+    Degraded(String),
+    Unhealthy(String),
+}
+
+// Periodically called by the device manager to check if the device can still be reached
+#[async_trait]
+pub trait DeviceHealth: Sync + Send {
+    async fn check_health(&self) -> HealthStatus;
+}
+
+// Devices that know if they can be reached, the device manager sends an event when it changes
+pub trait DeviceAvailability: Sync + Send {
+    fn availability(&self) -> &Availability;
 }
 
 pub trait Device:
@@ -72,9 +169,19 @@ pub trait Device:
     + Cast<dyn OnPresence>
     + Cast<dyn OnDarkness>
     + Cast<dyn OnNotification>
+    + Cast<dyn OnAlarm>
     + Cast<dyn OnOff>
+    + Cast<dyn Brightness>
+    + Cast<dyn Timeout>
+    + Cast<dyn DeviceLifecycle>
+    + Cast<dyn DeviceHealth>
+    + Cast<dyn DeviceAvailability>
 {
     fn get_id(&self) -> String;
+
+    fn get_info(&self) -> Option<&InfoConfig> {
+        None
+    }
 }
 
 impl mlua::FromLua for Box<dyn Device> {
@@ -94,6 +201,101 @@ impl mlua::FromLua for Box<dyn Device> {
         }
     }
 }
-impl mlua::UserData for Box<dyn Device> {}
+// Generic methods for when the concrete type of the device is not known, e.g. when it is retrieved
+// from the device manager
+impl mlua::UserData for Box<dyn Device> {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("get_id", |_lua, this, _: ()| Ok(this.get_id()));
+
+        methods.add_async_method("is_on", |_lua, this, _: ()| async move {
+            on_off(this.as_ref())?
+                .on()
+                .await
+                .map_err(mlua::ExternalError::into_lua_err)
+        });
+
+        methods.add_async_method("set_on", |_lua, this, on: bool| async move {
+            on_off(this.as_ref())?
+                .set_on(on)
+                .await
+                .map_err(mlua::ExternalError::into_lua_err)
+        });
+    }
+}
+
+fn on_off(device: &dyn Device) -> mlua::Result<&dyn OnOff> {
+    device.cast().ok_or_else(|| {
+        DeviceConfigError::MissingTrait(device.get_id(), "OnOff".into()).into_lua_err()
+    })
+}
 
 dyn_clone::clone_trait_object!(Device);
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct Flaky;
+
+    #[derive(Debug, Clone)]
+    struct FlakyConfig {
+        failures: u32,
+        attempts: Arc<AtomicU32>,
+    }
+
+    #[async_trait::async_trait]
+    impl LuaDeviceCreate for Flaky {
+        type Config = FlakyConfig;
+        type Error = String;
+
+        async fn create(config: Self::Config) -> Result<Self, Self::Error> {
+            let attempt = config.attempts.fetch_add(1, Ordering::Relaxed) + 1;
+            if attempt <= config.failures {
+                Err(format!("Attempt {attempt} failed"))
+            } else {
+                Ok(Flaky)
+            }
+        }
+    }
+
+    fn config(failures: u32) -> FlakyConfig {
+        FlakyConfig {
+            failures,
+            attempts: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn create_without_retry() {
+        let config = config(1);
+
+        let result = Flaky::create_with_retry("flaky", config.clone(), 0, Duration::ZERO).await;
+
+        assert_eq!(result.unwrap_err(), "Attempt 1 failed");
+        assert_eq!(config.attempts.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn create_succeeds_after_retry() {
+        let config = config(2);
+
+        let result = Flaky::create_with_retry("flaky", config.clone(), 3, Duration::ZERO).await;
+
+        assert!(result.is_ok());
+        assert_eq!(config.attempts.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn create_returns_last_error() {
+        let config = config(5);
+
+        let result = Flaky::create_with_retry("flaky", config.clone(), 2, Duration::ZERO).await;
+
+        assert_eq!(result.unwrap_err(), "Attempt 3 failed");
+        assert_eq!(config.attempts.load(Ordering::Relaxed), 3);
+    }
+}
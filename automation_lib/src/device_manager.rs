@@ -1,23 +1,38 @@
 use std::collections::HashMap;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use futures::future::join_all;
 use futures::Future;
-use tokio::sync::{RwLock, RwLockReadGuard};
+use rumqttc::Publish;
+use tokio::sync::{watch, RwLock, RwLockReadGuard};
 use tokio_cron_scheduler::{Job, JobScheduler};
-use tracing::{debug, instrument, trace};
+use tracing::{debug, error, instrument, trace, warn};
 
-use crate::device::Device;
-use crate::event::{Event, EventChannel, OnDarkness, OnMqtt, OnNotification, OnPresence};
+use crate::device::{Device, DeviceAvailability, DeviceHealth, DeviceLifecycle, HealthStatus};
+use crate::event::{
+    Alarm, Event, EventChannel, OnAlarm, OnDarkness, OnMqtt, OnNotification, OnPresence,
+};
+use crate::metrics;
+use crate::ntfy::Notification;
 
 pub type DeviceMap = HashMap<String, Box<dyn Device>>;
 
+pub const DEFAULT_HEALTH_INTERVAL: Duration = Duration::from_secs(60);
+
 #[derive(Clone)]
 pub struct DeviceManager {
     devices: Arc<RwLock<DeviceMap>>,
     event_channel: EventChannel,
     scheduler: JobScheduler,
+    started: Instant,
+    // Number of events that have been dispatched to the devices
+    events: Arc<AtomicU64>,
+    // Latest result of the health check of every device that supports it
+    health: Arc<RwLock<HashMap<String, HealthStatus>>>,
+    health_interval: Arc<watch::Sender<Duration>>,
 }
 
 impl DeviceManager {
@@ -28,14 +43,83 @@ impl DeviceManager {
             devices: Arc::new(RwLock::new(HashMap::new())),
             event_channel,
             scheduler: JobScheduler::new().await.unwrap(),
+            started: Instant::now(),
+            events: Default::default(),
+            health: Default::default(),
+            health_interval: Arc::new(watch::Sender::new(DEFAULT_HEALTH_INTERVAL)),
         };
 
+        // Each event type gets its own receiver, so a slow handler for one type of event does not
+        // hold up the others
+        let mut mqtt_rx = device_manager
+            .event_channel
+            .subscribe_filtered(|event| matches!(event, Event::MqttMessage(_)));
+        tokio::spawn({
+            let device_manager = device_manager.clone();
+            async move {
+                while let Some(Event::MqttMessage(message)) = mqtt_rx.recv().await {
+                    device_manager.handle_mqtt(message).await;
+                }
+            }
+        });
+
+        let mut darkness_rx = device_manager
+            .event_channel
+            .subscribe_filtered(|event| matches!(event, Event::Darkness(_)));
+        tokio::spawn({
+            let device_manager = device_manager.clone();
+            async move {
+                while let Some(Event::Darkness(dark)) = darkness_rx.recv().await {
+                    device_manager.handle_darkness(dark).await;
+                }
+            }
+        });
+
+        let mut presence_rx = device_manager
+            .event_channel
+            .subscribe_filtered(|event| matches!(event, Event::Presence(_)));
+        tokio::spawn({
+            let device_manager = device_manager.clone();
+            async move {
+                while let Some(Event::Presence(presence)) = presence_rx.recv().await {
+                    device_manager.handle_presence(presence).await;
+                }
+            }
+        });
+
+        let mut notification_rx = device_manager
+            .event_channel
+            .subscribe_filtered(|event| matches!(event, Event::Ntfy(_)));
+        tokio::spawn({
+            let device_manager = device_manager.clone();
+            async move {
+                while let Some(Event::Ntfy(notification)) = notification_rx.recv().await {
+                    device_manager.handle_notification(*notification).await;
+                }
+            }
+        });
+
+        // Alarms have their own receiver so they are never stuck behind a burst of other events
+        let mut alarm_rx = device_manager
+            .event_channel
+            .subscribe_filtered(|event| matches!(event, Event::Alarm(_)));
         tokio::spawn({
             let device_manager = device_manager.clone();
+            async move {
+                while let Some(Event::Alarm(alarm)) = alarm_rx.recv().await {
+                    device_manager.handle_alarm(alarm).await;
+                }
+            }
+        });
+
+        tokio::spawn({
+            let event_channel = device_manager.event_channel.clone();
+            let events = device_manager.events.clone();
             async move {
                 loop {
                     if let Some(event) = event_rx.recv().await {
-                        device_manager.handle_event(event).await;
+                        events.fetch_add(1, Ordering::Relaxed);
+                        event_channel.dispatch(event).await;
                     } else {
                         todo!("Handle errors with the event channel properly")
                     }
@@ -43,6 +127,23 @@ impl DeviceManager {
             }
         });
 
+        tokio::spawn({
+            let device_manager = device_manager.clone();
+            let mut interval = device_manager.health_interval.subscribe();
+            async move {
+                loop {
+                    let duration = *interval.borrow_and_update();
+                    // When the interval changes the wait is restarted with the new interval
+                    if tokio::time::timeout(duration, interval.changed())
+                        .await
+                        .is_err()
+                    {
+                        device_manager.check_health().await;
+                    }
+                }
+            }
+        });
+
         device_manager.scheduler.start().await.unwrap();
 
         device_manager
@@ -53,91 +154,261 @@ impl DeviceManager {
 
         debug!(id, "Adding device");
 
-        self.devices.write().await.insert(id, device);
+        let mut devices = self.devices.write().await;
+        devices.insert(id.clone(), device.clone());
+        metrics::set_devices(devices.len());
+        drop(devices);
+
+        let availability: Option<&dyn DeviceAvailability> = device.cast();
+        if let Some(availability) = availability {
+            let mut rx = availability.availability().watch();
+            let tx = self.event_channel.get_tx();
+            let id = id.clone();
+            // Stops once the device, and with that the sender, is dropped
+            tokio::spawn(async move {
+                while rx.changed().await.is_ok() {
+                    let online = *rx.borrow_and_update();
+                    let event = Event::Availability {
+                        id: id.clone(),
+                        online,
+                    };
+                    if tx.send(event).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        // Starting might take a while, so we do not want to hold up loading the config
+        tokio::spawn(async move {
+            let lifecycle: Option<&dyn DeviceLifecycle> = device.cast();
+            if let Some(lifecycle) = lifecycle {
+                trace!(id, "Starting");
+                lifecycle.on_start().await;
+            }
+        });
+    }
+
+    // Gives all devices a chance to clean up before the application exits
+    pub async fn stop(&self) {
+        debug!("Stopping devices");
+
+        let devices = self.devices.read().await;
+        let iter = devices.iter().map(|(id, device)| async move {
+            let device: Option<&dyn DeviceLifecycle> = device.cast();
+            if let Some(device) = device {
+                trace!(id, "Stopping");
+                device.on_stop().await;
+                trace!(id, "Done");
+            }
+        });
+
+        join_all(iter).await;
+    }
+
+    async fn add_job<F>(&self, schedule: &str, job: F) -> mlua::Result<()>
+    where
+        F: Fn() -> Pin<Box<dyn Future<Output = mlua::Result<()>> + Send>> + Send + Sync + 'static,
+    {
+        let job = Job::new_async(schedule, move |uuid, _scheduler| {
+            let future = job();
+            Box::pin(async move {
+                if let Err(err) = future.await {
+                    error!(%uuid, "Scheduled job failed: {err}");
+                }
+            })
+        })
+        .map_err(mlua::ExternalError::into_lua_err)?;
+
+        self.scheduler
+            .add(job)
+            .await
+            .map_err(mlua::ExternalError::into_lua_err)?;
+
+        Ok(())
     }
 
     pub fn event_channel(&self) -> EventChannel {
         self.event_channel.clone()
     }
 
+    pub fn event_count(&self) -> u64 {
+        self.events.load(Ordering::Relaxed)
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn set_health_interval(&self, interval: Duration) {
+        self.health_interval.send_replace(interval);
+    }
+
+    pub async fn health(&self) -> HashMap<String, HealthStatus> {
+        self.health.read().await.clone()
+    }
+
     pub async fn get(&self, name: &str) -> Option<Box<dyn Device>> {
         self.devices.read().await.get(name).cloned()
     }
 
+    pub async fn get_by_tag(&self, tag: &str) -> Vec<Box<dyn Device>> {
+        self.devices
+            .read()
+            .await
+            .values()
+            .filter(|device| device.get_info().is_some_and(|info| info.has_tag(tag)))
+            .cloned()
+            .collect()
+    }
+
     pub async fn devices(&self) -> RwLockReadGuard<DeviceMap> {
         self.devices.read().await
     }
 
     #[instrument(skip(self))]
-    async fn handle_event(&self, event: Event) {
-        match event {
-            Event::MqttMessage(message) => {
-                let devices = self.devices.read().await;
-                let iter = devices.iter().map(|(id, device)| {
-                    let message = message.clone();
-                    async move {
-                        let device: Option<&dyn OnMqtt> = device.cast();
-                        if let Some(device) = device {
-                            // let subscribed = device
-                            //     .topics()
-                            //     .iter()
-                            //     .any(|topic| matches(&message.topic, topic));
-                            //
-                            // if subscribed {
-                            trace!(id, "Handling");
-                            device.on_mqtt(message).await;
-                            trace!(id, "Done");
-                            // }
-                        }
-                    }
-                });
-
-                join_all(iter).await;
-            }
-            Event::Darkness(dark) => {
-                let devices = self.devices.read().await;
-                let iter = devices.iter().map(|(id, device)| async move {
-                    let device: Option<&dyn OnDarkness> = device.cast();
-                    if let Some(device) = device {
-                        trace!(id, "Handling");
-                        device.on_darkness(dark).await;
-                        trace!(id, "Done");
-                    }
-                });
+    async fn check_health(&self) {
+        let devices = self.devices.read().await;
+        let iter = devices
+            .iter()
+            .filter_map(|(id, device)| {
+                let device: Option<&dyn DeviceHealth> = device.cast();
+                device.map(|device| (id, device))
+            })
+            .map(|(id, device)| async move {
+                trace!(id, "Checking health");
+                (id.clone(), device.check_health().await)
+            });
 
-                join_all(iter).await;
-            }
-            Event::Presence(presence) => {
-                let devices = self.devices.read().await;
-                let iter = devices.iter().map(|(id, device)| async move {
-                    let device: Option<&dyn OnPresence> = device.cast();
-                    if let Some(device) = device {
-                        trace!(id, "Handling");
-                        device.on_presence(presence).await;
-                        trace!(id, "Done");
-                    }
-                });
+        let results = join_all(iter).await;
+        drop(devices);
 
-                join_all(iter).await;
+        let mut health = self.health.write().await;
+        for (id, status) in results {
+            let previous = health.insert(id.clone(), status.clone());
+            // Reported on every change into unhealthy, including the first check
+            if matches!(previous, Some(HealthStatus::Unhealthy(_))) {
+                continue;
             }
-            Event::Ntfy(notification) => {
-                let devices = self.devices.read().await;
-                let iter = devices.iter().map(|(id, device)| {
-                    let notification = notification.clone();
-                    async move {
-                        let device: Option<&dyn OnNotification> = device.cast();
-                        if let Some(device) = device {
-                            trace!(id, "Handling");
-                            device.on_notification(notification).await;
-                            trace!(id, "Done");
-                        }
-                    }
-                });
 
-                join_all(iter).await;
+            if let HealthStatus::Unhealthy(reason) = status {
+                warn!(id, reason, "Device became unhealthy");
+                self.event_channel
+                    .get_tx()
+                    .send(Event::DeviceError { id, reason })
+                    .await
+                    .ok();
             }
         }
     }
+
+    #[instrument(skip(self))]
+    async fn handle_mqtt(&self, message: Publish) {
+        metrics::mqtt_message_received();
+
+        let devices = self.devices.read().await;
+        let iter = devices
+            .iter()
+            .filter_map(|(id, device)| {
+                let device: Option<&dyn OnMqtt> = device.cast();
+                device.map(|device| (id, device))
+            })
+            .map(|(id, device)| {
+                let message = message.clone();
+                async move {
+                    trace!(id, "Handling");
+                    device.on_mqtt(message).await;
+                    trace!(id, "Done");
+                }
+            });
+
+        join_all(iter).await;
+    }
+
+    #[instrument(skip(self))]
+    async fn handle_darkness(&self, dark: bool) {
+        let devices = self.devices.read().await;
+        let iter = devices
+            .iter()
+            .filter_map(|(id, device)| {
+                let device: Option<&dyn OnDarkness> = device.cast();
+                device.map(|device| (id, device))
+            })
+            .map(|(id, device)| async move {
+                trace!(id, "Handling");
+                device.on_darkness(dark).await;
+                trace!(id, "Done");
+            });
+
+        join_all(iter).await;
+    }
+
+    #[instrument(skip(self))]
+    async fn handle_presence(&self, presence: bool) {
+        let devices = self.devices.read().await;
+        let iter = devices
+            .iter()
+            .filter_map(|(id, device)| {
+                let device: Option<&dyn OnPresence> = device.cast();
+                device.map(|device| (id, device))
+            })
+            .map(|(id, device)| async move {
+                trace!(id, "Handling");
+                device.on_presence(presence).await;
+                trace!(id, "Done");
+            });
+
+        join_all(iter).await;
+    }
+
+    #[instrument(skip(self))]
+    async fn handle_notification(&self, notification: Notification) {
+        let devices = self.devices.read().await;
+        let iter = devices
+            .iter()
+            .filter_map(|(id, device)| {
+                let device: Option<&dyn OnNotification> = device.cast();
+                device.map(|device| (id, device))
+            })
+            .map(|(id, device)| {
+                let notification = notification.clone();
+                async move {
+                    trace!(id, "Handling");
+                    device.on_notification(notification).await;
+                    trace!(id, "Done");
+                }
+            });
+
+        join_all(iter).await;
+    }
+
+    #[instrument(skip(self))]
+    async fn handle_alarm(&self, alarm: Alarm) {
+        warn!(
+            source = alarm.source,
+            kind = ?alarm.kind,
+            active = alarm.active,
+            "Alarm"
+        );
+
+        let devices = self.devices.read().await;
+        let iter = devices
+            .iter()
+            .filter_map(|(id, device)| {
+                let device: Option<&dyn OnAlarm> = device.cast();
+                device.map(|device| (id, device))
+            })
+            .map(|(id, device)| {
+                let alarm = alarm.clone();
+                async move {
+                    trace!(id, "Handling");
+                    device.on_alarm(alarm).await;
+                    trace!(id, "Done");
+                }
+            });
+
+        join_all(iter).await;
+    }
 }
 
 impl mlua::UserData for DeviceManager {
@@ -148,42 +419,424 @@ impl mlua::UserData for DeviceManager {
             Ok(())
         });
 
+        methods.add_async_method("get", |_lua, this, id: String| async move {
+            Ok(this.get(&id).await)
+        });
+
+        // Returns a table for every device, sorted by id
+        methods.add_async_method("list", |lua, this, _: ()| async move {
+            let mut ids: Vec<_> = this.devices().await.keys().cloned().collect();
+            ids.sort();
+
+            ids.into_iter()
+                .map(|id| lua.create_table_from([("id", id)]))
+                .collect::<mlua::Result<Vec<_>>>()
+        });
+
+        methods.add_async_method("count", |_lua, this, _: ()| async move {
+            Ok(this.devices().await.len())
+        });
+
+        methods.add_async_method("get_by_tag", |_lua, this, tag: String| async move {
+            Ok(this.get_by_tag(&tag).await)
+        });
+
         methods.add_async_method(
             "schedule",
-            |lua, this, (schedule, f): (String, mlua::Function)| async move {
+            |_lua, this, (schedule, f): (String, mlua::Function)| async move {
                 debug!("schedule = {schedule}");
-                // This creates a function, that returns the actual job we want to run
-                let create_job = {
-                    let lua = lua.clone();
-
-                    move |uuid: uuid::Uuid,
-                          _: tokio_cron_scheduler::JobScheduler|
-                          -> Pin<Box<dyn Future<Output = ()> + Send>> {
-                        let lua = lua.clone();
-
-                        // Create the actual function we want to run on a schedule
-                        let future = async move {
-                            let f: mlua::Function =
-                                lua.named_registry_value(uuid.to_string().as_str()).unwrap();
-                            f.call_async::<()>(()).await.unwrap();
-                        };
-
-                        Box::pin(future)
-                    }
-                };
-
-                let job = Job::new_async(schedule.as_str(), create_job).unwrap();
+                this.add_job(&schedule, move || {
+                    let f = f.clone();
+                    Box::pin(async move { f.call_async::<()>(()).await })
+                })
+                .await
+            },
+        );
 
-                let uuid = this.scheduler.add(job).await.unwrap();
+        // Call the functions one after the other, each receives the result of the previous one
+        methods.add_async_method(
+            "chain",
+            |_lua, this, (schedule, functions): (String, Vec<mlua::Function>)| async move {
+                debug!("chain = {schedule}");
+                this.add_job(&schedule, move || {
+                    let functions = functions.clone();
+                    Box::pin(async move {
+                        let mut result = mlua::MultiValue::new();
+                        for f in functions {
+                            result = f.call_async(result).await?;
+                        }
 
-                // Store the function in the registry
-                lua.set_named_registry_value(uuid.to_string().as_str(), f)
-                    .unwrap();
+                        Ok(())
+                    })
+                })
+                .await
+            },
+        );
 
-                Ok(())
+        // Call the function a fixed delay after every tick of the schedule
+        methods.add_async_method(
+            "after",
+            |_lua, this, (schedule, delay_ms, f): (String, u64, mlua::Function)| async move {
+                debug!("after = {schedule} + {delay_ms}ms");
+                let delay = Duration::from_millis(delay_ms);
+                this.add_job(&schedule, move || {
+                    let f = f.clone();
+                    Box::pin(async move {
+                        tokio::time::sleep(delay).await;
+                        f.call_async::<()>(()).await
+                    })
+                })
+                .await
             },
         );
 
         methods.add_method("event_channel", |_lua, this, ()| Ok(this.event_channel()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::availability::Availability;
+
+    #[derive(Debug, Clone, Default)]
+    struct TestDevice {
+        started: Arc<AtomicUsize>,
+        stopped: Arc<AtomicUsize>,
+    }
+
+    impl Device for TestDevice {
+        fn get_id(&self) -> String {
+            "test".into()
+        }
+    }
+
+    #[async_trait]
+    impl DeviceLifecycle for TestDevice {
+        async fn on_start(&self) {
+            self.started.fetch_add(1, Ordering::Relaxed);
+        }
+
+        async fn on_stop(&self) {
+            self.stopped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct CountingDevice {
+        id: String,
+        count: Arc<AtomicUsize>,
+    }
+
+    impl Device for CountingDevice {
+        fn get_id(&self) -> String {
+            self.id.clone()
+        }
+    }
+
+    #[async_trait]
+    impl OnPresence for CountingDevice {
+        async fn on_presence(&self, _presence: bool) {
+            self.count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct HealthDevice {
+        status: Arc<std::sync::Mutex<HealthStatus>>,
+    }
+
+    impl Device for HealthDevice {
+        fn get_id(&self) -> String {
+            "health".into()
+        }
+    }
+
+    #[async_trait]
+    impl DeviceHealth for HealthDevice {
+        async fn check_health(&self) -> HealthStatus {
+            self.status.lock().unwrap().clone()
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct AvailabilityDevice {
+        availability: Availability,
+    }
+
+    impl Device for AvailabilityDevice {
+        fn get_id(&self) -> String {
+            "availability".into()
+        }
+    }
+
+    impl DeviceAvailability for AvailabilityDevice {
+        fn availability(&self) -> &Availability {
+            &self.availability
+        }
+    }
+
+    #[tokio::test]
+    async fn availability() {
+        let device_manager = DeviceManager::new().await;
+        let mut events = device_manager
+            .event_channel()
+            .subscribe_filtered(|event| matches!(event, Event::Availability { .. }));
+        let device = AvailabilityDevice {
+            availability: Availability::new("availability"),
+        };
+        device_manager.add(Box::new(device.clone())).await;
+
+        device.availability.set(false);
+        assert!(matches!(
+            events.recv().await,
+            Some(Event::Availability { id, online: false }) if id == "availability"
+        ));
+
+        device.availability.set(true);
+        assert!(matches!(
+            events.recv().await,
+            Some(Event::Availability { online: true, .. })
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn health() {
+        let device_manager = DeviceManager::new().await;
+        let mut errors = device_manager
+            .event_channel()
+            .subscribe_filtered(|event| matches!(event, Event::DeviceError { .. }));
+        let device = HealthDevice {
+            status: Arc::new(std::sync::Mutex::new(HealthStatus::Healthy)),
+        };
+        device_manager.add(Box::new(device.clone())).await;
+
+        assert!(device_manager.health().await.is_empty());
+        tokio::time::sleep(DEFAULT_HEALTH_INTERVAL + Duration::from_secs(1)).await;
+        assert_eq!(
+            device_manager.health().await.get("health"),
+            Some(&HealthStatus::Healthy)
+        );
+
+        *device.status.lock().unwrap() = HealthStatus::Unhealthy("Timeout".into());
+        tokio::time::sleep(DEFAULT_HEALTH_INTERVAL).await;
+        assert!(matches!(
+            errors.recv().await,
+            Some(Event::DeviceError { id, reason }) if id == "health" && reason == "Timeout"
+        ));
+
+        // Only the transition is reported
+        tokio::time::sleep(DEFAULT_HEALTH_INTERVAL).await;
+        assert!(errors.try_recv().is_err());
+        assert_eq!(
+            device_manager.health().await.get("health"),
+            Some(&HealthStatus::Unhealthy("Timeout".into()))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn health_transitions() {
+        let device_manager = DeviceManager::new().await;
+        let mut errors = device_manager
+            .event_channel()
+            .subscribe_filtered(|event| matches!(event, Event::DeviceError { .. }));
+        let device = HealthDevice {
+            status: Arc::new(std::sync::Mutex::new(HealthStatus::Unhealthy(
+                "Refused".into(),
+            ))),
+        };
+        device_manager.add(Box::new(device.clone())).await;
+
+        // Unhealthy on the first check
+        tokio::time::sleep(DEFAULT_HEALTH_INTERVAL + Duration::from_secs(1)).await;
+        assert!(matches!(
+            errors.recv().await,
+            Some(Event::DeviceError { reason, .. }) if reason == "Refused"
+        ));
+
+        *device.status.lock().unwrap() = HealthStatus::Degraded("Slow".into());
+        tokio::time::sleep(DEFAULT_HEALTH_INTERVAL).await;
+        assert!(errors.try_recv().is_err());
+
+        // Degraded is not unhealthy yet
+        *device.status.lock().unwrap() = HealthStatus::Unhealthy("Timeout".into());
+        tokio::time::sleep(DEFAULT_HEALTH_INTERVAL).await;
+        assert!(matches!(
+            errors.recv().await,
+            Some(Event::DeviceError { reason, .. }) if reason == "Timeout"
+        ));
+    }
+
+    // The time it takes to dispatch events is measured in benches/dispatch.rs
+    #[tokio::test(start_paused = true)]
+    async fn dispatch() {
+        const DEVICES: usize = 10;
+        const EVENTS: usize = 10;
+
+        let device_manager = DeviceManager::new().await;
+        let count = Arc::new(AtomicUsize::new(0));
+        for i in 0..DEVICES {
+            device_manager
+                .add(Box::new(CountingDevice {
+                    id: format!("device_{i}"),
+                    count: count.clone(),
+                }))
+                .await;
+        }
+
+        let tx = device_manager.event_channel().get_tx();
+        for i in 0..EVENTS {
+            tx.send(Event::Presence(i % 2 == 0)).await.unwrap();
+            // Events for other traits should not reach the devices
+            tx.send(Event::Darkness(i % 2 == 0)).await.unwrap();
+        }
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(count.load(Ordering::Relaxed), DEVICES * EVENTS);
+    }
+
+    #[tokio::test]
+    async fn lifecycle() {
+        let device_manager = DeviceManager::new().await;
+        let device = TestDevice::default();
+
+        device_manager.add(Box::new(device.clone())).await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(device.started.load(Ordering::Relaxed), 1);
+        assert_eq!(device.stopped.load(Ordering::Relaxed), 0);
+
+        device_manager.stop().await;
+        assert_eq!(device.stopped.load(Ordering::Relaxed), 1);
+    }
+
+    #[derive(Debug, Clone, Default)]
+    struct Switch {
+        on: Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    impl Device for Switch {
+        fn get_id(&self) -> String {
+            "switch".into()
+        }
+    }
+
+    #[async_trait]
+    impl google_home::traits::OnOff for Switch {
+        async fn on(&self) -> Result<bool, google_home::errors::ErrorCode> {
+            Ok(self.on.load(Ordering::Relaxed))
+        }
+
+        async fn set_on(&self, on: bool) -> Result<(), google_home::errors::ErrorCode> {
+            self.on.store(on, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn lua_query() {
+        let device_manager = DeviceManager::new().await;
+        let switch = Switch::default();
+        device_manager.add(Box::new(switch.clone())).await;
+        device_manager.add(Box::new(TestDevice::default())).await;
+
+        let lua = mlua::Lua::new();
+        lua.globals()
+            .set("device_manager", device_manager.clone())
+            .unwrap();
+
+        let (count, ids, missing): (usize, Vec<String>, bool) = lua
+            .load(
+                r#"
+                local switch = device_manager:get("switch")
+                switch:set_on(true)
+                assert(switch:get_id() == "switch")
+                assert(switch:is_on())
+
+                local ids = {}
+                for _, device in ipairs(device_manager:list()) do
+                    table.insert(ids, device.id)
+                end
+
+                return device_manager:count(), ids, device_manager:get("missing") == nil
+                "#,
+            )
+            .eval_async()
+            .await
+            .unwrap();
+
+        assert_eq!(count, 2);
+        assert_eq!(ids, ["switch", "test"]);
+        assert!(missing);
+        assert!(switch.on.load(Ordering::Relaxed));
+
+        // Devices without OnOff give an error instead of a panic
+        let err = lua
+            .load(r#"device_manager:get("test"):is_on()"#)
+            .exec_async()
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Device 'test' does not implement expected trait 'OnOff'"));
+    }
+
+    #[tokio::test]
+    async fn chain() {
+        let device_manager = DeviceManager::new().await;
+        let lua = mlua::Lua::new();
+        lua.globals()
+            .set("device_manager", device_manager.clone())
+            .unwrap();
+
+        lua.load(
+            r#"
+            results = {}
+            device_manager:chain("* * * * * *", {
+                function()
+                    return 1
+                end,
+                function(n)
+                    return n + 1
+                end,
+                function(n)
+                    table.insert(results, n)
+                end,
+            })
+            "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+
+        tokio::time::timeout(Duration::from_secs(3), async {
+            loop {
+                let results: Vec<i64> = lua.globals().get("results").unwrap();
+                if let Some(result) = results.first() {
+                    assert_eq!(*result, 2);
+                    break;
+                }
+
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Chain should have run");
+    }
+
+    #[tokio::test]
+    async fn invalid_schedule() {
+        let device_manager = DeviceManager::new().await;
+        let lua = mlua::Lua::new();
+        lua.globals().set("device_manager", device_manager).unwrap();
+
+        assert!(lua
+            .load(r#"device_manager:after("not a schedule", 100, function() end)"#)
+            .exec_async()
+            .await
+            .is_err());
+    }
+}
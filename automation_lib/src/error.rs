@@ -81,8 +81,77 @@ impl MissingWildcard {
 pub enum DeviceConfigError {
     #[error("Device '{0}' does not implement expected trait '{1}'")]
     MissingTrait(String, String),
+    #[error("Invalid value for '{0}': {1}")]
+    InvalidValue(String, String),
     #[error(transparent)]
     MqttClientError(#[from] rumqttc::ClientError),
+    #[error("Error in '{device_type}' at field '{}': {}", field_path.join("."), ConfigParseSource(source))]
+    ConfigParse {
+        device_type: String,
+        field_path: Vec<String>,
+        source: mlua::Error,
+    },
+}
+
+// Runtime errors are created by the generated config code, the 'runtime error' prefix only adds
+// noise to the message
+struct ConfigParseSource<'a>(&'a mlua::Error);
+
+impl fmt::Display for ConfigParseSource<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            mlua::Error::RuntimeError(message) => write!(f, "{message}"),
+            err => write!(f, "{err}"),
+        }
+    }
+}
+
+pub trait ConfigContext<T> {
+    /// Wraps the error in [`DeviceConfigError::ConfigParse`], errors that already have context
+    /// (nested configs) get the field prepended to their path
+    fn with_context(self, device_type: &str, field: &str) -> mlua::Result<T>;
+
+    /// Replaces the name of the config struct in [`DeviceConfigError::ConfigParse`] with the name
+    /// of the device, most devices name their config struct 'Config'
+    fn with_device_type(self, device_type: &str) -> mlua::Result<T>;
+}
+
+impl<T> ConfigContext<T> for mlua::Result<T> {
+    fn with_context(self, device_type: &str, field: &str) -> mlua::Result<T> {
+        self.map_err(|err| {
+            let mut field_path = vec![field.to_owned()];
+            let source = match err.downcast_ref::<DeviceConfigError>() {
+                Some(DeviceConfigError::ConfigParse {
+                    field_path: path,
+                    source,
+                    ..
+                }) => {
+                    field_path.extend(path.iter().cloned());
+                    source.clone()
+                }
+                _ => err,
+            };
+
+            mlua::Error::external(DeviceConfigError::ConfigParse {
+                device_type: device_type.to_owned(),
+                field_path,
+                source,
+            })
+        })
+    }
+
+    fn with_device_type(self, device_type: &str) -> mlua::Result<T> {
+        self.map_err(|err| match err.downcast_ref::<DeviceConfigError>() {
+            Some(DeviceConfigError::ConfigParse {
+                field_path, source, ..
+            }) => mlua::Error::external(DeviceConfigError::ConfigParse {
+                device_type: device_type.to_owned(),
+                field_path: field_path.clone(),
+                source: source.clone(),
+            }),
+            _ => err,
+        })
+    }
 }
 
 #[derive(Debug, Error)]
@@ -98,3 +167,15 @@ pub enum LightSensorError {
     #[error(transparent)]
     SubscribeError(#[from] ClientError),
 }
+
+#[derive(Debug, Error)]
+pub enum NotificationError {
+    #[error("Notification can not have both 'delay' and 'scheduled_at' set")]
+    DelayAndScheduledAt,
+    #[error("Notification can not be delayed by more than 3 days")]
+    DelayTooLong,
+    #[error("Notification can not be delayed by less than 10 seconds")]
+    DelayTooShort,
+    #[error("Notification can not be scheduled at a time in the past")]
+    ScheduledAtInPast,
+}
@@ -1,6 +1,9 @@
+use std::sync::{Arc, RwLock};
+
 use async_trait::async_trait;
 use mlua::FromLua;
-use rumqttc::Publish;
+use rumqttc::{Publish, QoS};
+use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
 
 use crate::ntfy::Notification;
@@ -10,24 +13,230 @@ pub enum Event {
     MqttMessage(Publish),
     Darkness(bool),
     Presence(bool),
-    Ntfy(Notification),
+    Ntfy(Box<Notification>),
+    Alarm(Alarm),
+    // Sent when a device goes from healthy to unhealthy
+    DeviceError { id: String, reason: String },
+    // Sent when a device goes offline or comes back online
+    Availability { id: String, online: bool },
+    // Sent when the connection to the MQTT broker is (re)established or lost, the broker is
+    // formatted as host:port
+    MqttConnected { broker: String },
+    MqttDisconnected { broker: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlarmKind {
+    Smoke,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Alarm {
+    // Id of the device that raised the alarm
+    pub source: String,
+    pub kind: AlarmKind,
+    // Alarms are sent again with active set to false when they clear
+    pub active: bool,
+}
+
+impl Event {
+    /// Short name of the type of event, e.g. used in topics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::MqttMessage(_) => "mqtt",
+            Event::Darkness(_) => "darkness",
+            Event::Presence(_) => "presence",
+            Event::Ntfy(_) => "ntfy",
+            Event::Alarm(_) => "alarm",
+            Event::DeviceError { .. } => "device_error",
+            Event::Availability { .. } => "availability",
+            Event::MqttConnected { .. } => "mqtt_connected",
+            Event::MqttDisconnected { .. } => "mqtt_disconnected",
+        }
+    }
+}
+
+/// Serializable form of [`Event`], used to record and replay events
+///
+/// MQTT payloads are stored as (lossy) UTF-8.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventRecord {
+    Mqtt {
+        topic: String,
+        payload: String,
+        retain: bool,
+    },
+    Darkness {
+        state: bool,
+    },
+    Presence {
+        state: bool,
+    },
+    Ntfy {
+        notification: Box<Notification>,
+    },
+    Alarm {
+        alarm: Alarm,
+    },
+    DeviceError {
+        id: String,
+        reason: String,
+    },
+    Availability {
+        id: String,
+        online: bool,
+    },
+    MqttConnected {
+        broker: String,
+    },
+    MqttDisconnected {
+        broker: String,
+    },
+}
+
+impl From<&Event> for EventRecord {
+    fn from(event: &Event) -> Self {
+        match event {
+            Event::MqttMessage(message) => EventRecord::Mqtt {
+                topic: message.topic.clone(),
+                payload: String::from_utf8_lossy(&message.payload).into_owned(),
+                retain: message.retain,
+            },
+            Event::Darkness(state) => EventRecord::Darkness { state: *state },
+            Event::Presence(state) => EventRecord::Presence { state: *state },
+            Event::Ntfy(notification) => EventRecord::Ntfy {
+                notification: notification.clone(),
+            },
+            Event::Alarm(alarm) => EventRecord::Alarm {
+                alarm: alarm.clone(),
+            },
+            Event::DeviceError { id, reason } => EventRecord::DeviceError {
+                id: id.clone(),
+                reason: reason.clone(),
+            },
+            Event::Availability { id, online } => EventRecord::Availability {
+                id: id.clone(),
+                online: *online,
+            },
+            Event::MqttConnected { broker } => EventRecord::MqttConnected {
+                broker: broker.clone(),
+            },
+            Event::MqttDisconnected { broker } => EventRecord::MqttDisconnected {
+                broker: broker.clone(),
+            },
+        }
+    }
+}
+
+impl From<EventRecord> for Event {
+    fn from(record: EventRecord) -> Self {
+        match record {
+            EventRecord::Mqtt {
+                topic,
+                payload,
+                retain,
+            } => {
+                let mut message = Publish::new(topic, QoS::AtLeastOnce, payload);
+                message.retain = retain;
+                Event::MqttMessage(message)
+            }
+            EventRecord::Darkness { state } => Event::Darkness(state),
+            EventRecord::Presence { state } => Event::Presence(state),
+            EventRecord::Ntfy { notification } => Event::Ntfy(notification),
+            EventRecord::Alarm { alarm } => Event::Alarm(alarm),
+            EventRecord::DeviceError { id, reason } => Event::DeviceError { id, reason },
+            EventRecord::Availability { id, online } => Event::Availability { id, online },
+            EventRecord::MqttConnected { broker } => Event::MqttConnected { broker },
+            EventRecord::MqttDisconnected { broker } => Event::MqttDisconnected { broker },
+        }
+    }
 }
 
 pub type Sender = mpsc::Sender<Event>;
 pub type Receiver = mpsc::Receiver<Event>;
 
+const CHANNEL_SIZE: usize = 100;
+
+type Filter = Box<dyn Fn(&Event) -> bool + Send + Sync>;
+
+struct Subscriber {
+    filter: Filter,
+    tx: Sender,
+}
+
+impl std::fmt::Debug for Subscriber {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Subscriber").finish_non_exhaustive()
+    }
+}
+
 #[derive(Clone, Debug, FromLua)]
-pub struct EventChannel(Sender);
+pub struct EventChannel {
+    tx: Sender,
+    subscribers: Arc<RwLock<Vec<Subscriber>>>,
+}
 
 impl EventChannel {
     pub fn new() -> (Self, Receiver) {
-        let (tx, rx) = mpsc::channel(100);
+        let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
 
-        (Self(tx), rx)
+        (
+            Self {
+                tx,
+                subscribers: Default::default(),
+            },
+            rx,
+        )
     }
 
     pub fn get_tx(&self) -> Sender {
-        self.0.clone()
+        self.tx.clone()
+    }
+
+    /// Receive only the events that match the filter
+    ///
+    /// The filter runs before the event is forwarded, so subscribers are not woken up for events
+    /// they are not interested in. Events are forwarded by the owner of the receiver returned by
+    /// [`EventChannel::new`], normally the device manager.
+    pub fn subscribe_filtered<F>(&self, filter: F) -> Receiver
+    where
+        F: Fn(&Event) -> bool + Send + Sync + 'static,
+    {
+        let (tx, rx) = mpsc::channel(CHANNEL_SIZE);
+
+        self.subscribers
+            .write()
+            .expect("Lock should not be poisoned")
+            .push(Subscriber {
+                filter: Box::new(filter),
+                tx,
+            });
+
+        rx
+    }
+
+    // Forward the event to all subscribers that are interested in it
+    pub(crate) async fn dispatch(&self, event: Event) {
+        let senders: Vec<_> = {
+            let mut subscribers = self
+                .subscribers
+                .write()
+                .expect("Lock should not be poisoned");
+            subscribers.retain(|subscriber| !subscriber.tx.is_closed());
+
+            subscribers
+                .iter()
+                .filter(|subscriber| (subscriber.filter)(&event))
+                .map(|subscriber| subscriber.tx.clone())
+                .collect()
+        };
+
+        for tx in senders {
+            // The subscriber might have gone away in the meantime, which is fine
+            tx.send(event.clone()).await.ok();
+        }
     }
 }
 
@@ -53,3 +262,55 @@ pub trait OnDarkness: Sync + Send {
 pub trait OnNotification: Sync + Send {
     async fn on_notification(&self, notification: Notification);
 }
+
+#[async_trait]
+pub trait OnAlarm: Sync + Send {
+    async fn on_alarm(&self, alarm: Alarm);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn subscribe_filtered() {
+        let (event_channel, _rx) = EventChannel::new();
+        let mut darkness =
+            event_channel.subscribe_filtered(|event| matches!(event, Event::Darkness(_)));
+        let mut presence =
+            event_channel.subscribe_filtered(|event| matches!(event, Event::Presence(_)));
+
+        event_channel.dispatch(Event::Darkness(true)).await;
+        event_channel.dispatch(Event::Presence(false)).await;
+
+        assert!(matches!(darkness.try_recv(), Ok(Event::Darkness(true))));
+        assert!(darkness.try_recv().is_err());
+        assert!(matches!(presence.try_recv(), Ok(Event::Presence(false))));
+        assert!(presence.try_recv().is_err());
+
+        // Subscribers that went away are cleaned up
+        drop(darkness);
+        event_channel.dispatch(Event::Darkness(false)).await;
+        assert_eq!(event_channel.subscribers.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn event_record() {
+        let event = Event::MqttMessage(Publish::new("test/topic", QoS::AtMostOnce, "{}"));
+        let record = EventRecord::from(&event);
+        assert_eq!(
+            serde_json::to_value(&record).unwrap(),
+            serde_json::json!({ "type": "mqtt", "topic": "test/topic", "payload": "{}", "retain": false })
+        );
+
+        let Event::MqttMessage(message) = Event::from(record) else {
+            panic!("Expected an MQTT message");
+        };
+        assert_eq!(message.topic, "test/topic");
+        assert_eq!(message.payload.as_ref(), b"{}");
+
+        let record: EventRecord =
+            serde_json::from_str(r#"{"type": "darkness", "state": true}"#).unwrap();
+        assert!(matches!(Event::from(record), Event::Darkness(true)));
+    }
+}
@@ -1,11 +1,22 @@
 pub mod serialization;
 mod timeout;
+pub mod weak;
 
 pub use timeout::Timeout;
 
+use crate::action_callback::{ActionCallback, CancellableCallback};
+
 pub fn register_with_lua(lua: &mlua::Lua) -> mlua::Result<()> {
     lua.globals()
         .set("Timeout", lua.create_proxy::<Timeout>()?)?;
+    lua.globals().set(
+        "ActionCallback",
+        lua.create_proxy::<ActionCallback<mlua::Value, mlua::Value>>()?,
+    )?;
+    lua.globals().set(
+        "CancellableCallback",
+        lua.create_proxy::<CancellableCallback<mlua::Value, ()>>()?,
+    )?;
 
     Ok(())
 }
@@ -7,9 +7,17 @@ use tracing::debug;
 
 use crate::action_callback::ActionCallback;
 
+#[derive(Debug, Clone)]
+struct OnComplete {
+    callback: ActionCallback<Timeout, ()>,
+    // Run before instead of after the callback passed to start
+    before: bool,
+}
+
 #[derive(Debug, Default)]
 pub struct State {
     handle: Option<JoinHandle<()>>,
+    on_complete: Option<OnComplete>,
 }
 
 #[derive(Debug, Clone)]
@@ -39,10 +47,24 @@ impl mlua::UserData for Timeout {
                 let timeout = Duration::from_secs(timeout);
 
                 this.state.write().await.handle = Some(tokio::spawn({
+                    let this = this.clone();
                     async move {
                         tokio::time::sleep(timeout).await;
 
+                        let on_complete = this.state.read().await.on_complete.clone();
+                        if let Some(on_complete) = &on_complete
+                            && on_complete.before
+                        {
+                            on_complete.callback.call(&this, &()).await;
+                        }
+
                         callback.call(&mlua::Nil, &false).await;
+
+                        if let Some(on_complete) = &on_complete
+                            && !on_complete.before
+                        {
+                            on_complete.callback.call(&this, &()).await;
+                        }
                     }
                 }));
 
@@ -50,6 +72,20 @@ impl mlua::UserData for Timeout {
             },
         );
 
+        // Register a callback that runs every time the timeout fires, it does not run if the
+        // timeout is canceled
+        methods.add_async_method(
+            "on_complete",
+            |_lua, this, (callback, before): (ActionCallback<Timeout, ()>, Option<bool>)| async move {
+                this.state.write().await.on_complete = Some(OnComplete {
+                    callback,
+                    before: before.unwrap_or(false),
+                });
+
+                Ok(())
+            },
+        );
+
         methods.add_async_method("cancel", |_lua, this, ()| async move {
             debug!("Canceling timeout callback");
 
@@ -74,3 +110,84 @@ impl mlua::UserData for Timeout {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lua() -> mlua::Lua {
+        let lua = mlua::Lua::new();
+        lua.globals()
+            .set("Timeout", lua.create_proxy::<Timeout>().unwrap())
+            .unwrap();
+
+        lua
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn on_complete() {
+        let lua = lua();
+
+        lua.load(
+            r#"
+            calls = {}
+            timeout = Timeout.new()
+            timeout:on_complete(function()
+                table.insert(calls, "complete")
+            end)
+            timeout:start(1, function()
+                table.insert(calls, "timeout")
+            end)
+            "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let calls: Vec<String> = lua.globals().get("calls").unwrap();
+        assert_eq!(calls, vec!["timeout", "complete"]);
+
+        lua.load(
+            r#"
+            calls = {}
+            timeout:on_complete(function()
+                table.insert(calls, "complete")
+            end, true)
+            timeout:start(1, function()
+                table.insert(calls, "timeout")
+            end)
+            "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let calls: Vec<String> = lua.globals().get("calls").unwrap();
+        assert_eq!(calls, vec!["complete", "timeout"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn on_complete_canceled() {
+        let lua = lua();
+
+        lua.load(
+            r#"
+            completed = false
+            timeout = Timeout.new()
+            timeout:on_complete(function()
+                completed = true
+            end)
+            timeout:start(1, function() end)
+            timeout:cancel()
+            "#,
+        )
+        .exec_async()
+        .await
+        .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert!(!lua.globals().get::<bool>("completed").unwrap());
+    }
+}
@@ -0,0 +1,101 @@
+//! Background tasks that only hold on to a weak reference to a device
+//!
+//! A device is cheap to clone and shares its state between all copies, so a background task could
+//! simply keep a copy of the device around. That copy would keep the state alive for as long as
+//! the task runs however, and the task runs for as long as the state is alive, so neither would
+//! ever be dropped. Instead the task holds on to a weak reference and only rebuilds the device for
+//! as long as it needs it. Once all other copies of the device are dropped the upgrade fails and
+//! the task stops.
+
+use std::future::Future;
+use std::time::Duration;
+
+use futures::{Stream, StreamExt};
+use tokio::task::JoinHandle;
+
+/// Calls `f` with the upgraded device for every item of the stream
+///
+/// Stops when the stream ends or `upgrade` returns `None` because the device is gone
+pub fn spawn_weak<D, U, S, F, Fut>(upgrade: U, stream: S, mut f: F) -> JoinHandle<()>
+where
+    D: Send,
+    U: Fn() -> Option<D> + Send + 'static,
+    S: Stream + Send + 'static,
+    S::Item: Send,
+    F: FnMut(D, S::Item) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    tokio::spawn(async move {
+        let mut stream = std::pin::pin!(stream);
+        while let Some(item) = stream.next().await {
+            let Some(device) = upgrade() else {
+                break;
+            };
+
+            f(device, item).await;
+        }
+    })
+}
+
+/// Calls `f` with the upgraded device every period, starting straight away
+pub fn spawn_weak_interval<D, U, F, Fut>(upgrade: U, period: Duration, mut f: F) -> JoinHandle<()>
+where
+    D: Send,
+    U: Fn() -> Option<D> + Send + 'static,
+    F: FnMut(D) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    let ticks = futures::stream::unfold(tokio::time::interval(period), |mut interval| async move {
+        interval.tick().await;
+        Some(((), interval))
+    });
+
+    spawn_weak(upgrade, ticks, move |device, _| f(device))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn stops_when_dropped() {
+        let device = Arc::new(AtomicUsize::new(0));
+        let weak = Arc::downgrade(&device);
+
+        let handle = spawn_weak_interval(
+            move || weak.upgrade(),
+            Duration::from_secs(1),
+            |device| async move {
+                device.fetch_add(1, Ordering::Relaxed);
+            },
+        );
+
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert_eq!(device.load(Ordering::Relaxed), 3);
+
+        drop(device);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(handle.is_finished());
+    }
+
+    #[tokio::test]
+    async fn stops_when_stream_ends() {
+        let device = Arc::new(AtomicUsize::new(0));
+        let weak = Arc::downgrade(&device);
+
+        spawn_weak(
+            move || weak.upgrade(),
+            futures::stream::iter([1, 2, 3]),
+            |device, item| async move {
+                device.fetch_add(item, Ordering::Relaxed);
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(device.load(Ordering::Relaxed), 6);
+    }
+}
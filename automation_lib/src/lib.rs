@@ -2,14 +2,20 @@
 #![feature(specialization)]
 #![feature(let_chains)]
 
+// Allows the code generated by automation_macro to also be used inside this crate
+extern crate self as automation_lib;
+
 pub mod action_callback;
+pub mod availability;
 pub mod config;
 pub mod device;
 pub mod device_manager;
 pub mod error;
 pub mod event;
 pub mod helpers;
+pub mod lua;
 pub mod messages;
+pub mod metrics;
 pub mod mqtt;
 pub mod ntfy;
 pub mod presence;
@@ -0,0 +1,48 @@
+//! Functions that are available to the config under `automation.diagnostics`
+use crate::device_manager::DeviceManager;
+
+pub fn register_with_lua(
+    lua: &mlua::Lua,
+    diagnostics: &mlua::Table,
+    device_manager: &DeviceManager,
+) -> mlua::Result<()> {
+    let device_count = lua.create_async_function({
+        let device_manager = device_manager.clone();
+        move |_lua, ()| {
+            let device_manager = device_manager.clone();
+            async move { Ok(device_manager.devices().await.len()) }
+        }
+    })?;
+    diagnostics.set("device_count", device_count)?;
+
+    let device_ids = lua.create_async_function({
+        let device_manager = device_manager.clone();
+        move |_lua, ()| {
+            let device_manager = device_manager.clone();
+            async move {
+                let mut ids: Vec<_> = device_manager.devices().await.keys().cloned().collect();
+                ids.sort();
+
+                Ok(ids)
+            }
+        }
+    })?;
+    diagnostics.set("device_ids", device_ids)?;
+
+    let event_count = lua.create_function({
+        let device_manager = device_manager.clone();
+        move |_lua, ()| Ok(device_manager.event_count())
+    })?;
+    diagnostics.set("event_count", event_count)?;
+
+    let uptime_ms = lua.create_function({
+        let device_manager = device_manager.clone();
+        move |_lua, ()| Ok(device_manager.uptime().as_millis() as u64)
+    })?;
+    diagnostics.set("uptime_ms", uptime_ms)?;
+
+    let lua_memory_kb = lua.create_function(|lua, ()| Ok(lua.used_memory() as f64 / 1024.0))?;
+    diagnostics.set("lua_memory_kb", lua_memory_kb)?;
+
+    Ok(())
+}
@@ -0,0 +1,62 @@
+pub mod diagnostics;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod traits;
+pub mod utils;
+
+use tracing::warn;
+
+use crate::device_manager::DeviceManager;
+use crate::helpers;
+use crate::mqtt::WrappedAsyncClient;
+use crate::ntfy::Ntfy;
+use crate::presence::Presence;
+
+/// Set up the `automation` table and the globals that every config can use
+///
+/// How MQTT clients are created is left to the caller, so tests can hand out a mock client
+/// instead. Devices are not registered here, as they live in a different crate.
+pub fn register<F>(
+    lua: &mlua::Lua,
+    device_manager: &DeviceManager,
+    new_mqtt_client: F,
+) -> mlua::Result<()>
+where
+    F: Fn(&mlua::Lua, mlua::Value) -> mlua::Result<WrappedAsyncClient> + Send + 'static,
+{
+    lua.set_warning_function(|_lua, text, _cont| {
+        warn!("{text}");
+        Ok(())
+    });
+
+    let automation = lua.create_table()?;
+    automation.set("new_mqtt_client", lua.create_function(new_mqtt_client)?)?;
+    automation.set("device_manager", device_manager.clone())?;
+
+    let util = lua.create_table()?;
+    let get_env = lua.create_function(|_lua, name: String| {
+        std::env::var(name).map_err(mlua::ExternalError::into_lua_err)
+    })?;
+    util.set("get_env", get_env)?;
+    let get_hostname = lua.create_function(|_lua, ()| {
+        hostname::get()
+            .map(|name| name.to_str().unwrap_or("unknown").to_owned())
+            .map_err(mlua::ExternalError::into_lua_err)
+    })?;
+    util.set("get_hostname", get_hostname)?;
+    utils::register_with_lua(lua, &util)?;
+    automation.set("util", util)?;
+
+    let diagnostics = lua.create_table()?;
+    diagnostics::register_with_lua(lua, &diagnostics, device_manager)?;
+    automation.set("diagnostics", diagnostics)?;
+
+    lua.globals().set("automation", automation)?;
+
+    helpers::register_with_lua(lua)?;
+    lua.globals().set("Ntfy", lua.create_proxy::<Ntfy>()?)?;
+    lua.globals()
+        .set("Presence", lua.create_proxy::<Presence>()?)?;
+
+    Ok(())
+}
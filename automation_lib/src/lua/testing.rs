@@ -0,0 +1,438 @@
+//! Helpers for testing lua scripts without a real MQTT broker or HTTP servers
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rumqttc::{AsyncClient, Publish, QoS, Request};
+use tokio::sync::watch;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use crate::device_manager::DeviceManager;
+use crate::event::{Event, EventChannel};
+use crate::mqtt::{self, ConnectionStatus, WrappedAsyncClient};
+
+// How long assert_published waits for a message to show up
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// MQTT client that records all published messages instead of sending them to a broker
+#[derive(Debug, Clone)]
+pub struct MockMqttClient {
+    client: WrappedAsyncClient,
+    published: Arc<Mutex<Vec<(String, String)>>>,
+    event_channel: EventChannel,
+    // Keeps the connection status alive for as long as the client exists
+    _status: Arc<watch::Sender<ConnectionStatus>>,
+}
+
+impl MockMqttClient {
+    pub fn new(event_channel: EventChannel) -> Self {
+        let (request_tx, request_rx) = flume::unbounded();
+        let (status_tx, status_rx) = watch::channel(ConnectionStatus::Connected);
+
+        let published = Arc::new(Mutex::new(Vec::new()));
+        tokio::spawn({
+            let published = published.clone();
+            async move {
+                while let Ok(request) = request_rx.recv_async().await {
+                    if let Request::Publish(publish) = request {
+                        let payload = String::from_utf8_lossy(&publish.payload).into_owned();
+                        published.lock().unwrap().push((publish.topic, payload));
+                    }
+                }
+            }
+        });
+
+        Self {
+            client: WrappedAsyncClient::new(
+                AsyncClient::from_senders(request_tx),
+                status_rx,
+                mqtt::DEFAULT_OFFLINE_QUEUE_SIZE,
+            ),
+            published,
+            event_channel,
+            _status: Arc::new(status_tx),
+        }
+    }
+
+    pub fn client(&self) -> WrappedAsyncClient {
+        self.client.clone()
+    }
+
+    /// All messages that have been published so far as (topic, payload)
+    pub fn published(&self) -> Vec<(String, String)> {
+        self.published.lock().unwrap().clone()
+    }
+
+    /// Deliver a message to the devices as if it was received from the broker
+    pub async fn inject_message(&self, topic: &str, payload: &str) {
+        let message = Publish::new(topic, QoS::AtLeastOnce, payload);
+        self.event_channel
+            .get_tx()
+            .send(Event::MqttMessage(message))
+            .await
+            .expect("Event channel should be open");
+    }
+}
+
+/// HTTP server that stands in for the services that devices talk to
+pub struct MockHttpServer {
+    server: MockServer,
+}
+
+impl MockHttpServer {
+    pub async fn start() -> Self {
+        Self {
+            server: MockServer::start().await,
+        }
+    }
+
+    pub fn url(&self) -> String {
+        self.server.uri()
+    }
+
+    /// Respond to requests matching the method and path with the given json
+    pub async fn respond_json(&self, http_method: &str, http_path: &str, body: serde_json::Value) {
+        Mock::given(method(http_method))
+            .and(path(http_path))
+            .respond_with(ResponseTemplate::new(200).set_body_json(body))
+            .mount(&self.server)
+            .await;
+    }
+
+    /// The json bodies of all requests that were made to the given path
+    pub async fn received_json(&self, http_path: &str) -> Vec<serde_json::Value> {
+        self.server
+            .received_requests()
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|request| request.url.path() == http_path)
+            .filter_map(|request| serde_json::from_slice(&request.body).ok())
+            .collect()
+    }
+}
+
+impl Deref for MockHttpServer {
+    type Target = MockServer;
+
+    fn deref(&self) -> &Self::Target {
+        &self.server
+    }
+}
+
+/// Lua runtime with all modules registered against mock transports
+///
+/// `automation.new_mqtt_client` always returns the same mock client and the url of the mock HTTP
+/// server is available to the script as `testing.http_url`
+pub struct TestContext {
+    lua: mlua::Lua,
+    device_manager: DeviceManager,
+    mqtt: MockMqttClient,
+    http: MockHttpServer,
+}
+
+impl TestContext {
+    pub async fn new() -> mlua::Result<Self> {
+        let device_manager = DeviceManager::new().await;
+        let mqtt = MockMqttClient::new(device_manager.event_channel());
+        let http = MockHttpServer::start().await;
+
+        let lua = mlua::Lua::new();
+
+        let client = mqtt.client();
+        crate::lua::register(&lua, &device_manager, move |_lua, _config| {
+            Ok(client.clone())
+        })?;
+
+        let testing = lua.create_table()?;
+        testing.set("http_url", http.url())?;
+        lua.globals().set("testing", testing)?;
+
+        Ok(Self {
+            lua,
+            device_manager,
+            mqtt,
+            http,
+        })
+    }
+
+    /// Access to the runtime, e.g. to register additional devices
+    pub fn lua(&self) -> &mlua::Lua {
+        &self.lua
+    }
+
+    pub fn device_manager(&self) -> &DeviceManager {
+        &self.device_manager
+    }
+
+    pub fn mqtt(&self) -> &MockMqttClient {
+        &self.mqtt
+    }
+
+    pub fn http(&self) -> &MockHttpServer {
+        &self.http
+    }
+
+    pub async fn exec(&self, script: &str) -> mlua::Result<()> {
+        self.lua.load(script).exec_async().await
+    }
+
+    pub async fn inject_mqtt(&self, topic: &str, payload: &str) {
+        self.mqtt.inject_message(topic, payload).await;
+    }
+
+    /// Wait for a message to be published on the topic, json payloads are compared by value
+    pub async fn assert_published(&self, topic: &str, payload: &str) {
+        let deadline = Instant::now() + PUBLISH_TIMEOUT;
+        loop {
+            let published = self.mqtt.published();
+            if published
+                .iter()
+                .any(|(t, p)| t == topic && payload_eq(p, payload))
+            {
+                return;
+            }
+
+            if Instant::now() > deadline {
+                panic!(
+                    "Expected '{payload}' to be published on '{topic}', published: {published:?}"
+                );
+            }
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
+
+fn payload_eq(a: &str, b: &str) -> bool {
+    match (
+        serde_json::from_str::<serde_json::Value>(a),
+        serde_json::from_str::<serde_json::Value>(b),
+    ) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Create a new test context and run the script in it, panics if the script fails
+pub async fn run_script(script: &str) -> TestContext {
+    let context = TestContext::new()
+        .await
+        .expect("Test context should be created");
+
+    if let Err(err) = context.exec(script).await {
+        panic!("Script failed: {err}");
+    }
+
+    context
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn send_message() {
+        let context = run_script(
+            r#"
+            local client = automation.new_mqtt_client({})
+            client:send_message("test/topic", { state = true })
+            "#,
+        )
+        .await;
+
+        context
+            .assert_published("test/topic", r#"{"state": true}"#)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn util() {
+        run_script(
+            r#"
+            assert(type(automation.util.get_hostname()) == "string")
+            automation.util.sleep(1)
+            "#,
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn presence_callback() {
+        let context = run_script(
+            r#"
+            local client = automation.new_mqtt_client({})
+            automation.device_manager:add(Presence.new({
+                topic = "automation_dev/presence/+/#",
+                client = client,
+                event_channel = automation.device_manager:event_channel(),
+                person_callback = function(_, person)
+                    client:send_message("automation_dev/people/" .. person.name, { present = person.present })
+                end,
+            }))
+            "#,
+        )
+        .await;
+
+        context
+            .inject_mqtt(
+                "automation_dev/presence/alice/phone",
+                r#"{"state": true, "updated": 0}"#,
+            )
+            .await;
+
+        context
+            .assert_published("automation_dev/people/alice", r#"{"present": true}"#)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn ntfy_notification() {
+        let context = run_script(
+            r#"
+            local ntfy = Ntfy.new({
+                url = testing.http_url,
+                topic = "test",
+                event_channel = automation.device_manager:event_channel(),
+            })
+            ntfy:send_notification({ title = "Hello", message = "World" })
+            "#,
+        )
+        .await;
+
+        assert_eq!(
+            context.http().received_json("/").await,
+            vec![json!({
+                "topic": "test",
+                "title": "Hello",
+                "message": "World"
+            })]
+        );
+    }
+
+    #[tokio::test]
+    async fn ntfy_scheduled() {
+        let context = run_script(
+            r#"
+            local ntfy = Ntfy.new({
+                url = testing.http_url,
+                topic = "test",
+                event_channel = automation.device_manager:event_channel(),
+            })
+            ntfy:send_notification({ title = "Bins", delay = "30min" })
+            "#,
+        )
+        .await;
+
+        assert_eq!(
+            context.http().received_json("/").await,
+            vec![json!({
+                "topic": "test",
+                "title": "Bins",
+                "delay": "1800s"
+            })]
+        );
+    }
+
+    #[tokio::test]
+    async fn ntfy_authentication() {
+        let context = run_script(
+            r#"
+            local event_channel = automation.device_manager:event_channel()
+            Ntfy.new({
+                url = testing.http_url,
+                topic = "test",
+                token = "tk_secret",
+                event_channel = event_channel,
+            }):send_notification({ title = "Token" })
+            Ntfy.new({
+                url = testing.http_url,
+                topic = "test",
+                username = "user",
+                password = "pass",
+                event_channel = event_channel,
+            }):send_notification({ title = "Basic" })
+            "#,
+        )
+        .await;
+
+        let authorization: Vec<_> = context
+            .http()
+            .received_requests()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|request| {
+                request.headers["authorization"]
+                    .to_str()
+                    .unwrap()
+                    .to_owned()
+            })
+            .collect();
+        assert_eq!(authorization, ["Bearer tk_secret", "Basic dXNlcjpwYXNz"]);
+    }
+
+    #[tokio::test]
+    async fn implements() {
+        run_script(
+            r#"
+            local ntfy = Ntfy.new({
+                url = testing.http_url,
+                topic = "test",
+                event_channel = automation.device_manager:event_channel(),
+            })
+            assert(not ntfy:implements("OnOff"))
+            assert(not ntfy:implements("action.devices.traits.OnOff"))
+            "#,
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn diagnostics() {
+        let context = run_script(
+            r#"
+            local client = automation.new_mqtt_client({})
+            local event_channel = automation.device_manager:event_channel()
+            automation.device_manager:add(Ntfy.new({
+                url = testing.http_url,
+                topic = "test",
+                event_channel = event_channel,
+            }))
+            automation.device_manager:add(Presence.new({
+                topic = "automation_dev/presence/+/#",
+                client = client,
+                event_channel = event_channel,
+            }))
+
+            local diagnostics = automation.diagnostics
+            assert(diagnostics.device_count() == 2)
+            local ids = diagnostics.device_ids()
+            assert(ids[1] == "ntfy" and ids[2] == "presence")
+            assert(diagnostics.uptime_ms() >= 0)
+            assert(diagnostics.lua_memory_kb() > 0)
+            "#,
+        )
+        .await;
+
+        let events = context.device_manager().event_count();
+        context.inject_mqtt("test/topic", "{}").await;
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while context.device_manager().event_count() == events {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("Event should be counted");
+    }
+
+    #[tokio::test]
+    async fn script_error() {
+        let context = TestContext::new().await.unwrap();
+
+        assert!(context.exec("error('oops')").await.is_err());
+    }
+}
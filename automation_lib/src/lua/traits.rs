@@ -0,0 +1,32 @@
+pub mod on_off;
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use google_home::errors::ErrorCode;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Effect {
+    Blink,
+    Breathe,
+    Okay,
+    ChannelChange,
+    FinishEffect,
+    StopEffect,
+}
+
+// Traits that are only available to local automations and not exposed to Google Home
+#[async_trait]
+pub trait LightEffect: Sync + Send {
+    async fn effect(&self, effect: Effect) -> Result<(), ErrorCode>;
+}
+
+// Turns the device off after the timeout has elapsed, starting a new timeout replaces the pending one
+#[async_trait]
+pub trait Timeout: Sync + Send {
+    async fn start_timeout(&self, timeout: Duration);
+
+    async fn stop_timeout(&self);
+}
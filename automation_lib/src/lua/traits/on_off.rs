@@ -0,0 +1,47 @@
+use google_home::errors::ErrorCode;
+use google_home::traits::OnOff;
+
+// Google Home does not have a toggle command, so this is only available from Lua
+pub async fn toggle(device: &dyn OnOff) -> Result<bool, ErrorCode> {
+    let on = !device.on().await?;
+    device.set_on(on).await?;
+
+    Ok(on)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use async_trait::async_trait;
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct MockDevice {
+        on: AtomicBool,
+    }
+
+    #[async_trait]
+    impl OnOff for MockDevice {
+        async fn on(&self) -> Result<bool, ErrorCode> {
+            Ok(self.on.load(Ordering::Relaxed))
+        }
+
+        async fn set_on(&self, on: bool) -> Result<(), ErrorCode> {
+            self.on.store(on, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn toggle_flips_state() {
+        let device = MockDevice::default();
+
+        assert!(toggle(&device).await.unwrap());
+        assert!(device.on().await.unwrap());
+
+        assert!(!toggle(&device).await.unwrap());
+        assert!(!device.on().await.unwrap());
+    }
+}
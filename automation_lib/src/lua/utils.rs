@@ -0,0 +1,147 @@
+//! Functions that are available to the config under `automation.util`
+use std::time::Duration;
+
+use mlua::{Function, MultiValue};
+use tracing::debug;
+
+async fn sleep(ms: u64) {
+    tokio::time::sleep(Duration::from_millis(ms)).await;
+}
+
+// Calls the function until it succeeds, the error of the last attempt is raised if all attempts fail
+async fn retry(f: Function, attempts: u32, delay_ms: u64) -> mlua::Result<MultiValue> {
+    if attempts == 0 {
+        return Err(mlua::Error::RuntimeError(
+            "retry needs at least one attempt".into(),
+        ));
+    }
+
+    let mut attempt = 1;
+    loop {
+        match f.call_async(()).await {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt >= attempts => return Err(err),
+            Err(err) => {
+                debug!("Attempt {attempt}/{attempts} failed, retrying in {delay_ms}ms: {err}");
+            }
+        }
+
+        sleep(delay_ms).await;
+        attempt += 1;
+    }
+}
+
+pub fn register_with_lua(lua: &mlua::Lua, util: &mlua::Table) -> mlua::Result<()> {
+    let sleep = lua.create_async_function(|_lua, ms: u64| async move {
+        sleep(ms).await;
+
+        Ok(())
+    })?;
+    util.set("sleep", sleep)?;
+
+    let retry = lua.create_async_function(
+        |_lua, (f, attempts, delay_ms): (Function, u32, u64)| async move {
+            retry(f, attempts, delay_ms).await
+        },
+    )?;
+    util.set("retry", retry)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::Instant;
+
+    use super::*;
+
+    fn lua() -> mlua::Lua {
+        let lua = mlua::Lua::new();
+        let util = lua.create_table().unwrap();
+        register_with_lua(&lua, &util).unwrap();
+        lua.globals().set("util", util).unwrap();
+
+        lua
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sleep() {
+        let lua = lua();
+
+        let start = Instant::now();
+        lua.load("util.sleep(2000)").exec_async().await.unwrap();
+
+        assert!(start.elapsed() >= Duration::from_millis(2000));
+        assert!(start.elapsed() < Duration::from_millis(2100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry() {
+        let lua = lua();
+
+        let start = Instant::now();
+        let (result, attempts): (String, u32) = lua
+            .load(
+                r#"
+                local attempts = 0
+                local result = util.retry(function()
+                    attempts = attempts + 1
+                    if attempts < 3 then
+                        error("flaky")
+                    end
+
+                    return "done"
+                end, 5, 100)
+
+                return result, attempts
+                "#,
+            )
+            .eval_async()
+            .await
+            .unwrap();
+
+        assert_eq!(result, "done");
+        assert_eq!(attempts, 3);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_failed() {
+        let lua = lua();
+
+        let err = lua
+            .load(
+                r#"
+                local attempts = 0
+                util.retry(function()
+                    attempts = attempts + 1
+                    error("attempt " .. attempts)
+                end, 3, 100)
+                "#,
+            )
+            .exec_async()
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("attempt 3"));
+    }
+
+    #[tokio::test]
+    async fn retry_zero_attempts() {
+        let lua = lua();
+
+        let err = lua
+            .load(
+                r#"
+                called = false
+                util.retry(function() called = true end, 0, 100)
+                "#,
+            )
+            .exec_async()
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("at least one attempt"));
+        assert!(!lua.globals().get::<bool>("called").unwrap());
+    }
+}
@@ -33,6 +33,75 @@ impl TryFrom<Publish> for OnOffMessage {
     }
 }
 
+// Message used by zigbee2mqtt to report if a device (or the bridge itself) is available
+#[derive(Debug)]
+pub struct AvailabilityMessage {
+    available: bool,
+}
+
+impl AvailabilityMessage {
+    pub fn available(&self) -> bool {
+        self.available
+    }
+}
+
+impl TryFrom<Publish> for AvailabilityMessage {
+    type Error = ParseError;
+
+    fn try_from(message: Publish) -> Result<Self, Self::Error> {
+        #[derive(Deserialize)]
+        struct State {
+            state: String,
+        }
+
+        // Depending on the version of zigbee2mqtt the state is send as plain text or as json
+        let state = serde_json::from_slice::<State>(&message.payload)
+            .map(|message| message.state)
+            .unwrap_or_else(|_| String::from_utf8_lossy(&message.payload).into_owned());
+
+        match state.as_str() {
+            "online" => Ok(Self { available: true }),
+            "offline" => Ok(Self { available: false }),
+            _ => Err(ParseError::InvalidPayload(message.payload.clone())),
+        }
+    }
+}
+
+// Device information published by zigbee2mqtt on '{topic}/$info'
+#[derive(Debug, Deserialize)]
+pub struct DeviceInfoMessage {
+    // Called vendor in the device definitions of zigbee2mqtt
+    #[serde(default, alias = "vendor")]
+    manufacturer: Option<String>,
+    #[serde(default)]
+    model_id: Option<String>,
+    #[serde(default, alias = "sw_build_id")]
+    software_build_id: Option<String>,
+    #[serde(default)]
+    ieee_address: Option<String>,
+}
+
+impl From<DeviceInfoMessage> for google_home::device::Info {
+    fn from(message: DeviceInfoMessage) -> Self {
+        Self {
+            manufacturer: message.manufacturer,
+            model: message.model_id,
+            sw_version: message.software_build_id,
+            serial_number: message.ieee_address,
+            ..Default::default()
+        }
+    }
+}
+
+impl TryFrom<Publish> for DeviceInfoMessage {
+    type Error = ParseError;
+
+    fn try_from(message: Publish) -> Result<Self, Self::Error> {
+        serde_json::from_slice(&message.payload)
+            .or(Err(ParseError::InvalidPayload(message.payload.clone())))
+    }
+}
+
 // Message send to request activating a device
 #[derive(Debug, Deserialize)]
 pub struct ActivateMessage {
@@ -63,6 +132,12 @@ pub enum RemoteAction {
     BrightnessMoveUp,
     BrightnessMoveDown,
     BrightnessStop,
+    ArrowLeftClick,
+    ArrowLeftHold,
+    ArrowLeftRelease,
+    ArrowRightClick,
+    ArrowRightHold,
+    ArrowRightRelease,
 }
 
 // Message used to report the action performed by a remote
@@ -91,6 +166,8 @@ impl TryFrom<Publish> for RemoteMessage {
 pub struct PresenceMessage {
     state: bool,
     updated: Option<u128>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    person: Option<String>,
 }
 
 impl PresenceMessage {
@@ -103,12 +180,17 @@ impl PresenceMessage {
                     .expect("Time is after UNIX EPOCH")
                     .as_millis(),
             ),
+            person: None,
         }
     }
 
     pub fn presence(&self) -> bool {
         self.state
     }
+
+    pub fn person(&self) -> Option<&str> {
+        self.person.as_deref()
+    }
 }
 
 impl TryFrom<Publish> for PresenceMessage {
@@ -241,3 +323,39 @@ impl TryFrom<Bytes> for HueMessage {
         serde_json::from_slice(&bytes).or(Err(ParseError::InvalidPayload(bytes.clone())))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rumqttc::QoS;
+
+    use super::*;
+
+    fn availability(payload: &str) -> Option<bool> {
+        AvailabilityMessage::try_from(Publish::new("test", QoS::AtLeastOnce, payload))
+            .ok()
+            .map(|message| message.available())
+    }
+
+    #[test]
+    fn availability_message() {
+        assert_eq!(availability(r#"{"state":"online"}"#), Some(true));
+        assert_eq!(availability(r#"{"state":"offline"}"#), Some(false));
+        assert_eq!(availability("online"), Some(true));
+        assert_eq!(availability("offline"), Some(false));
+        assert_eq!(availability("unknown"), None);
+    }
+
+    #[test]
+    fn device_info_vendor() {
+        let message = DeviceInfoMessage::try_from(Publish::new(
+            "test",
+            QoS::AtLeastOnce,
+            r#"{"vendor":"Philips","model_id":"LWB010","description":"Hue white A60 bulb"}"#,
+        ))
+        .unwrap();
+
+        let info: google_home::device::Info = message.into();
+        assert_eq!(info.manufacturer.as_deref(), Some("Philips"));
+        assert_eq!(info.model.as_deref(), Some("LWB010"));
+    }
+}
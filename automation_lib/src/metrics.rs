@@ -0,0 +1,68 @@
+//! Prometheus metrics, the fulfillment metrics are recorded by google_home itself
+pub use google_home::{FULFILLMENT_DUPLICATE_REQUESTS, FULFILLMENT_DURATION, FULFILLMENT_REQUESTS};
+use metrics::{counter, gauge};
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder, PrometheusHandle};
+
+pub const DEVICES: &str = "automation_devices_total";
+pub const MQTT_MESSAGES: &str = "automation_mqtt_messages_total";
+pub const MQTT_OFFLINE_QUEUE_DEPTH: &str = "automation_mqtt_offline_queue_depth";
+pub const OUTLET_ENERGY: &str = "automation_outlet_energy_wh_total";
+
+// Fulfillment requests should be handled well within the time Google Home waits for a response
+const FULFILLMENT_BUCKETS: &[f64] = &[0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+fn builder() -> Result<PrometheusBuilder, BuildError> {
+    PrometheusBuilder::new().set_buckets_for_metric(
+        Matcher::Full(FULFILLMENT_DURATION.into()),
+        FULFILLMENT_BUCKETS,
+    )
+}
+
+/// Install the global recorder, the handle renders the metrics in the Prometheus text format
+pub fn install() -> Result<PrometheusHandle, BuildError> {
+    builder()?.install_recorder()
+}
+
+pub fn set_devices(count: usize) {
+    gauge!(DEVICES).set(count as f64);
+}
+
+pub fn mqtt_message_received() {
+    counter!(MQTT_MESSAGES).increment(1);
+}
+
+pub fn set_mqtt_offline_queue_depth(depth: usize) {
+    gauge!(MQTT_OFFLINE_QUEUE_DEPTH).set(depth as f64);
+}
+
+// Energy used by the outlet since the automation system was started
+pub fn set_outlet_energy(device_id: &str, watt_hours: f64) {
+    gauge!(OUTLET_ENERGY, "device_id" => device_id.to_owned()).set(watt_hours);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let recorder = builder().unwrap().build_recorder();
+        let handle = recorder.handle();
+
+        metrics::with_local_recorder(&recorder, || {
+            set_devices(3);
+            mqtt_message_received();
+            mqtt_message_received();
+            set_mqtt_offline_queue_depth(5);
+            set_outlet_energy("kettle", 12.5);
+            metrics::histogram!(FULFILLMENT_DURATION).record(0.02);
+        });
+
+        let rendered = handle.render();
+        assert!(rendered.contains("automation_devices_total 3"));
+        assert!(rendered.contains("automation_mqtt_messages_total 2"));
+        assert!(rendered.contains("automation_mqtt_offline_queue_depth 5"));
+        assert!(rendered.contains("automation_outlet_energy_wh_total{device_id=\"kettle\"} 12.5"));
+        assert!(rendered.contains("automation_fulfillment_duration_seconds_bucket{le=\"0.025\"} 1"));
+    }
+}
@@ -1,48 +1,607 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Display;
 use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
 
-use mlua::FromLua;
-use rumqttc::{AsyncClient, Event, EventLoop, Incoming};
+use mlua::{FromLua, LuaSerdeExt};
+use rumqttc::{
+    AsyncClient, ClientError, ConnectReturnCode, Event, EventLoop, Incoming, MqttOptions, Publish,
+    QoS,
+};
+use tokio::sync::{watch, Mutex};
 use tracing::{debug, warn};
 
+use crate::action_callback::ActionCallback;
 use crate::event::{self, EventChannel};
+use crate::metrics;
+
+pub const DEFAULT_OFFLINE_QUEUE_SIZE: usize = 100;
+
+// Number of consecutive connection errors before we switch to the next broker
+const FAILOVER_AFTER: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    Connected,
+    Disconnected,
+    Reconnecting,
+}
+
+impl Display for ConnectionStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionStatus::Connected => write!(f, "connected"),
+            ConnectionStatus::Disconnected => write!(f, "disconnected"),
+            ConnectionStatus::Reconnecting => write!(f, "reconnecting"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct QueuedMessage {
+    topic: String,
+    payload: Vec<u8>,
+    qos: QoS,
+    retain: bool,
+}
+
+// Messages that are published while we are not connected to the broker
+#[derive(Debug)]
+struct OfflineQueue {
+    size: usize,
+    messages: VecDeque<QueuedMessage>,
+}
+
+impl OfflineQueue {
+    fn new(size: usize) -> Self {
+        Self {
+            size,
+            messages: VecDeque::with_capacity(size),
+        }
+    }
+
+    fn push(&mut self, message: QueuedMessage) {
+        if self.size == 0 {
+            warn!(topic = message.topic, "Not connected, dropping message");
+            return;
+        }
+
+        if self.messages.len() >= self.size {
+            if let Some(dropped) = self.messages.pop_front() {
+                warn!(
+                    topic = dropped.topic,
+                    "Offline queue is full, dropping oldest message"
+                );
+            }
+        }
+
+        self.messages.push_back(message);
+        metrics::set_mqtt_offline_queue_depth(self.messages.len());
+    }
+
+    fn drain(&mut self) -> Vec<QueuedMessage> {
+        let messages = self.messages.drain(..).collect();
+        metrics::set_mqtt_offline_queue_depth(0);
+
+        messages
+    }
+}
+
+// The broker sends all retained messages again when we reconnect, this keeps track of the retained
+// messages that have already been dispatched so the devices do not process them twice
+#[derive(Debug, Clone, Default)]
+pub struct MessageDeduplicator {
+    // Topic -> crc32 of the payload
+    retained: Arc<std::sync::Mutex<HashMap<String, u32>>>,
+}
+
+impl MessageDeduplicator {
+    // Returns false if the message is a retained message that was already seen
+    pub fn should_dispatch(&self, message: &Publish) -> bool {
+        let mut retained = self.retained.lock().unwrap();
+        if !message.retain {
+            // The retained message is no longer the latest state of the topic
+            retained.remove(&message.topic);
+            return true;
+        }
+
+        let hash = crc32fast::hash(&message.payload);
+        retained.insert(message.topic.clone(), hash) != Some(hash)
+    }
+
+    // A device that subscribes might not have existed when the retained messages were first
+    // dispatched, so it needs to see them again. Subscribing again after reconnecting or switching
+    // brokers does not go through here, so those replays are still skipped.
+    pub fn forget(&self, filter: &str) {
+        self.retained
+            .lock()
+            .unwrap()
+            .retain(|topic, _| !rumqttc::matches(topic, filter));
+    }
+}
 
 #[derive(Debug, Clone, FromLua)]
-pub struct WrappedAsyncClient(pub AsyncClient);
+pub struct WrappedAsyncClient {
+    client: AsyncClient,
+    status: watch::Receiver<ConnectionStatus>,
+    queue: Arc<Mutex<OfflineQueue>>,
+    // Address of the broker we are currently using
+    broker: Option<watch::Receiver<String>>,
+    // Everything we subscribed to, so we can subscribe again after switching brokers
+    subscriptions: Arc<std::sync::Mutex<Vec<(String, QoS)>>>,
+    deduplicator: Option<MessageDeduplicator>,
+}
+
+impl WrappedAsyncClient {
+    pub fn new(
+        client: AsyncClient,
+        status: watch::Receiver<ConnectionStatus>,
+        offline_queue_size: usize,
+    ) -> Self {
+        let queue = Arc::new(Mutex::new(OfflineQueue::new(offline_queue_size)));
+
+        // Publish everything that was queued up as soon as we are connected
+        tokio::spawn({
+            let client = client.clone();
+            let mut status = status.clone();
+            let queue = queue.clone();
+            async move {
+                while status.changed().await.is_ok() {
+                    if *status.borrow_and_update() != ConnectionStatus::Connected {
+                        continue;
+                    }
+
+                    // The lock is held while publishing so new messages can not overtake the
+                    // queued ones
+                    let mut queue = queue.lock().await;
+                    let messages = queue.drain();
+                    if !messages.is_empty() {
+                        debug!("Publishing {} queued messages", messages.len());
+                    }
+
+                    for message in messages {
+                        if let Err(err) = client
+                            .publish(message.topic, message.qos, message.retain, message.payload)
+                            .await
+                        {
+                            warn!("Failed to publish queued message: {err}");
+                        }
+                    }
+                }
+            }
+        });
+
+        Self {
+            client,
+            status,
+            queue,
+            broker: None,
+            subscriptions: Default::default(),
+            deduplicator: None,
+        }
+    }
+
+    // Should be the deduplicator that was passed to `start`
+    pub fn with_deduplicator(mut self, deduplicator: Option<MessageDeduplicator>) -> Self {
+        self.deduplicator = deduplicator;
+        self
+    }
+
+    // Keeps track of the active broker and subscribes to all topics again when it changes
+    pub fn with_broker(mut self, broker: watch::Receiver<String>) -> Self {
+        tokio::spawn({
+            let client = self.client.clone();
+            let subscriptions = self.subscriptions.clone();
+            let mut broker = broker.clone();
+            async move {
+                while broker.changed().await.is_ok() {
+                    debug!("Switched to MQTT broker {}", *broker.borrow_and_update());
+
+                    let subscriptions = subscriptions.lock().unwrap().clone();
+                    for (topic, qos) in subscriptions {
+                        if let Err(err) = client.subscribe(&topic, qos).await {
+                            warn!(topic, "Failed to subscribe again: {err}");
+                        }
+                    }
+                }
+            }
+        });
+
+        self.broker = Some(broker);
+        self
+    }
+
+    pub async fn subscribe<S: Into<String>>(&self, topic: S, qos: QoS) -> Result<(), ClientError> {
+        let topic = topic.into();
+        {
+            let mut subscriptions = self.subscriptions.lock().unwrap();
+            if !subscriptions.contains(&(topic.clone(), qos)) {
+                subscriptions.push((topic.clone(), qos));
+            }
+        }
+
+        if let Some(deduplicator) = &self.deduplicator {
+            deduplicator.forget(&topic);
+        }
+
+        self.client.subscribe(topic, qos).await
+    }
+
+    // Address (host:port) of the broker we are currently using
+    pub fn current_broker(&self) -> Option<String> {
+        self.broker.as_ref().map(|broker| broker.borrow().clone())
+    }
+
+    // Publishes the message, if we are not connected the message is queued until we are
+    pub async fn publish<S, V>(
+        &self,
+        topic: S,
+        qos: QoS,
+        retain: bool,
+        payload: V,
+    ) -> Result<(), ClientError>
+    where
+        S: Into<String>,
+        V: Into<Vec<u8>>,
+    {
+        let mut queue = self.queue.lock().await;
+        if !self.is_connected() {
+            queue.push(QueuedMessage {
+                topic: topic.into(),
+                payload: payload.into(),
+                qos,
+                retain,
+            });
+
+            return Ok(());
+        }
+        drop(queue);
+
+        self.client.publish(topic, qos, retain, payload).await
+    }
+
+    pub fn status(&self) -> ConnectionStatus {
+        *self.status.borrow()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.status() == ConnectionStatus::Connected
+    }
+
+    // Calls the callback every time the client (re)connects to the broker
+    pub fn on_connect(&self, callback: ActionCallback<WrappedAsyncClient, ()>) {
+        if !callback.is_set() {
+            return;
+        }
+
+        let client = self.clone();
+        let mut status = self.status.clone();
+        tokio::spawn(async move {
+            while status.changed().await.is_ok() {
+                if *status.borrow_and_update() == ConnectionStatus::Connected {
+                    callback.call(&client, &()).await;
+                }
+            }
+        });
+    }
+}
 
 impl Deref for WrappedAsyncClient {
     type Target = AsyncClient;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.client
     }
 }
 
 impl DerefMut for WrappedAsyncClient {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.client
+    }
+}
+
+impl mlua::UserData for WrappedAsyncClient {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("is_connected", |_lua, this, _: ()| Ok(this.is_connected()));
+
+        methods.add_method("status", |_lua, this, _: ()| Ok(this.status().to_string()));
+
+        methods.add_method("current_broker", |_lua, this, _: ()| {
+            Ok(this.current_broker())
+        });
+
+        methods.add_async_method(
+            "send_message",
+            |lua, this, (topic, message): (String, mlua::Value)| async move {
+                let message: serde_json::Value = lua.from_value(message)?;
+                let message =
+                    serde_json::to_string(&message).map_err(mlua::ExternalError::into_lua_err)?;
+
+                this.publish(topic, QoS::AtLeastOnce, false, message)
+                    .await
+                    .map_err(mlua::ExternalError::into_lua_err)?;
+
+                Ok(())
+            },
+        );
     }
 }
 
-impl mlua::UserData for WrappedAsyncClient {}
+async fn handle_notification(
+    notification: Result<Event, rumqttc::ConnectionError>,
+    tx: &event::Sender,
+    status_tx: &watch::Sender<ConnectionStatus>,
+    deduplicator: Option<&MessageDeduplicator>,
+    broker: &str,
+) {
+    let disconnected = || async {
+        tx.send(event::Event::MqttDisconnected {
+            broker: broker.to_owned(),
+        })
+        .await
+        .ok();
+    };
+
+    match notification {
+        Ok(Event::Incoming(Incoming::Publish(p))) => {
+            if let Some(deduplicator) = deduplicator {
+                if !deduplicator.should_dispatch(&p) {
+                    debug!(
+                        topic = p.topic,
+                        "Skipping retained message that was already seen"
+                    );
+                    return;
+                }
+            }
+
+            tx.send(event::Event::MqttMessage(p)).await.ok();
+        }
+        Ok(Event::Incoming(Incoming::ConnAck(ack))) => {
+            if ack.code == ConnectReturnCode::Success {
+                debug!(broker, "Connected to MQTT broker");
+                if status_tx.send_replace(ConnectionStatus::Connected)
+                    != ConnectionStatus::Connected
+                {
+                    tx.send(event::Event::MqttConnected {
+                        broker: broker.to_owned(),
+                    })
+                    .await
+                    .ok();
+                }
+            }
+        }
+        Ok(Event::Incoming(Incoming::Disconnect)) => {
+            if status_tx.send_replace(ConnectionStatus::Disconnected) == ConnectionStatus::Connected
+            {
+                disconnected().await;
+            }
+        }
+        Ok(..) => {}
+        Err(err) => {
+            // Something has gone wrong
+            // We stay in the loop as that will attempt to reconnect
+            warn!(broker, "{}", err);
+            if status_tx.send_replace(ConnectionStatus::Reconnecting) == ConnectionStatus::Connected
+            {
+                disconnected().await;
+            }
+        }
+    }
+}
+
+fn broker_address(options: &MqttOptions) -> String {
+    let (host, port) = options.broker_address();
+    format!("{host}:{port}")
+}
 
-pub fn start(mut eventloop: EventLoop, event_channel: &EventChannel) {
+// The options of the eventloop are used for the first broker, if we fail to connect to it we move
+// on to the failover brokers in order
+pub fn start(
+    mut eventloop: EventLoop,
+    event_channel: &EventChannel,
+    deduplicator: Option<MessageDeduplicator>,
+    failover: Vec<MqttOptions>,
+) -> (watch::Receiver<ConnectionStatus>, watch::Receiver<String>) {
     let tx = event_channel.get_tx();
+    let (status_tx, status_rx) = watch::channel(ConnectionStatus::Disconnected);
+
+    let brokers: Vec<_> = std::iter::once(eventloop.mqtt_options.clone())
+        .chain(failover)
+        .collect();
+    let (broker_tx, broker_rx) = watch::channel(broker_address(&brokers[0]));
 
     tokio::spawn(async move {
         debug!("Listening for MQTT events");
+        let mut current = 0;
+        let mut failures = 0;
         loop {
             let notification = eventloop.poll().await;
-            match notification {
-                Ok(Event::Incoming(Incoming::Publish(p))) => {
-                    tx.send(event::Event::MqttMessage(p)).await.ok();
-                }
-                Ok(..) => continue,
-                Err(err) => {
-                    // Something has gone wrong
-                    // We stay in the loop as that will attempt to reconnect
-                    warn!("{}", err);
-                }
+            failures = if notification.is_err() {
+                failures + 1
+            } else {
+                0
+            };
+
+            let broker = broker_tx.borrow().clone();
+            handle_notification(
+                notification,
+                &tx,
+                &status_tx,
+                deduplicator.as_ref(),
+                &broker,
+            )
+            .await;
+
+            if failures >= FAILOVER_AFTER && brokers.len() > 1 {
+                current = (current + 1) % brokers.len();
+                failures = 0;
+
+                let options = &brokers[current];
+                warn!(
+                    broker,
+                    "Failing over to MQTT broker {}",
+                    broker_address(options)
+                );
+                eventloop.mqtt_options = options.clone();
+                broker_tx.send_replace(broker_address(options));
             }
         }
     });
+
+    (status_rx, broker_rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rumqttc::{Outgoing, Request};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn published(rx: &flume::Receiver<Request>) -> Vec<String> {
+        rx.try_iter()
+            .filter_map(|request| match request {
+                Request::Publish(publish) => {
+                    Some(String::from_utf8_lossy(&publish.payload).into_owned())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn offline_queue() {
+        let (request_tx, request_rx) = flume::unbounded();
+        let (status_tx, status_rx) = watch::channel(ConnectionStatus::Disconnected);
+        let client = WrappedAsyncClient::new(AsyncClient::from_senders(request_tx), status_rx, 2);
+
+        for payload in ["1", "2", "3"] {
+            client
+                .publish("test", QoS::AtLeastOnce, false, payload)
+                .await
+                .unwrap();
+        }
+        assert!(published(&request_rx).is_empty());
+
+        // The oldest message is dropped when the queue is full
+        status_tx.send_replace(ConnectionStatus::Connected);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(published(&request_rx), vec!["2", "3"]);
+
+        client
+            .publish("test", QoS::AtLeastOnce, false, "4")
+            .await
+            .unwrap();
+        assert_eq!(published(&request_rx), vec!["4"]);
+    }
+
+    #[tokio::test]
+    async fn deduplicate_retained() {
+        let (event_channel, mut rx) = EventChannel::new();
+        let tx = event_channel.get_tx();
+        let (status_tx, status_rx) = watch::channel(ConnectionStatus::Connected);
+        let deduplicator = MessageDeduplicator::default();
+        let (request_tx, _request_rx) = flume::unbounded();
+        let client = WrappedAsyncClient::new(AsyncClient::from_senders(request_tx), status_rx, 2)
+            .with_deduplicator(Some(deduplicator.clone()));
+
+        let retained = |payload: &str| {
+            let mut message = Publish::new("zigbee2mqtt/light", QoS::AtLeastOnce, payload);
+            message.retain = true;
+            Ok(Event::Incoming(Incoming::Publish(message)))
+        };
+        let live = |payload: &str| {
+            Ok(Event::Incoming(Incoming::Publish(Publish::new(
+                "zigbee2mqtt/light",
+                QoS::AtLeastOnce,
+                payload,
+            ))))
+        };
+        let handle = |notification| {
+            handle_notification(
+                notification,
+                &tx,
+                &status_tx,
+                Some(&deduplicator),
+                "localhost:1883",
+            )
+        };
+
+        handle(retained("on")).await;
+        // Replayed by the broker after reconnecting, which also subscribes again
+        handle(Ok(Event::Outgoing(Outgoing::Subscribe(1)))).await;
+        handle(retained("on")).await;
+        handle(retained("off")).await;
+        handle(live("off")).await;
+        // After a live message the retained message is dispatched again
+        handle(retained("off")).await;
+        handle(retained("off")).await;
+
+        // Unrelated subscriptions do not affect the topic
+        client
+            .subscribe("zigbee2mqtt/outlet", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        handle(retained("off")).await;
+
+        // A device that subscribes to the topic needs the retained message
+        client
+            .subscribe("zigbee2mqtt/#", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        handle(retained("off")).await;
+
+        let mut payloads = Vec::new();
+        while let Ok(event::Event::MqttMessage(message)) = rx.try_recv() {
+            payloads.push(String::from_utf8_lossy(&message.payload).into_owned());
+        }
+        assert_eq!(payloads, ["on", "off", "off", "off", "off"]);
+    }
+
+    #[tokio::test]
+    async fn failover() {
+        // Nothing is listening on this port anymore, so connections are refused
+        let refused = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let broker = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = broker.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut socket, _) = broker.accept().await.unwrap();
+            let mut buffer = [0; 1024];
+            // Accept the CONNECT packet
+            assert!(socket.read(&mut buffer).await.unwrap() > 0);
+            socket.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+            while socket.read(&mut buffer).await.is_ok_and(|n| n > 0) {}
+        });
+
+        let options = |port| MqttOptions::new("automation_rs", "127.0.0.1", port);
+        let (client, eventloop) = AsyncClient::new(options(refused.port()), 10);
+        let (event_channel, mut rx) = EventChannel::new();
+        let (status, broker) = start(
+            eventloop,
+            &event_channel,
+            None,
+            vec![options(
+                address.rsplit_once(':').unwrap().1.parse().unwrap(),
+            )],
+        );
+        let client = WrappedAsyncClient::new(client, status, 0).with_broker(broker);
+
+        let event = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap();
+        assert!(
+            matches!(&event, Some(event::Event::MqttConnected { broker }) if *broker == address),
+            "{event:?}"
+        );
+        assert!(client.is_connected());
+        assert_eq!(client.current_broker(), Some(address));
+    }
 }
@@ -1,18 +1,24 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::convert::Infallible;
 use std::ops::Deref;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use async_trait::async_trait;
 use automation_cast::Cast;
 use automation_macro::LuaDeviceConfig;
-use serde::Serialize;
+use mlua::{FromLua, LuaSerdeExt};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_repr::*;
-use tracing::{error, trace, warn};
+use tracing::{debug, error, trace, warn};
 
+use crate::action_callback::ActionCallback;
+use crate::config::Secret;
 use crate::device::{impl_device, Device, LuaDeviceCreate};
+use crate::error::NotificationError;
 use crate::event::{self, Event, EventChannel, OnNotification, OnPresence};
 
-#[derive(Debug, Serialize_repr, Clone, Copy)]
+#[derive(Debug, Serialize_repr, Deserialize_repr, Clone, Copy)]
 #[repr(u8)]
 pub enum Priority {
     Min = 1,
@@ -22,18 +28,18 @@ pub enum Priority {
     Max,
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "snake_case", tag = "action")]
 pub enum ActionType {
     Broadcast {
-        #[serde(skip_serializing_if = "HashMap::is_empty")]
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
         extras: HashMap<String, String>,
     },
     // View,
     // Http
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Action {
     #[serde(flatten)]
     pub action: ActionType,
@@ -48,28 +54,132 @@ struct NotificationFinal {
     inner: Notification,
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Notification {
-    #[serde(skip_serializing_if = "Option::is_none")]
+    // Set when the notification was received from ntfy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     title: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     message: Option<String>,
-    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     tags: Vec<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     priority: Option<Priority>,
-    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     actions: Vec<Action>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    click: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    icon: Option<String>,
+    // URL of an external attachment, images are shown inline in the app
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    attach: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    filename: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    markdown: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    // Seconds to wait before delivering the notification, can also be given as a duration like
+    // '30min' or '2h'
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_delay",
+        deserialize_with = "deserialize_delay"
+    )]
+    delay: Option<u64>,
+    // Unix timestamp at which the notification should be delivered
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        rename(serialize = "delay"),
+        alias = "at",
+        serialize_with = "serialize_scheduled_at"
+    )]
+    scheduled_at: Option<u64>,
+}
+
+// ntfy only allows scheduling notifications between 10 seconds and 3 days in advance
+const MIN_DELAY: Duration = Duration::from_secs(10);
+const MAX_DELAY: Duration = Duration::from_secs(3 * 24 * 60 * 60);
+
+fn serialize_delay<S: Serializer>(delay: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+    match delay {
+        Some(delay) => serializer.serialize_str(&format!("{delay}s")),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn serialize_scheduled_at<S: Serializer>(
+    scheduled_at: &Option<u64>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match scheduled_at {
+        Some(scheduled_at) => serializer.serialize_str(&scheduled_at.to_string()),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn deserialize_delay<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Delay {
+        Seconds(u64),
+        Duration(String),
+    }
+
+    match Option::<Delay>::deserialize(deserializer)? {
+        Some(Delay::Seconds(seconds)) => Ok(Some(seconds)),
+        Some(Delay::Duration(duration)) => parse_duration(&duration)
+            .map(Some)
+            .ok_or_else(|| serde::de::Error::custom(format!("Invalid delay '{duration}'"))),
+        None => Ok(None),
+    }
+}
+
+// Parses durations in the same format as ntfy, e.g. '30s', '30min', '2 hours' or '1d'
+fn parse_duration(duration: &str) -> Option<u64> {
+    let duration = duration.trim();
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (value, unit) = duration.split_at(split);
+    let value: u64 = value.parse().ok()?;
+
+    let multiplier = match unit.trim_start() {
+        "" | "s" | "sec" | "secs" | "second" | "seconds" => 1,
+        "m" | "min" | "mins" | "minute" | "minutes" => 60,
+        "h" | "hour" | "hours" => 60 * 60,
+        "d" | "day" | "days" => 24 * 60 * 60,
+        _ => return None,
+    };
+
+    value.checked_mul(multiplier)
 }
 
 impl Notification {
     pub fn new() -> Self {
         Self {
+            id: None,
             title: None,
             message: None,
             tags: Vec::new(),
             priority: None,
             actions: Vec::new(),
+            click: None,
+            icon: None,
+            attach: None,
+            filename: None,
+            markdown: false,
+            email: None,
+            delay: None,
+            scheduled_at: None,
         }
     }
 
@@ -98,6 +208,66 @@ impl Notification {
         self
     }
 
+    pub fn set_click(mut self, url: &str) -> Self {
+        self.click = Some(url.into());
+        self
+    }
+
+    pub fn set_icon(mut self, url: &str) -> Self {
+        self.icon = Some(url.into());
+        self
+    }
+
+    pub fn set_attachment(mut self, url: &str, filename: Option<&str>) -> Self {
+        self.attach = Some(url.into());
+        self.filename = filename.map(Into::into);
+        self
+    }
+
+    pub fn set_markdown(mut self, markdown: bool) -> Self {
+        self.markdown = markdown;
+        self
+    }
+
+    pub fn set_email(mut self, email: &str) -> Self {
+        self.email = Some(email.into());
+        self
+    }
+
+    pub fn set_delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay.as_secs());
+        self
+    }
+
+    pub fn set_scheduled_at(mut self, scheduled_at: SystemTime) -> Self {
+        self.scheduled_at = Some(
+            scheduled_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        );
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), NotificationError> {
+        let delay = match (self.delay, self.scheduled_at) {
+            (Some(_), Some(_)) => return Err(NotificationError::DelayAndScheduledAt),
+            (Some(delay), None) => Duration::from_secs(delay),
+            (None, Some(scheduled_at)) => (UNIX_EPOCH + Duration::from_secs(scheduled_at))
+                .duration_since(SystemTime::now())
+                .map_err(|_| NotificationError::ScheduledAtInPast)?,
+            (None, None) => return Ok(()),
+        };
+
+        if delay < MIN_DELAY {
+            return Err(NotificationError::DelayTooShort);
+        } else if delay > MAX_DELAY {
+            return Err(NotificationError::DelayTooLong);
+        }
+
+        Ok(())
+    }
+
     fn finalize(self, topic: &str) -> NotificationFinal {
         NotificationFinal {
             topic: topic.into(),
@@ -112,21 +282,119 @@ impl Default for Notification {
     }
 }
 
+// Message as received from the json stream of a topic
+#[derive(Debug, Deserialize)]
+struct StreamMessage {
+    id: String,
+    event: String,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    message: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    priority: Option<Priority>,
+    #[serde(default)]
+    click: Option<String>,
+}
+
+impl From<StreamMessage> for Notification {
+    fn from(message: StreamMessage) -> Self {
+        Self {
+            id: Some(message.id),
+            title: message.title,
+            message: message.message,
+            tags: message.tags,
+            priority: message.priority,
+            click: message.click,
+            ..Default::default()
+        }
+    }
+}
+
+// Keeps track of the most recently received message ids
+#[derive(Debug, Default)]
+struct RecentIds(VecDeque<String>);
+
+impl RecentIds {
+    const CAPACITY: usize = 100;
+
+    // Returns false if the id has already been seen
+    fn insert(&mut self, id: &str) -> bool {
+        if self.0.iter().any(|seen| seen == id) {
+            return false;
+        }
+
+        if self.0.len() == Self::CAPACITY {
+            self.0.pop_front();
+        }
+        self.0.push_back(id.into());
+
+        true
+    }
+
+    fn last(&self) -> Option<&str> {
+        self.0.back().map(String::as_str)
+    }
+}
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+// The stream itself is kept open indefinitely, so only establishing the connection has a timeout
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[derive(Debug, Clone, LuaDeviceConfig)]
 pub struct Config {
     #[device_config(default("https://ntfy.sh".into()))]
     pub url: String,
     pub topic: String,
+    // Access token, takes precedence over username and password
+    #[device_config(secret, default)]
+    pub token: Secret<Option<String>>,
+    #[device_config(default)]
+    pub username: Option<String>,
+    #[device_config(secret, default)]
+    pub password: Secret<Option<String>>,
+    // Listen for messages that are published to the topic
+    #[device_config(default)]
+    pub subscribe: bool,
+    #[device_config(from_lua, default)]
+    pub on_message: ActionCallback<Ntfy, Notification>,
     #[device_config(rename("event_channel"), from_lua, with(|ec: EventChannel| ec.get_tx()))]
     pub tx: event::Sender,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, FromLua)]
 pub struct Ntfy {
-    config: Config,
+    config: Arc<Config>,
+    client: reqwest::Client,
 }
 
-impl_device!(Ntfy);
+#[derive(Debug, Default, Deserialize)]
+struct SendOptions {
+    // Send the notification to this topic instead of the configured one
+    topic: Option<String>,
+}
+
+impl_device!(Ntfy, methods => {
+    methods.add_async_method(
+        "send_notification",
+        |lua, this, (notification, options): (mlua::Value, Option<mlua::Value>)| async move {
+            let notification: Notification = lua.from_value(notification)?;
+            notification.validate().map_err(mlua::ExternalError::into_lua_err)?;
+
+            let options: SendOptions = match options {
+                Some(options) => lua.from_value(options)?,
+                None => Default::default(),
+            };
+
+            this.send(notification, options.topic.as_deref()).await;
+
+            Ok(())
+        },
+    );
+});
 
 #[async_trait]
 impl LuaDeviceCreate for Ntfy {
@@ -135,7 +403,21 @@ impl LuaDeviceCreate for Ntfy {
 
     async fn create(config: Self::Config) -> Result<Self, Self::Error> {
         trace!(id = "ntfy", "Setting up Ntfy");
-        Ok(Self { config })
+        let ntfy = Self {
+            config: Arc::new(config),
+            client: reqwest::Client::new(),
+        };
+
+        if ntfy.config.subscribe {
+            let client = reqwest::Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .build()
+                .expect("Client should build");
+
+            tokio::spawn(Self::subscribe(Arc::downgrade(&ntfy.config), client));
+        }
+
+        Ok(ntfy)
     }
 }
 
@@ -146,27 +428,179 @@ impl Device for Ntfy {
 }
 
 impl Ntfy {
-    async fn send(&self, notification: Notification) {
-        let notification = notification.finalize(&self.config.topic);
+    fn authenticate(&self, req: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        if let Some(token) = self.config.token.as_ref() {
+            req.bearer_auth(token)
+        } else if let Some(username) = &self.config.username {
+            req.basic_auth(username, self.config.password.as_ref())
+        } else {
+            req
+        }
+    }
+
+    async fn send(&self, notification: Notification, topic: Option<&str>) {
+        let notification = notification.finalize(topic.unwrap_or(&self.config.topic));
 
         // Create the request
-        let res = reqwest::Client::new()
+        let req = self
+            .client
             .post(self.config.url.clone())
-            .json(&notification)
-            .send()
-            .await;
+            .json(&notification);
+
+        let res = self.authenticate(req).send().await;
 
         if let Err(err) = res {
             error!("Something went wrong while sending the notification: {err}");
         } else if let Ok(res) = res {
             let status = res.status();
-            if !status.is_success() {
+            if status == reqwest::StatusCode::UNAUTHORIZED
+                || status == reqwest::StatusCode::FORBIDDEN
+            {
+                error!("Received status {status} when sending notification, check the configured token or username and password");
+            } else if !status.is_success() {
                 warn!("Received status {status} when sending notification");
             }
         }
     }
 }
 
+impl Ntfy {
+    // Uploads the data as an attachment, ntfy shows images inline
+    pub async fn send_attachment(
+        &self,
+        data: Vec<u8>,
+        filename: &str,
+        title: &str,
+        message: Option<&str>,
+    ) -> Result<(), reqwest::Error> {
+        let url = format!(
+            "{}/{}",
+            self.config.url.trim_end_matches('/'),
+            self.config.topic
+        );
+
+        let mut req = self
+            .client
+            .put(url)
+            .header("X-Title", title)
+            .header("X-Filename", filename)
+            .body(data);
+        if let Some(message) = message {
+            req = req.header("X-Message", message);
+        }
+
+        self.authenticate(req).send().await?.error_for_status()?;
+
+        Ok(())
+    }
+}
+
+impl Ntfy {
+    fn upgrade(config: &Weak<Config>, client: &reqwest::Client) -> Option<Self> {
+        Some(Self {
+            config: config.upgrade()?,
+            client: client.clone(),
+        })
+    }
+
+    // Only holds on to a weak reference, see crate::helpers::weak. While waiting on the stream
+    // it is only noticed that ntfy is dropped when the next message or keepalive comes in.
+    async fn subscribe(config: Weak<Config>, client: reqwest::Client) {
+        let mut recent = RecentIds::default();
+        let mut backoff = INITIAL_BACKOFF;
+
+        loop {
+            match Self::listen(&config, &client, &mut recent, &mut backoff).await {
+                Ok(()) => debug!("Ntfy stream closed, reconnecting in {backoff:?}"),
+                Err(err) => warn!("Ntfy stream failed: {err}, reconnecting in {backoff:?}"),
+            }
+
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+
+            if config.strong_count() == 0 {
+                break;
+            }
+        }
+    }
+
+    async fn listen(
+        config: &Weak<Config>,
+        client: &reqwest::Client,
+        recent: &mut RecentIds,
+        backoff: &mut Duration,
+    ) -> Result<(), reqwest::Error> {
+        let mut res = {
+            let Some(ntfy) = Self::upgrade(config, client) else {
+                return Ok(());
+            };
+
+            let url = format!(
+                "{}/{}/json",
+                ntfy.config.url.trim_end_matches('/'),
+                ntfy.config.topic
+            );
+
+            let mut req = ntfy.client.get(url);
+            // Pick up any messages that were missed while disconnected
+            if let Some(since) = recent.last() {
+                req = req.query(&[("since", since)]);
+            }
+
+            let res = ntfy.authenticate(req).send().await?.error_for_status()?;
+            debug!("Subscribed to ntfy topic '{}'", ntfy.config.topic);
+            res
+        };
+        *backoff = INITIAL_BACKOFF;
+
+        // Every message in the stream is on a separate line
+        let mut buffer = Vec::new();
+        while let Some(chunk) = res.chunk().await? {
+            buffer.extend_from_slice(&chunk);
+
+            while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<_> = buffer.drain(..=end).collect();
+                let Some(ntfy) = Self::upgrade(config, client) else {
+                    return Ok(());
+                };
+                ntfy.handle_message(&line, recent).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_message(&self, line: &[u8], recent: &mut RecentIds) {
+        let message: StreamMessage = match serde_json::from_slice(line) {
+            Ok(message) => message,
+            Err(err) => {
+                warn!("Failed to parse ntfy message: {err}");
+                return;
+            }
+        };
+
+        // Other events are used to keep the connection alive
+        if message.event != "message" || !recent.insert(&message.id) {
+            return;
+        }
+
+        let notification: Notification = message.into();
+        debug!("Received ntfy message: {notification:?}");
+
+        self.config.on_message.call(self, &notification).await;
+
+        if self
+            .config
+            .tx
+            .send(Event::Ntfy(Box::new(notification)))
+            .await
+            .is_err()
+        {
+            warn!("There are no receivers on the event channel");
+        }
+    }
+}
+
 #[async_trait]
 impl OnPresence for Ntfy {
     async fn on_presence(&self, presence: bool) {
@@ -194,7 +628,7 @@ impl OnPresence for Ntfy {
         if self
             .config
             .tx
-            .send(Event::Ntfy(notification))
+            .send(Event::Ntfy(Box::new(notification)))
             .await
             .is_err()
         {
@@ -206,6 +640,261 @@ impl OnPresence for Ntfy {
 #[async_trait]
 impl OnNotification for Ntfy {
     async fn on_notification(&self, notification: Notification) {
-        self.send(notification).await;
+        // Notifications received from ntfy do not need to be send again
+        if notification.id.is_some() {
+            return;
+        }
+
+        if let Err(err) = notification.validate() {
+            error!("Invalid notification: {err}");
+            return;
+        }
+
+        self.send(notification, None).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn serialize_delay() {
+        let notification = Notification::new()
+            .set_title("Bins")
+            .set_delay(Duration::from_secs(30))
+            .finalize("test");
+
+        let notification = serde_json::to_value(notification).unwrap();
+
+        let notification_expected = json!({
+            "topic": "test",
+            "title": "Bins",
+            "delay": "30s"
+        });
+
+        assert_eq!(notification, notification_expected);
+    }
+
+    #[test]
+    fn serialize_scheduled_at() {
+        let notification = Notification::new()
+            .set_title("Bins")
+            .set_scheduled_at(UNIX_EPOCH + Duration::from_secs(1735758000))
+            .finalize("test");
+
+        let notification = serde_json::to_value(notification).unwrap();
+
+        let notification_expected = json!({
+            "topic": "test",
+            "title": "Bins",
+            "delay": "1735758000"
+        });
+
+        assert_eq!(notification, notification_expected);
+    }
+
+    #[test]
+    fn deserialize_scheduled_at() {
+        for field in ["scheduled_at", "at"] {
+            let notification: Notification = serde_json::from_value(json!({
+                "title": "Bins",
+                field: 1735758000
+            }))
+            .unwrap();
+
+            assert_eq!(notification.scheduled_at, Some(1735758000));
+            assert_eq!(notification.delay, None);
+        }
+    }
+
+    #[test]
+    fn deserialize_delay() {
+        for (delay, seconds) in [
+            (json!(1800), 1800),
+            (json!("45"), 45),
+            (json!("30s"), 30),
+            (json!("30min"), 30 * 60),
+            (json!("2 hours"), 2 * 60 * 60),
+            (json!("1d"), 24 * 60 * 60),
+        ] {
+            let notification: Notification =
+                serde_json::from_value(json!({ "delay": delay })).unwrap();
+            assert_eq!(notification.delay, Some(seconds));
+        }
+
+        for delay in ["", "soon", "10 weeks", "-5m"] {
+            assert!(serde_json::from_value::<Notification>(json!({ "delay": delay })).is_err());
+        }
+    }
+
+    #[test]
+    fn deserialize_stream_message() {
+        let message: StreamMessage = serde_json::from_value(json!({
+            "id": "sPs71M8A2T",
+            "time": 1643138845,
+            "event": "message",
+            "topic": "test",
+            "title": "Presence",
+            "message": "Set away",
+            "tags": ["house"],
+            "priority": 2,
+            "actions": [{ "id": "abc", "action": "view", "label": "Open", "url": "https://example.com" }]
+        }))
+        .unwrap();
+
+        let notification: Notification = message.into();
+        let notification = serde_json::to_value(notification).unwrap();
+
+        let notification_expected = json!({
+            "id": "sPs71M8A2T",
+            "title": "Presence",
+            "message": "Set away",
+            "tags": ["house"],
+            "priority": 2
+        });
+
+        assert_eq!(notification, notification_expected);
+    }
+
+    #[test]
+    fn recent_ids() {
+        let mut recent = RecentIds::default();
+
+        assert!(recent.insert("a"));
+        assert!(recent.insert("b"));
+        assert!(!recent.insert("a"));
+        assert_eq!(recent.last(), Some("b"));
+
+        for i in 0..RecentIds::CAPACITY {
+            recent.insert(&i.to_string());
+        }
+
+        // The oldest ids are forgotten
+        assert!(recent.insert("a"));
+    }
+
+    #[tokio::test]
+    async fn subscribe() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, ResponseTemplate};
+
+        use crate::lua::testing::TestContext;
+
+        let context = TestContext::new().await.unwrap();
+
+        let mut stream = String::new();
+        for message in [
+            json!({ "id": "a", "event": "open", "topic": "test" }),
+            json!({ "id": "b", "event": "message", "topic": "test", "title": "Hello" }),
+            json!({ "id": "b", "event": "message", "topic": "test", "title": "Hello" }),
+            json!({ "id": "c", "event": "keepalive", "topic": "test" }),
+        ] {
+            stream.push_str(&format!("{message}\n"));
+        }
+
+        Mock::given(method("GET"))
+            .and(path("/test/json"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(stream))
+            .mount(context.http())
+            .await;
+
+        context
+            .exec(
+                r#"
+                received = {}
+                Ntfy.new({
+                    url = testing.http_url,
+                    topic = "test",
+                    subscribe = true,
+                    event_channel = automation.device_manager:event_channel(),
+                    on_message = function(_, notification)
+                        table.insert(received, notification.title)
+                    end,
+                })
+                "#,
+            )
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let received: Vec<String> = context.lua().globals().get("received").unwrap();
+        assert_eq!(received, vec!["Hello"]);
+    }
+
+    #[tokio::test]
+    async fn subscribe_stops_with_ntfy() {
+        use crate::lua::testing::MockHttpServer;
+
+        let server = MockHttpServer::start().await;
+        // The stream closes straight away, so the subscriber is waiting to reconnect
+        server.respond_json("GET", "/test/json", json!({})).await;
+
+        let (event_channel, _rx) = EventChannel::new();
+        let ntfy = Ntfy::create(Config {
+            url: server.url(),
+            topic: "test".into(),
+            token: None.into(),
+            username: None,
+            password: None.into(),
+            subscribe: true,
+            on_message: Default::default(),
+            tx: event_channel.get_tx(),
+        })
+        .await
+        .unwrap();
+        let config = Arc::downgrade(&ntfy.config);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        drop(ntfy);
+        assert!(config.upgrade().is_none());
+
+        // No reconnect happens after the backoff
+        tokio::time::sleep(INITIAL_BACKOFF + Duration::from_millis(500)).await;
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn validate() {
+        let now = SystemTime::now();
+
+        assert!(Notification::new().validate().is_ok());
+        assert!(Notification::new()
+            .set_delay(Duration::from_secs(60))
+            .validate()
+            .is_ok());
+        assert!(Notification::new()
+            .set_scheduled_at(now + Duration::from_secs(60 * 60))
+            .validate()
+            .is_ok());
+
+        assert!(matches!(
+            Notification::new()
+                .set_delay(Duration::from_secs(60))
+                .set_scheduled_at(now + Duration::from_secs(60))
+                .validate(),
+            Err(NotificationError::DelayAndScheduledAt)
+        ));
+        assert!(matches!(
+            Notification::new()
+                .set_delay(Duration::from_secs(5))
+                .validate(),
+            Err(NotificationError::DelayTooShort)
+        ));
+        assert!(matches!(
+            Notification::new()
+                .set_delay(MAX_DELAY + Duration::from_secs(1))
+                .validate(),
+            Err(NotificationError::DelayTooLong)
+        ));
+        assert!(matches!(
+            Notification::new()
+                .set_scheduled_at(now - Duration::from_secs(60))
+                .validate(),
+            Err(NotificationError::ScheduledAtInPast)
+        ));
     }
 }
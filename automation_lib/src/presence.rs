@@ -1,14 +1,18 @@
 use std::collections::HashMap;
 use std::ops::Deref;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
 use automation_cast::Cast;
 use automation_macro::LuaDeviceConfig;
 use rumqttc::Publish;
+use serde::Serialize;
 use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::task::JoinHandle;
 use tracing::{debug, trace, warn};
 
+use crate::action_callback::ActionCallback;
 use crate::config::MqttDeviceConfig;
 use crate::device::{impl_device, Device, LuaDeviceCreate};
 use crate::event::{self, Event, EventChannel, OnMqtt};
@@ -23,16 +27,194 @@ pub struct Config {
     pub tx: event::Sender,
     #[device_config(from_lua)]
     pub client: WrappedAsyncClient,
+    #[device_config(from_lua, default)]
+    pub person_callback: ActionCallback<Presence, PersonPresence>,
+    // Number of consecutive readings required before a device changes state
+    #[device_config(default(1))]
+    pub debounce_count: usize,
+    // Time a reading needs to be stable for before a device changes state
+    #[device_config(rename("debounce_seconds"), default, with(|s: Option<u64>| s.map(Duration::from_secs)))]
+    pub debounce: Option<Duration>,
 }
 
 pub const DEFAULT_PRESENCE: bool = false;
 
+#[derive(Debug, Clone, Serialize)]
+pub struct PersonPresence {
+    pub name: String,
+    pub present: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Debounce {
+    count: usize,
+    duration: Option<Duration>,
+}
+
+impl From<&Config> for Debounce {
+    fn from(config: &Config) -> Self {
+        Self {
+            count: config.debounce_count,
+            duration: config.debounce,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Reading {
+    // The reading matches the current state of the device
+    Unchanged,
+    // The reading should be applied right away
+    Apply,
+    // The reading is waiting for more readings or for the debounce duration to pass
+    Pending,
+}
+
+#[derive(Debug)]
+struct PendingChange {
+    present: bool,
+    count: usize,
+    since: Instant,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl PendingChange {
+    fn new(present: bool, now: Instant) -> Self {
+        Self {
+            present,
+            count: 0,
+            since: now,
+            handle: None,
+        }
+    }
+
+    fn is_ready(&self, debounce: &Debounce, now: Instant) -> bool {
+        self.count >= debounce.count
+            && now.duration_since(self.since) >= debounce.duration.unwrap_or_default()
+    }
+
+    fn cancel(self) {
+        if let Some(handle) = self.handle {
+            handle.abort();
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct State {
     devices: HashMap<String, bool>,
+    // Person explicitly set in the presence message of a device
+    device_people: HashMap<String, String>,
+    pending: HashMap<String, PendingChange>,
+    debounce: Debounce,
+    people: HashMap<String, bool>,
     current_overall_presence: bool,
 }
 
+impl State {
+    fn new(debounce: Debounce) -> Self {
+        Self {
+            devices: HashMap::new(),
+            device_people: HashMap::new(),
+            pending: HashMap::new(),
+            debounce,
+            people: HashMap::new(),
+            current_overall_presence: DEFAULT_PRESENCE,
+        }
+    }
+
+    fn debounce(
+        &mut self,
+        device_name: &str,
+        present: bool,
+        retain: bool,
+        now: Instant,
+    ) -> Reading {
+        let current = self.devices.get(device_name).copied();
+
+        // Retained messages and devices we have not seen before are applied immediately, this
+        // makes sure the state is bootstrapped without delay on startup
+        if retain || current.is_none() || current == Some(present) {
+            if let Some(pending) = self.pending.remove(device_name) {
+                trace!("Pending change of device [{device_name}] has been cancelled");
+                pending.cancel();
+            }
+
+            return if current == Some(present) {
+                Reading::Unchanged
+            } else {
+                Reading::Apply
+            };
+        }
+
+        let pending = self
+            .pending
+            .entry(device_name.into())
+            .or_insert_with(|| PendingChange::new(present, now));
+        pending.count += 1;
+
+        if pending.is_ready(&self.debounce, now) {
+            if let Some(pending) = self.pending.remove(device_name) {
+                pending.cancel();
+            }
+
+            return Reading::Apply;
+        }
+
+        Reading::Pending
+    }
+
+    // Applies the pending change of the device if it is ready, returns the new presence
+    fn apply_pending(&mut self, device_name: &str, now: Instant) -> Option<bool> {
+        // If not enough readings have been received yet, the next reading will apply the change
+        if !self
+            .pending
+            .get(device_name)
+            .is_some_and(|pending| pending.is_ready(&self.debounce, now))
+        {
+            return None;
+        }
+
+        // NOTE: This is called from inside the pending task, so we can not abort it here
+        let pending = self.pending.remove(device_name)?;
+        self.devices.insert(device_name.into(), pending.present);
+
+        Some(pending.present)
+    }
+
+    // The person is the first topic segment matched by the wildcard, e.g. with the topic
+    // 'presence/+/#' a message on 'presence/alice/phone' belongs to 'alice'
+    // The person can also be set explicitly by including it in the presence message
+    fn person<'a>(&'a self, device_name: &'a str) -> &'a str {
+        if let Some(person) = self.device_people.get(device_name) {
+            return person;
+        }
+
+        device_name.split('/').next().unwrap_or(device_name)
+    }
+
+    // Returns true if the person the device belongs to has changed
+    fn set_person(&mut self, device_name: &str, person: Option<String>) -> bool {
+        match person {
+            Some(person) => self
+                .device_people
+                .insert(device_name.into(), person.clone())
+                .is_none_or(|previous| previous != person),
+            // Without a person in the message, the device falls back to the topic again
+            None => self.device_people.remove(device_name).is_some(),
+        }
+    }
+
+    fn calculate_people(&self) -> HashMap<String, bool> {
+        let mut people: HashMap<String, bool> = HashMap::new();
+        for (device_name, present) in &self.devices {
+            *people.entry(self.person(device_name).into()).or_default() |= *present;
+        }
+
+        people
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Presence {
     config: Config,
@@ -47,9 +229,56 @@ impl Presence {
     async fn state_mut(&self) -> RwLockWriteGuard<State> {
         self.state.write().await
     }
+
+    pub async fn is_person_home(&self, person: &str) -> bool {
+        self.state()
+            .await
+            .people
+            .get(person)
+            .copied()
+            .unwrap_or(DEFAULT_PRESENCE)
+    }
+
+    pub async fn any_home(&self) -> bool {
+        self.state().await.people.values().any(|present| *present)
+    }
+
+    pub async fn get_presence(&self) -> HashMap<String, bool> {
+        self.state().await.people.clone()
+    }
+
+    pub async fn who_is_home(&self) -> Vec<String> {
+        let mut people: Vec<_> = self
+            .state()
+            .await
+            .people
+            .iter()
+            .filter(|(_, present)| **present)
+            .map(|(name, _)| name.clone())
+            .collect();
+        people.sort();
+
+        people
+    }
 }
 
-impl_device!(Presence);
+impl_device!(Presence, methods => {
+    methods.add_async_method("is_home", |_lua, this, name: String| async move {
+        Ok(this.is_person_home(&name).await)
+    });
+
+    methods.add_async_method("any_home", |_lua, this, ()| async move {
+        Ok(this.any_home().await)
+    });
+
+    methods.add_async_method("get_presence", |_lua, this, ()| async move {
+        Ok(this.get_presence().await)
+    });
+
+    methods.add_async_method("who_is_home", |_lua, this, ()| async move {
+        Ok(this.who_is_home().await)
+    });
+});
 
 #[async_trait]
 impl LuaDeviceCreate for Presence {
@@ -64,10 +293,7 @@ impl LuaDeviceCreate for Presence {
             .subscribe(&config.mqtt.topic, rumqttc::QoS::AtLeastOnce)
             .await?;
 
-        let state = State {
-            devices: HashMap::new(),
-            current_overall_presence: DEFAULT_PRESENCE,
-        };
+        let state = State::new(Debounce::from(&config));
         let state = Arc::new(RwLock::new(state));
 
         Ok(Self { config, state })
@@ -94,26 +320,123 @@ impl OnMqtt for Presence {
             .find('+')
             .or(self.config.mqtt.topic.find('#'))
             .expect("Presence::create fails if it does not contain wildcards");
-        let device_name = message.topic[offset..].into();
+        let device_name: String = message.topic[offset..].into();
 
         if message.payload.is_empty() {
             // Remove the device from the map
             debug!("State of device [{device_name}] has been removed");
-            self.state_mut().await.devices.remove(&device_name);
+            let mut state = self.state_mut().await;
+            state.devices.remove(&device_name);
+            state.device_people.remove(&device_name);
+            if let Some(pending) = state.pending.remove(&device_name) {
+                pending.cancel();
+            }
         } else {
-            let present = match PresenceMessage::try_from(message) {
-                Ok(state) => state.presence(),
+            let retain = message.retain;
+            let (present, person) = match PresenceMessage::try_from(message) {
+                Ok(state) => (state.presence(), state.person().map(str::to_owned)),
                 Err(err) => {
                     warn!("Failed to parse message: {err}");
                     return;
                 }
             };
 
-            debug!("State of device [{device_name}] has changed: {}", present);
-            self.state_mut().await.devices.insert(device_name, present);
+            let person_changed = self.state_mut().await.set_person(&device_name, person);
+
+            if self.debounce(&device_name, present, retain).await {
+                debug!("State of device [{device_name}] has changed: {}", present);
+                self.state_mut().await.devices.insert(device_name, present);
+            } else if !person_changed {
+                return;
+            }
         }
 
-        let overall_presence = self.state().await.devices.iter().any(|(_, v)| *v);
+        self.update_presence().await;
+    }
+}
+
+impl Presence {
+    // Returns true if the new reading should be applied immediately
+    async fn debounce(&self, device_name: &str, present: bool, retain: bool) -> bool {
+        let mut state = self.state_mut().await;
+        match state.debounce(device_name, present, retain, Instant::now()) {
+            Reading::Unchanged => false,
+            Reading::Apply => true,
+            Reading::Pending => {
+                if let Some(debounce) = self.config.debounce
+                    && let Some(pending) = state.pending.get_mut(device_name)
+                    && pending.handle.is_none()
+                {
+                    trace!("Debouncing change of device [{device_name}] for {debounce:?}");
+
+                    let device = self.clone();
+                    let device_name = device_name.to_owned();
+                    pending.handle = Some(tokio::spawn(async move {
+                        tokio::time::sleep(debounce).await;
+                        device.apply_pending(device_name).await;
+                    }));
+                }
+
+                false
+            }
+        }
+    }
+
+    async fn apply_pending(&self, device_name: String) {
+        let Some(present) = self
+            .state_mut()
+            .await
+            .apply_pending(&device_name, Instant::now())
+        else {
+            return;
+        };
+
+        debug!("State of device [{device_name}] has changed: {}", present);
+        self.update_presence().await;
+    }
+
+    async fn update_presence(&self) {
+        let changed: Vec<_> = {
+            let mut state = self.state_mut().await;
+            let people = state.calculate_people();
+
+            let mut changed: Vec<_> = people
+                .iter()
+                .filter(|(name, present)| {
+                    state.people.get(*name).copied().unwrap_or(DEFAULT_PRESENCE) != **present
+                })
+                .map(|(name, present)| PersonPresence {
+                    name: name.clone(),
+                    present: *present,
+                })
+                .collect();
+
+            // People that no longer have any devices are no longer present
+            changed.extend(
+                state
+                    .people
+                    .iter()
+                    .filter(|(name, present)| **present && !people.contains_key(*name))
+                    .map(|(name, _)| PersonPresence {
+                        name: name.clone(),
+                        present: false,
+                    }),
+            );
+
+            state.people = people;
+
+            changed
+        };
+
+        for person in changed {
+            debug!(
+                "Presence of [{}] has changed: {}",
+                person.name, person.present
+            );
+            self.config.person_callback.call(self, &person).await;
+        }
+
+        let overall_presence = self.any_home().await;
         if overall_presence != self.state().await.current_overall_presence {
             debug!("Overall presence updated: {overall_presence}");
             self.state_mut().await.current_overall_presence = overall_presence;
@@ -130,3 +453,130 @@ impl OnMqtt for Presence {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(count: usize, duration: Option<u64>) -> State {
+        State::new(Debounce {
+            count,
+            duration: duration.map(Duration::from_secs),
+        })
+    }
+
+    #[test]
+    fn person_from_message() {
+        let mut state = state(1, None);
+        state.devices.insert("alice/phone".into(), true);
+
+        assert!(state.set_person("alice/phone", Some("bob".into())));
+        assert!(!state.set_person("alice/phone", Some("bob".into())));
+        assert_eq!(
+            state.calculate_people(),
+            HashMap::from([("bob".into(), true)])
+        );
+
+        // Once the message no longer contains a person, the topic is used again
+        assert!(state.set_person("alice/phone", None));
+        assert!(!state.set_person("alice/phone", None));
+        assert_eq!(
+            state.calculate_people(),
+            HashMap::from([("alice".into(), true)])
+        );
+    }
+
+    #[test]
+    fn first_reading_applies_immediately() {
+        let now = Instant::now();
+        let mut state = state(3, Some(60));
+
+        assert_eq!(
+            state.debounce("alice/phone", true, false, now),
+            Reading::Apply
+        );
+    }
+
+    #[test]
+    fn retained_reading_applies_immediately() {
+        let now = Instant::now();
+        let mut state = state(3, Some(60));
+        state.devices.insert("alice/phone".into(), true);
+
+        assert_eq!(
+            state.debounce("alice/phone", false, true, now),
+            Reading::Apply
+        );
+        assert!(state.pending.is_empty());
+    }
+
+    #[test]
+    fn count_reached() {
+        let now = Instant::now();
+        let mut state = state(3, None);
+        state.devices.insert("alice/phone".into(), true);
+
+        assert_eq!(
+            state.debounce("alice/phone", false, false, now),
+            Reading::Pending
+        );
+        assert_eq!(
+            state.debounce("alice/phone", false, false, now),
+            Reading::Pending
+        );
+        assert_eq!(
+            state.debounce("alice/phone", false, false, now),
+            Reading::Apply
+        );
+        assert!(state.pending.is_empty());
+    }
+
+    #[test]
+    fn flap_then_revert() {
+        let now = Instant::now();
+        let mut state = state(2, None);
+        state.devices.insert("alice/phone".into(), true);
+
+        assert_eq!(
+            state.debounce("alice/phone", false, false, now),
+            Reading::Pending
+        );
+        // Reverting to the current state cancels the pending change
+        assert_eq!(
+            state.debounce("alice/phone", true, false, now),
+            Reading::Unchanged
+        );
+        assert!(state.pending.is_empty());
+
+        // So the count starts over
+        assert_eq!(
+            state.debounce("alice/phone", false, false, now),
+            Reading::Pending
+        );
+        assert_eq!(
+            state.debounce("alice/phone", false, false, now),
+            Reading::Apply
+        );
+    }
+
+    #[test]
+    fn duration_reached() {
+        let now = Instant::now();
+        let mut state = state(1, Some(60));
+        state.devices.insert("alice/phone".into(), true);
+
+        assert_eq!(
+            state.debounce("alice/phone", false, false, now),
+            Reading::Pending
+        );
+        assert_eq!(
+            state.apply_pending("alice/phone", now + Duration::from_secs(30)),
+            None
+        );
+        assert_eq!(
+            state.apply_pending("alice/phone", now + Duration::from_secs(60)),
+            Some(false)
+        );
+        assert_eq!(state.devices.get("alice/phone"), Some(&false));
+    }
+}
@@ -6,8 +6,8 @@ use syn::punctuated::Punctuated;
 use syn::spanned::Spanned;
 use syn::token::Paren;
 use syn::{
-    parenthesized, Data, DataStruct, DeriveInput, Expr, Field, Fields, FieldsNamed, LitStr, Result,
-    Token, Type,
+    parenthesized, Data, DataStruct, DeriveInput, Expr, Field, Fields, FieldsNamed,
+    GenericArgument, LitStr, PathArguments, Result, Token, Type,
 };
 
 mod kw {
@@ -17,9 +17,12 @@ mod kw {
     custom_keyword!(flatten);
     custom_keyword!(from_lua);
     custom_keyword!(rename);
+    custom_keyword!(alias);
     custom_keyword!(with);
     custom_keyword!(from);
     custom_keyword!(default);
+    custom_keyword!(secret);
+    custom_keyword!(env);
 }
 
 #[derive(Debug)]
@@ -35,6 +38,11 @@ enum Argument {
         _paren: Paren,
         ident: LitStr,
     },
+    Alias {
+        _keyword: kw::alias,
+        _paren: Paren,
+        ident: LitStr,
+    },
     With {
         _keyword: kw::with,
         _paren: Paren,
@@ -54,6 +62,14 @@ enum Argument {
         _paren: Paren,
         expr: Expr,
     },
+    Secret {
+        _keyword: kw::secret,
+    },
+    Env {
+        _keyword: kw::env,
+        _paren: Paren,
+        var_name: LitStr,
+    },
 }
 
 impl Parse for Argument {
@@ -74,6 +90,13 @@ impl Parse for Argument {
                 _paren: parenthesized!(content in input),
                 ident: content.parse()?,
             })
+        } else if lookahead.peek(kw::alias) {
+            let content;
+            Ok(Self::Alias {
+                _keyword: input.parse()?,
+                _paren: parenthesized!(content in input),
+                ident: content.parse()?,
+            })
         } else if lookahead.peek(kw::with) {
             let content;
             Ok(Self::With {
@@ -100,6 +123,17 @@ impl Parse for Argument {
             } else {
                 Ok(Self::Default { _keyword: keyword })
             }
+        } else if lookahead.peek(kw::secret) {
+            Ok(Self::Secret {
+                _keyword: input.parse()?,
+            })
+        } else if lookahead.peek(kw::env) {
+            let content;
+            Ok(Self::Env {
+                _keyword: input.parse()?,
+                _paren: parenthesized!(content in input),
+                var_name: content.parse()?,
+            })
         } else {
             Err(lookahead.error())
         }
@@ -119,7 +153,7 @@ impl Parse for Args {
     }
 }
 
-fn field_from_lua(field: &Field) -> TokenStream {
+fn field_from_lua(device_type: &str, field: &Field) -> TokenStream {
     let (args, errors): (Vec<_>, Vec<_>) = field
         .attrs
         .iter()
@@ -159,6 +193,23 @@ fn field_from_lua(field: &Field) -> TokenStream {
         }
     };
 
+    // Older names of the field that are still accepted, the actual name takes precedence
+    let aliases: Vec<_> = args
+        .iter()
+        .filter_map(|arg| match arg {
+            Argument::Alias { ident, .. } => Some(ident.value()),
+            _ => None,
+        })
+        .collect();
+
+    if !aliases.is_empty()
+        && args
+            .iter()
+            .any(|arg| matches!(arg, Argument::Flatten { .. }))
+    {
+        return quote_spanned! {field.span() => compile_error!("'alias' can not be combined with 'flatten'")};
+    }
+
     // TODO: Detect Option<_> properly and use Default::default() as fallback automatically
     let missing = format!("Missing field '{table_name}'");
     let default = match args
@@ -171,7 +222,7 @@ fn field_from_lua(field: &Field) -> TokenStream {
         .collect::<Vec<_>>()
         .as_slice()
     {
-        [] => quote! {panic!(#missing)},
+        [] => quote! { return Err(mlua::Error::runtime(#missing)) },
         [default] => default.to_owned(),
         _ => {
             return quote_spanned! {field.span() => compile_error!("Field contains duplicate 'default'")}
@@ -185,10 +236,20 @@ fn field_from_lua(field: &Field) -> TokenStream {
 				mlua::LuaSerdeExt::from_value_with(lua, value.clone(), mlua::DeserializeOptions::new().deny_unsupported_types(false))?
 			}),
 			Argument::FromLua { .. } => Some(quote! {
-				if table.contains_key(#table_name)? {
-					table.get(#table_name)?
-				} else {
-					#default
+				{
+					let key = #table_name;
+					#(
+						let key = if !table.contains_key(key)? && table.contains_key(#aliases)? {
+							#aliases
+						} else {
+							key
+						};
+					)*
+					if table.contains_key(key)? {
+						table.get(key)?
+					} else {
+						#default
+					}
 				}
 			}),
 			_ => None,
@@ -198,6 +259,13 @@ fn field_from_lua(field: &Field) -> TokenStream {
 		[] => quote! {
 			{
 				let value: mlua::Value = table.get(#table_name)?;
+				#(
+					let value = if value.is_nil() {
+						table.get(#aliases)?
+					} else {
+						value
+					};
+				)*
 				if !value.is_nil() {
 					mlua::LuaSerdeExt::from_value(lua, value)?
 				} else {
@@ -209,6 +277,40 @@ fn field_from_lua(field: &Field) -> TokenStream {
 		_ => return quote_spanned! {field.span() => compile_error!("Only one of either 'flatten' or 'from_lua' is allowed")},
 	};
 
+    // The environment variable takes precedence over the value in the table
+    let value = match args
+        .iter()
+        .filter_map(|arg| match arg {
+            Argument::Env { var_name, .. } => Some(var_name),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        [] => value,
+        [_] if args
+            .iter()
+            .any(|arg| matches!(arg, Argument::Flatten { .. })) =>
+        {
+            return quote_spanned! {field.span() => compile_error!("'env' can not be combined with 'flatten'")}
+        }
+        [var_name] => quote! {
+            if let Ok(val) = std::env::var(#var_name) {
+                val.parse().map_err(|err| {
+                    mlua::Error::runtime(format!(
+                        "Failed to parse environment variable '{}': {}",
+                        #var_name, err
+                    ))
+                })?
+            } else {
+                #value
+            }
+        },
+        _ => {
+            return quote_spanned! {field.span() => compile_error!("Field contains duplicate 'env'")}
+        }
+    };
+
     let value = match args
         .iter()
         .filter_map(|arg| match arg {
@@ -236,7 +338,59 @@ fn field_from_lua(field: &Field) -> TokenStream {
         }
     };
 
-    quote! { #value }
+    let value = match args
+        .iter()
+        .filter(|arg| matches!(arg, Argument::Secret { .. }))
+        .count()
+    {
+        0 => value,
+        1 => {
+            let Some(ty) = extract_type_from_secret(&field.ty) else {
+                return quote_spanned! {field.ty.span() => compile_error!("'secret' can only be used on fields of type 'Secret<T>'")};
+            };
+
+            quote! {
+                {
+                    let temp: #ty = #value;
+                    temp.into()
+                }
+            }
+        }
+        _ => {
+            return quote_spanned! {field.span() => compile_error!("Field contains duplicate 'secret'")}
+        }
+    };
+
+    // Errors are wrapped so they point at the field that could not be parsed
+    quote! {
+        ::automation_lib::error::ConfigContext::with_context(
+            (|| -> mlua::Result<_> {
+                let value = #value;
+                Ok(value)
+            })(),
+            #device_type,
+            #table_name,
+        )?
+    }
+}
+
+fn extract_type_from_secret(ty: &Type) -> Option<&Type> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+
+    let segment = path.path.segments.last()?;
+    if segment.ident != "Secret" {
+        return None;
+    }
+
+    match &segment.arguments {
+        PathArguments::AngleBracketed(params) => match params.args.first()? {
+            GenericArgument::Type(ty) => Some(ty),
+            _ => None,
+        },
+        _ => None,
+    }
 }
 
 pub fn impl_lua_device_config_macro(ast: &DeriveInput) -> TokenStream {
@@ -251,11 +405,12 @@ pub fn impl_lua_device_config_macro(ast: &DeriveInput) -> TokenStream {
         return quote_spanned! {ast.span() => compile_error!("This macro only works on named structs")};
     };
 
+    let device_type = name.to_string();
     let lua_fields: Vec<_> = fields
         .iter()
         .map(|field| {
             let name = field.ident.clone().unwrap();
-            let value = field_from_lua(field);
+            let value = field_from_lua(&device_type, field);
             quote! { #name: #value }
         })
         .collect();
@@ -44,6 +44,7 @@ automation.device_manager:add(DebugBridge.new({
 	identifier = "debug_bridge",
 	topic = mqtt_automation("debug"),
 	client = mqtt_client,
+	event_channel = automation.device_manager:event_channel(),
 }))
 
 local hue_ip = "10.0.0.102"
@@ -104,8 +105,8 @@ automation.device_manager:add(LightSensor.new({
 	identifier = "living_light_sensor",
 	topic = mqtt_z2m("living/light"),
 	client = mqtt_client,
-	min = 22000,
-	max = 23500,
+	threshold_low = 22000,
+	threshold_high = 23500,
 	event_channel = automation.device_manager:event_channel(),
 }))
 
@@ -23,6 +23,9 @@ pub trait Device: DeviceFulfillment {
     fn get_device_info(&self) -> Option<Info> {
         None
     }
+    fn get_custom_data(&self) -> Option<serde_json::Value> {
+        None
+    }
 
     async fn sync(&self) -> response::sync::Device {
         let name = self.get_device_name();
@@ -36,6 +39,7 @@ pub trait Device: DeviceFulfillment {
             device.room_hint = Some(room.into());
         }
         device.device_info = self.get_device_info();
+        device.custom_data = self.get_custom_data();
 
         // TODO: Return the appropriate error
         if let Ok((traits, attributes)) = DeviceFulfillment::sync(self).await {
@@ -62,12 +66,11 @@ pub trait Device: DeviceFulfillment {
 
     async fn execute(&self, command: Command) -> Result<(), ErrorCode> {
         // TODO: Do something with the return value, or just get rut of the return value?
-        if DeviceFulfillment::execute(self, command.clone())
-            .await
-            .is_err()
-        {
-            return Err(ErrorCode::DeviceError(
-                crate::errors::DeviceError::TransientError,
+        if let Err(err) = DeviceFulfillment::execute(self, command.clone()).await {
+            // Pass on the error reported by the device, anything else is treated as transient
+            return Err(err.downcast::<ErrorCode>().map_or(
+                ErrorCode::DeviceError(crate::errors::DeviceError::TransientError),
+                |err| *err,
             ));
         }
 
@@ -75,7 +78,7 @@ pub trait Device: DeviceFulfillment {
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Name {
     #[serde(skip_serializing_if = "Vec::is_empty")]
@@ -103,7 +106,7 @@ impl Name {
     }
 }
 
-#[derive(Debug, Default, Serialize)]
+#[derive(Debug, Default, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Info {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -114,7 +117,8 @@ pub struct Info {
     pub hw_version: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub sw_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial_number: Option<String>,
     // attributes
-    // customData
     // otherDeviceIds
 }
@@ -1,19 +1,78 @@
 use std::collections::HashMap;
+use std::num::NonZeroUsize;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use automation_cast::Cast;
 use futures::future::{join_all, OptionFuture};
+use lru::LruCache;
 use thiserror::Error;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, OnceCell};
 
 use crate::errors::{DeviceError, ErrorCode};
 use crate::request::{self, Intent, Request};
 use crate::response::{self, execute, query, sync, Response, ResponsePayload};
 use crate::Device;
 
+// How long a response is kept around to answer retries of the same request
+const RECENT_REQUEST_TTL: Duration = Duration::from_secs(5);
+pub const DEFAULT_REQUEST_CACHE_SIZE: usize = 32;
+
+// Names of the metrics that are recorded while handling requests
+pub const FULFILLMENT_REQUESTS: &str = "automation_fulfillment_requests_total";
+pub const FULFILLMENT_DUPLICATE_REQUESTS: &str = "automation_fulfillment_duplicate_requests_total";
+pub const FULFILLMENT_DURATION: &str = "automation_fulfillment_duration_seconds";
+
+// The response is set once the request is done, until then retries wait for it
+type RecentRequest = Arc<OnceCell<(Instant, Response)>>;
+type RecentRequests = LruCache<(String, String), RecentRequest>;
+
+// Google Home sometimes retries a request, keeping the response for a short while prevents
+// executing the same commands twice. Clones share the same cache.
+#[derive(Debug, Clone)]
+pub struct RecentRequestCache {
+    cache: Option<Arc<Mutex<RecentRequests>>>,
+}
+
+impl RecentRequestCache {
+    // A size of 0 disables the cache
+    pub fn new(size: usize) -> Self {
+        Self {
+            cache: NonZeroUsize::new(size).map(|size| Arc::new(Mutex::new(LruCache::new(size)))),
+        }
+    }
+
+    // Returns the entry of a request that is still running or finished recently, otherwise a new
+    // entry is inserted
+    async fn entry(&self, user_id: &str, request_id: &str) -> Option<RecentRequest> {
+        let mut cache = self.cache.as_ref()?.lock().await;
+        let key = (user_id.to_owned(), request_id.to_owned());
+
+        if let Some(entry) = cache.get(&key)
+            && entry
+                .get()
+                .is_none_or(|(finished, _)| finished.elapsed() < RECENT_REQUEST_TTL)
+        {
+            return Some(entry.clone());
+        }
+
+        let entry = RecentRequest::default();
+        cache.put(key, entry.clone());
+
+        Some(entry)
+    }
+}
+
+impl Default for RecentRequestCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_REQUEST_CACHE_SIZE)
+    }
+}
+
 #[derive(Debug)]
 pub struct GoogleHome {
     user_id: String,
+    recent_requests: RecentRequestCache,
     // Add credentials so we can notify google home of actions
 }
 
@@ -27,20 +86,67 @@ impl GoogleHome {
     pub fn new(user_id: &str) -> Self {
         Self {
             user_id: user_id.into(),
+            recent_requests: Default::default(),
         }
     }
 
+    // Share the cache between instances, e.g. when creating one for every request
+    pub fn with_recent_requests(mut self, recent_requests: RecentRequestCache) -> Self {
+        self.recent_requests = recent_requests;
+        self
+    }
+
     pub async fn handle_request<T: Cast<dyn Device> + ?Sized + 'static>(
         &self,
         request: Request,
         devices: &HashMap<String, Box<T>>,
+    ) -> Result<Response, FulfillmentError> {
+        let Some(entry) = self
+            .recent_requests
+            .entry(&self.user_id, &request.request_id)
+            .await
+        else {
+            return self.process(request, devices).await;
+        };
+
+        // Concurrent duplicates wait for the first request instead of executing it again
+        let mut processed = false;
+        let (_, response) = entry
+            .get_or_try_init(|| {
+                processed = true;
+                async {
+                    let response = self.process(request, devices).await?;
+                    Ok::<_, FulfillmentError>((Instant::now(), response))
+                }
+            })
+            .await?;
+
+        if !processed {
+            metrics::counter!(FULFILLMENT_DUPLICATE_REQUESTS).increment(1);
+        }
+
+        Ok(response.clone())
+    }
+
+    async fn process<T: Cast<dyn Device> + ?Sized + 'static>(
+        &self,
+        request: Request,
+        devices: &HashMap<String, Box<T>>,
     ) -> Result<Response, FulfillmentError> {
         // TODO: What do we do if we actually get more then one thing in the input array, right now
         // we only respond to the first thing
         let intent = request.inputs.into_iter().next();
 
+        let start = Instant::now();
         let payload: OptionFuture<_> = intent
             .map(|intent| async move {
+                let name = match intent {
+                    Intent::Sync => "sync",
+                    Intent::Query(_) => "query",
+                    Intent::Execute(_) => "execute",
+                };
+                metrics::counter!(FULFILLMENT_REQUESTS, "intent" => name).increment(1);
+
                 match intent {
                     Intent::Sync => ResponsePayload::Sync(self.sync(devices).await),
                     Intent::Query(payload) => {
@@ -53,8 +159,10 @@ impl GoogleHome {
             })
             .into();
 
+        let payload = payload.await;
+        metrics::histogram!(FULFILLMENT_DURATION).record(start.elapsed().as_secs_f64());
+
         payload
-            .await
             .ok_or(FulfillmentError::ExpectedOnePayload)
             .map(|payload| Response::new(&request.request_id, payload))
     }
@@ -150,16 +258,11 @@ impl GoogleHome {
                                     results.push(Device::execute(device, cmd.clone()).await);
                                 }
 
-                                // Convert vec of results to a result with a vec and the first
-                                // encountered error
-                                let results =
-                                    results.into_iter().collect::<Result<Vec<_>, ErrorCode>>();
-
-                                // TODO: We only get one error not all errors
-                                if let Err(err) = results {
-                                    (id, Err(err))
-                                } else {
-                                    (id, Ok(true))
+                                // A device can only be part of one command in the response, so
+                                // only the first error is reported
+                                match results.into_iter().find_map(Result::err) {
+                                    Some(err) => (id, Err(err)),
+                                    None => (id, Ok(true)),
                                 }
                             } else {
                                 (id.clone(), Err(DeviceError::DeviceNotFound.into()))
@@ -204,243 +307,587 @@ impl GoogleHome {
     }
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
-//     use crate::{
-//         device::{self, GoogleHomeDevice},
-//         errors::ErrorCode,
-//         request::Request,
-//         traits, types,
-//     };
-//
-//     #[derive(Debug)]
-//     struct TestOutlet {
-//         name: String,
-//         on: bool,
-//     }
-//
-//     impl TestOutlet {
-//         fn new(name: &str) -> Self {
-//             Self {
-//                 name: name.into(),
-//                 on: false,
-//             }
-//         }
-//     }
-//
-//     impl GoogleHomeDevice for TestOutlet {
-//         fn get_device_type(&self) -> types::Type {
-//             types::Type::Outlet
-//         }
-//
-//         fn get_device_name(&self) -> device::Name {
-//             let mut name = device::Name::new("Nightstand");
-//             name.add_default_name("Outlet");
-//             name.add_nickname("Nightlight");
-//
-//             name
-//         }
-//
-//         fn get_id(&self) -> &str {
-//             &self.name
-//         }
-//
-//         fn is_online(&self) -> bool {
-//             true
-//         }
-//
-//         fn get_room_hint(&self) -> Option<&str> {
-//             Some("Bedroom")
-//         }
-//
-//         fn get_device_info(&self) -> Option<device::Info> {
-//             Some(device::Info {
-//                 manufacturer: Some("Company".into()),
-//                 model: Some("Outlet II".into()),
-//                 hw_version: None,
-//                 sw_version: None,
-//             })
-//         }
-//     }
-//
-//     impl traits::OnOff for TestOutlet {
-//         fn is_on(&self) -> Result<bool, ErrorCode> {
-//             Ok(self.on)
-//         }
-//
-//         fn set_on(&mut self, on: bool) -> Result<(), ErrorCode> {
-//             self.on = on;
-//             Ok(())
-//         }
-//     }
-//
-//     #[derive(Debug)]
-//     struct TestScene;
-//
-//     impl TestScene {
-//         fn new() -> Self {
-//             Self {}
-//         }
-//     }
-//
-//     impl GoogleHomeDevice for TestScene {
-//         fn get_device_type(&self) -> types::Type {
-//             types::Type::Scene
-//         }
-//
-//         fn get_device_name(&self) -> device::Name {
-//             device::Name::new("Party")
-//         }
-//
-//         fn get_id(&self) -> &str {
-//             "living/party_mode"
-//         }
-//
-//         fn is_online(&self) -> bool {
-//             true
-//         }
-//
-//         fn get_room_hint(&self) -> Option<&str> {
-//             Some("Living room")
-//         }
-//     }
-//
-//     impl traits::Scene for TestScene {
-//         fn set_active(&self, _activate: bool) -> Result<(), ErrorCode> {
-//             println!("Activating the party scene");
-//             Ok(())
-//         }
-//     }
-//
-//     #[test]
-//     fn handle_sync() {
-//         let json = r#"{
-//   "requestId": "ff36a3cc-ec34-11e6-b1a0-64510650abcf",
-//   "inputs": [
-//     {
-//       "intent": "action.devices.SYNC"
-//     }
-//   ]
-// }"#;
-//         let req: Request = serde_json::from_str(json).unwrap();
-//
-//         let gh = GoogleHome {
-//             user_id: "Dreaded_X".into(),
-//         };
-//
-//         let mut nightstand = TestOutlet::new("bedroom/nightstand");
-//         let mut lamp = TestOutlet::new("living/lamp");
-//         let mut scene = TestScene::new();
-//         let mut devices: HashMap<&str, &mut dyn GoogleHomeDevice> = HashMap::new();
-//         let id = nightstand.get_id().into();
-//         devices.insert(&id, &mut nightstand);
-//         let id = lamp.get_id().into();
-//         devices.insert(&id, &mut lamp);
-//         let id = scene.get_id().into();
-//         devices.insert(&id, &mut scene);
-//
-//         let resp = gh.handle_request(req, &mut devices).unwrap();
-//
-//         let json = serde_json::to_string(&resp).unwrap();
-//         println!("{}", json)
-//     }
-//
-//     #[test]
-//     fn handle_query() {
-//         let json = r#"{
-//   "requestId": "ff36a3cc-ec34-11e6-b1a0-64510650abcf",
-//   "inputs": [
-//     {
-//       "intent": "action.devices.QUERY",
-//       "payload": {
-//         "devices": [
-//           {
-//             "id": "bedroom/nightstand"
-//           },
-//           {
-//             "id": "living/party_mode"
-//           }
-//         ]
-//       }
-//     }
-//   ]
-// }"#;
-//         let req: Request = serde_json::from_str(json).unwrap();
-//
-//         let gh = GoogleHome {
-//             user_id: "Dreaded_X".into(),
-//         };
-//
-//         let mut nightstand = TestOutlet::new("bedroom/nightstand");
-//         let mut lamp = TestOutlet::new("living/lamp");
-//         let mut scene = TestScene::new();
-//         let mut devices: HashMap<&str, &mut dyn GoogleHomeDevice> = HashMap::new();
-//         let id = nightstand.get_id().into();
-//         devices.insert(&id, &mut nightstand);
-//         let id = lamp.get_id().into();
-//         devices.insert(&id, &mut lamp);
-//         let id = scene.get_id().into();
-//         devices.insert(&id, &mut scene);
-//
-//         let resp = gh.handle_request(req, &mut devices).unwrap();
-//
-//         let json = serde_json::to_string(&resp).unwrap();
-//         println!("{}", json)
-//     }
-//
-//     #[test]
-//     fn handle_execute() {
-//         let json = r#"{
-//   "requestId": "ff36a3cc-ec34-11e6-b1a0-64510650abcf",
-//   "inputs": [
-//     {
-//       "intent": "action.devices.EXECUTE",
-//       "payload": {
-//         "commands": [
-//           {
-//             "devices": [
-//               {
-//                 "id": "bedroom/nightstand"
-//               },
-//               {
-//                 "id": "living/lamp"
-//               }
-//             ],
-//             "execution": [
-//               {
-//                 "command": "action.devices.commands.OnOff",
-//                 "params": {
-//                   "on": true
-//                 }
-//               }
-//             ]
-//           }
-//         ]
-//       }
-//     }
-//   ]
-// }"#;
-//         let req: Request = serde_json::from_str(json).unwrap();
-//
-//         let gh = GoogleHome {
-//             user_id: "Dreaded_X".into(),
-//         };
-//
-//         let mut nightstand = TestOutlet::new("bedroom/nightstand");
-//         let mut lamp = TestOutlet::new("living/lamp");
-//         let mut scene = TestScene::new();
-//         let mut devices: HashMap<&str, &mut dyn GoogleHomeDevice> = HashMap::new();
-//         let id = nightstand.get_id().into();
-//         devices.insert(&id, &mut nightstand);
-//         let id = lamp.get_id().into();
-//         devices.insert(&id, &mut lamp);
-//         let id = scene.get_id().into();
-//         devices.insert(&id, &mut scene);
-//
-//         let resp = gh.handle_request(req, &mut devices).unwrap();
-//
-//         let json = serde_json::to_string(&resp).unwrap();
-//         println!("{}", json)
-//     }
-// }
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use async_trait::async_trait;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::device::{Info, Name};
+    use crate::traits::{AvailableSpeeds, FanSpeed, OnOff, Scene, Speed, SpeedValue};
+    use crate::types::Type;
+
+    struct TestOutlet {
+        id: String,
+        result: Result<(), ErrorCode>,
+    }
+
+    #[async_trait]
+    impl Device for TestOutlet {
+        fn get_device_type(&self) -> Type {
+            Type::Outlet
+        }
+
+        fn get_device_name(&self) -> Name {
+            Name::new(&self.id)
+        }
+
+        fn get_id(&self) -> String {
+            self.id.clone()
+        }
+
+        async fn is_online(&self) -> bool {
+            true
+        }
+    }
+
+    #[async_trait]
+    impl OnOff for TestOutlet {
+        async fn on(&self) -> Result<bool, ErrorCode> {
+            Ok(false)
+        }
+
+        async fn set_on(&self, _on: bool) -> Result<(), ErrorCode> {
+            self.result
+        }
+    }
+
+    #[tokio::test]
+    async fn execute_multiple_errors() {
+        let devices: HashMap<String, Box<TestOutlet>> = [
+            ("offline", Err(DeviceError::DeviceOffline.into())),
+            ("not_available", Err(DeviceError::ActionNotAvailable.into())),
+            ("success", Ok(())),
+        ]
+        .into_iter()
+        .map(|(id, result)| {
+            let device = TestOutlet {
+                id: id.into(),
+                result,
+            };
+            (id.to_string(), Box::new(device))
+        })
+        .collect();
+
+        let request: Request = serde_json::from_value(json!({
+            "requestId": "ff36a3cc-ec34-11e6-b1a0-64510650abcf",
+            "inputs": [{
+                "intent": "action.devices.EXECUTE",
+                "payload": {
+                    "commands": [{
+                        "devices": [{ "id": "offline" }, { "id": "not_available" }, { "id": "success" }],
+                        "execution": [{
+                            "command": "action.devices.commands.OnOff",
+                            "params": { "on": true }
+                        }]
+                    }]
+                }
+            }]
+        }))
+        .unwrap();
+
+        let response = GoogleHome::new("user")
+            .handle_request(request, &devices)
+            .await
+            .unwrap();
+        let response = serde_json::to_value(response).unwrap();
+
+        let mut commands = response["payload"]["commands"].as_array().unwrap().clone();
+        commands.sort_by_key(|command| command["ids"][0].as_str().unwrap().to_owned());
+
+        assert_eq!(
+            commands,
+            vec![
+                json!({ "ids": ["not_available"], "status": "ERROR", "errorCode": "actionNotAvailable" }),
+                json!({ "ids": ["offline"], "status": "ERROR", "errorCode": "deviceOffline" }),
+                json!({ "ids": ["success"], "status": "SUCCESS", "states": { "online": true } }),
+            ]
+        );
+    }
+
+    // Fails differently depending on the requested state
+    struct FailingOutlet {
+        id: String,
+    }
+
+    #[async_trait]
+    impl Device for FailingOutlet {
+        fn get_device_type(&self) -> Type {
+            Type::Outlet
+        }
+
+        fn get_device_name(&self) -> Name {
+            Name::new(&self.id)
+        }
+
+        fn get_id(&self) -> String {
+            self.id.clone()
+        }
+
+        async fn is_online(&self) -> bool {
+            true
+        }
+    }
+
+    #[async_trait]
+    impl OnOff for FailingOutlet {
+        async fn on(&self) -> Result<bool, ErrorCode> {
+            Ok(false)
+        }
+
+        async fn set_on(&self, on: bool) -> Result<(), ErrorCode> {
+            if on {
+                Err(DeviceError::DeviceOffline.into())
+            } else {
+                Err(DeviceError::ActionNotAvailable.into())
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn execute_error_per_device() {
+        let devices: HashMap<String, Box<FailingOutlet>> = ["outlet_1", "outlet_2"]
+            .into_iter()
+            .map(|id| (id.to_string(), Box::new(FailingOutlet { id: id.into() })))
+            .collect();
+
+        let request: Request = serde_json::from_value(json!({
+            "requestId": "ff36a3cc-ec34-11e6-b1a0-64510650abcf",
+            "inputs": [{
+                "intent": "action.devices.EXECUTE",
+                "payload": {
+                    "commands": [{
+                        "devices": [{ "id": "outlet_1" }, { "id": "outlet_2" }],
+                        "execution": [{
+                            "command": "action.devices.commands.OnOff",
+                            "params": { "on": true }
+                        }, {
+                            "command": "action.devices.commands.OnOff",
+                            "params": { "on": false }
+                        }]
+                    }]
+                }
+            }]
+        }))
+        .unwrap();
+
+        let response = GoogleHome::new("user")
+            .handle_request(request, &devices)
+            .await
+            .unwrap();
+        let response = serde_json::to_value(response).unwrap();
+
+        let mut commands = response["payload"]["commands"].as_array().unwrap().clone();
+        commands[0]["ids"]
+            .as_array_mut()
+            .unwrap()
+            .sort_by_key(|id| id.to_string());
+
+        // Each device only shows up once, under the error of its first command
+        assert_eq!(
+            commands,
+            vec![json!({
+                "ids": ["outlet_1", "outlet_2"],
+                "status": "ERROR",
+                "errorCode": "deviceOffline"
+            })]
+        );
+    }
+
+    struct QueryCounter {
+        queries: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Device for QueryCounter {
+        fn get_device_type(&self) -> Type {
+            Type::Outlet
+        }
+
+        fn get_device_name(&self) -> Name {
+            Name::new("counter")
+        }
+
+        fn get_id(&self) -> String {
+            "counter".into()
+        }
+
+        async fn is_online(&self) -> bool {
+            true
+        }
+    }
+
+    #[async_trait]
+    impl OnOff for QueryCounter {
+        async fn on(&self) -> Result<bool, ErrorCode> {
+            self.queries.fetch_add(1, Ordering::Relaxed);
+            // Slow enough for a retry to arrive while the query is still running
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok(true)
+        }
+
+        async fn set_on(&self, _on: bool) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+    }
+
+    fn counter(queries: &Arc<AtomicUsize>) -> HashMap<String, Box<QueryCounter>> {
+        HashMap::from([(
+            "counter".to_owned(),
+            Box::new(QueryCounter {
+                queries: queries.clone(),
+            }),
+        )])
+    }
+
+    fn request(id: &str) -> Request {
+        serde_json::from_value(json!({
+            "requestId": id,
+            "inputs": [{
+                "intent": "action.devices.QUERY",
+                "payload": {
+                    "devices": [{ "id": "counter" }]
+                }
+            }]
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn duplicate_request() {
+        let queries = Arc::new(AtomicUsize::new(0));
+        let devices = counter(&queries);
+
+        let google_home = GoogleHome::new("user");
+        let first = google_home
+            .handle_request(request("1"), &devices)
+            .await
+            .unwrap();
+        let second = google_home
+            .handle_request(request("1"), &devices)
+            .await
+            .unwrap();
+        assert_eq!(queries.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            serde_json::to_value(first).unwrap(),
+            serde_json::to_value(second).unwrap()
+        );
+
+        // The cache is shared with other instances, but only used for the same request
+        GoogleHome::new("user")
+            .with_recent_requests(google_home.recent_requests.clone())
+            .handle_request(request("2"), &devices)
+            .await
+            .unwrap();
+        assert_eq!(queries.load(Ordering::Relaxed), 2);
+
+        let uncached = GoogleHome::new("user").with_recent_requests(RecentRequestCache::new(0));
+        uncached
+            .handle_request(request("3"), &devices)
+            .await
+            .unwrap();
+        uncached
+            .handle_request(request("3"), &devices)
+            .await
+            .unwrap();
+        assert_eq!(queries.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn concurrent_duplicate_request() {
+        let queries = Arc::new(AtomicUsize::new(0));
+        let devices = counter(&queries);
+
+        // The retry arrives while the first request is still running
+        let google_home = GoogleHome::new("user");
+        let (first, second) = tokio::join!(
+            google_home.handle_request(request("1"), &devices),
+            google_home.handle_request(request("1"), &devices)
+        );
+        assert_eq!(queries.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            serde_json::to_value(first.unwrap()).unwrap(),
+            serde_json::to_value(second.unwrap()).unwrap()
+        );
+    }
+
+    struct Nightstand {
+        id: String,
+        on: AtomicBool,
+    }
+
+    impl Nightstand {
+        fn new(id: &str) -> Self {
+            Self {
+                id: id.into(),
+                on: AtomicBool::new(false),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Device for Nightstand {
+        fn get_device_type(&self) -> Type {
+            Type::Outlet
+        }
+
+        fn get_device_name(&self) -> Name {
+            let mut name = Name::new("Nightstand");
+            name.add_default_name("Outlet");
+            name.add_nickname("Nightlight");
+
+            name
+        }
+
+        fn get_id(&self) -> String {
+            self.id.clone()
+        }
+
+        async fn is_online(&self) -> bool {
+            true
+        }
+
+        fn get_room_hint(&self) -> Option<&str> {
+            Some("Bedroom")
+        }
+
+        fn get_device_info(&self) -> Option<Info> {
+            Some(Info {
+                manufacturer: Some("Company".into()),
+                model: Some("Outlet II".into()),
+                hw_version: None,
+                sw_version: None,
+                serial_number: None,
+            })
+        }
+    }
+
+    #[async_trait]
+    impl OnOff for Nightstand {
+        async fn on(&self) -> Result<bool, ErrorCode> {
+            Ok(self.on.load(Ordering::Relaxed))
+        }
+
+        async fn set_on(&self, on: bool) -> Result<(), ErrorCode> {
+            self.on.store(on, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    struct PartyScene;
+
+    #[async_trait]
+    impl Device for PartyScene {
+        fn get_device_type(&self) -> Type {
+            Type::Scene
+        }
+
+        fn get_device_name(&self) -> Name {
+            Name::new("Party")
+        }
+
+        fn get_id(&self) -> String {
+            "living/party_mode".into()
+        }
+
+        async fn is_online(&self) -> bool {
+            true
+        }
+
+        fn get_room_hint(&self) -> Option<&str> {
+            Some("Living room")
+        }
+    }
+
+    #[async_trait]
+    impl Scene for PartyScene {
+        async fn set_active(&self, _deactivate: bool) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+    }
+
+    struct AirPurifier {
+        speed: std::sync::Mutex<String>,
+    }
+
+    #[async_trait]
+    impl Device for AirPurifier {
+        fn get_device_type(&self) -> Type {
+            Type::AirPurifier
+        }
+
+        fn get_device_name(&self) -> Name {
+            Name::new("Air purifier")
+        }
+
+        fn get_id(&self) -> String {
+            "bedroom/air_purifier".into()
+        }
+
+        async fn is_online(&self) -> bool {
+            true
+        }
+
+        fn get_room_hint(&self) -> Option<&str> {
+            Some("Bedroom")
+        }
+    }
+
+    #[async_trait]
+    impl FanSpeed for AirPurifier {
+        fn available_fan_speeds(&self) -> AvailableSpeeds {
+            AvailableSpeeds {
+                speeds: ["low", "high"]
+                    .into_iter()
+                    .map(|speed| Speed {
+                        speed_name: speed.into(),
+                        speed_values: vec![SpeedValue {
+                            speed_synonym: vec![speed.into()],
+                            lang: "en".into(),
+                        }],
+                    })
+                    .collect(),
+                ordered: true,
+            }
+        }
+
+        async fn current_fan_speed_setting(&self) -> Result<String, ErrorCode> {
+            Ok(self.speed.lock().unwrap().clone())
+        }
+
+        async fn set_fan_speed(&self, fan_speed: String) -> Result<(), ErrorCode> {
+            *self.speed.lock().unwrap() = fan_speed;
+            Ok(())
+        }
+    }
+
+    trait FixtureDevice: Cast<dyn Device> + Cast<dyn OnOff> + Cast<dyn FanSpeed> {}
+    impl<T> FixtureDevice for T where T: Cast<dyn Device> + Cast<dyn OnOff> + Cast<dyn FanSpeed> {}
+
+    fn fixture_devices() -> HashMap<String, Box<dyn FixtureDevice>> {
+        let devices: [Box<dyn FixtureDevice>; 4] = [
+            Box::new(Nightstand::new("bedroom/nightstand")),
+            Box::new(Nightstand::new("living/lamp")),
+            Box::new(PartyScene),
+            Box::new(AirPurifier {
+                speed: std::sync::Mutex::new("low".into()),
+            }),
+        ];
+
+        devices
+            .into_iter()
+            .map(|device| {
+                let id = Cast::<dyn Device>::cast(device.as_ref()).unwrap().get_id();
+                (id, device)
+            })
+            .collect()
+    }
+
+    fn fixture_path(name: &str) -> PathBuf {
+        [env!("CARGO_MANIFEST_DIR"), "tests", "fixtures", name]
+            .iter()
+            .collect()
+    }
+
+    fn read_fixture(name: &str) -> Value {
+        let path = fixture_path(name);
+        let fixture = std::fs::read_to_string(&path)
+            .unwrap_or_else(|err| panic!("Failed to read {}: {err}", path.display()));
+
+        serde_json::from_str(&fixture).unwrap()
+    }
+
+    // Devices and commands are collected from a HashMap, so the order of arrays is not stable
+    fn normalize(value: Value) -> Value {
+        match value {
+            Value::Array(values) => {
+                let mut values: Vec<_> = values.into_iter().map(normalize).collect();
+                values.sort_by_cached_key(Value::to_string);
+                Value::Array(values)
+            }
+            Value::Object(map) => Value::Object(
+                map.into_iter()
+                    .map(|(key, value)| (key, normalize(value)))
+                    .collect(),
+            ),
+            value => value,
+        }
+    }
+
+    // Run with UPDATE_FIXTURES=1 to write the actual responses to the fixtures instead
+    async fn assert_fixture(
+        request: &str,
+        response: &str,
+        devices: &HashMap<String, Box<dyn FixtureDevice>>,
+    ) {
+        let request: Request = serde_json::from_value(read_fixture(request)).unwrap();
+        let actual = GoogleHome::new("Dreaded_X")
+            .handle_request(request, devices)
+            .await
+            .unwrap();
+        let actual = normalize(serde_json::to_value(actual).unwrap());
+
+        if std::env::var("UPDATE_FIXTURES").is_ok_and(|update| update == "1") {
+            let fixture = serde_json::to_string_pretty(&actual).unwrap() + "\n";
+            std::fs::write(fixture_path(response), fixture).unwrap();
+            return;
+        }
+
+        assert_eq!(actual, normalize(read_fixture(response)));
+    }
+
+    #[tokio::test]
+    async fn handle_sync() {
+        assert_fixture(
+            "sync_request.json",
+            "sync_response.json",
+            &fixture_devices(),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn handle_query() {
+        let devices = fixture_devices();
+        let nightstand = Cast::<dyn OnOff>::cast(devices["bedroom/nightstand"].as_ref()).unwrap();
+        nightstand.set_on(true).await.unwrap();
+
+        assert_fixture("query_request.json", "query_response.json", &devices).await;
+    }
+
+    #[tokio::test]
+    async fn handle_execute_on_off() {
+        let devices = fixture_devices();
+
+        assert_fixture(
+            "execute_on_off_request.json",
+            "execute_on_off_response.json",
+            &devices,
+        )
+        .await;
+
+        for id in ["bedroom/nightstand", "living/lamp"] {
+            let outlet = Cast::<dyn OnOff>::cast(devices[id].as_ref()).unwrap();
+            assert!(outlet.on().await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn handle_execute_set_fan_speed() {
+        let devices = fixture_devices();
+
+        assert_fixture(
+            "execute_set_fan_speed_request.json",
+            "execute_set_fan_speed_response.json",
+            &devices,
+        )
+        .await;
+
+        let purifier =
+            Cast::<dyn FanSpeed>::cast(devices["bedroom/air_purifier"].as_ref()).unwrap();
+        assert_eq!(purifier.current_fan_speed_setting().await.unwrap(), "high");
+    }
+}
@@ -12,6 +12,9 @@ pub mod traits;
 pub mod types;
 
 pub use device::Device;
-pub use fulfillment::{FulfillmentError, GoogleHome};
+pub use fulfillment::{
+    FulfillmentError, GoogleHome, RecentRequestCache, DEFAULT_REQUEST_CACHE_SIZE,
+    FULFILLMENT_DUPLICATE_REQUESTS, FULFILLMENT_DURATION, FULFILLMENT_REQUESTS,
+};
 pub use request::Request;
 pub use response::Response;
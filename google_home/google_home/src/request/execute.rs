@@ -72,6 +72,91 @@ mod tests {
         };
     }
 
+    #[test]
+    fn deserialize_color_absolute() {
+        let req = json!({
+          "requestId": "ff36a3cc-ec34-11e6-b1a0-64510650abcf",
+          "inputs": [
+            {
+              "intent": "action.devices.EXECUTE",
+              "payload": {
+                "commands": [
+                  {
+                    "devices": [],
+                    "execution": [
+                      {
+                        "command": "action.devices.commands.ColorAbsolute",
+                        "params": {
+                          "color": {
+                            "name": "magenta",
+                            "spectrumRGB": 16711935
+                          }
+                        }
+                      }
+                    ]
+                  }
+                ]
+              }
+            }
+          ]
+        });
+
+        let req: Request = serde_json::from_value(req).unwrap();
+
+        match &req.inputs[0] {
+            Intent::Execute(payload) => match &payload.commands[0].execution[0] {
+                traits::Command::ColorAbsolute { color } => assert_eq!(
+                    color,
+                    &traits::Color::Rgb(traits::ColorRGB {
+                        r: 255,
+                        g: 0,
+                        b: 255
+                    })
+                ),
+                _ => panic!("Expected ColorAbsolute"),
+            },
+            _ => panic!("Expected Execute intent"),
+        };
+    }
+
+    #[test]
+    fn deserialize_reverse() {
+        let req = json!({
+          "requestId": "ff36a3cc-ec34-11e6-b1a0-64510650abcf",
+          "inputs": [
+            {
+              "intent": "action.devices.EXECUTE",
+              "payload": {
+                "commands": [
+                  {
+                    "devices": [],
+                    "execution": [
+                      {
+                        "command": "action.devices.commands.Reverse"
+                      }
+                    ]
+                  }
+                ]
+              }
+            }
+          ]
+        });
+
+        let req: Request = serde_json::from_value(req).unwrap();
+
+        assert_eq!(req.inputs.len(), 1);
+        match &req.inputs[0] {
+            Intent::Execute(payload) => {
+                assert_eq!(payload.commands[0].execution.len(), 1);
+                match &payload.commands[0].execution[0] {
+                    traits::Command::Reverse => {}
+                    _ => panic!("Expected Reverse"),
+                }
+            }
+            _ => panic!("Expected Execute intent"),
+        };
+    }
+
     #[test]
     fn deserialize() {
         let req = json!({
@@ -4,7 +4,7 @@ pub mod sync;
 
 use serde::Serialize;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Response {
     request_id: String,
@@ -20,7 +20,7 @@ impl Response {
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 #[serde(untagged)]
 pub enum ResponsePayload {
     Sync(sync::Payload),
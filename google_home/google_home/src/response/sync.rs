@@ -5,7 +5,7 @@ use crate::errors::ErrorCode;
 use crate::traits::Trait;
 use crate::types::Type;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Payload {
     agent_user_id: String,
@@ -31,7 +31,7 @@ impl Payload {
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Device {
     id: String,
@@ -48,6 +48,8 @@ pub struct Device {
     pub device_info: Option<device::Info>,
     #[serde(skip_serializing_if = "serde_json::Value::is_null")]
     pub attributes: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub custom_data: Option<serde_json::Value>,
 }
 
 impl Device {
@@ -62,6 +64,7 @@ impl Device {
             room_hint: None,
             device_info: None,
             attributes: Default::default(),
+            custom_data: None,
         }
     }
 }
@@ -90,6 +93,7 @@ mod tests {
             model: Some("hs1234".into()),
             hw_version: Some("3.2".into()),
             sw_version: Some("11.4".into()),
+            serial_number: None,
         });
 
         sync_resp.add_device(device);
@@ -130,4 +134,101 @@ mod tests {
 
         assert_eq!(resp, resp_expected);
     }
+
+    #[test]
+    fn serialize_serial_number() {
+        let mut sync_resp = Payload::new("1836.15267389");
+
+        let mut device = Device::new("123", "Night light", Type::Light);
+        device.traits.push(Trait::OnOff);
+        device.device_info = Some(device::Info {
+            manufacturer: Some("IKEA of Sweden".into()),
+            model: Some("LED1836G9".into()),
+            sw_version: Some("2.3.093".into()),
+            serial_number: Some("0x90fd9ffffe6494fc".into()),
+            ..Default::default()
+        });
+
+        sync_resp.add_device(device);
+
+        let resp = Response::new(
+            "ff36a3cc-ec34-11e6-b1a0-64510650abcf",
+            ResponsePayload::Sync(sync_resp),
+        );
+
+        let resp = serde_json::to_value(resp).unwrap();
+
+        let resp_expected = json!({
+            "requestId": "ff36a3cc-ec34-11e6-b1a0-64510650abcf",
+            "payload": {
+                "agentUserId": "1836.15267389",
+                "devices": [
+                    {
+                        "id": "123",
+                        "type": "action.devices.types.LIGHT",
+                        "traits": ["action.devices.traits.OnOff"],
+                        "name": {
+                            "name": "Night light"
+                        },
+                        "willReportState": false,
+                        "deviceInfo": {
+                            "manufacturer": "IKEA of Sweden",
+                            "model": "LED1836G9",
+                            "swVersion": "2.3.093",
+                            "serialNumber": "0x90fd9ffffe6494fc"
+                        }
+                    }
+                ]
+            }
+        });
+
+        assert_eq!(resp, resp_expected);
+    }
+
+    #[test]
+    fn serialize_custom_data() {
+        let mut sync_resp = Payload::new("1836.15267389");
+
+        let mut device = Device::new("123", "Night light", Type::Kettle);
+        device.traits.push(Trait::OnOff);
+        device.custom_data = Some(json!({
+            "fooValue": 74,
+            "barValue": true,
+            "bazValue": "foo"
+        }));
+
+        sync_resp.add_device(device);
+
+        let resp = Response::new(
+            "ff36a3cc-ec34-11e6-b1a0-64510650abcf",
+            ResponsePayload::Sync(sync_resp),
+        );
+
+        let resp = serde_json::to_value(resp).unwrap();
+
+        let resp_expected = json!({
+            "requestId": "ff36a3cc-ec34-11e6-b1a0-64510650abcf",
+            "payload": {
+                "agentUserId": "1836.15267389",
+                "devices": [
+                    {
+                        "id": "123",
+                        "type": "action.devices.types.KETTLE",
+                        "traits": ["action.devices.traits.OnOff"],
+                        "name": {
+                            "name": "Night light"
+                        },
+                        "willReportState": false,
+                        "customData": {
+                            "fooValue": 74,
+                            "barValue": true,
+                            "bazValue": "foo"
+                        }
+                    }
+                ]
+            }
+        });
+
+        assert_eq!(resp, resp_expected);
+    }
 }
@@ -1,9 +1,11 @@
 #![allow(non_snake_case)]
+use std::collections::HashMap;
+
 use automation_cast::Cast;
 use google_home_macro::traits;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-use crate::errors::ErrorCode;
+use crate::errors::{DeviceError, ErrorCode};
 use crate::Device;
 
 traits! {
@@ -24,7 +26,26 @@ traits! {
     "action.devices.traits.Brightness" => trait Brightness {
         command_only_brightness: Option<bool>,
         async fn brightness(&self) -> Result<u8, ErrorCode>,
+        helper fn supports_brightness_relative(&self) -> bool {
+            true
+        },
+
         "action.devices.commands.BrightnessAbsolute" => async fn set_brightness(&self, brightness: u8) -> Result<(), ErrorCode>,
+        "action.devices.commands.BrightnessRelative" => async fn set_brightness_relative(&self, brightness_relative_percent: i8) -> Result<(), ErrorCode> {
+            if !self.supports_brightness_relative() {
+                return Err(DeviceError::ActionNotAvailable.into());
+            }
+
+            let brightness = (self.brightness().await? as i16 + brightness_relative_percent as i16).clamp(0, 100);
+            self.set_brightness(brightness as u8).await
+        },
+    },
+    "action.devices.traits.ColorSetting" => trait ColorSetting {
+        command_only_color_setting: Option<bool>,
+        color_model: Option<ColorModel>,
+        color_temperature_range: Option<ColorTemperatureRange>,
+        async fn color(&self) -> Result<Color, ErrorCode>,
+        "action.devices.commands.ColorAbsolute" => async fn set_color(&self, color: Color) -> Result<(), ErrorCode>,
     },
     "action.devices.traits.Scene" => trait Scene {
         scene_reversible: Option<bool>,
@@ -37,15 +58,35 @@ traits! {
         available_fan_speeds: AvailableSpeeds,
 
         async fn current_fan_speed_setting(&self) -> Result<String, ErrorCode>,
+        helper async fn current_fan_speed_direction(&self) -> Result<String, ErrorCode> {
+            Err(DeviceError::ActionNotAvailable.into())
+        },
+        helper async fn set_fan_speed_direction(&self, _direction: String) -> Result<(), ErrorCode> {
+            Err(DeviceError::ActionNotAvailable.into())
+        },
 
-        // TODO: Figure out some syntax for optional command?
-        // Probably better to just force the user to always implement commands?
         "action.devices.commands.SetFanSpeed" => async fn set_fan_speed(&self, fan_speed: String) -> Result<(), ErrorCode>,
+        "action.devices.commands.Reverse" => async fn reverse(&self) -> Result<(), ErrorCode> {
+            let direction = match self.current_fan_speed_direction().await?.as_str() {
+                "REVERSE" => "FORWARD",
+                _ => "REVERSE",
+            };
+
+            self.set_fan_speed_direction(direction.into()).await
+        },
     },
     "action.devices.traits.HumiditySetting" => trait HumiditySetting {
         query_only_humidity_setting: Option<bool>,
+        humidity_setpoint_range: Option<HumiditySetpointRange>,
 
         async fn humidity_ambient_percent(&self) -> Result<isize, ErrorCode>,
+        async fn humidity_setpoint_percent(&self) -> Result<Option<isize>, ErrorCode>,
+
+        "action.devices.commands.SetHumidity" => async fn set_humidity_setpoint(&self, humidity: isize) -> Result<(), ErrorCode> {
+            // Devices that only report the humidity can not change it
+            let _ = humidity;
+            Err(DeviceError::ActionNotAvailable.into())
+        },
     },
     "action.devices.traits.TemperatureControl" => trait TemperatureSetting {
         query_only_temperature_control: Option<bool>,
@@ -53,6 +94,48 @@ traits! {
         temperatureUnitForUX: TemperatureUnit,
 
         async fn temperature_ambient_celsius(&self) -> Result<f32, ErrorCode>,
+    },
+    "action.devices.traits.OccupancySensing" => trait OccupancySensing {
+        occupancy_sensor_configuration: Vec<OccupancySensorConfiguration>,
+
+        async fn occupancy(&self) -> Result<Occupancy, ErrorCode>,
+    },
+    "action.devices.traits.SensorState" => trait SensorState {
+        sensor_states_supported: Vec<SupportedSensorState>,
+
+        async fn current_sensor_state_data(&self) -> Result<Vec<CurrentSensorState>, ErrorCode>,
+    },
+    "action.devices.traits.Toggles" => trait Toggles {
+        command_only_toggles: Option<bool>,
+        query_only_toggles: Option<bool>,
+        available_toggles: Vec<Toggle>,
+
+        async fn current_toggle_settings(&self) -> Result<HashMap<String, bool>, ErrorCode>,
+        helper async fn set_toggle(&self, toggle: String, enable: bool) -> Result<(), ErrorCode>,
+
+        "action.devices.commands.SetToggles" => async fn set_toggles(&self, update_toggle_settings: HashMap<String, bool>) -> Result<(), ErrorCode> {
+            for (toggle, enable) in update_toggle_settings {
+                self.set_toggle(toggle, enable).await?;
+            }
+
+            Ok(())
+        },
+    },
+    "action.devices.traits.Modes" => trait Modes {
+        command_only_modes: Option<bool>,
+        query_only_modes: Option<bool>,
+        available_modes: Vec<Mode>,
+
+        async fn current_mode_settings(&self) -> Result<HashMap<String, String>, ErrorCode>,
+        helper async fn set_mode(&self, mode: String, setting: String) -> Result<(), ErrorCode>,
+
+        "action.devices.commands.SetModes" => async fn set_modes(&self, update_mode_settings: HashMap<String, String>) -> Result<(), ErrorCode> {
+            for (mode, setting) in update_mode_settings {
+                self.set_mode(mode, setting).await?;
+            }
+
+            Ok(())
+        },
     }
 }
 
@@ -74,6 +157,44 @@ pub struct AvailableSpeeds {
     pub ordered: bool,
 }
 
+#[derive(Debug, Serialize)]
+pub struct ToggleValue {
+    pub name_synonym: Vec<String>,
+    pub lang: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Toggle {
+    pub name: String,
+    pub name_values: Vec<ToggleValue>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModeValue {
+    pub name_synonym: Vec<String>,
+    pub lang: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModeSettingValue {
+    pub setting_synonym: Vec<String>,
+    pub lang: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModeSetting {
+    pub setting_name: String,
+    pub setting_values: Vec<ModeSettingValue>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Mode {
+    pub name: String,
+    pub name_values: Vec<ModeValue>,
+    pub settings: Vec<ModeSetting>,
+    pub ordered: bool,
+}
+
 #[derive(Debug, Serialize)]
 pub enum TemperatureUnit {
     #[serde(rename = "C")]
@@ -81,3 +202,305 @@ pub enum TemperatureUnit {
     #[serde(rename = "F")]
     Fahrenheit,
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum OccupancySensorType {
+    Pir,
+    Ultrasonic,
+    PhysicalContact,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OccupancySensorConfiguration {
+    pub occupancy_sensor_type: OccupancySensorType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub occupied_to_unoccupied_delay_sec: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Occupancy {
+    Occupied,
+    Unoccupied,
+    Unknown,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DescriptiveCapabilities {
+    pub available_states: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SupportedSensorState {
+    // E.g. 'WaterLeak' or 'SmokeLevel'
+    pub name: String,
+    pub descriptive_capabilities: DescriptiveCapabilities,
+    // Only for sensors that also report a measurement, e.g. 'PM2.5'
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub numeric_capabilities: Option<NumericCapabilities>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NumericCapabilities {
+    // E.g. 'MICROGRAMS_PER_CUBIC_METER'
+    pub raw_value_unit: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CurrentSensorState {
+    pub name: String,
+    // One of the available states of the supported sensor state with the same name
+    pub current_sensor_state: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_value: Option<f32>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorModel {
+    Rgb,
+    Hsv,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ColorTemperatureRange {
+    pub temperature_min_k: u32,
+    pub temperature_max_k: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HumiditySetpointRange {
+    pub min_percent: isize,
+    pub max_percent: isize,
+}
+
+impl HumiditySetpointRange {
+    pub fn clamp(&self, humidity: isize) -> isize {
+        humidity.clamp(self.min_percent, self.max_percent)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ColorRGB {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+// Google Home represents rgb colors as a single integer, e.g. 0xFF0000 for red
+impl From<u32> for ColorRGB {
+    fn from(spectrum: u32) -> Self {
+        Self {
+            r: (spectrum >> 16) as u8,
+            g: (spectrum >> 8) as u8,
+            b: spectrum as u8,
+        }
+    }
+}
+
+impl From<ColorRGB> for u32 {
+    fn from(color: ColorRGB) -> Self {
+        (color.r as u32) << 16 | (color.g as u32) << 8 | color.b as u32
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "RawColor", try_from = "RawColor")]
+pub enum Color {
+    // Color temperature in Kelvin
+    Temperature(u32),
+    Rgb(ColorRGB),
+}
+
+// The state reports temperatureK and spectrumRgb, while the commands use temperature and
+// spectrumRGB and can also include the name of the color
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawColor {
+    #[serde(alias = "temperature", skip_serializing_if = "Option::is_none")]
+    temperature_k: Option<u32>,
+    #[serde(alias = "spectrumRGB", skip_serializing_if = "Option::is_none")]
+    spectrum_rgb: Option<u32>,
+}
+
+impl From<Color> for RawColor {
+    fn from(color: Color) -> Self {
+        match color {
+            Color::Temperature(temperature) => Self {
+                temperature_k: Some(temperature),
+                ..Default::default()
+            },
+            Color::Rgb(color) => Self {
+                spectrum_rgb: Some(color.into()),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl TryFrom<RawColor> for Color {
+    type Error = &'static str;
+
+    fn try_from(color: RawColor) -> Result<Self, Self::Error> {
+        match color {
+            RawColor {
+                spectrum_rgb: Some(spectrum),
+                ..
+            } => Ok(Color::Rgb(spectrum.into())),
+            RawColor {
+                temperature_k: Some(temperature),
+                ..
+            } => Ok(Color::Temperature(temperature)),
+            _ => Err("Unsupported color, expected a temperature or rgb spectrum"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+
+    use super::*;
+
+    struct Switch;
+
+    #[async_trait]
+    impl OnOff for Switch {
+        async fn on(&self) -> Result<bool, ErrorCode> {
+            Ok(true)
+        }
+
+        async fn set_on(&self, _on: bool) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn has_trait_by_name() {
+        assert!(has_trait(&Switch, "OnOff"));
+        assert!(has_trait(&Switch, "action.devices.traits.OnOff"));
+        assert!(!has_trait(&Switch, "Brightness"));
+        assert!(!has_trait(&Switch, "action.devices.traits.Brightness"));
+        assert!(!has_trait(&Switch, "Unknown"));
+
+        assert!(has_any(&Switch, &["Brightness", "OnOff"]));
+        assert!(!has_any(&Switch, &["Brightness", "Scene"]));
+        assert!(!has_any(&Switch, &[]));
+
+        assert!(Cast::<dyn OnOff>::has(&Switch));
+        assert!(!Cast::<dyn Brightness>::has(&Switch));
+    }
+
+    #[test]
+    fn set_toggles_command() {
+        let command: Command = serde_json::from_value(serde_json::json!({
+            "command": "action.devices.commands.SetToggles",
+            "params": {
+                "updateToggleSettings": {
+                    "socket_1": true,
+                    "socket_2": false
+                }
+            }
+        }))
+        .unwrap();
+
+        let Command::SetToggles {
+            update_toggle_settings,
+        } = command
+        else {
+            panic!("Expected SetToggles, got {command:?}");
+        };
+        assert_eq!(update_toggle_settings.get("socket_1"), Some(&true));
+        assert_eq!(update_toggle_settings.get("socket_2"), Some(&false));
+    }
+
+    struct Dimmer(std::sync::Mutex<u8>);
+
+    #[async_trait]
+    impl Brightness for Dimmer {
+        async fn brightness(&self) -> Result<u8, ErrorCode> {
+            Ok(*self.0.lock().unwrap())
+        }
+
+        async fn set_brightness(&self, brightness: u8) -> Result<(), ErrorCode> {
+            *self.0.lock().unwrap() = brightness;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn brightness_relative_command() {
+        let command: Command = serde_json::from_value(serde_json::json!({
+            "command": "action.devices.commands.BrightnessRelative",
+            "params": {
+                "brightnessRelativePercent": -10
+            }
+        }))
+        .unwrap();
+
+        let Command::BrightnessRelative {
+            brightness_relative_percent,
+        } = command
+        else {
+            panic!("Expected BrightnessRelative, got {command:?}");
+        };
+        assert_eq!(brightness_relative_percent, -10);
+
+        let dimmer = Dimmer(std::sync::Mutex::new(50));
+        dimmer
+            .set_brightness_relative(brightness_relative_percent)
+            .await
+            .unwrap();
+        assert_eq!(dimmer.brightness().await, Ok(40));
+
+        // The result is clamped to a valid percentage
+        dimmer.set_brightness_relative(-50).await.unwrap();
+        assert_eq!(dimmer.brightness().await, Ok(0));
+        dimmer.set_brightness_relative(127).await.unwrap();
+        assert_eq!(dimmer.brightness().await, Ok(100));
+    }
+
+    struct Hygrometer;
+
+    #[async_trait]
+    impl HumiditySetting for Hygrometer {
+        fn query_only_humidity_setting(&self) -> Option<bool> {
+            Some(true)
+        }
+
+        async fn humidity_ambient_percent(&self) -> Result<isize, ErrorCode> {
+            Ok(45)
+        }
+    }
+
+    #[tokio::test]
+    async fn set_humidity_command() {
+        let command: Command = serde_json::from_value(serde_json::json!({
+            "command": "action.devices.commands.SetHumidity",
+            "params": {
+                "humidity": 50
+            }
+        }))
+        .unwrap();
+
+        let Command::SetHumidity { humidity } = command else {
+            panic!("Expected SetHumidity, got {command:?}");
+        };
+        assert_eq!(humidity, 50);
+
+        // Query only devices do not have a setpoint
+        assert_eq!(Hygrometer.humidity_setpoint_percent().await, Ok(None));
+        assert_eq!(
+            Hygrometer.set_humidity_setpoint(humidity).await,
+            Err(DeviceError::ActionNotAvailable.into())
+        );
+    }
+}
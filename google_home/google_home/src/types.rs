@@ -1,6 +1,6 @@
 use serde::Serialize;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub enum Type {
     #[serde(rename = "action.devices.types.KETTLE")]
     Kettle,
@@ -12,10 +12,22 @@ pub enum Type {
     Scene,
     #[serde(rename = "action.devices.types.AIRPURIFIER")]
     AirPurifier,
+    #[serde(rename = "action.devices.types.DEHUMIDIFIER")]
+    Dehumidifier,
     #[serde(rename = "action.devices.types.DOOR")]
     Door,
     #[serde(rename = "action.devices.types.WINDOW")]
     Window,
     #[serde(rename = "action.devices.types.DRAWER")]
     Drawer,
+    #[serde(rename = "action.devices.types.BLINDS")]
+    Blinds,
+    #[serde(rename = "action.devices.types.SENSOR")]
+    Sensor,
+    #[serde(rename = "action.devices.types.SMOKE_DETECTOR")]
+    SmokeDetector,
+    #[serde(rename = "action.devices.types.SWITCH")]
+    Switch,
+    #[serde(rename = "action.devices.types.SPRINKLER")]
+    Sprinkler,
 }
@@ -0,0 +1,73 @@
+// All commands defined by the Google Home traits, see: https://developers.home.google.com/cloud-to-cloud/traits
+// Unknown commands are a compile error, so this needs to be updated when Google adds new commands
+pub const KNOWN_COMMANDS: &[&str] = &[
+    "action.devices.commands.ActivateScene",
+    "action.devices.commands.appInstall",
+    "action.devices.commands.appSearch",
+    "action.devices.commands.appSelect",
+    "action.devices.commands.ArmDisarm",
+    "action.devices.commands.BrightnessAbsolute",
+    "action.devices.commands.BrightnessRelative",
+    "action.devices.commands.Charge",
+    "action.devices.commands.ColorAbsolute",
+    "action.devices.commands.ColorLoop",
+    "action.devices.commands.Cook",
+    "action.devices.commands.Dispense",
+    "action.devices.commands.Dock",
+    "action.devices.commands.EnableDisableGuestNetwork",
+    "action.devices.commands.EnableDisableNetworkProfile",
+    "action.devices.commands.Fill",
+    "action.devices.commands.GetCameraStream",
+    "action.devices.commands.GetGuestNetworkPassword",
+    "action.devices.commands.HumidityRelative",
+    "action.devices.commands.Locate",
+    "action.devices.commands.LockUnlock",
+    "action.devices.commands.mediaClosedCaptioningOff",
+    "action.devices.commands.mediaClosedCaptioningOn",
+    "action.devices.commands.mediaNext",
+    "action.devices.commands.mediaPause",
+    "action.devices.commands.mediaPrevious",
+    "action.devices.commands.mediaRepeatMode",
+    "action.devices.commands.mediaResume",
+    "action.devices.commands.mediaSeekRelative",
+    "action.devices.commands.mediaSeekToPosition",
+    "action.devices.commands.mediaShuffle",
+    "action.devices.commands.mediaStop",
+    "action.devices.commands.mute",
+    "action.devices.commands.NextInput",
+    "action.devices.commands.OnOff",
+    "action.devices.commands.OpenClose",
+    "action.devices.commands.OpenCloseRelative",
+    "action.devices.commands.PauseUnpause",
+    "action.devices.commands.PreviousInput",
+    "action.devices.commands.Reboot",
+    "action.devices.commands.relativeChannel",
+    "action.devices.commands.returnChannel",
+    "action.devices.commands.Reverse",
+    "action.devices.commands.RotateAbsolute",
+    "action.devices.commands.selectChannel",
+    "action.devices.commands.SetFanSpeed",
+    "action.devices.commands.SetFanSpeedRelative",
+    "action.devices.commands.SetHumidity",
+    "action.devices.commands.SetInput",
+    "action.devices.commands.SetModes",
+    "action.devices.commands.SetTemperature",
+    "action.devices.commands.SetToggles",
+    "action.devices.commands.setVolume",
+    "action.devices.commands.Sleep",
+    "action.devices.commands.SoftwareUpdate",
+    "action.devices.commands.StartStop",
+    "action.devices.commands.StopEffect",
+    "action.devices.commands.TemperatureRelative",
+    "action.devices.commands.TestNetworkSpeed",
+    "action.devices.commands.ThermostatSetMode",
+    "action.devices.commands.ThermostatTemperatureSetpoint",
+    "action.devices.commands.ThermostatTemperatureSetRange",
+    "action.devices.commands.TimerAdjust",
+    "action.devices.commands.TimerCancel",
+    "action.devices.commands.TimerPause",
+    "action.devices.commands.TimerResume",
+    "action.devices.commands.TimerStart",
+    "action.devices.commands.volumeRelative",
+    "action.devices.commands.Wake",
+];
@@ -1,19 +1,25 @@
 #![feature(let_chains)]
 #![feature(iter_intersperse)]
+mod known_commands;
+
+use std::collections::HashSet;
+
+use known_commands::KNOWN_COMMANDS;
 use proc_macro::TokenStream;
 use quote::quote;
 use syn::parse::Parse;
 use syn::punctuated::Punctuated;
 use syn::token::Brace;
 use syn::{
-    braced, parse_macro_input, GenericArgument, Ident, LitStr, Path, PathArguments, PathSegment,
-    ReturnType, Signature, Token, Type, TypePath,
+    braced, parse_macro_input, Block, GenericArgument, Ident, LitStr, Path, PathArguments,
+    PathSegment, ReturnType, Signature, Token, Type, TypePath,
 };
 
 mod kw {
     use syn::custom_keyword;
 
     custom_keyword!(required);
+    custom_keyword!(helper);
 }
 
 #[derive(Debug)]
@@ -33,15 +39,26 @@ impl Parse for FieldAttribute {
     }
 }
 
+// Functions can optionally provide a default implementation
+fn parse_default(input: syn::parse::ParseStream) -> syn::Result<Option<Block>> {
+    if input.peek(Brace) {
+        Ok(Some(input.parse()?))
+    } else {
+        Ok(None)
+    }
+}
+
 #[derive(Debug)]
 struct FieldState {
     sign: Signature,
+    default: Option<Block>,
 }
 
 impl Parse for FieldState {
     fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
         Ok(Self {
             sign: input.parse()?,
+            default: parse_default(input)?,
         })
     }
 }
@@ -51,6 +68,7 @@ struct FieldExecute {
     name: LitStr,
     _fat_arrow_token: Token![=>],
     sign: Signature,
+    default: Option<Block>,
 }
 
 impl Parse for FieldExecute {
@@ -59,6 +77,7 @@ impl Parse for FieldExecute {
             name: input.parse()?,
             _fat_arrow_token: input.parse()?,
             sign: input.parse()?,
+            default: parse_default(input)?,
         })
     }
 }
@@ -67,12 +86,17 @@ impl Parse for FieldExecute {
 enum Field {
     Attribute(FieldAttribute),
     State(FieldState),
+    // Functions marked with `helper` are not part of the state, but can be used by the commands
+    Method(FieldState),
     Execute(FieldExecute),
 }
 
 impl Parse for Field {
     fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
-        if input.peek(Ident) {
+        if input.peek(kw::helper) && !input.peek2(Token![:]) {
+            input.parse::<kw::helper>()?;
+            Ok(Field::Method(input.parse()?))
+        } else if input.peek(Ident) {
             Ok(Field::Attribute(input.parse()?))
         } else if input.peek(LitStr) {
             Ok(Field::Execute(input.parse()?))
@@ -113,14 +137,78 @@ struct Input {
     traits: Punctuated<Trait, Token![,]>,
 }
 
-// TODO: Error on duplicate name?
 impl Parse for Input {
     fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
-        Ok(Self {
+        let input = Self {
             ty: input.parse()?,
             _comma: input.parse()?,
             traits: input.parse_terminated(Trait::parse, Token![,])?,
-        })
+        };
+
+        input.check_duplicates()?;
+        input.check_commands()?;
+
+        Ok(input)
+    }
+}
+
+impl Input {
+    fn check_duplicates(&self) -> syn::Result<()> {
+        let mut names = HashSet::new();
+        let mut idents = HashSet::new();
+
+        let errors = self.traits.iter().flat_map(|t| {
+            let name = (!names.insert(t.name.value())).then(|| {
+                syn::Error::new(
+                    t.name.span(),
+                    format!("Duplicate trait name '{}'", t.name.value()),
+                )
+            });
+
+            let ident = (!idents.insert(t.ident.to_string())).then(|| {
+                syn::Error::new(
+                    t.ident.span(),
+                    format!("Duplicate trait name '{}'", t.ident),
+                )
+            });
+
+            name.into_iter().chain(ident)
+        });
+
+        match errors.reduce(|mut acc, error| {
+            acc.combine(error);
+            acc
+        }) {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    // Catch typos in the command names, as those would otherwise only show up at runtime
+    fn check_commands(&self) -> syn::Result<()> {
+        let errors = self
+            .traits
+            .iter()
+            .flat_map(|t| t.fields.iter())
+            .filter_map(|f| match f {
+                Field::Execute(execute)
+                    if !KNOWN_COMMANDS.contains(&execute.name.value().as_str()) =>
+                {
+                    Some(syn::Error::new(
+                        execute.name.span(),
+                        format!("Unknown command '{}'", execute.name.value()),
+                    ))
+                }
+                _ => None,
+            });
+
+        match errors.reduce(|mut acc, error| {
+            acc.combine(error);
+            acc
+        }) {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
     }
 }
 
@@ -266,6 +354,14 @@ fn get_command_enum(traits: &Punctuated<Trait, Token![,]>) -> proc_macro2::Token
                     execute.name.span(),
                 );
 
+                // Commands without parameters do not need to include params
+                if execute.sign.inputs.len() <= 1 {
+                    return Some(quote! {
+                        #[serde(rename = #name)]
+                        #ident
+                    });
+                }
+
                 let parameters = execute.sign.inputs.iter().skip(1);
 
                 Some(quote! {
@@ -299,7 +395,7 @@ fn get_trait_enum(traits: &Punctuated<Trait, Token![,]>) -> proc_macro2::TokenSt
     });
 
     quote! {
-        #[derive(Debug, serde::Serialize)]
+        #[derive(Debug, Clone, serde::Serialize)]
         pub enum Trait {
             #(#items,)*
         }
@@ -333,8 +429,12 @@ fn get_trait(t: &Trait) -> proc_macro2::TokenStream {
             };
 
             let inner = extract_type_from_result(ty);
+            if let Some(default) = &state.default {
+                quote! {
+                    #sign #default
+                }
             // If the default type is marked as optional, respond None by default
-            if extract_type_from_option(inner.unwrap_or(ty)).is_some() {
+            } else if extract_type_from_option(inner.unwrap_or(ty)).is_some() {
                 if inner.is_some() {
                     quote! {
                         #sign {
@@ -354,10 +454,16 @@ fn get_trait(t: &Trait) -> proc_macro2::TokenStream {
                 }
             }
         }
-        Field::Execute(execute) => {
-            let sign = &execute.sign;
-            quote! {
-                #sign;
+        Field::Method(FieldState { sign, default })
+        | Field::Execute(FieldExecute { sign, default, .. }) => {
+            if let Some(default) = default {
+                quote! {
+                    #sign #default
+                }
+            } else {
+                quote! {
+                    #sign;
+                }
             }
         }
     });
@@ -439,6 +545,17 @@ pub fn traits(item: TokenStream) -> TokenStream {
     let command_enum = get_command_enum(&traits);
     let trait_enum = get_trait_enum(&traits);
 
+    // Traits can be referred to by either their name in Google Home or their identifier
+    let has_trait = traits.iter().map(|t| {
+        let name = &t.name;
+        let ident = &t.ident;
+        let ident_str = ident.to_string();
+
+        quote! {
+            #name | #ident_str => ::automation_cast::Cast::<dyn #ident>::has(device),
+        }
+    });
+
     let sync = traits.iter().map(|t| {
         let ident = &t.ident;
 
@@ -499,8 +616,14 @@ pub fn traits(item: TokenStream) -> TokenStream {
                     quote! {}
                 };
 
+                let pattern = if parameters.is_empty() {
+                    quote! { Command::#command_name }
+                } else {
+                    quote! { Command::#command_name {#(#parameters,)*} }
+                };
+
                 Some(quote! {
-                    Command::#command_name {#(#parameters,)*} => {
+                    #pattern => {
                         if let Some(t) = self.cast() as Option<&dyn #ident> {
                             t.#f_name(#(#parameters,)*) #asyncness #errors;
                             serde_json::to_value(t.get_state().await?)?
@@ -536,6 +659,19 @@ pub fn traits(item: TokenStream) -> TokenStream {
 		#command_enum
 		#trait_enum
 
+        /// Check if the device implements the trait with the given name, e.g. "OnOff"
+        pub fn has_trait<D>(device: &D, name: &str) -> bool {
+            match name {
+                #(#has_trait)*
+                _ => false,
+            }
+        }
+
+        /// Check if the device implements any of the traits
+        pub fn has_any<D>(device: &D, names: &[&str]) -> bool {
+            names.iter().any(|name| has_trait(device, name))
+        }
+
         #[async_trait::async_trait]
 		impl<D> #fulfillment for D where D: #ty
 		{
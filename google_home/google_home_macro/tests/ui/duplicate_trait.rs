@@ -0,0 +1,13 @@
+use google_home_macro::traits;
+
+traits! {
+    google_home::Device,
+    "action.devices.traits.OnOff" => trait OnOff {
+        async fn on(&self) -> Result<bool, ()>,
+    },
+    "action.devices.traits.OnOff" => trait OnOff {
+        async fn on(&self) -> Result<bool, ()>,
+    }
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use google_home_macro::traits;
+
+traits! {
+    google_home::Device,
+    "action.devices.traits.OnOff" => trait OnOff {
+        async fn on(&self) -> Result<bool, ()>,
+        "action.devices.commands.OnOf" => async fn set_on(&self, on: bool) -> Result<(), ()>,
+    }
+}
+
+fn main() {}
@@ -1,32 +1,36 @@
 mod web;
 
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::path::Path;
 use std::process;
+use std::time::Duration;
 
 use anyhow::anyhow;
 use automation_lib::config::{FulfillmentConfig, MqttConfig};
+use automation_lib::device::HealthStatus;
 use automation_lib::device_manager::DeviceManager;
-use automation_lib::helpers;
-use automation_lib::mqtt::{self, WrappedAsyncClient};
-use automation_lib::ntfy::Ntfy;
-use automation_lib::presence::Presence;
+use automation_lib::metrics;
+use automation_lib::mqtt::{self, MessageDeduplicator, WrappedAsyncClient};
 use axum::extract::{FromRef, State};
 use axum::http::StatusCode;
-use axum::routing::post;
+use axum::routing::{get, post};
 use axum::{Json, Router};
+use axum_server::tls_rustls::RustlsConfig;
+use axum_server::Handle;
 use dotenvy::dotenv;
-use google_home::{GoogleHome, Request, Response};
+use google_home::{GoogleHome, RecentRequestCache, Request, Response};
 use mlua::LuaSerdeExt;
 use rumqttc::AsyncClient;
 use tokio::net::TcpListener;
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, info};
 use web::{ApiError, User};
 
 #[derive(Clone)]
 struct AppState {
     pub openid_url: String,
     pub device_manager: DeviceManager,
+    pub recent_requests: RecentRequestCache,
 }
 
 impl FromRef<AppState> for String {
@@ -54,7 +58,8 @@ async fn fulfillment(
     Json(payload): Json<Request>,
 ) -> Result<Json<Response>, ApiError> {
     debug!(username = user.preferred_username, "{payload:#?}");
-    let gc = GoogleHome::new(&user.preferred_username);
+    let gc = GoogleHome::new(&user.preferred_username)
+        .with_recent_requests(state.recent_requests.clone());
     let devices = state.device_manager.devices().await;
     let result = gc
         .handle_request(payload, &devices)
@@ -66,6 +71,37 @@ async fn fulfillment(
     Ok(Json(result))
 }
 
+async fn health(State(state): State<AppState>) -> Json<HashMap<String, HealthStatus>> {
+    Json(state.device_manager.health().await)
+}
+
+// Resolves when the application is asked to stop, either through Ctrl+C or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    info!("Shutting down...");
+}
+
 async fn app() -> anyhow::Result<()> {
     dotenv().ok();
 
@@ -74,53 +110,34 @@ async fn app() -> anyhow::Result<()> {
 
     info!("Starting automation_rs...");
 
+    // Installed before the config is loaded so that the devices that are added are recorded
+    let metrics_handle = metrics::install()?;
+
     // Setup the device handler
     let device_manager = DeviceManager::new().await;
 
     let fulfillment_config = {
         let lua = mlua::Lua::new();
 
-        lua.set_warning_function(|_lua, text, _cont| {
-            warn!("{text}");
-            Ok(())
-        });
-
-        let automation = lua.create_table()?;
         let event_channel = device_manager.event_channel();
-        let new_mqtt_client = lua.create_function(move |lua, config: mlua::Value| {
+        automation_lib::lua::register(&lua, &device_manager, move |lua, config| {
             let config: MqttConfig = lua.from_value(config)?;
+            let offline_queue_size = config.offline_queue_size;
+            let deduplicate_retained = config.deduplicate_retained;
+            let failover = config.failover_options();
 
             // Create a mqtt client
             // TODO: When starting up, the devices are not yet created, this could lead to a device being out of sync
             let (client, eventloop) = AsyncClient::new(config.into(), 100);
-            mqtt::start(eventloop, &event_channel);
-
-            Ok(WrappedAsyncClient(client))
-        })?;
+            let deduplicator = deduplicate_retained.then(MessageDeduplicator::default);
+            let (status, broker) =
+                mqtt::start(eventloop, &event_channel, deduplicator.clone(), failover);
 
-        automation.set("new_mqtt_client", new_mqtt_client)?;
-        automation.set("device_manager", device_manager.clone())?;
-
-        let util = lua.create_table()?;
-        let get_env = lua.create_function(|_lua, name: String| {
-            std::env::var(name).map_err(mlua::ExternalError::into_lua_err)
-        })?;
-        util.set("get_env", get_env)?;
-        let get_hostname = lua.create_function(|_lua, ()| {
-            hostname::get()
-                .map(|name| name.to_str().unwrap_or("unknown").to_owned())
-                .map_err(mlua::ExternalError::into_lua_err)
+            Ok(WrappedAsyncClient::new(client, status, offline_queue_size)
+                .with_broker(broker)
+                .with_deduplicator(deduplicator))
         })?;
-        util.set("get_hostname", get_hostname)?;
-        automation.set("util", util)?;
-
-        lua.globals().set("automation", automation)?;
-
         automation_devices::register_with_lua(&lua)?;
-        helpers::register_with_lua(&lua)?;
-        lua.globals().set("Ntfy", lua.create_proxy::<Ntfy>()?)?;
-        lua.globals()
-            .set("Presence", lua.create_proxy::<Presence>()?)?;
 
         // TODO: Make this not hardcoded
         let config_filename = std::env::var("AUTOMATION_CONFIG").unwrap_or("./config.lua".into());
@@ -144,22 +161,80 @@ async fn app() -> anyhow::Result<()> {
         }
     };
 
+    device_manager.set_health_interval(Duration::from_secs(
+        fulfillment_config.health_interval_seconds,
+    ));
+
+    if let Some(metrics_config) = fulfillment_config.metrics.clone() {
+        // Kept separate from the fulfillment so it can be scraped without authentication
+        let app = Router::new().route(
+            "/metrics",
+            get(move || std::future::ready(metrics_handle.render())),
+        );
+
+        let addr: SocketAddr = metrics_config.into();
+        info!("Metrics available on http://{addr}/metrics");
+        let listener = TcpListener::bind(addr).await?;
+        tokio::spawn(async move {
+            if let Err(err) = axum::serve(listener, app).await {
+                error!("Metrics server failed: {err}");
+            }
+        });
+    }
+
     // Create google home fulfillment route
     let fulfillment = Router::new().route("/google_home", post(fulfillment));
 
     // Combine together all the routes
     let app = Router::new()
         .nest("/fulfillment", fulfillment)
+        .route("/api/health", get(health))
+        .layer(web::cors_layer(fulfillment_config.cors.as_ref())?)
         .with_state(AppState {
             openid_url: fulfillment_config.openid_url.clone(),
-            device_manager,
+            device_manager: device_manager.clone(),
+            recent_requests: RecentRequestCache::new(fulfillment_config.request_cache_size),
         });
 
+    let tls = match (
+        fulfillment_config.tls_cert_path.clone(),
+        fulfillment_config.tls_key_path.clone(),
+    ) {
+        (Some(cert), Some(key)) => Some(RustlsConfig::from_pem_file(cert, key).await?),
+        (None, None) => None,
+        _ => {
+            return Err(anyhow!(
+                "Both tls_cert_path and tls_key_path need to be set to enable TLS"
+            ))
+        }
+    };
+
     // Start the web server
     let addr: SocketAddr = fulfillment_config.into();
-    info!("Server started on http://{addr}");
-    let listener = TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    if let Some(tls) = tls {
+        info!("Server started on https://{addr}");
+        let handle = Handle::new();
+        tokio::spawn({
+            let handle = handle.clone();
+            async move {
+                shutdown_signal().await;
+                handle.graceful_shutdown(Some(Duration::from_secs(5)));
+            }
+        });
+
+        axum_server::bind_rustls(addr, tls)
+            .handle(handle)
+            .serve(app.into_make_service())
+            .await?;
+    } else {
+        info!("Server started on http://{addr}");
+        let listener = TcpListener::bind(addr).await?;
+        axum::serve(listener, app)
+            .with_graceful_shutdown(shutdown_signal())
+            .await?;
+    }
+
+    device_manager.stop().await;
 
     Ok(())
 }
@@ -1,13 +1,16 @@
 use std::result;
 
+use anyhow::Context;
+use automation_lib::config::CorsConfig;
 use axum::async_trait;
 use axum::extract::{FromRef, FromRequestParts};
 use axum::http::request::Parts;
 use axum::http::status::InvalidStatusCode;
-use axum::http::StatusCode;
+use axum::http::{header, HeaderValue, Method, StatusCode};
 use axum::response::IntoResponse;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use tower_http::cors::{AllowOrigin, CorsLayer};
 
 #[derive(Debug, Error)]
 #[error("{source}")]
@@ -130,3 +133,124 @@ where
         }
     }
 }
+
+/// Build the CORS layer for the API, without config only requests from the same origin are allowed
+pub fn cors_layer(config: Option<&CorsConfig>) -> anyhow::Result<CorsLayer> {
+    let Some(config) = config else {
+        // Not sending any CORS headers makes the browser enforce the same-origin policy
+        return Ok(CorsLayer::new());
+    };
+
+    if config.allowed_origins.iter().any(|origin| origin == "*") {
+        return Ok(CorsLayer::permissive());
+    }
+
+    let origins = config
+        .allowed_origins
+        .iter()
+        .map(|origin| {
+            HeaderValue::from_str(origin).with_context(|| format!("Invalid CORS origin '{origin}'"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let methods = config
+        .allowed_methods
+        .iter()
+        .map(|method| {
+            Method::from_bytes(method.as_bytes())
+                .with_context(|| format!("Invalid CORS method '{method}'"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins))
+        .allow_methods(methods)
+        .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE])
+        .allow_credentials(config.allow_credentials))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::post;
+    use axum::Router;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    // Send a preflight request from the origin and return the allowed origin
+    async fn preflight(layer: CorsLayer, origin: &str) -> Option<String> {
+        let app = Router::new().route("/test", post(|| async {})).layer(layer);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let response = reqwest::Client::new()
+            .request(Method::OPTIONS, format!("http://{addr}/test"))
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .send()
+            .await
+            .unwrap();
+
+        response
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .map(|origin| origin.to_str().unwrap().to_owned())
+    }
+
+    #[tokio::test]
+    async fn cors() {
+        let config = CorsConfig {
+            allowed_origins: vec!["https://dashboard.example.com".into()],
+            allowed_methods: vec!["GET".into(), "POST".into()],
+            allow_credentials: true,
+        };
+
+        assert_eq!(
+            preflight(
+                cors_layer(Some(&config)).unwrap(),
+                "https://dashboard.example.com"
+            )
+            .await,
+            Some("https://dashboard.example.com".into())
+        );
+        assert_eq!(
+            preflight(
+                cors_layer(Some(&config)).unwrap(),
+                "https://evil.example.com"
+            )
+            .await,
+            None
+        );
+
+        let config = CorsConfig {
+            allowed_origins: vec!["*".into()],
+            ..config
+        };
+        assert_eq!(
+            preflight(
+                cors_layer(Some(&config)).unwrap(),
+                "https://evil.example.com"
+            )
+            .await,
+            Some("*".into())
+        );
+
+        assert_eq!(
+            preflight(cors_layer(None).unwrap(), "https://dashboard.example.com").await,
+            None
+        );
+    }
+
+    #[test]
+    fn invalid_method() {
+        let config = CorsConfig {
+            allowed_origins: vec!["https://dashboard.example.com".into()],
+            allowed_methods: vec!["NOT A METHOD".into()],
+            allow_credentials: false,
+        };
+
+        assert!(cors_layer(Some(&config)).is_err());
+    }
+}